@@ -20,6 +20,7 @@ use smartstring::alias::{String as SmartString};
 use diamond_types::{DTRange, HasLength};
 use diamond_types::causalgraph::agent_assignment::remote_ids::RemoteVersionSpan;
 use rle::SplitableSpan;
+use chrono::{DateTime, SecondsFormat, Utc};
 
 // Note this discards the fwd/backwards direction of the changes. This shouldn't matter in
 // practice given the whole operation is unitary.
@@ -185,6 +186,15 @@ pub struct TraceSimpleExportTxn {
     patches: SmallVec<[SimpleTextOp; 4]>,
 }
 
+/// Format the timestamp stored in operation metadata (milliseconds since the unix epoch) the same
+/// way we format file modification times.
+fn format_timestamp(millis: u64) -> Option<SmartString> {
+    let time = DateTime::<Utc>::from_timestamp_millis(millis as i64)?;
+    Some(time.to_rfc3339_opts(SecondsFormat::Secs, true).into())
+}
+
+/// Export the transformed operations as a simple editing trace. Transactions use the timestamps
+/// stored in the operations' metadata where possible, falling back to the passed timestamp.
 pub fn export_transformed(oplog: &ListOpLog, timestamp: String) -> TraceSimpleExportData {
     // The file format stores a set of transactions, and each transaction stores a list of patches.
     // It would be really simple to just export everything into one big transaction, but thats a bit
@@ -205,8 +215,16 @@ pub fn export_transformed(oplog: &ListOpLog, timestamp: String) -> TraceSimpleEx
 
     for (range, op) in oplog.iter_xf_operations() {
         if let Some(mut op) = op {
+            let mut lv = range.start;
             for RemoteVersionSpan(agent, seq_range) in oplog.cg.agent_assignment.iter_remote_mappings_range(range) {
-                let can_append = last_agent == Some(agent) || last_agent == None;
+                let time = oplog.metadata_at(lv)
+                    .and_then(|meta| meta.timestamp)
+                    .and_then(format_timestamp)
+                    .unwrap_or_else(|| timestamp.clone());
+                lv += seq_range.len();
+
+                let can_append = last_agent.is_none()
+                    || (last_agent == Some(agent) && current_txn.time == time);
 
                 let op_here = op.truncate_keeping_right(seq_range.len());
 
@@ -215,9 +233,11 @@ pub fn export_transformed(oplog: &ListOpLog, timestamp: String) -> TraceSimpleEx
                     assert!(!current_txn.patches.is_empty());
                     txns.push(current_txn);
                     current_txn = TraceSimpleExportTxn {
-                        time: timestamp.clone(),
+                        time,
                         patches: smallvec![],
                     };
+                } else {
+                    current_txn.time = time;
                 }
 
                 current_txn.patches.push(op_here.into());
@@ -0,0 +1,126 @@
+//! Helpers for safely modifying .dt files on disk.
+//!
+//! Files are never overwritten in place. New content is written to a temporary file in the same
+//! directory, flushed to disk and then renamed over the original file. Readers always see either
+//! the old file or the new file.
+//!
+//! Commands which read a file, modify it and write it back also hold an advisory lock for the
+//! whole operation, so concurrent invocations (eg two `dt set` calls from a script) run one after
+//! the other instead of silently dropping each other's operations. The lock is taken on a separate
+//! `<filename>.lock` file, because the .dt file itself is replaced on every write.
+//!
+//! Lock files are left behind when the command finishes. Deleting a lock file while another
+//! process is waiting on it would let a third process lock a new file with the same name, and then
+//! two processes would both think they hold the lock.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+/// An exclusive advisory lock on a file. The lock is released when this is dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Lock the named file for writing. This blocks until any other dt process holding the lock
+    /// releases it.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::lock(&lock_path(path.as_ref())?)
+    }
+
+    /// Lock several files. The locks are always taken in the same order, so two processes locking
+    /// the same set of files can't deadlock. If more than one path names the same file, it's only
+    /// locked once.
+    pub fn acquire_all<P: AsRef<Path>>(paths: &[P]) -> io::Result<Vec<Self>> {
+        let mut lock_paths = paths.iter()
+            .map(|p| lock_path(p.as_ref()))
+            .collect::<io::Result<Vec<_>>>()?;
+        lock_paths.sort();
+        lock_paths.dedup();
+        lock_paths.iter().map(|p| Self::lock(p)).collect()
+    }
+
+    fn lock(lock_path: &Path) -> io::Result<Self> {
+        let lock_file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)?;
+        lock_file.lock()?;
+        Ok(FileLock { _file: lock_file })
+    }
+}
+
+/// Returns the path of the lock file for the named file. The directory is canonicalized so
+/// different names for the same file share a lock.
+fn lock_path(path: &Path) -> io::Result<PathBuf> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut name: OsString = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    Ok(fs::canonicalize(dir)?.join(name))
+}
+
+/// Returns the path of a file next to path, with the given suffix appended to its name.
+pub fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Write data to a new file. This fails with [`io::ErrorKind::AlreadyExists`] if the file already
+/// exists. Checking for the file and creating it happen as a single atomic operation.
+pub fn write_new<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+
+    let result = file.write_all(data).and_then(|_| file.sync_all());
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// Atomically replace the contents of the named file with data.
+pub fn write_atomic<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let tmp_path = sibling_path(path, &format!(".{suffix}.tmp"));
+
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    // Make sure the rename itself is persisted. This isn't possible on every platform, so errors
+    // are ignored.
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
@@ -3,20 +3,26 @@
 
 // #![allow(unused_imports)]
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
 use std::fs::File;
+use std::hash::Hash;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use anyhow::Context;
-use git2::{BranchType, Commit, Oid, Repository};
+use git2::{BranchType, Commit, Delta, DiffFindOptions, FileMode, ObjectType, Oid, Repository, Signature, Time, Tree, TreeWalkMode, TreeWalkResult};
+use git2::build::TreeUpdateBuilder;
 use git2::ObjectType::Blob;
-use similar::{ChangeTag, TextDiff};
-use similar::utils::TextDiffRemapper;
-use smallvec::{SmallVec, smallvec};
+use smallvec::SmallVec;
 use indicatif::ProgressBar;
 use std::io::{BufWriter, Write};
 
 use diamond_types::list::*;
+use diamond_types::list::operation::TextOperation;
+use diamond_types::list::set_content::DiffGranularity;
+use diamond_types::{AgentId, CRDTKind, CreateValue, DTRange, Frontier, HasLength, LV, OpLog, Primitive, ROOT_CRDT_ID};
+use crate::log::{build_nodes, fmt_op, LogFilter, OpChunker};
 
 /// In the git repository for linux, there are commits (maybe just one commit?) with the same commit
 /// named twice in the parents list. Its this commit: 13e652800d1644dfedcd0d59ac95ef0beb7f3165
@@ -59,78 +65,132 @@ impl<'commit> UniqParentIds<'commit> {
     }
 }
 
-pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet: bool, map_out: Option<PathBuf>) -> anyhow::Result<ListOpLog> {
-    // let mut args: Args = argh::from_env();
-
+/// Open the git repository containing `input_path`. Returns the repository and the path of
+/// `input_path` relative to the root of the repository.
+fn open_repository(mut input_path: PathBuf) -> anyhow::Result<(Repository, PathBuf)> {
     if input_path.is_relative() {
         input_path = std::env::current_dir()?.join(input_path);
     }
     assert!(input_path.is_absolute());
 
     let mut repo_path = Repository::discover_path(&input_path, &[] as &[&PathBuf])?;
-    // assert!(input_path.starts_with(&repo_path));
 
-    // dbg!(&repo_path);
     if repo_path.ends_with(".git") {
         repo_path = repo_path.parent().unwrap().to_path_buf();
     }
-    // dbg!(&input_path, &repo_path);
-    let file_path = input_path.strip_prefix(&repo_path)?;
+    let file_path = input_path.strip_prefix(&repo_path)?.to_path_buf();
 
-    // dbg!(&repo_path, &file_path);
+    Ok((Repository::open(&repo_path)?, file_path))
+}
 
-    let repo = Repository::open(&repo_path)?;
-    let file = file_path;
-    let branch = branch.unwrap_or_else(|| "master".into());
+/// The parents and children of every commit reachable from a set of heads.
+struct CommitGraph {
+    parents: HashMap<Oid, SmallVec<[Oid; 3]>>,
+    children: HashMap<Oid, SmallVec<[Oid; 3]>>,
+    /// Commits with no parents.
+    roots: Vec<Oid>,
+}
 
-    let path = Path::new(&file);
+impl CommitGraph {
+    fn scan(repo: &Repository, heads: &[Oid]) -> anyhow::Result<Self> {
+        let mut scan_frontier = heads.to_vec();
+        let mut parents = HashMap::<Oid, SmallVec<[Oid; 3]>>::new();
+        let mut children = HashMap::<Oid, SmallVec<[Oid; 3]>>::new();
+        let mut roots = Vec::new();
 
-    if !quiet { println!("Loading {:?} from {:?}", path, repo.path()); }
+        // Mark the heads as having no children. (Unless they're an ancestor of another head.)
+        for h in heads {
+            children.entry(*h).or_default();
+        }
 
-    // let head = repo.head().unwrap();
-    let head = repo.find_branch(&branch, BranchType::Local).unwrap().into_reference();
+        while let Some(c_id) = scan_frontier.pop() {
+            if parents.contains_key(&c_id) { continue; }
 
-    let mut scan_frontier = Vec::new();
-    let mut fwd_frontier = Vec::new();
+            let commit = repo.find_commit(c_id)?;
 
-    // Could wrap this stuff up in a struct or something, but its not a big deal.
-    // let mut commits_seen = HashSet::new();
-    let mut commit_children = HashMap::<Oid, SmallVec<[Oid; 3]>>::new();
-    let mut commit_parents = HashMap::<Oid, SmallVec<[Oid; 3]>>::new();
+            parents.insert(c_id, UniqParentIds::new(&commit).collect());
+            for p_id in UniqParentIds::new(&commit) {
+                scan_frontier.push(p_id);
+                children.entry(p_id).or_default().push(c_id);
+            }
 
-    // (parents, children).
-    // let mut commit_info = HashMap::<Oid, (SmallVec<[Oid; 3]>, SmallVec<[Oid; 3]>)>::new();
+            if commit.parent_count() == 0 {
+                roots.push(c_id);
+            }
+        }
 
-    let c = head.peel_to_commit().unwrap();
-    scan_frontier.push(c.id());
-    // Mark the final change as having no children.
-    commit_children.insert(c.id(), smallvec![]);
+        Ok(Self { parents, children, roots })
+    }
 
-    let start = std::time::SystemTime::now();
+    /// Once a commit has been processed, add any of its children whose parents have all been
+    /// processed to the frontier.
+    fn push_ready_children<T>(&self, commit_id: Oid, processed: &HashMap<Oid, T>, frontier: &mut Vec<Oid>) {
+        for c in &self.children[&commit_id] {
+            if !processed.contains_key(c)
+                && self.parents[c].iter().all(|p_id| processed.contains_key(p_id))
+            {
+                frontier.push(*c);
+            }
+        }
+    }
+}
 
-    if !quiet { println!("Scanning frontier..."); }
-    while let Some(c_id) = scan_frontier.pop() {
-        // println!("cc: {} / cp: {} / sf {} / ff {}", commit_children.len(), commit_parents.len(), scan_frontier.len(), fwd_frontier.len());
-        if commit_parents.contains_key(&c_id) { continue; }
+/// Take the state stored for a commit. The state is removed once its last child has taken it.
+fn take_state<K: Hash + Eq + Copy + Display, T: Clone>(states: &mut HashMap<K, (T, usize)>, id: K) -> T {
+    let (state, num_children) = states.get_mut(&id)
+        .with_context(|| format!("When looking up {}", id))
+        .unwrap();
 
-        // println!("Scanning {:?}", c);
+    debug_assert!(*num_children >= 1);
+    if *num_children == 1 {
+        states.remove(&id).unwrap().0
+    } else {
+        *num_children -= 1;
+        state.clone()
+    }
+}
 
-        let commit = repo.find_commit(c_id)?;
+/// Like [`take_state`], but for children which don't need the state.
+fn release_state<K: Hash + Eq + Copy, T>(states: &mut HashMap<K, (T, usize)>, id: K) {
+    let (_, num_children) = states.get_mut(&id).unwrap();
+    *num_children -= 1;
+    if *num_children == 0 { states.remove(&id); }
+}
 
-        commit_parents.insert(c_id, UniqParentIds::new(&commit).collect());
-        for p_id in UniqParentIds::new(&commit) {
-            scan_frontier.push(p_id);
+/// The name of the agent used for changes made in a commit.
+fn author_name(commit: &Commit) -> String {
+    let sig = commit.author();
+    let mut author = sig.name().unwrap_or("unknown");
+
+    // Diamond types only allows agent IDs up to 50 bytes long. We'll trim the
+    // name down to 30 bytes, just to be on the safe side.
+    if author.len() > 30 {
+        let mut end = 30;
+        // Make sure we cut at a unicode-safe boundary.
+        while !author.is_char_boundary(end) { end -= 1; }
+        author = &author[..end];
+    }
+    author.into()
+}
 
-            commit_children.entry(p_id).or_insert_with(|| SmallVec::new())
-                .push(c_id);
-        }
+pub fn extract_from_git(input_path: PathBuf, branch: Option<String>, quiet: bool, map_out: Option<PathBuf>) -> anyhow::Result<ListOpLog> {
+    let (repo, file) = open_repository(input_path)?;
+    let branch = branch.unwrap_or_else(|| "master".into());
 
-        if commit.parent_count() == 0 {
-            fwd_frontier.push(commit.id());
-        }
-    }
+    let path = Path::new(&file);
+
+    if !quiet { println!("Loading {:?} from {:?}", path, repo.path()); }
+
+    // let head = repo.head().unwrap();
+    let head = repo.find_branch(&branch, BranchType::Local).unwrap().into_reference();
+    let c = head.peel_to_commit().unwrap();
 
-    drop(scan_frontier);
+    let start = std::time::SystemTime::now();
+
+    if !quiet { println!("Scanning frontier..."); }
+    let graph = CommitGraph::scan(&repo, &[c.id()])?;
+    let commit_parents = &graph.parents;
+    let mut fwd_frontier = graph.roots.clone();
 
     let scan_commits_time = std::time::SystemTime::now();
 
@@ -271,52 +331,9 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
 
                 if branch.content() != &new {
                     git_bytes_read += new.len();
-                    let sig = commit.author();
-                    let mut author = sig.name().unwrap_or("unknown");
-
-                    // Diamond types only allows agent IDs up to 50 bytes long. We'll trim the
-                    // name down to 30 bytes, just to be on the safe side.
-                    if author.len() > 30 {
-                        let mut end = 30;
-                        // Make sure we cut at a unicode-safe boundary.
-                        while !author.is_char_boundary(end) { end -= 1; }
-                        author = &author[..end];
-                    }
-                    let agent = oplog.get_or_create_agent_id(author);
-
-                    let branch_string = branch.content().to_string();
-                    let old = branch_string.as_str();
-                    let diff = TextDiff::from_chars(old, &new);
-                    // I could just consume diff.ops() directly here - but that would be awkward
-                    // without the string utilities.
-                    // dbg!(diff.ops());
-
-                    let remapper = TextDiffRemapper::from_text_diff(&diff, old, &new);
-                    // .collect::<Vec<_>>();
-                    // dbg!(changes);
-                    // for change in diff.iter
-
-                    let mut pos = 0;
-                    for (tag, str) in diff.ops().iter()
-                        .flat_map(move |x| remapper.iter_slices(x)) {
-                        // dbg!(tag, str);
-                        let len = str.chars().count();
-                        // dbg!((tag, str, len));
-                        match tag {
-                            ChangeTag::Equal => pos += len,
-                            ChangeTag::Delete => {
-                                branch.delete(&mut oplog, agent, pos .. pos+len);
-
-                                // let op = branch.make_delete_op(pos, len);
-                                // apply_local_operation(&mut oplog, &mut branch, agent, &[op]);
-                            }
-                            ChangeTag::Insert => {
-                                branch.insert(&mut oplog, agent, pos, str);
-                                // local_insert(&mut oplog, &mut branch, agent, pos, str);
-                                pos += len;
-                            }
-                        }
-                    }
+                    let agent = oplog.get_or_create_agent_id(&author_name(&commit));
+
+                    branch.set_content(&mut oplog, agent, &new);
 
                     assert_eq!(branch.content(), &new);
                     // println!("branch '{}' -> '{}'", old, branch.content);
@@ -334,21 +351,11 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
             oid_here
         } else { stored_oid.unwrap_or(commit_id) }; // the commit ID here is pointless but eh.
 
-        let children = commit_children.get(&commit_id).unwrap();
-        branch_at_oid.insert(commit_id, (branch, oid_here, children.len()));
-
-        // Go through all the children. Add any child which has all its dependencies met to the
-        // frontier set.
-        for c in children {
-            if !branch_at_oid.contains_key(c) {
-                let processed_all = commit_parents[c].iter()
-                    .all(|p_id| branch_at_oid.contains_key(p_id));
-                if processed_all {
-                    // println!("Adding {:?} to children", c);
-                    fwd_frontier.push(*c);
-                }
-            }
-        }
+        let num_children = graph.children[&commit_id].len();
+        branch_at_oid.insert(commit_id, (branch, oid_here, num_children));
+
+        // Add any child which has all its dependencies met to the frontier set.
+        graph.push_ready_children(commit_id, &branch_at_oid, &mut fwd_frontier);
     }
     bar.finish();
 
@@ -382,3 +389,680 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
 
     Ok(oplog)
 }
+
+/// A file in the repository, as of some commit.
+#[derive(Debug, Clone)]
+struct RepoFile {
+    /// The file's key in the root map. This is the path the file was created with.
+    key: String,
+    path: String,
+    /// The versions which last set the file's path. There's more than one if the file was renamed
+    /// concurrently in different branches.
+    path_v: Frontier,
+    text: LV,
+    /// The versions which last changed the file's content.
+    text_v: Frontier,
+    /// The git blob with the file's content, or None if the content came from merging concurrent
+    /// edits.
+    oid: Option<Oid>,
+    content: Rc<str>,
+}
+
+/// The files in the repository at some commit, keyed by the LV of each file's map CRDT.
+#[derive(Debug, Clone, Default)]
+struct RepoState {
+    version: Frontier,
+    files: BTreeMap<LV, RepoFile>,
+}
+
+#[derive(Debug)]
+enum FileChange {
+    Add(String, Oid),
+    Modify(String, Oid),
+    Delete(String),
+    Rename(String, String, Oid),
+}
+
+fn dominators(oplog: &OpLog, versions: impl Iterator<Item = LV>) -> Frontier {
+    let mut versions: Vec<LV> = versions.collect();
+    versions.sort_unstable();
+    versions.dedup();
+    oplog.cg.graph.find_dominators(&versions)
+}
+
+fn is_regular_file(mode: i32) -> bool {
+    mode == i32::from(FileMode::Blob) || mode == i32::from(FileMode::BlobExecutable)
+}
+
+/// Read the content of a blob, or None if the blob contains binary data.
+fn read_text_blob(repo: &Repository, oid: Oid) -> anyhow::Result<Option<String>> {
+    let blob = repo.find_blob(oid)?;
+    Ok(if blob.is_binary() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(blob.content()).into_owned())
+    })
+}
+
+/// The tree of the imported directory in a commit, or None if the directory doesn't exist.
+fn subtree<'r>(repo: &'r Repository, commit: &Commit<'r>, dir: &Path) -> anyhow::Result<Option<Tree<'r>>> {
+    let tree = commit.tree()?;
+    if dir.as_os_str().is_empty() { return Ok(Some(tree)); }
+
+    Ok(match tree.get_path(dir) {
+        Ok(entry) if entry.kind() == Some(ObjectType::Tree) => Some(entry.to_object(repo)?.peel_to_tree()?),
+        _ => None,
+    })
+}
+
+/// Merge the states of a commit's parents. The merged state matches what the oplog contains at
+/// the union of the parents' versions.
+fn merge_states(oplog: &OpLog, states: Vec<RepoState>) -> RepoState {
+    if states.len() == 1 { return states.into_iter().next().unwrap(); }
+
+    let graph = &oplog.cg.graph;
+    let mut result = RepoState {
+        version: dominators(oplog, states.iter().flat_map(|s| s.version.iter().copied())),
+        files: BTreeMap::new(),
+    };
+
+    let ids: BTreeSet<LV> = states.iter().flat_map(|s| s.files.keys().copied()).collect();
+    for id in ids {
+        // A file which was deleted in any of the branches stays deleted.
+        let deleted = states.iter().any(|s| {
+            !s.files.contains_key(&id) && graph.frontier_contains_frontier(s.version.as_ref(), &[id])
+        });
+        if deleted { continue; }
+
+        let copies: Vec<&RepoFile> = states.iter().filter_map(|s| s.files.get(&id)).collect();
+        let mut file = copies[0].clone();
+
+        file.path_v = dominators(oplog, copies.iter().flat_map(|f| f.path_v.iter().copied()));
+        if let Some(c) = copies.iter().find(|c| c.path_v == file.path_v) {
+            file.path = c.path.clone();
+        }
+
+        file.text_v = dominators(oplog, copies.iter().flat_map(|f| f.text_v.iter().copied()));
+        if let Some(c) = copies.iter().find(|c| c.text_v == file.text_v) {
+            file.content = c.content.clone();
+            file.oid = c.oid;
+        } else {
+            file.content = oplog.checkout_text_at(file.text, file.text_v.as_ref()).to_string().into();
+            file.oid = None;
+        }
+
+        result.files.insert(id, file);
+    }
+
+    result
+}
+
+/// The changes to get from the parent tree to the tree of a commit, with renames detected by git.
+fn diff_changes(repo: &Repository, old: Option<&Tree>, new: Option<&Tree>) -> anyhow::Result<Vec<FileChange>> {
+    let mut diff = repo.diff_tree_to_tree(old, new, None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+    let mut changes = vec![];
+    for delta in diff.deltas() {
+        let (old_file, new_file) = (delta.old_file(), delta.new_file());
+        let path_str = |p: Option<&Path>| p.unwrap().to_string_lossy().into_owned();
+        let old_regular = is_regular_file(old_file.mode().into());
+        let new_regular = is_regular_file(new_file.mode().into());
+
+        match delta.status() {
+            Delta::Renamed if old_regular && new_regular => {
+                changes.push(FileChange::Rename(path_str(old_file.path()), path_str(new_file.path()), new_file.id()));
+                continue;
+            }
+            Delta::Added | Delta::Modified | Delta::Deleted | Delta::Renamed
+            | Delta::Copied | Delta::Typechange => {}
+            _ => continue,
+        }
+
+        // Anything else is treated as a delete followed by an add.
+        let copied = delta.status() == Delta::Copied;
+        if old_regular && !copied && (!new_regular || old_file.path() != new_file.path()) {
+            changes.push(FileChange::Delete(path_str(old_file.path())));
+        }
+        if new_regular {
+            let path = path_str(new_file.path());
+            changes.push(if old_regular && old_file.path() == new_file.path() {
+                FileChange::Modify(path, new_file.id())
+            } else {
+                FileChange::Add(path, new_file.id())
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// The changes to get from a merged state to the tree of a commit. Renames are only detected
+/// when a file was moved without being modified.
+fn full_tree_changes(state: &RepoState, tree: Option<&Tree>) -> Vec<FileChange> {
+    let mut tree_files = BTreeMap::<String, Oid>::new();
+    if let Some(tree) = tree {
+        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            if is_regular_file(entry.filemode()) {
+                tree_files.insert(format!("{dir}{}", String::from_utf8_lossy(entry.name_bytes())), entry.id());
+            }
+            TreeWalkResult::Ok
+        }).unwrap();
+    }
+
+    let files: BTreeMap<&str, &RepoFile> = state.files.values().map(|f| (f.path.as_str(), f)).collect();
+    let mut deleted: BTreeMap<Oid, Vec<&str>> = BTreeMap::new();
+    for (path, file) in files.iter() {
+        if !tree_files.contains_key(*path) {
+            deleted.entry(file.oid.unwrap_or(Oid::zero())).or_default().push(path);
+        }
+    }
+
+    let mut changes = vec![];
+    for (path, oid) in tree_files {
+        match files.get(path.as_str()) {
+            Some(file) if file.oid == Some(oid) => {},
+            Some(_) => changes.push(FileChange::Modify(path, oid)),
+            None => {
+                match deleted.get_mut(&oid).and_then(|paths| paths.pop()) {
+                    Some(old_path) => changes.push(FileChange::Rename(old_path.into(), path, oid)),
+                    None => changes.push(FileChange::Add(path, oid)),
+                }
+            }
+        }
+    }
+    for path in deleted.into_values().flatten() {
+        changes.push(FileChange::Delete(path.into()));
+    }
+    changes
+}
+
+/// Writes the operations for a single commit. Every operation's parent is the previous operation,
+/// so the commit's changes form a single run in the causal graph.
+struct CommitWriter<'a> {
+    oplog: &'a mut OpLog,
+    agent: AgentId,
+    version: Frontier,
+}
+
+impl CommitWriter<'_> {
+    fn map_set(&mut self, crdt: LV, key: &str, value: CreateValue) -> LV {
+        let v = self.oplog.local_map_set_at(self.agent, crdt, self.version.as_ref(), key, value);
+        self.version = Frontier::new_1(v);
+        v
+    }
+
+    fn delete(&mut self, file: RepoFile) {
+        self.map_set(ROOT_CRDT_ID, &file.key, CreateValue::Primitive(Primitive::Nil));
+    }
+
+    fn set_path(&mut self, id: LV, file: &mut RepoFile, path: String) {
+        let v = self.map_set(id, "path", CreateValue::Primitive(Primitive::Str(path.as_str().into())));
+        file.path = path;
+        file.path_v = Frontier::new_1(v);
+    }
+
+    fn set_content(&mut self, file: &mut RepoFile, content: String, oid: Oid) {
+        if let Some(v) = self.oplog.set_text_content_at(self.agent, file.text, self.version.as_ref(), &file.content, &content, DiffGranularity::Chars) {
+            self.version = Frontier::new_1(v);
+            file.text_v = Frontier::new_1(v);
+        }
+        file.content = content.into();
+        file.oid = Some(oid);
+    }
+}
+
+/// Import every text file in a directory of a git repository into a multi-CRDT oplog. The root
+/// map has an entry for each file (keyed by the path the file was created with), containing the
+/// file's current `"path"` and its `"content"` as a text CRDT. Renamed files keep their key, so
+/// their history is preserved. Binary files, symlinks and submodules are skipped.
+///
+/// This imports the history of every local branch, or just the named branch.
+pub fn extract_repo_from_git(input_path: PathBuf, branch: Option<String>, quiet: bool, map_out: Option<PathBuf>) -> anyhow::Result<OpLog> {
+    let (repo, dir) = open_repository(input_path)?;
+
+    let branches = match branch {
+        Some(name) => vec![repo.find_branch(&name, BranchType::Local)?],
+        None => repo.branches(Some(BranchType::Local))?
+            .map(|b| b.map(|(b, _)| b))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let mut heads = vec![];
+    for b in branches.iter() {
+        let id = b.get().peel_to_commit()?.id();
+        if !heads.contains(&id) { heads.push(id); }
+    }
+
+    if !quiet { println!("Loading {:?} from {:?} ({} branches)", dir, repo.path(), branches.len()); }
+
+    let graph = CommitGraph::scan(&repo, &heads)?;
+    let mut fwd_frontier = graph.roots.clone();
+
+    let mut oplog = OpLog::new();
+    let mut states = HashMap::<Oid, (RepoState, usize)>::new();
+    let mut used_keys = HashSet::<String>::new();
+    let mut map_file = map_out.map(File::create).transpose()?.map(BufWriter::new);
+    // The number of files at the head of each branch.
+    let mut head_files = HashMap::<Oid, usize>::new();
+
+    let bar = if quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(graph.parents.len() as _)
+    };
+
+    while let Some(commit_id) = fwd_frontier.pop() {
+        bar.inc(1);
+        let commit = repo.find_commit(commit_id)?;
+        let parent_ids = &graph.parents[&commit_id];
+
+        let parent_states = parent_ids.iter().map(|p| take_state(&mut states, *p)).collect();
+        let mut state = merge_states(&oplog, parent_states);
+        let tree = subtree(&repo, &commit, &dir)?;
+
+        let agent = oplog.cg.get_or_create_agent_id(&author_name(&commit));
+        let mut w = CommitWriter { oplog: &mut oplog, agent, version: state.version.clone() };
+        let mut index: HashMap<String, LV> = HashMap::new();
+        let mut duplicates = vec![];
+        for (id, f) in state.files.iter() {
+            // If several files have the same path, they were added concurrently. Keep the oldest.
+            if index.contains_key(&f.path) { duplicates.push(*id); }
+            else { index.insert(f.path.clone(), *id); }
+        }
+        for id in duplicates {
+            w.delete(state.files.remove(&id).unwrap());
+        }
+
+        let mut changes = if parent_ids.len() == 1 {
+            let parent_tree = subtree(&repo, &repo.find_commit(parent_ids[0])?, &dir)?;
+            diff_changes(&repo, parent_tree.as_ref(), tree.as_ref())?
+        } else {
+            full_tree_changes(&state, tree.as_ref())
+        };
+        // Process deletes and renames first, to free up the paths they move away from.
+        changes.sort_by_key(|c| match c {
+            FileChange::Delete(_) => 0,
+            FileChange::Rename(..) => 1,
+            _ => 2,
+        });
+
+        let mut to_delete = vec![];
+        let mut moved = vec![];
+        let mut added = vec![];
+        for change in changes {
+            match change {
+                FileChange::Delete(path) => {
+                    if let Some(id) = index.remove(&path) { to_delete.push(id); }
+                }
+                FileChange::Rename(old, new, oid) => match index.remove(&old) {
+                    Some(id) => moved.push((id, new, oid)),
+                    None => added.push((new, oid)),
+                },
+                FileChange::Add(path, oid) | FileChange::Modify(path, oid) => added.push((path, oid)),
+            }
+        }
+
+        for id in to_delete {
+            w.delete(state.files.remove(&id).unwrap());
+        }
+
+        for (id, path, oid) in moved {
+            if let Some(old_id) = index.insert(path.clone(), id) {
+                w.delete(state.files.remove(&old_id).unwrap());
+            }
+            let file = state.files.get_mut(&id).unwrap();
+            w.set_path(id, file, path);
+            if file.oid != Some(oid) { added.push((file.path.clone(), oid)); }
+        }
+
+        for (path, oid) in added {
+            let content = read_text_blob(&repo, oid)?;
+            match (index.get(&path), content) {
+                (Some(id), Some(content)) => {
+                    let file = state.files.get_mut(id).unwrap();
+                    if file.oid != Some(oid) { w.set_content(file, content, oid); }
+                }
+                (Some(id), None) => {
+                    // The file has become binary. Treat it as deleted.
+                    w.delete(state.files.remove(id).unwrap());
+                    index.remove(&path);
+                }
+                (None, Some(content)) => {
+                    let mut key = path.clone();
+                    let mut n = 1;
+                    while used_keys.contains(&key) {
+                        n += 1;
+                        key = format!("{path}#{n}");
+                    }
+                    used_keys.insert(key.clone());
+
+                    let id = w.map_set(ROOT_CRDT_ID, &key, CreateValue::NewCRDT(CRDTKind::Map));
+                    let path_v = w.map_set(id, "path", CreateValue::Primitive(Primitive::Str(path.as_str().into())));
+                    let text = w.map_set(id, "content", CreateValue::NewCRDT(CRDTKind::Text));
+                    let mut file = RepoFile {
+                        key, path: path.clone(),
+                        path_v: Frontier::new_1(path_v),
+                        text,
+                        text_v: Frontier::new_1(text),
+                        oid: None,
+                        content: "".into(),
+                    };
+                    w.set_content(&mut file, content, oid);
+                    index.insert(path, id);
+                    state.files.insert(id, file);
+                }
+                (None, None) => {}
+            }
+        }
+
+        // Resolve any concurrent renames which weren't resolved by the commit.
+        for (id, file) in state.files.iter_mut() {
+            if file.path_v.len() > 1 {
+                let path = file.path.clone();
+                w.set_path(*id, file, path);
+            }
+        }
+
+        state.version = w.version;
+
+        if let Some(map_file) = map_file.as_mut() {
+            let rv = oplog.cg.agent_assignment.local_to_remote_frontier(state.version.as_ref());
+            writeln!(map_file, "{},{}", commit_id, serde_json::to_string(&rv).unwrap())?;
+        }
+
+        if heads.contains(&commit_id) {
+            head_files.insert(commit_id, state.files.len());
+        }
+        states.insert(commit_id, (state, graph.children[&commit_id].len()));
+        graph.push_ready_children(commit_id, &states, &mut fwd_frontier);
+    }
+    bar.finish();
+
+    if !quiet {
+        for b in branches.iter() {
+            let id = b.get().peel_to_commit()?.id();
+            println!("Branch {}: {} files", b.name()?.unwrap_or("?"), head_files[&id]);
+        }
+    }
+
+    Ok(oplog)
+}
+
+/// A run of operations from one agent, which is exported as a single git commit.
+struct ExportRun<'a> {
+    span: DTRange,
+    agent: &'a str,
+    seq_start: usize,
+    parents: Frontier,
+    ops: Vec<TextOperation>,
+}
+
+/// Split the oplog into the runs which become git commits. Each run of operations in the causal
+/// graph becomes a commit, and runs longer than `batch` operations are split into several.
+fn export_runs(oplog: &ListOpLog, batch: Option<usize>) -> Vec<ExportRun<'_>> {
+    let batch = batch.unwrap_or(usize::MAX).max(1);
+    let mut runs = vec![];
+    for node in build_nodes(oplog, &LogFilter::default()) {
+        let mut ops = OpChunker::new(node.ops);
+        let mut start = node.span.start;
+        let mut parents = node.parents;
+
+        while start < node.span.end {
+            let end = node.span.end.min(start.saturating_add(batch));
+            runs.push(ExportRun {
+                span: (start..end).into(),
+                agent: node.agent,
+                seq_start: node.seq_start + (start - node.span.start),
+                parents,
+                ops: ops.take(end - start),
+            });
+            parents = Frontier::new_1(end - 1);
+            start = end;
+        }
+    }
+    runs
+}
+
+/// The commit message for a run. This uses the message stored in the operations' metadata if
+/// there is one.
+fn export_message(oplog: &ListOpLog, run: &ExportRun) -> String {
+    const MAX_OPS: usize = 10;
+
+    if let Some(message) = oplog.metadata_at(run.span.start).and_then(|m| m.message.as_ref()) {
+        return message.clone();
+    }
+
+    let mut message = format!("{} {}..{}\n\n", run.agent, run.seq_start, run.seq_start + run.span.len());
+    for op in run.ops.iter().take(MAX_OPS) {
+        message.push_str(&fmt_op(op));
+        message.push('\n');
+    }
+    if run.ops.len() > MAX_OPS {
+        message.push_str(&format!("(+{} more)\n", run.ops.len() - MAX_OPS));
+    }
+    message
+}
+
+/// Agents don't have email addresses, so every exported commit uses this one.
+const EXPORT_EMAIL: &str = "unknown@diamond-types";
+
+/// The author of a commit, named after the agent. The commit time comes from the operations'
+/// metadata, or the current time.
+fn export_signature(oplog: &ListOpLog, agent: &str, v: LV) -> anyhow::Result<Signature<'static>> {
+    // Git doesn't allow angle brackets or newlines in names, but agent names can contain anything.
+    let name: String = agent.trim()
+        .chars()
+        .map(|c| if matches!(c, '<' | '>' | '\n' | '\r' | '\0') { '_' } else { c })
+        .collect();
+    let name = if name.is_empty() { "unknown" } else { name.as_str() };
+
+    Ok(match oplog.metadata_at(v).and_then(|m| m.timestamp) {
+        Some(ms) => Signature::new(name, EXPORT_EMAIL, &Time::new((ms / 1000) as i64, 0))?,
+        None => Signature::now(name, EXPORT_EMAIL)?,
+    })
+}
+
+/// Export the history of a document to a git repository, storing the document's content at
+/// `file_path`. The repository is created if it doesn't exist.
+///
+/// Each run of operations in the causal graph becomes a commit (or several commits, if runs are
+/// longer than `batch` operations). Runs with several parents become merge commits. If the
+/// document ends with concurrent edits, a final merge commit is made with the merged content.
+///
+/// The named branch is pointed at the last commit. Returns the number of commits created.
+pub fn export_to_git(oplog: &ListOpLog, repo_path: &Path, file_path: &Path, branch: &str, batch: Option<usize>, force: bool, quiet: bool) -> anyhow::Result<usize> {
+    if oplog.is_empty() {
+        return Err(anyhow::anyhow!("The document has no history to export"));
+    }
+
+    let repo = match Repository::open(repo_path) {
+        Ok(repo) => repo,
+        Err(_) => Repository::init(repo_path)?,
+    };
+    if !force && repo.find_branch(branch, BranchType::Local).is_ok() {
+        return Err(anyhow::anyhow!("Branch {branch} already exists. Use --force to overwrite it"));
+    }
+
+    let runs = export_runs(oplog, batch);
+
+    // The number of runs which have each version as a parent. Each run's branch is kept until
+    // all of its children have been exported.
+    let mut num_children = HashMap::<LV, usize>::new();
+    for run in runs.iter() {
+        for p in run.parents.iter() {
+            *num_children.entry(*p).or_default() += 1;
+        }
+    }
+
+    let empty_tree = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+    let write_tree = |content: &str| -> anyhow::Result<Oid> {
+        let blob = repo.blob(content.as_bytes())?;
+        Ok(TreeUpdateBuilder::new()
+            .upsert(file_path, blob, FileMode::Blob)
+            .create_updated(&repo, &empty_tree)?)
+    };
+
+    let bar = if quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(runs.len() as _)
+    };
+
+    let mut branches = HashMap::<LV, (ListBranch, usize)>::new();
+    let mut commits: Vec<(DTRange, Oid)> = Vec::with_capacity(runs.len());
+    let commit_at = |commits: &[(DTRange, Oid)], v: LV| -> anyhow::Result<Commit> {
+        let idx = commits.partition_point(|(span, _)| span.end <= v);
+        Ok(repo.find_commit(commits[idx].1)?)
+    };
+
+    for run in runs.iter() {
+        bar.inc(1);
+
+        // Start from the newest parent, since it's usually the closest to this run's version.
+        let mut branch = match run.parents.as_ref().split_last() {
+            Some((p, rest)) => {
+                for p in rest { release_state(&mut branches, *p); }
+                take_state(&mut branches, *p)
+            }
+            None => ListBranch::new(),
+        };
+        branch.merge(oplog, &[run.span.last()]);
+
+        let tree = repo.find_tree(write_tree(&branch.content().to_string())?)?;
+        let parents = run.parents.iter()
+            .map(|p| commit_at(&commits, *p))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let sig = export_signature(oplog, run.agent, run.span.start)?;
+        let oid = repo.commit(None, &sig, &sig, &export_message(oplog, run), &tree, &parents.iter().collect::<Vec<_>>())?;
+        commits.push((run.span, oid));
+
+        if let Some(n) = num_children.get(&run.span.last()) {
+            branches.insert(run.span.last(), (branch, *n));
+        }
+    }
+    bar.finish();
+
+    let frontier = oplog.local_frontier();
+    let mut head = commit_at(&commits, frontier[0])?;
+    if frontier.len() > 1 {
+        // Merge the concurrent edits at the end of the document.
+        let last = *frontier.iter().max().unwrap();
+        let agent = oplog.cg.agent_assignment.local_to_remote_version(last).0;
+        let tree = repo.find_tree(write_tree(&oplog.checkout_tip().content().to_string())?)?;
+        let parents = frontier.iter()
+            .map(|v| commit_at(&commits, *v))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let sig = export_signature(oplog, agent, last)?;
+        let oid = repo.commit(None, &sig, &sig, "Merge concurrent edits", &tree, &parents.iter().collect::<Vec<_>>())?;
+        head = repo.find_commit(oid)?;
+        commits.push(((oplog.len()..oplog.len()).into(), oid));
+    }
+
+    repo.branch(branch, &head, force)?;
+    Ok(commits.len())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use git2::{Commit, FileMode, Oid, Repository, Signature, Time};
+    use diamond_types::list::ListOpLog;
+    use diamond_types::{DTValue, Primitive};
+    use super::{export_signature, export_to_git, extract_repo_from_git};
+
+    fn commit<'a>(repo: &'a Repository, files: &[(&str, &str)], parents: &[&Commit], update_ref: Option<&str>) -> Commit<'a> {
+        let mut tree = repo.treebuilder(None).unwrap();
+        for (path, content) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            tree.insert(path, blob, FileMode::Blob.into()).unwrap();
+        }
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let sig = Signature::new("seph", "seph@example.com", &Time::new(1700000000, 0)).unwrap();
+        let id: Oid = repo.commit(update_ref, &sig, &sig, "msg", &tree, parents).unwrap();
+        repo.find_commit(id).unwrap()
+    }
+
+    fn file(path: &str, content: &str) -> Box<DTValue> {
+        Box::new(DTValue::Map(BTreeMap::from([
+            ("path".into(), Box::new(DTValue::Primitive(Primitive::Str(path.into())))),
+            ("content".into(), Box::new(DTValue::Text(content.into()))),
+        ])))
+    }
+
+    #[test]
+    fn import_renames_and_merges() {
+        let dir = std::env::temp_dir().join(format!("dt-git-import-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+
+        let base = commit(&repo, &[("a.txt", "hello\n"), ("b.txt", "bee\n")], &[], None);
+        // One branch renames a.txt, the other edits b.txt.
+        let renamed = commit(&repo, &[("c.txt", "hello\n"), ("b.txt", "bee\n")], &[&base], None);
+        let edited = commit(&repo, &[("a.txt", "hello\n"), ("b.txt", "bee buzz\n")], &[&base], None);
+        commit(&repo, &[("c.txt", "hello there\n"), ("b.txt", "bee buzz\n")], &[&renamed, &edited], Some("refs/heads/main"));
+
+        let oplog = extract_repo_from_git(dir.clone(), None, true, None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The renamed file keeps the key it was created with.
+        assert_eq!(oplog.checkout(), BTreeMap::from([
+            ("a.txt".into(), file("c.txt", "hello there\n")),
+            ("b.txt".into(), file("b.txt", "bee buzz\n")),
+        ]));
+        assert_eq!(oplog.cg.version.len(), 1);
+    }
+
+    #[test]
+    fn export_signature_sanitises_agent_names() {
+        let mut oplog = ListOpLog::new();
+        let agent = oplog.get_or_create_agent_id("seph <seph@example.com>\n");
+        let v = oplog.add_insert(agent, 0, "hi");
+
+        let sig = export_signature(&oplog, oplog.get_agent_name(agent), v).unwrap();
+        assert_eq!(sig.name(), Some("seph _seph@example.com_"));
+        assert!(export_signature(&oplog, "", v).is_ok());
+    }
+
+    #[test]
+    fn export_branches_and_merges() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "hello");
+        let a = oplog.add_insert_at(mike, &[base], 5, " world");
+        let b = oplog.add_insert_at(seph, &[base], 0, ">> ");
+        let m = oplog.add_insert_at(seph, &[a, b], 14, "!");
+        // The document ends with two concurrent edits.
+        oplog.add_insert_at(mike, &[m], 0, "1");
+        oplog.add_insert_at(seph, &[m], 0, "2");
+        let content = oplog.checkout_tip().content().to_string();
+
+        let dir = std::env::temp_dir().join(format!("dt-git-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let file = std::path::Path::new("doc.txt");
+
+        // One commit per run, plus a final merge commit.
+        assert_eq!(export_to_git(&oplog, &dir, file, "main", None, false, true).unwrap(), 7);
+        // Runs are split into batches of (at most) 2 operations.
+        assert_eq!(export_to_git(&oplog, &dir, file, "batched", Some(2), false, true).unwrap(), 12);
+        // Existing branches aren't overwritten without force.
+        assert!(export_to_git(&oplog, &dir, file, "main", None, false, true).is_err());
+
+        let repo = Repository::open(&dir).unwrap();
+        for (branch, count) in [("main", 7), ("batched", 12)] {
+            let head = repo.find_branch(branch, git2::BranchType::Local).unwrap().get().peel_to_commit().unwrap();
+            assert_eq!(head.parent_count(), 2);
+            assert_eq!(head.message(), Some("Merge concurrent edits"));
+            let blob = head.tree().unwrap().get_path(file).unwrap().to_object(&repo).unwrap().peel_to_blob().unwrap();
+            assert_eq!(std::str::from_utf8(blob.content()).unwrap(), content);
+
+            let mut walk = repo.revwalk().unwrap();
+            walk.push(head.id()).unwrap();
+            let commits: Vec<Commit> = walk.map(|id| repo.find_commit(id.unwrap()).unwrap()).collect();
+            assert_eq!(commits.len(), count);
+            // The merge of a and b, and the final merge.
+            assert_eq!(commits.iter().filter(|c| c.parent_count() == 2).count(), 2);
+            assert_eq!(commits.iter().filter(|c| c.parent_count() == 0).count(), 1);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
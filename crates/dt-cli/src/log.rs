@@ -0,0 +1,368 @@
+//! Human readable output for `dt log`, including filtering and an ASCII rendering of the causal
+//! graph (like `git log --graph`).
+
+use std::collections::BTreeSet;
+use clap::ValueEnum;
+use rle::{HasLength, SplitableSpan};
+use diamond_types::{DTRange, Frontier, LV};
+use diamond_types::list::ListOpLog;
+use diamond_types::list::operation::{ListOpKind, TextOperation};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum OpKindArg {
+    /// Inserts
+    Ins,
+    /// Deletes
+    Del,
+}
+
+impl From<OpKindArg> for ListOpKind {
+    fn from(kind: OpKindArg) -> Self {
+        match kind {
+            OpKindArg::Ins => ListOpKind::Ins,
+            OpKindArg::Del => ListOpKind::Del,
+        }
+    }
+}
+
+/// Which operations to show.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    /// Only show operations from these agents. Empty means all agents.
+    pub agents: Vec<String>,
+    /// Only show operations which aren't in the history of this version.
+    pub since: Frontier,
+    /// Only show operations in the history of this version. None means the latest version.
+    pub until: Option<Frontier>,
+    /// Only show inserts or deletes.
+    pub kind: Option<ListOpKind>,
+}
+
+impl LogFilter {
+    /// Returns true if the filter shows every operation.
+    pub fn is_empty(&self) -> bool {
+        self.agents.is_empty() && self.since.is_empty() && self.until.is_none() && self.kind.is_none()
+    }
+}
+
+/// A run of operations from one agent. Runs are split so that every parent named in the graph
+/// is the last operation of some run.
+pub(crate) struct LogNode<'a> {
+    pub(crate) span: DTRange,
+    pub(crate) agent: &'a str,
+    pub(crate) seq_start: usize,
+    pub(crate) parents: Frontier,
+    pub(crate) ops: Vec<TextOperation>,
+    visible: bool,
+}
+
+fn fmt_parents(oplog: &ListOpLog, parents: &[LV]) -> String {
+    if parents.is_empty() { return "(root)".into(); }
+
+    parents.iter().map(|p| {
+        let rv = oplog.cg.agent_assignment.local_to_remote_version(*p);
+        format!("{} {}", rv.0, rv.1)
+    }).collect::<Vec<_>>().join(", ")
+}
+
+/// Format an operation compactly, like `ins 10..15 "hello"`.
+pub(crate) fn fmt_op(op: &TextOperation) -> String {
+    const MAX_EXCERPT: usize = 30;
+
+    let kind = match op.kind {
+        ListOpKind::Ins => "ins",
+        ListOpKind::Del => "del",
+    };
+    let rev = if op.loc.fwd || op.len() == 1 { "" } else { " (rev)" };
+    let mut result = format!("{kind} {}..{}{rev}", op.loc.span.start, op.loc.span.end);
+
+    if let Some(content) = op.content.as_ref() {
+        let excerpt: String = content.chars().take(MAX_EXCERPT).collect();
+        result.push_str(&format!(" {excerpt:?}"));
+        if content.chars().count() > MAX_EXCERPT { result.push_str("..."); }
+    }
+    result
+}
+
+/// Splits a list of operations into consecutive pieces, each covering a chosen number of items.
+pub(crate) struct OpChunker<I: Iterator<Item=TextOperation>> {
+    ops: I,
+    pending: Option<TextOperation>,
+}
+
+impl<I: Iterator<Item=TextOperation>> OpChunker<I> {
+    pub(crate) fn new<T: IntoIterator<IntoIter=I>>(ops: T) -> Self {
+        Self { ops: ops.into_iter(), pending: None }
+    }
+
+    /// Take the operations for the next `len` items, splitting the last operation if needed.
+    pub(crate) fn take(&mut self, len: usize) -> Vec<TextOperation> {
+        let mut result = vec![];
+        let mut remaining = len;
+        while remaining > 0 {
+            let mut op = self.pending.take().or_else(|| self.ops.next()).unwrap();
+            if op.len() > remaining {
+                self.pending = Some(op.truncate(remaining));
+            }
+            remaining -= op.len();
+            result.push(op);
+        }
+        result
+    }
+}
+
+pub(crate) fn build_nodes<'a>(oplog: &'a ListOpLog, filter: &LogFilter) -> Vec<LogNode<'a>> {
+    let until = filter.until.clone().unwrap_or_else(|| oplog.local_frontier());
+    let in_range = oplog.cg.graph.diff(filter.since.as_ref(), until.as_ref()).1;
+
+    // Split runs at every version which is named as a parent, and at the edges of the range.
+    let entries = oplog.as_chunked_operation_vec();
+    let mut split_points: BTreeSet<LV> = entries.iter()
+        .flat_map(|e| e.parents.iter().map(|p| p + 1))
+        .collect();
+    split_points.extend(in_range.iter().flat_map(|r| [r.start, r.end]));
+
+    let mut nodes = vec![];
+    for e in entries {
+        let agent = oplog.get_agent_name(e.agent_span.agent);
+        let mut ops = OpChunker::new(e.ops);
+        let mut start = e.span.start;
+        let mut parents = e.parents;
+
+        let ends = split_points.range(e.span.start + 1..e.span.end).copied()
+            .chain(std::iter::once(e.span.end));
+        for end in ends {
+            let node_ops = ops.take(end - start);
+
+            let span: DTRange = (start..end).into();
+            let visible = in_range.iter().any(|r| r.start <= span.start && span.end <= r.end)
+                && (filter.agents.is_empty() || filter.agents.iter().any(|a| a == agent))
+                && filter.kind.is_none_or(|kind| node_ops.iter().any(|op| op.kind == kind));
+
+            nodes.push(LogNode {
+                span,
+                agent,
+                seq_start: e.agent_span.seq_range.start + (start - e.span.start),
+                parents,
+                ops: node_ops,
+                visible,
+            });
+
+            parents = Frontier::new_1(end - 1);
+            start = end;
+        }
+    }
+    nodes
+}
+
+/// Print every operation which matches the filter, oldest first. In compact mode each line names
+/// the agent and sequence numbers, the operation's parents, and the operation itself.
+pub fn print_log(oplog: &ListOpLog, filter: &LogFilter, json: bool, compact: bool) {
+    for node in build_nodes(oplog, filter).iter().filter(|n| n.visible) {
+        let mut lv = node.span.start;
+        for op in node.ops.iter() {
+            let op_lv = lv;
+            lv += op.len();
+            if filter.kind.is_some_and(|kind| op.kind != kind) { continue; }
+
+            if json {
+                println!("{}", serde_json::to_string(op).unwrap());
+            } else if !compact {
+                println!("{:?}", op);
+            } else {
+                let parents = if op_lv == node.span.start {
+                    node.parents.clone()
+                } else {
+                    Frontier::new_1(op_lv - 1)
+                };
+                let seq = node.seq_start + (op_lv - node.span.start);
+                println!("{} {}..{}  <- {}  {}", node.agent, seq, seq + op.len(),
+                    fmt_parents(oplog, parents.as_ref()), fmt_op(op));
+            }
+        }
+    }
+}
+
+/// Draw the rows needed to move each line from its current column to its target column. Lines
+/// move at most one column per row.
+fn draw_transition(edges: &[(usize, usize)], out: &mut Vec<String>) {
+    let mut cur: Vec<usize> = edges.iter().map(|e| e.0).collect();
+    while cur.iter().zip(edges).any(|(c, e)| *c != e.1) {
+        let width = cur.iter().chain(edges.iter().map(|e| &e.1)).max().map_or(0, |m| m * 2 + 2);
+        let mut row = vec![b' '; width];
+        for (c, (_, target)) in cur.iter_mut().zip(edges) {
+            if *c == *target {
+                row[*c * 2] = b'|';
+            } else if *c > *target {
+                row[*c * 2 - 1] = b'/';
+                *c -= 1;
+            } else {
+                row[*c * 2 + 1] = b'\\';
+                *c += 1;
+            }
+        }
+        out.push(String::from_utf8(row).unwrap().trim_end().into());
+    }
+}
+
+/// Print the causal graph of the operations matching the filter, newest first. Runs of operations
+/// which are hidden by the filter are skipped, and their children are connected to their nearest
+/// visible ancestors instead.
+pub fn print_graph(oplog: &ListOpLog, filter: &LogFilter) {
+    for line in draw_graph(oplog, filter) {
+        println!("{line}");
+    }
+}
+
+/// Render the graph printed by [`print_graph`], one line per row.
+fn draw_graph(oplog: &ListOpLog, filter: &LogFilter) -> Vec<String> {
+    let mut out = vec![];
+    let nodes = build_nodes(oplog, filter);
+    let node_at = |lv: LV| nodes.partition_point(|n| n.span.end <= lv);
+
+    // The visible parents of each node. Parents always come before their children.
+    let mut graph_parents: Vec<Vec<usize>> = Vec::with_capacity(nodes.len());
+    for node in nodes.iter() {
+        let mut parents = vec![];
+        for p in node.parents.iter() {
+            let idx = node_at(*p);
+            if nodes[idx].visible { parents.push(idx); }
+            else { parents.extend(graph_parents[idx].iter().copied()); }
+        }
+        parents.sort_unstable_by(|a, b| b.cmp(a));
+        parents.dedup();
+        graph_parents.push(parents);
+    }
+
+    // Each column is waiting to draw the node with this index.
+    let mut columns: Vec<usize> = vec![];
+    for i in (0..nodes.len()).rev().filter(|i| nodes[*i].visible) {
+        let node = &nodes[i];
+
+        // If several children are waiting for this node, merge their lines together first.
+        let col = match columns.iter().position(|c| *c == i) {
+            Some(first) => {
+                let mut new_columns = vec![];
+                let mut edges = vec![];
+                for (k, c) in columns.iter().enumerate() {
+                    if *c == i && k != first {
+                        edges.push((k, first));
+                    } else {
+                        edges.push((k, new_columns.len()));
+                        new_columns.push(*c);
+                    }
+                }
+                draw_transition(&edges, &mut out);
+                columns = new_columns;
+                first
+            }
+            None => {
+                columns.push(i);
+                columns.len() - 1
+            }
+        };
+
+        let graph: String = (0..columns.len())
+            .map(|k| if k == col { "* " } else { "| " })
+            .collect();
+        let mut ops: Vec<String> = node.ops.iter().take(3).map(fmt_op).collect();
+        if node.ops.len() > 3 { ops.push(format!("(+{} more)", node.ops.len() - 3)); }
+        out.push(format!("{graph} {} {}..{}  {}", node.agent, node.seq_start, node.seq_start + node.span.len(), ops.join("; ")));
+
+        // And then branch out to this node's parents.
+        let mut new_columns = vec![];
+        let mut edges = vec![];
+        for (k, c) in columns.iter().enumerate() {
+            if k == col {
+                for p in graph_parents[i].iter() {
+                    edges.push((k, new_columns.len()));
+                    new_columns.push(*p);
+                }
+            } else {
+                edges.push((k, new_columns.len()));
+                new_columns.push(*c);
+            }
+        }
+        draw_transition(&edges, &mut out);
+        columns = new_columns;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use diamond_types::list::ListOpLog;
+    use diamond_types::list::operation::ListOpKind;
+    use super::{build_nodes, draw_graph, LogFilter};
+
+    /// A document with two concurrent branches, which are then merged.
+    fn branchy_oplog() -> ListOpLog {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "abc");
+        let a = oplog.add_insert_at(mike, &[base], 3, "XY");
+        let b = oplog.add_delete_at(seph, &[base], 0..1);
+        oplog.add_insert_at(seph, &[a, b], 0, "!");
+        oplog
+    }
+
+    /// The (agent, seq start) of each visible node.
+    fn visible(oplog: &ListOpLog, filter: &LogFilter) -> Vec<(String, usize)> {
+        build_nodes(oplog, filter).into_iter()
+            .filter(|n| n.visible)
+            .map(|n| (n.agent.to_string(), n.seq_start))
+            .collect()
+    }
+
+    #[test]
+    fn filters_hide_nodes() {
+        let oplog = branchy_oplog();
+        let all = vec![("seph".into(), 0), ("mike".into(), 0), ("seph".into(), 3), ("seph".into(), 4)];
+        assert_eq!(visible(&oplog, &LogFilter::default()), all);
+
+        assert_eq!(visible(&oplog, &LogFilter { agents: vec!["mike".into()], ..Default::default() }),
+            vec![("mike".into(), 0)]);
+        assert_eq!(visible(&oplog, &LogFilter { kind: Some(ListOpKind::Del), ..Default::default() }),
+            vec![("seph".into(), 3)]);
+        // Since and until select a range of history. Seph's delete is only in the history of 5.
+        assert_eq!(visible(&oplog, &LogFilter { since: 2.into(), until: Some(5.into()), ..Default::default() }),
+            vec![("seph".into(), 3)]);
+        assert_eq!(visible(&oplog, &LogFilter { since: 4.into(), ..Default::default() }),
+            vec![("seph".into(), 3), ("seph".into(), 4)]);
+    }
+
+    #[test]
+    fn graph_branches_and_merges() {
+        let oplog = branchy_oplog();
+        assert_eq!(draw_graph(&oplog, &LogFilter::default()), vec![
+            r#"*  seph 4..5  ins 0..1 "!""#,
+            r#"|\"#,
+            r#"* |  seph 3..4  del 0..1"#,
+            r#"| *  mike 0..2  ins 3..5 "XY""#,
+            r#"|/"#,
+            r#"*  seph 0..3  ins 0..3 "abc""#,
+        ]);
+    }
+
+    #[test]
+    fn graph_skips_hidden_nodes() {
+        let oplog = branchy_oplog();
+
+        // The merge is connected to the base directly, since mike's branch is hidden.
+        let filter = LogFilter { agents: vec!["seph".into()], ..Default::default() };
+        assert_eq!(draw_graph(&oplog, &filter), vec![
+            r#"*  seph 4..5  ins 0..1 "!""#,
+            r#"|\"#,
+            r#"* |  seph 3..4  del 0..1"#,
+            r#"|/"#,
+            r#"*  seph 0..3  ins 0..3 "abc""#,
+        ]);
+
+        let filter = LogFilter { kind: Some(ListOpKind::Ins), since: 2.into(), ..Default::default() };
+        assert_eq!(draw_graph(&oplog, &filter), vec![
+            r#"*  seph 4..5  ins 0..1 "!""#,
+            r#"*  mike 0..2  ins 3..5 "XY""#,
+        ]);
+    }
+}
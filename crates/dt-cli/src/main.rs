@@ -1,27 +1,38 @@
 mod export;
 mod dot;
 mod git;
+mod fsutil;
+mod watch;
+mod log;
 
 use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
 use std::io::{BufWriter, ErrorKind, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use anyhow::Error;
+use std::time::Duration;
+use anyhow::{anyhow, Error};
 use chrono::{DateTime, SecondsFormat, Timelike, Utc};
 use clap::{Parser, Subcommand};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use serde::Serialize;
-use similar::{ChangeTag, TextDiff};
-use similar::utils::TextDiffRemapper;
 use diamond_types::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
+use diamond_types::causalgraph::summary::VersionSummary;
+use diamond_types::Frontier;
 use diamond_types::list::{ListBranch, ListOpLog};
-use diamond_types::list::encoding::{ENCODE_FULL, EncodeOptions};
+use diamond_types::list::set_content::DiffGranularity;
+use diamond_types::list::stats::{ChunkStats, OpLogStats};
+use diamond_types::list::encoding::fsck::{ChecksumStatus, FsckReport};
+use diamond_types::list::metadata::OpMetadata;
+use diamond_types::list::encoding::{ENCODE_FULL, ENCODE_PATCH, EncodeOptions};
 use crate::dot::{generate_svg_with_dot};
 use crate::export::{export_full_to_json, export_trace_to_json, export_transformed};
-use crate::git::extract_from_git;
+use crate::git::{export_to_git, extract_from_git, extract_repo_from_git};
+use crate::fsutil::{FileLock, write_atomic, write_new};
+use crate::watch::watch;
+use crate::log::{LogFilter, OpKindArg, print_graph, print_log};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -67,8 +78,33 @@ enum Commands {
         ///
         /// If not specified, the version defaults to the latest version, printing the result of
         /// merging all changes.
+        #[arg(short, long, conflicts_with = "tag")]
+        version: Option<Version>,
+
+        /// Checkout at the version with the specified tag
+        #[arg(short, long)]
+        tag: Option<String>,
+    },
+
+    /// List the named tags in a diamond types file, or create / move a tag.
+    Tag {
+        /// Diamond types file to read or modify
+        dt_filename: OsString,
+
+        /// Name of the tag to set. If not specified, all tags are listed.
+        name: Option<String>,
+
+        /// Point the tag at this version. If not specified, the tag points to the latest version.
         #[arg(short, long)]
         version: Option<Version>,
+
+        /// Agent name to record as having set the tag. If not specified, a random name is chosen.
+        #[arg(short, long)]
+        agent: Option<String>,
+
+        /// List tags in JSON format
+        #[arg(short, long)]
+        json: bool,
     },
 
     /// Print the operations contained within a diamond types file
@@ -88,6 +124,133 @@ enum Commands {
         /// Output the history instead (time DAG)
         #[arg(long)]
         history: bool,
+
+        /// Print one readable line per operation, naming its agent, sequence numbers and parents
+        #[arg(short, long, conflicts_with_all = ["json", "history", "transformed"])]
+        compact: bool,
+
+        /// Draw the causal graph, with the newest operations first (like `git log --graph`)
+        #[arg(long, conflicts_with_all = ["json", "compact", "history", "transformed"])]
+        graph: bool,
+
+        /// Only show operations from this agent. Can be passed multiple times.
+        #[arg(short, long, conflicts_with_all = ["history", "transformed"])]
+        agent: Vec<String>,
+
+        /// Only show operations which happened after this version.
+        #[arg(long, conflicts_with_all = ["history", "transformed"])]
+        since: Option<Version>,
+
+        /// Only show operations up to and including this version. Defaults to the latest version.
+        #[arg(long, conflicts_with_all = ["history", "transformed"])]
+        until: Option<Version>,
+
+        /// Only show inserts or deletes
+        #[arg(long, value_enum, conflicts_with_all = ["history", "transformed"])]
+        kind: Option<OpKindArg>,
+    },
+
+    /// Print the changes needed to turn the document at one version into the document at another
+    /// version. The versions don't need to be related.
+    Diff {
+        /// Diamond types file to read
+        #[arg(value_name = "filename", value_parser = parse_dt_oplog)]
+        oplog: ListOpLog,
+
+        /// The version to diff from. Defaults to the start of history (an empty document).
+        #[arg(short, long)]
+        from: Option<Version>,
+
+        /// The version to diff to. Defaults to the latest version.
+        #[arg(short, long)]
+        to: Option<Version>,
+
+        /// Output the changes in JSON format
+        #[arg(short, long)]
+        json: bool,
+    },
+
+    /// Merge the operations from two or more diamond types files into a single file.
+    ///
+    /// Inputs are merged in order, so patch files must come after a file containing the
+    /// operations they're based on.
+    Merge {
+        /// Diamond types files to merge
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<OsString>,
+
+        /// Save the merged oplog to this file
+        #[arg(short, long)]
+        output: OsString,
+
+        /// Force overwrite the file which exists with the same name.
+        #[arg(short, long)]
+        force: bool,
+
+        /// If an input reuses (agent, seq) IDs to describe different edits, rename the
+        /// conflicting agent instead of failing.
+        #[arg(long)]
+        rename_reused_agents: bool,
+
+        /// Suppress all output to stdout
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Print a summary of all the operations a DT file contains, as JSON. The summary can be passed
+    /// to `dt patch --since` on another machine, to create a patch containing only the operations
+    /// missing here.
+    Summary {
+        /// Diamond types file to read
+        #[arg(value_name = "filename", value_parser = parse_dt_oplog)]
+        oplog: ListOpLog,
+
+        /// Output the summary to the specified filename. If missing, output is printed to stdout.
+        #[arg(short, long)]
+        output: Option<OsString>,
+
+        /// Use pretty JSON output
+        #[arg(short, long)]
+        pretty: bool,
+    },
+
+    /// Create a patch file containing the operations another peer is missing. The patch can be
+    /// merged into the other peer's file with `dt apply`.
+    Patch {
+        /// Diamond types file to read
+        #[arg(value_name = "filename", value_parser = parse_dt_oplog)]
+        oplog: ListOpLog,
+
+        /// Summary (from `dt summary`) of the operations the other peer already has. If not
+        /// specified, the patch contains every operation.
+        #[arg(short, long)]
+        since: Option<OsString>,
+
+        /// Save the patch to this file
+        #[arg(short, long)]
+        output: OsString,
+
+        /// Force overwrite the file which exists with the same name.
+        #[arg(short, long)]
+        force: bool,
+
+        /// Suppress all output to stdout
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Merge one or more patch files (from `dt patch`) into a DT file.
+    Apply {
+        /// Diamond types file to modify
+        dt_filename: OsString,
+
+        /// Patch files to merge, in order
+        #[arg(required = true)]
+        patches: Vec<OsString>,
+
+        /// Suppress all output to stdout
+        #[arg(short, long)]
+        quiet: bool,
     },
 
     /// Get (print) the current version of a DT file
@@ -95,6 +258,41 @@ enum Commands {
         /// Diamond types file to read
         #[arg(value_name = "filename", value_parser = parse_dt_oplog)]
         oplog: ListOpLog,
+
+        /// Also print the content-addressed hash of the version. Peers with the same hash have
+        /// identical histories.
+        #[arg(long)]
+        hash: bool,
+    },
+
+    /// Print a report on the size and shape of a DT file: where the bytes go, how well the
+    /// operations compress and how much concurrent editing happened.
+    Stats {
+        /// Diamond types file to read
+        dt_filename: OsString,
+
+        /// Output the report as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Use pretty JSON output. Implies --json.
+        #[arg(short, long)]
+        pretty: bool,
+    },
+
+    /// Check a DT file for damage. If the file can't be loaded, this reports which chunk of the
+    /// file is damaged. Pass -o to salvage everything which can still be read into a new file.
+    Fsck {
+        /// File to check
+        dt_filename: OsString,
+
+        /// Save the salvaged data to this file.
+        #[arg(short, long)]
+        output: Option<OsString>,
+
+        /// Force overwrite the output file if it exists.
+        #[arg(short, long)]
+        force: bool,
     },
 
     /// Set the contents of a DT file by applying a diff
@@ -121,6 +319,50 @@ enum Commands {
         /// reused to describe two *different* edits, weird & bad things happen.
         #[arg(short, long)]
         agent: Option<String>,
+
+        /// Diff the old and new content line by line, rather than character by character.
+        #[arg(long)]
+        lines: bool,
+
+        /// Message describing the change. This is stored with the new operations, along with the
+        /// current time.
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+
+    /// Keep a diamond types file in sync with a plain text file.
+    ///
+    /// Whenever the text file is saved, the changes are recorded as new operations in the DT file
+    /// (like `dt set`). Whenever the DT file gains operations from elsewhere (eg via `dt merge` or
+    /// `dt apply`), the text file is rewritten to include them. Edits made to the text file in the
+    /// meantime are preserved.
+    Watch {
+        /// Diamond types file to keep in sync
+        dt_filename: OsString,
+
+        /// The plain text file to keep in sync. If it doesn't exist, it is created with the
+        /// document's current contents.
+        text_filename: OsString,
+
+        /// Agent name for edits. If not specified, a random name is chosen.
+        #[arg(short, long)]
+        agent: Option<String>,
+
+        /// Diff the old and new content line by line, rather than character by character.
+        #[arg(long)]
+        lines: bool,
+
+        /// How often to check the files for changes, in milliseconds.
+        #[arg(long, default_value_t = 500)]
+        interval: u64,
+
+        /// Sync the files once and exit, instead of watching for changes.
+        #[arg(long)]
+        once: bool,
+
+        /// Suppress all output to stdout
+        #[arg(short, long)]
+        quiet: bool,
     },
 
     /// Re-save a diamond types file with different options. This method can:
@@ -167,6 +409,11 @@ enum Commands {
         #[arg(long)]
         no_deleted_content: bool,
 
+        /// Store a content-addressed hash of the file's version. The hash is checked when the file
+        /// is loaded, to detect tampering or agents reusing IDs.
+        #[arg(long)]
+        version_hash: bool,
+
         /// Suppress all output to stdout
         #[arg(short, long)]
         quiet: bool,
@@ -224,18 +471,24 @@ enum Commands {
 
     /// Generate a diagram of the causal graph contained in a diamond types' file.
     ///
-    /// This depends on having the `dot` tool from [graphviz](https://graphviz.org/download/)
-    /// installed on your computer.
+    /// By default the graph is rendered to SVG using a built-in layout. Hovering over each run of
+    /// operations in the image shows a summary of the operations.
     ///
-    /// By default, we will execute `dot` in the system path to render graphs. But this can be
-    /// overridden using `--dot-path="xxx/dot"`.
+    /// Use `--graphviz` to render using the `dot` tool from
+    /// [graphviz](https://graphviz.org/download/) instead. This will execute `dot` in the system
+    /// path, but this can be overridden using `--dot-path="xxx/dot"`.
     Dot {
         /// File to edit
         dt_filename: PathBuf,
 
+        /// Output the graph in graphviz's DOT format instead of rendering it.
         #[arg(short, long)]
         no_render: bool,
 
+        /// Render the SVG using graphviz instead of the built-in renderer.
+        #[arg(long)]
+        graphviz: bool,
+
         /// Output the result to the specified filename. If missing, output is saved to
         /// (dt file).svg / .dot.
         ///
@@ -243,17 +496,21 @@ enum Commands {
         #[arg(short, long)]
         output: Option<OsString>,
 
-        /// Path to `dot` command
+        /// Path to `dot` command. Implies --graphviz.
         #[arg(long)]
         dot_path: Option<OsString>,
     },
 
     /// Import & convert the editing history for a file from git to diamond types.
+    ///
+    /// If the path is a directory, every text file inside it is imported into a multi-document
+    /// oplog (with one text CRDT for each file), which is saved as JSON.
     GitImport {
-        /// Path to the file being read. Must be inside a git repository.
+        /// Path to the file or directory being read. Must be inside a git repository.
         path: PathBuf,
 
-        /// branch to be read. Defaults to 'master'.
+        /// branch to be read. Defaults to 'master' for files, and all local branches for
+        /// directories.
         #[arg(short, long)]
         branch: Option<String>,
 
@@ -268,7 +525,40 @@ enum Commands {
         /// Output an extra file containing mapping from git commits <-> DT versions.
         #[arg(short, long)]
         map_out: Option<PathBuf>,
-    }
+    },
+
+    /// Export the editing history of a DT file to a git repository. Each run of operations
+    /// becomes a commit, authored by the agent which made the changes. Concurrent edits become
+    /// branches and merge commits.
+    GitExport {
+        /// Diamond types file to read
+        dt_filename: OsString,
+
+        /// Git repository to write to. The repository is created if it doesn't exist.
+        repo: PathBuf,
+
+        /// Path of the document in the repository. Defaults to the name of the DT file, with a
+        /// .txt extension.
+        #[arg(long)]
+        path: Option<PathBuf>,
+
+        /// Branch to write. Defaults to 'master'.
+        #[arg(short, long)]
+        branch: Option<String>,
+
+        /// Commit at most this many operations at a time. By default each run of operations
+        /// becomes a single commit.
+        #[arg(long)]
+        batch: Option<usize>,
+
+        /// Overwrite the branch if it already exists.
+        #[arg(short, long)]
+        force: bool,
+
+        /// Quiet mode
+        #[arg(short, long)]
+        quiet: bool,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -282,6 +572,15 @@ impl FromStr for Version {
     }
 }
 
+impl Version {
+    /// Convert the version to a frontier of local versions in the oplog. This fails if the oplog
+    /// doesn't contain the version.
+    fn to_local(&self, oplog: &ListOpLog) -> Result<Frontier, Error> {
+        oplog.cg.agent_assignment.try_remote_to_local_frontier(self.0.iter())
+            .map_err(|e| anyhow!("Version {} is not in the document ({e:?})", serde_json::to_string(&self.0).unwrap_or_default()))
+    }
+}
+
 fn parse_dt_oplog(filename: &str) -> Result<ListOpLog, anyhow::Error> {
     let data = fs::read(filename)?;
     let oplog = ListOpLog::load_from(&data)?;
@@ -310,20 +609,29 @@ fn main() -> Result<(), anyhow::Error> {
                 let agent_name = agent.unwrap_or_else(random_agent_name);
                 let agent = oplog.get_or_create_agent_id(&agent_name);
                 oplog.add_insert(agent, 0, &content);
+                oplog.attach_metadata((0..oplog.len()).into(), &OpMetadata::now());
             }
 
             let data = oplog.encode(ENCODE_FULL);
 
+            let _lock = FileLock::acquire(&filename)?;
             maybe_overwrite(&filename, &data, force)?;
         }
 
-        Commands::Cat { oplog, output, version } => {
+        Commands::Cat { oplog, output, version, tag } => {
             // let data = fs::read(filename)?;
             // Using custom oplog / branch here to support custom versions
             // let oplog = OpLog::load_from(&data).unwrap();
 
             // let branch = checkout_version_or_tip(oplog, version.map(|v| &v));
-            let branch = checkout_version_or_tip(&oplog, version.map(|v| v.0));
+            let branch = if let Some(tag) = tag {
+                let Some(v) = oplog.get_tag(&tag) else {
+                    return Err(anyhow!("Unknown tag '{tag}'"));
+                };
+                oplog.checkout(v.as_ref())
+            } else {
+                checkout_version_or_tip(&oplog, version.map(|v| v.0))
+            };
             let content = branch.content();
 
             // There's probably some fancy way to switch and share code here - either write to a
@@ -336,7 +644,36 @@ fn main() -> Result<(), anyhow::Error> {
             }
         }
 
-        Commands::Log { oplog, transformed, json, history: history_mode } => {
+        Commands::Tag { dt_filename, name, version, agent, json } => {
+            let _lock = if name.is_some() { Some(FileLock::acquire(&dt_filename)?) } else { None };
+            let data = fs::read(&dt_filename)?;
+            let mut oplog = ListOpLog::load_from(&data)?;
+
+            if let Some(name) = name {
+                let v = match version {
+                    Some(v) => v.to_local(&oplog)?,
+                    None => oplog.local_frontier(),
+                };
+
+                let agent_name = agent.unwrap_or_else(random_agent_name);
+                let agent_id = oplog.get_or_create_agent_id(&agent_name);
+                oplog.set_tag(agent_id, &name, v.as_ref());
+
+                let out_data = oplog.encode(EncodeOptions::default());
+                write_atomic(&dt_filename, &out_data)?;
+            } else {
+                for tag in oplog.tags().iter() {
+                    if json {
+                        let s = serde_json::to_string(&tag).unwrap();
+                        println!("{s}");
+                    } else {
+                        println!("{}\t{}", tag.name, serde_json::to_string(&tag.target).unwrap());
+                    }
+                }
+            }
+        }
+
+        Commands::Log { oplog, transformed, json, history: history_mode, compact, graph, agent, since, until, kind } => {
             if history_mode {
                 for hist in oplog.iter_history() {
                     if json {
@@ -358,24 +695,205 @@ fn main() -> Result<(), anyhow::Error> {
                         }
                     }
             } else {
-                for op in oplog.iter() {
-                    // println!("{} len {}", op.tag, op.len());
-                    if json {
-                        let s = serde_json::to_string(&op).unwrap();
-                        println!("{s}");
-                    } else {
-                        println!("{:?}", op);
+                let filter = LogFilter {
+                    agents: agent,
+                    since: since.map(|v| v.to_local(&oplog)).transpose()?.unwrap_or_default(),
+                    until: until.map(|v| v.to_local(&oplog)).transpose()?,
+                    kind: kind.map(|k| k.into()),
+                };
+
+                if graph {
+                    print_graph(&oplog, &filter);
+                } else if filter.is_empty() && !compact {
+                    for op in oplog.iter() {
+                        if json {
+                            let s = serde_json::to_string(&op).unwrap();
+                            println!("{s}");
+                        } else {
+                            println!("{:?}", op);
+                        }
                     }
+                } else {
+                    print_log(&oplog, &filter, json, compact);
+                }
+            }
+        }
+
+        Commands::Diff { oplog, from, to, json } => {
+            let from = match from {
+                Some(v) => v.to_local(&oplog)?,
+                None => Default::default(),
+            };
+            let to = match to {
+                Some(v) => v.to_local(&oplog)?,
+                None => oplog.local_frontier(),
+            };
+
+            for op in oplog.diff_versions(from.as_ref(), to.as_ref())? {
+                if json {
+                    let s = serde_json::to_string(&op).unwrap();
+                    println!("{s}");
+                } else {
+                    println!("{:?}", op);
+                }
+            }
+        }
+
+        Commands::Merge { inputs, output, force, rename_reused_agents, quiet } => {
+            let _lock = FileLock::acquire(&output)?;
+            if !force && PathBuf::from(&output).exists() {
+                let f = output.to_str().unwrap_or("(invalid)");
+                return Err(anyhow!("Output file '{f}' already exists. Overwrite by passing -f"));
+            }
+
+            let mut oplog = ListOpLog::new();
+            let mut any_conflicts = false;
+
+            for input in inputs.iter() {
+                let name = input.to_string_lossy();
+                let data = fs::read(input)?;
+                let old_len = oplog.len();
+                let old_version = oplog.local_frontier();
+
+                let result = if rename_reused_agents {
+                    oplog.decode_and_add_renaming_collisions(&data)
+                } else {
+                    oplog.decode_and_add(&data).map(|v| (v, vec![]))
+                };
+
+                let (file_version, renames) = match result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        // Give a more useful error if the file reuses agent IDs.
+                        let collisions = ListOpLog::load_from(&data)
+                            .map(|other| oplog.find_agent_collisions(&other))
+                            .unwrap_or_default();
+                        if let Some((agent, seq)) = collisions.first() {
+                            return Err(anyhow!("{name} reuses the ID ({agent}, {seq}) for a different edit. \
+                                Merge with --rename-reused-agents to rename the conflicting agent"));
+                        }
+                        return Err(anyhow!("Could not merge {name}: {e}"));
+                    }
+                };
+
+                let conflicts = oplog.find_merge_conflicts(old_version.as_ref(), file_version.as_ref());
+                any_conflicts |= !conflicts.is_empty();
+
+                if !quiet {
+                    println!("{name}: {} new operations", oplog.len() - old_len);
+                    for r in renames.iter() {
+                        println!("  Agent '{}' reused IDs from seq {}. Renamed to '{}'", r.agent, r.from_seq, r.renamed_to);
+                    }
+                    if !conflicts.is_empty() {
+                        println!("  Concurrent edits overlap in {} region(s)", conflicts.len());
+                    }
+                }
+            }
+
+            let data = oplog.encode(ENCODE_FULL);
+            maybe_overwrite(&output, &data, force)?;
+
+            if !quiet {
+                if any_conflicts {
+                    println!("Concurrent edits to the same regions were merged. Check the result.");
+                }
+                println!("Written {} operations ({} bytes) to {}", oplog.len(), data.len(), output.to_string_lossy());
+            }
+        }
+
+        Commands::Summary { oplog, output, pretty } => {
+            let summary = oplog.cg.agent_assignment.summarize_versions();
+            write_serde_data(output, pretty, &summary)?;
+        }
+
+        Commands::Patch { oplog, since, output, force, quiet } => {
+            let from_version = if let Some(since) = since {
+                let summary: VersionSummary = serde_json::from_slice(&fs::read(since)?)?;
+                oplog.cg.intersect_with_summary(&summary, &[]).0
+            } else {
+                Default::default()
+            };
+
+            let data = oplog.encode_from(ENCODE_PATCH, from_version.as_ref());
+            let _lock = FileLock::acquire(&output)?;
+            maybe_overwrite(&output, &data, force)?;
+
+            if !quiet {
+                let num_ops: usize = oplog.cg.graph.diff(from_version.as_ref(), oplog.cg.version.as_ref()).1
+                    .iter()
+                    .map(|r| r.end - r.start)
+                    .sum();
+                println!("Written patch with {num_ops} operations ({} bytes) to {}", data.len(), output.to_string_lossy());
+            }
+        }
+
+        Commands::Apply { dt_filename, patches, quiet } => {
+            let _lock = FileLock::acquire(&dt_filename)?;
+            let data = fs::read(&dt_filename)?;
+            let mut oplog = ListOpLog::load_from(&data)?;
+
+            for patch in patches.iter() {
+                let name = patch.to_string_lossy();
+                let old_len = oplog.len();
+                oplog.decode_and_add(&fs::read(patch)?)
+                    .map_err(|e| anyhow!("Could not apply {name}: {e}"))?;
+
+                if !quiet {
+                    println!("{name}: {} new operations", oplog.len() - old_len);
                 }
             }
+
+            let out_data = oplog.encode(EncodeOptions::default());
+            write_atomic(&dt_filename, &out_data)?;
         }
 
-        Commands::Version { oplog } => {
+        Commands::Version { oplog, hash } => {
             let version = serde_json::to_string(&oplog.remote_frontier()).unwrap();
             println!("{version}");
+            if hash {
+                println!("{}", oplog.local_version_hash());
+            }
         }
 
-        Commands::Set { dt_filename, target_content_file, version, quiet, agent } => {
+        Commands::Stats { dt_filename, json, pretty } => {
+            let data = fs::read(&dt_filename)?;
+            let oplog = ListOpLog::load_from(&data)?;
+            let stats = oplog.stats_for_encoded(&data)?;
+
+            if json || pretty {
+                write_serde_data(None, pretty, &stats)?;
+            } else {
+                print_stats(&stats);
+            }
+        }
+
+        Commands::Fsck { dt_filename, output, force } => {
+            let data = fs::read(&dt_filename)?;
+            let report = ListOpLog::fsck(&data);
+            print_fsck_report(&report);
+
+            if let Some(output) = output {
+                let salvaged = ListOpLog::salvage(&data)
+                    .map_err(|e| anyhow!("Could not salvage any data: {e}"))?;
+                // This panics (printing the problem) if the salvaged data is inconsistent.
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| salvaged.oplog.dbg_check(true)))
+                    .map_err(|_| anyhow!("Salvaged data failed the consistency check. Nothing was written"))?;
+
+                maybe_overwrite(&output, &salvaged.oplog.encode(EncodeOptions::default()), force)?;
+                println!("Salvaged {} operations to {}", salvaged.oplog.len(), output.to_string_lossy());
+                if salvaged.ops_truncated {
+                    println!("Warning: the operations were damaged. Only operations before the damage were recovered");
+                }
+                for chunk in salvaged.dropped_chunks.iter() {
+                    println!("Warning: dropped damaged {chunk} chunk");
+                }
+            } else if !report.is_ok() {
+                return Err(anyhow!("{} is damaged. Use -o to salvage it into a new file", dt_filename.to_string_lossy()));
+            }
+        }
+
+        Commands::Set { dt_filename, target_content_file, version, quiet, agent, lines, message } => {
+            let _lock = FileLock::acquire(&dt_filename)?;
             let data = fs::read(&dt_filename)?;
 
             let new = if target_content_file == "-" {
@@ -401,31 +919,16 @@ fn main() -> Result<(), anyhow::Error> {
 
             let mut branch = checkout_version_or_tip(&oplog, version.map(|v| v.0));
 
-            let old = branch.content().to_string();
-            let diff = TextDiff::from_chars(&old, &new);
-            let remapper = TextDiffRemapper::from_text_diff(&diff, &old, &new);
-
             let agent_name = agent.unwrap_or_else(random_agent_name);
             let agent_id = oplog.get_or_create_agent_id(&agent_name);
 
-            let mut pos = 0;
-            for (tag, str) in diff.ops().iter()
-                .flat_map(move |x| remapper.iter_slices(x)) {
+            let granularity = if lines { DiffGranularity::Lines } else { DiffGranularity::Chars };
+            let start = oplog.len();
+            branch.set_content_with(&mut oplog, agent_id, &new, granularity);
 
-                let len = str.chars().count();
-                match tag {
-                    ChangeTag::Equal => pos += len,
-                    ChangeTag::Delete => {
-                        // dbg!(("delete", pos .. pos+len));
-                        branch.delete(&mut oplog, agent_id, pos .. pos+len);
-                    }
-                    ChangeTag::Insert => {
-                        // dbg!(("insert", pos, str));
-                        branch.insert(&mut oplog, agent_id, pos, str);
-                        pos += len;
-                    }
-                }
-            }
+            let mut meta = OpMetadata::now();
+            meta.message = message;
+            oplog.attach_metadata((start..oplog.len()).into(), &meta);
 
             if !quiet {
                 println!("Resulting branch version after changes {}",
@@ -434,12 +937,22 @@ fn main() -> Result<(), anyhow::Error> {
                          serde_json::to_string(&oplog.remote_frontier()).unwrap());
             }
 
-            // TODO: Do that atomic rename nonsense instead of just overwriting.
             let out_data = oplog.encode(EncodeOptions::default());
-            fs::write(&dt_filename, out_data)?;
+            write_atomic(&dt_filename, &out_data)?;
         }
 
-        Commands::Repack { dt_filename, output, force, uncompressed, version, patch, no_inserted_content, no_deleted_content, quiet } => {
+        Commands::Watch { dt_filename, text_filename, agent, lines, interval, once, quiet } => {
+            let agent_name = agent.unwrap_or_else(random_agent_name);
+            let granularity = if lines { DiffGranularity::Lines } else { DiffGranularity::Chars };
+            watch(dt_filename, text_filename, agent_name, granularity, Duration::from_millis(interval), once, quiet)?;
+        }
+
+        Commands::Repack { dt_filename, output, force, uncompressed, version, patch, no_inserted_content, no_deleted_content, version_hash, quiet } => {
+            // Lock the input too, so we don't read it while someone else is modifying it.
+            let _locks = match output.as_ref() {
+                Some(output) => FileLock::acquire_all(&[Path::new(&dt_filename), Path::new(output)])?,
+                None => vec![FileLock::acquire(&dt_filename)?],
+            };
             let data = fs::read(&dt_filename)?;
             let oplog = ListOpLog::load_from(&data)?;
 
@@ -456,6 +969,7 @@ fn main() -> Result<(), anyhow::Error> {
                 store_inserted_content: !no_inserted_content,
                 store_deleted_content: !no_deleted_content,
                 compress_content: !uncompressed,
+                store_version_hash: version_hash,
                 verbose: false
             }, from_version.as_ref());
 
@@ -470,7 +984,7 @@ fn main() -> Result<(), anyhow::Error> {
             } else {
                 // Just overwrite the input file. We've already checked that --force is set or the
                 // change is not lossy.
-                fs::write(&dt_filename, &new_data)?;
+                write_atomic(&dt_filename, &new_data)?;
             }
 
             if !quiet {
@@ -525,18 +1039,19 @@ fn main() -> Result<(), anyhow::Error> {
             write_serde_data(output, pretty, &result)?;
         }
 
-        Commands::Dot { dt_filename, no_render, output, dot_path } => {
+        Commands::Dot { dt_filename, no_render, graphviz, output, dot_path } => {
             let data = fs::read(&dt_filename)?;
             let oplog = ListOpLog::load_from(&data)?;
 
-            let dot_input = oplog.cg.to_dot_graph();
-            // println!("{dot_input}");
-
             let render = !no_render;
 
             if render {
-                let svg_contents = generate_svg_with_dot(dot_input, dot_path)
-                    .expect("Error running DOT");
+                let svg_contents = if graphviz || dot_path.is_some() {
+                    generate_svg_with_dot(oplog.cg.to_dot_graph(), dot_path)
+                        .expect("Error running DOT")
+                } else {
+                    oplog.to_svg()
+                };
                 let out_filename = get_filename_from(&dt_filename, output, "svg");
                 if let Some(out_filename) = out_filename {
                     fs::write(&out_filename, svg_contents)?;
@@ -545,6 +1060,7 @@ fn main() -> Result<(), anyhow::Error> {
                     println!("{svg_contents}");
                 }
             } else {
+                let dot_input = oplog.cg.to_dot_graph();
                 let out_filename = get_filename_from(&dt_filename, output, "dot");
                 if let Some(out_filename) = out_filename {
                     fs::write(&out_filename, dot_input)?;
@@ -556,6 +1072,28 @@ fn main() -> Result<(), anyhow::Error> {
         }
 
         Commands::GitImport { path, branch, quiet, out, map_out } => {
+            if path.is_dir() {
+                let oplog = extract_repo_from_git(path.clone(), branch, quiet, map_out)?;
+
+                let out_filename = match out {
+                    Some(out) => out,
+                    None => {
+                        let dir = path.canonicalize()?;
+                        let mut out = PathBuf::from(dir.file_name().expect("Invalid path"));
+                        out.set_extension("json");
+                        out
+                    }
+                };
+
+                let data = serde_json::to_vec(&oplog)?;
+                let _lock = FileLock::acquire(&out_filename)?;
+                write_atomic(&out_filename, &data)?;
+                if !quiet {
+                    println!("{} bytes written to {}", data.len(), out_filename.display());
+                }
+                return Ok(());
+            }
+
             let oplog = extract_from_git(path.clone(), branch, quiet, map_out)?;
 
             let out_filename = out.unwrap_or_else(|| {
@@ -566,16 +1104,90 @@ fn main() -> Result<(), anyhow::Error> {
             });
 
             let data = oplog.encode(ENCODE_FULL);
-            fs::write(&out_filename, &data).unwrap();
+            let _lock = FileLock::acquire(&out_filename)?;
+            write_atomic(&out_filename, &data)?;
             if !quiet {
                 println!("{} bytes written to {}", data.len(), out_filename.display());
             }
         }
+
+        Commands::GitExport { dt_filename, repo, path, branch, batch, force, quiet } => {
+            let oplog = ListOpLog::load_from(&fs::read(&dt_filename)?)?;
+
+            let path = path.unwrap_or_else(|| {
+                let mut path = PathBuf::from(Path::new(&dt_filename).file_stem().expect("Invalid path"));
+                path.set_extension("txt");
+                path
+            });
+            let branch = branch.unwrap_or_else(|| "master".into());
+
+            let num_commits = export_to_git(&oplog, &repo, &path, &branch, batch, force, quiet)?;
+            if !quiet {
+                println!("Wrote {num_commits} commits to branch {branch} in {}", repo.display());
+            }
+        }
     }
     // dbg!(&cli);
     Ok(())
 }
 
+fn print_chunk_stats(chunks: &[ChunkStats], depth: usize) {
+    for c in chunks {
+        let name = format!("{}{}", "  ".repeat(depth + 1), c.chunk);
+        println!("{name:<24} {:>10} bytes", c.bytes);
+        print_chunk_stats(&c.children, depth + 1);
+    }
+}
+
+fn print_stats(stats: &OpLogStats) {
+    println!("Encoded size: {} bytes", stats.encoded_bytes);
+    println!("Operations: {} from {} agents ({} causal graph entries)", stats.num_ops, stats.num_agents, stats.cg_entries);
+
+    for (name, s) in [("Inserted", &stats.ins), ("Deleted", &stats.del)] {
+        println!("{name}: {} characters in {} runs ({} single, {} forwards, {} backwards), {} bytes of content stored",
+            s.chars, s.single_runs + s.fwd_runs + s.rev_runs, s.single_runs, s.fwd_runs, s.rev_runs, s.content_bytes);
+    }
+
+    let c = &stats.concurrency;
+    println!("Concurrency: at most {} concurrent branches, {} merges", c.max_width, c.merges);
+
+    println!("Columns:");
+    for col in stats.columns.iter() {
+        println!("  {:<22} {:>10} items in {:>8} runs ({:.1} per run)", col.name, col.items, col.runs, col.items_per_run);
+    }
+
+    println!("Chunks:");
+    print_chunk_stats(&stats.chunks, 0);
+}
+
+fn print_fsck_report(report: &FsckReport) {
+    println!("File size: {} bytes", report.file_bytes);
+    if let Some(e) = report.header_error {
+        println!("Invalid file header: {e}");
+        return;
+    }
+
+    for c in report.chunks.iter() {
+        println!("  {:<22} at {:>8} ({} bytes)", c.chunk, c.offset, c.bytes);
+    }
+    if let Some((offset, e)) = report.chunks_error {
+        println!("Could not read chunk at {offset}: {e}. The file may be truncated");
+    }
+
+    match report.checksum {
+        ChecksumStatus::Missing => println!("Checksum: none"),
+        ChecksumStatus::Valid => println!("Checksum: valid"),
+        ChecksumStatus::Invalid => println!("Checksum: INVALID"),
+    }
+    if let Some(chunk) = report.bad_chunk.as_ref() {
+        println!("Damaged chunk: {chunk}");
+    }
+    match report.load_error {
+        None => println!("File loads successfully"),
+        Some(e) => println!("File fails to load: {e}"),
+    }
+}
+
 fn write_serde_data<T: Serialize>(mut output: Option<OsString>, pretty: bool, data: &T) -> Result<(), Error> {
     // This repetition is gross, but I'm not sure a better way to do it given the type of
     // stdout and File are different. Halp!
@@ -617,22 +1229,18 @@ fn get_filename_from(dt_filename: &PathBuf, output: Option<OsString>, extension:
     }
 }
 
-fn maybe_overwrite(output: &OsString, new_data: &Vec<u8>, force: bool) -> Result<(), anyhow::Error> {
-    let file_result = fs::OpenOptions::new()
-        .create_new(!force)
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(output);
-
-    if let Err(x) = file_result.as_ref() {
-        if x.kind() == ErrorKind::AlreadyExists {
+/// Write the data to output, unless a file already exists there and force is false.
+fn maybe_overwrite<P: AsRef<Path>>(output: P, new_data: &[u8], force: bool) -> Result<(), anyhow::Error> {
+    let output = output.as_ref();
+    if force {
+        write_atomic(output, new_data)?;
+    } else if let Err(e) = write_new(output, new_data) {
+        if e.kind() == ErrorKind::AlreadyExists {
             let f = output.to_str().unwrap_or("(invalid)");
-            eprintln!("Output file '{f}' already exists. Overwrite by passing -f");
+            return Err(anyhow!("Output file '{f}' already exists. Overwrite by passing -f"));
         }
+        return Err(e.into());
     }
-
-    file_result?.write_all(&new_data)?;
     Ok(())
 }
 
@@ -0,0 +1,285 @@
+//! Keep a diamond types file in sync with a plain text file, so documents can be edited in any
+//! text editor.
+//!
+//! We remember the version of the document the text file was last synced at. Whenever either
+//! file changes, we:
+//!
+//! 1. Check out the document at the last synced version. (This matches what the text file said
+//!    the last time we looked at it).
+//! 2. Diff that against the text file's current contents, and record any changes as new operations
+//! 3. Merge in any operations which were added to the .dt file by someone else.
+//!
+//! This is a three-way merge. Local edits and remote edits are both kept, and the merged result is
+//! written back out to the text file.
+//!
+//! The last synced version is saved next to the text file (in `<text file>.dt-version`), so
+//! syncing can pick up where it left off after dt watch is restarted.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use anyhow::{anyhow, Error};
+use diamond_types::{Frontier, LV};
+use diamond_types::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
+use diamond_types::list::ListOpLog;
+use diamond_types::list::metadata::OpMetadata;
+use diamond_types::list::set_content::DiffGranularity;
+use diamond_types::list::encoding::EncodeOptions;
+use crate::fsutil::{FileLock, sibling_path, write_atomic};
+
+/// The state of the text file and .dt file as of the last sync.
+struct SyncState {
+    /// The version of the document which the text file contains.
+    version: Frontier,
+    /// The text file's contents, as of the last sync.
+    text: String,
+}
+
+/// Returns the modification time and size of the named file, or None if it doesn't exist.
+fn file_stamp(path: &Path) -> Result<Option<(SystemTime, u64)>, Error> {
+    match fs::metadata(path) {
+        Ok(m) => Ok(Some((m.modified()?, m.len()))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the text file. If the file has been deleted, we treat it as unchanged.
+fn read_text(path: &Path, last_text: &str) -> Result<String, Error> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(s),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(last_text.to_string()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn state_path(text_path: &Path) -> PathBuf {
+    sibling_path(text_path, ".dt-version")
+}
+
+/// We need to check out the document to sync it with the text file.
+fn check_inserted_content(oplog: &ListOpLog) -> Result<(), Error> {
+    if oplog.has_inserted_content() { Ok(()) }
+    else { Err(anyhow!("The document is missing inserted content (was it repacked with --no-inserted-content?), so it can't be synced")) }
+}
+
+/// Load the version the text file was last synced at. If we haven't synced the text file before,
+/// its contents are treated as an edit to the latest version.
+fn load_state(oplog: &ListOpLog, text_path: &Path) -> Result<SyncState, Error> {
+    check_inserted_content(oplog)?;
+    let version = fs::read(state_path(text_path)).ok()
+        .filter(|_| text_path.exists())
+        .and_then(|data| serde_json::from_slice::<Vec<RemoteVersionOwned>>(&data).ok())
+        .and_then(|v| oplog.cg.agent_assignment.try_remote_to_local_frontier(v.iter()).ok())
+        .unwrap_or_else(|| oplog.local_frontier());
+
+    Ok(SyncState {
+        text: oplog.checkout(version.as_ref()).content().to_string(),
+        version,
+    })
+}
+
+/// Record that the text file now contains the document at version.
+fn save_state(oplog: &ListOpLog, text_path: &Path, state: &mut SyncState, version: Frontier, text: String) -> Result<(), Error> {
+    if state.version != version || !state_path(text_path).exists() {
+        let remote_version = oplog.cg.agent_assignment.local_to_remote_frontier_owned(version.as_ref());
+        write_atomic(state_path(text_path), serde_json::to_string(&remote_version)?.as_bytes())?;
+    }
+
+    state.version = version;
+    state.text = text;
+    Ok(())
+}
+
+/// The result of merging the text file's contents with the operations in the oplog.
+struct MergeResult {
+    /// The number of operations recorded from edits to the text file.
+    local_ops: usize,
+    /// The version containing the text file's edits, but nothing else new from the oplog.
+    local_version: Frontier,
+    /// True if the oplog contained operations which the text file didn't have yet.
+    remote_changes: bool,
+    /// The version of the merged document. This is the latest version in the oplog.
+    version: Frontier,
+    /// The merged document's content.
+    content: String,
+}
+
+/// Three-way merge the text file's contents with the oplog. `base` is the version the text file
+/// was last synced at and `base_text` is its content at that version. Any edits from base_text to
+/// text are recorded in the oplog as new operations from the named agent, then merged with
+/// everything else in the oplog.
+fn merge_text(oplog: &mut ListOpLog, base: &[LV], base_text: &str, text: &str, agent_name: &str, granularity: DiffGranularity) -> Result<MergeResult, Error> {
+    check_inserted_content(oplog)?;
+    let mut branch = oplog.checkout(base);
+
+    let mut local_ops = 0;
+    if text != base_text {
+        let agent = oplog.get_or_create_agent_id(agent_name);
+        let start = oplog.len();
+        branch.set_content_with(oplog, agent, text, granularity);
+        oplog.attach_metadata((start..oplog.len()).into(), &OpMetadata::now());
+        local_ops = oplog.len() - start;
+    }
+
+    let local_version = branch.local_frontier();
+    let remote_changes = branch.local_frontier_ref() != oplog.local_frontier_ref();
+    branch.merge(oplog, oplog.local_frontier_ref());
+
+    Ok(MergeResult {
+        local_ops,
+        local_version,
+        remote_changes,
+        version: branch.local_frontier(),
+        content: branch.content().to_string(),
+    })
+}
+
+fn sync(dt_path: &Path, text_path: &Path, agent_name: &str, granularity: DiffGranularity, state: &mut SyncState, quiet: bool) -> Result<(), Error> {
+    let _lock = FileLock::acquire(dt_path)?;
+    let mut oplog = ListOpLog::load_from(&fs::read(dt_path)?)?;
+
+    let text = read_text(text_path, &state.text)?;
+    let merged = merge_text(&mut oplog, state.version.as_ref(), &state.text, &text, agent_name, granularity)?;
+
+    if merged.local_ops > 0 {
+        write_atomic(dt_path, &oplog.encode(EncodeOptions::default()))?;
+        if !quiet { println!("Recorded {} operations from {}", merged.local_ops, text_path.display()); }
+    }
+
+    if merged.content != text || !text_path.exists() {
+        // Don't clobber edits made while we were merging. We'll pick them up next time around.
+        if read_text(text_path, &text)? != text {
+            return save_state(&oplog, text_path, state, merged.local_version, text);
+        }
+        write_atomic(text_path, merged.content.as_bytes())?;
+        if !quiet && merged.remote_changes {
+            println!("Updated {} with remote changes ({} -> {} characters)", text_path.display(),
+                text.chars().count(), merged.content.chars().count());
+        }
+    }
+
+    save_state(&oplog, text_path, state, merged.version, merged.content)
+}
+
+/// Watch the .dt file and the text file for changes, keeping them in sync. If once is true, the
+/// files are synced once and this returns immediately.
+pub fn watch(dt_filename: OsString, text_filename: OsString, agent_name: String, granularity: DiffGranularity, interval: Duration, once: bool, quiet: bool) -> Result<(), Error> {
+    let dt_path = Path::new(&dt_filename);
+    let text_path = Path::new(&text_filename);
+
+    let oplog = ListOpLog::load_from(&fs::read(dt_path)?)?;
+    let mut state = load_state(&oplog, text_path)?;
+    drop(oplog);
+
+    sync(dt_path, text_path, &agent_name, granularity, &mut state, quiet)?;
+    if once { return Ok(()); }
+
+    if !quiet {
+        println!("Watching {} and {} for changes. Press Ctrl+C to stop.", dt_path.display(), text_path.display());
+    }
+
+    let mut stamps = (file_stamp(dt_path)?, file_stamp(text_path)?);
+    loop {
+        thread::sleep(interval);
+
+        let result = (|| -> Result<(), Error> {
+            let new_stamps = (file_stamp(dt_path)?, file_stamp(text_path)?);
+            if new_stamps != stamps {
+                sync(dt_path, text_path, &agent_name, granularity, &mut state, quiet)?;
+                // Our own writes change the stamps too.
+                stamps = (file_stamp(dt_path)?, file_stamp(text_path)?);
+            }
+            Ok(())
+        })();
+
+        // Errors are often temporary (eg, an editor is halfway through saving the file). The
+        // stamps aren't updated when sync fails, so we'll try again on the next tick.
+        if let Err(e) = result {
+            eprintln!("Error syncing {} and {}: {e}", dt_path.display(), text_path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use diamond_types::list::ListOpLog;
+    use diamond_types::list::operation::{ListOpKind, TextOperation};
+    use diamond_types::list::set_content::DiffGranularity;
+    use super::{load_state, merge_text};
+
+    #[test]
+    fn local_edits_are_recorded() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_insert(seph, 0, "hello");
+
+        let result = merge_text(&mut oplog, &[v], "hello", "hello world", "mike", DiffGranularity::Chars).unwrap();
+        assert_eq!(result.content, "hello world");
+        assert_eq!(result.local_ops, 6);
+        assert!(!result.remote_changes);
+        assert_eq!(result.version, oplog.local_frontier());
+        assert_eq!(oplog.checkout_tip().content().to_string(), "hello world");
+    }
+
+    #[test]
+    fn remote_edits_are_merged() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_insert(seph, 0, "hello");
+        oplog.add_insert(seph, 5, " world");
+        let len = oplog.len();
+
+        let result = merge_text(&mut oplog, &[v], "hello", "hello", "mike", DiffGranularity::Chars).unwrap();
+        assert_eq!(result.content, "hello world");
+        assert_eq!(result.local_ops, 0);
+        assert!(result.remote_changes);
+        assert_eq!(result.local_version.as_ref(), &[v]);
+        assert_eq!(oplog.len(), len);
+    }
+
+    #[test]
+    fn concurrent_edits_are_both_kept() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_insert(seph, 0, "hello world");
+        oplog.add_delete_without_content(seph, 0..1);
+        oplog.add_insert(seph, 0, "H");
+
+        let result = merge_text(&mut oplog, &[v], "hello world", "hello world!", "mike", DiffGranularity::Chars).unwrap();
+        assert_eq!(result.content, "Hello world!");
+        assert_eq!(result.local_ops, 1);
+        assert!(result.remote_changes);
+        assert_eq!(oplog.checkout(result.local_version.as_ref()).content().to_string(), "hello world!");
+        assert_eq!(oplog.checkout_tip().content().to_string(), "Hello world!");
+    }
+
+    #[test]
+    fn unchanged_text_is_a_noop() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_insert(seph, 0, "hello");
+
+        let result = merge_text(&mut oplog, &[v], "hello", "hello", "mike", DiffGranularity::Lines).unwrap();
+        assert_eq!(result.content, "hello");
+        assert_eq!(result.local_ops, 0);
+        assert!(!result.remote_changes);
+        assert_eq!(oplog.len(), 5);
+    }
+
+    #[test]
+    fn missing_content_is_an_error() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_operations(seph, &[TextOperation {
+            loc: (0..5).into(), kind: ListOpKind::Ins, content: None
+        }]);
+
+        assert!(load_state(&oplog, std::path::Path::new("missing.txt")).is_err());
+        assert!(merge_text(&mut oplog, &[v], "hello", "hello world", "mike", DiffGranularity::Chars).is_err());
+        assert_eq!(oplog.len(), 5);
+    }
+}
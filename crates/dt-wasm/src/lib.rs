@@ -285,6 +285,13 @@ impl OpLog {
         to_bytes(&self.inner)
     }
 
+    /// Render the causal graph as an SVG image.
+    #[cfg(feature = "svg")]
+    #[wasm_bindgen(js_name = toSVG)]
+    pub fn to_svg(&self) -> String {
+        self.inner.to_svg()
+    }
+
     #[wasm_bindgen(js_name = getPatchSince)]
     pub fn get_patch_since(&self, from_version: &[usize]) -> Vec<u8> {
         get_patch_since(&self.inner, from_version)
@@ -393,6 +400,13 @@ impl Doc {
         to_bytes(&self.inner.oplog)
     }
 
+    /// Render the causal graph as an SVG image.
+    #[cfg(feature = "svg")]
+    #[wasm_bindgen(js_name = toSVG)]
+    pub fn to_svg(&self) -> String {
+        self.inner.oplog.to_svg()
+    }
+
     #[wasm_bindgen(js_name = getPatchSince)]
     pub fn get_patch_since(&self, from_version: &[LV]) -> Vec<u8> {
         get_patch_since(&self.inner.oplog, from_version)
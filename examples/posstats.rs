@@ -77,7 +77,8 @@ fn print_stats_for_testdata(name: &str) {
         store_inserted_content: true,
         store_deleted_content: false,
         compress_content: true,
-        verbose: true
+        verbose: true,
+        ..Default::default()
     });
     println!("Regular file size {} bytes", data.len());
     std::fs::write(out_file.clone(), data.as_slice()).unwrap();
@@ -110,7 +111,8 @@ fn print_stats_for_file(name: &str) {
         store_inserted_content: false,
         store_deleted_content: false,
         compress_content: true,
-        verbose: true
+        verbose: true,
+        ..Default::default()
     });
     println!("Smol size {}", data_smol.len());
 
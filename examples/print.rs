@@ -68,6 +68,7 @@ fn print_stats_for_file(name: &str) {
         store_deleted_content: true,
         compress_content: true,
         verbose: true,
+        ..Default::default()
     });
 }
 
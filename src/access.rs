@@ -0,0 +1,409 @@
+//! Access control for remote operations.
+//!
+//! By default any peer can edit any part of a document. A server which relays edits between users
+//! often needs finer grained control than that - for example, a reviewer might be allowed to add
+//! comments but not to edit the document's text.
+//!
+//! An [`AccessPolicy`] can be attached to an [`OpLog`] or a [`ListOpLog`]. When set, it is
+//! consulted for every incoming remote operation, with the name of the agent which authored the
+//! operation and a description of what the operation modifies. If the policy rejects any operation,
+//! the whole merge is rejected with [`ParseError::AccessDenied`] and the oplog is left unmodified.
+//!
+//! Incoming changes to [tags](crate::tags) are checked with [`AccessPolicy::allow_tag`]. Incoming
+//! [metadata](crate::list::metadata) is checked like an operation by the agent whose operations it
+//! describes. Tags and metadata which are already known are not checked again.
+//!
+//! Local changes are never checked.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use rle::HasLength;
+use crate::{AgentId, CRDTKind, CreateValue, DTRange, LV, LVKey, OpLog, ROOT_CRDT_ID, SerializedOps};
+use crate::encoding::parseerror::ParseError;
+use crate::list::ListOpLog;
+use crate::list::metadata::{iter_metadata_in, MetadataRuns};
+use crate::tags::{Tag, Tags};
+
+/// Describes a single incoming operation for an [`AccessPolicy`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IncomingOp<'a> {
+    /// The name of the agent which authored the operation.
+    pub agent: &'a str,
+
+    /// The CRDT modified by the operation. For a [`ListOpLog`] this is always `ROOT_CRDT_ID`.
+    pub crdt: LVKey,
+
+    /// The kind of CRDT modified by the operation.
+    pub kind: CRDTKind,
+
+    /// The path of map keys from the root of the document to the modified CRDT. Empty for
+    /// operations on the root map, and for all operations in a [`ListOpLog`].
+    pub path: &'a [&'a str],
+
+    /// For map operations, the key being set.
+    pub key: Option<&'a str>,
+}
+
+/// Decides which remote operations are allowed to be merged into an oplog.
+pub trait AccessPolicy: Debug + Send + Sync {
+    /// Returns true if the operation should be accepted.
+    fn allow(&self, op: &IncomingOp) -> bool;
+
+    /// Returns true if a change to a tag should be accepted. The change was made by the agent
+    /// named in `tag.set_by`.
+    fn allow_tag(&self, tag: &Tag) -> bool;
+}
+
+impl Tags {
+    /// Check the incoming tags which would modify self against the access policy, if there is one.
+    pub(crate) fn check_access<'a, I: IntoIterator<Item = &'a Tag>>(&self, policy: Option<&Arc<dyn AccessPolicy>>, incoming: I) -> Result<(), ParseError> {
+        let Some(policy) = policy else { return Ok(()); };
+
+        for tag in incoming {
+            if self.would_change(tag) && !policy.allow_tag(tag) {
+                return Err(ParseError::AccessDenied);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ListOpLog {
+    /// Set (or clear) the policy used to accept or reject incoming operations. When set,
+    /// [`decode_and_add`](ListOpLog::decode_and_add) and [`merge_ops`](ListOpLog::merge_ops) will
+    /// return [`ParseError::AccessDenied`] (and merge nothing) if the policy rejects any new
+    /// operation.
+    ///
+    /// The policy is called once per run of new operations from the same agent.
+    pub fn set_access_policy(&mut self, policy: Option<Arc<dyn AccessPolicy>>) {
+        self.access_policy = policy;
+    }
+
+    /// Check the operations in `spans` against the access policy, if there is one. Agent names
+    /// are looked up in `self`, so this must be called on the oplog which contains the operations.
+    pub(crate) fn check_access(&self, policy: Option<&Arc<dyn AccessPolicy>>, spans: &[DTRange]) -> Result<(), ParseError> {
+        let Some(policy) = policy else { return Ok(()); };
+
+        for &span in spans {
+            for agent_span in self.iter_agent_mappings_range(span) {
+                let op = IncomingOp {
+                    agent: self.get_agent_name(agent_span.agent),
+                    crdt: ROOT_CRDT_ID,
+                    kind: CRDTKind::Text,
+                    path: &[],
+                    key: None,
+                };
+                if !policy.allow(&op) { return Err(ParseError::AccessDenied); }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check incoming metadata runs against the access policy, if there is one. Each run is checked
+    /// as if it were an operation by the run's agent. Runs are passed as (agent, seq range, agent
+    /// in `existing`). Runs which wouldn't add any new metadata to `existing` are skipped. Agent
+    /// names are looked up in `self`.
+    pub(crate) fn check_metadata_access<I>(&self, policy: Option<&Arc<dyn AccessPolicy>>, existing: &MetadataRuns, runs: I) -> Result<(), ParseError>
+        where I: IntoIterator<Item = (AgentId, DTRange, AgentId)>
+    {
+        let Some(policy) = policy else { return Ok(()); };
+
+        for (agent, seq_range, existing_agent) in runs {
+            if !iter_metadata_in(existing, existing_agent, seq_range).any(|(_, meta)| meta.is_none()) {
+                continue;
+            }
+
+            let op = IncomingOp {
+                agent: self.get_agent_name(agent),
+                crdt: ROOT_CRDT_ID,
+                kind: CRDTKind::Text,
+                path: &[],
+                key: None,
+            };
+            if !policy.allow(&op) { return Err(ParseError::AccessDenied); }
+        }
+
+        Ok(())
+    }
+}
+
+impl OpLog {
+    /// Set (or clear) the policy used to accept or reject incoming operations. When set,
+    /// [`merge_ops`](OpLog::merge_ops), [`try_remote_map_set`](OpLog::try_remote_map_set) and
+    /// [`try_remote_text_op`](OpLog::try_remote_text_op) will return [`ParseError::AccessDenied`] if the
+    /// policy rejects an operation.
+    pub fn set_access_policy(&mut self, policy: Option<Arc<dyn AccessPolicy>>) {
+        self.access_policy = policy;
+    }
+
+    /// Find the path of keys from the root map to the named CRDT. `pending` contains CRDTs created
+    /// by operations which haven't been applied yet, mapped to their parent CRDT and key.
+    fn crdt_path<'a>(&'a self, mut crdt: LVKey, pending: &BTreeMap<LV, (LVKey, &'a str)>) -> Vec<&'a str> {
+        let mut path = vec![];
+        while crdt != ROOT_CRDT_ID {
+            let parent = if let Some(&(parent, key)) = pending.get(&crdt) {
+                Some((parent, key))
+            } else if let Some((parent, key)) = self.map_index.get(&crdt) {
+                Some((*parent, key.as_str()))
+            } else {
+                // The CRDT has been overwritten. This is rare, so just scan for the operation which
+                // created it.
+                self.map_keys.iter()
+                    .find(|(_, info)| info.ops.iter().any(|(v, _)| *v == crdt))
+                    .map(|((parent, key), _)| (*parent, key.as_str()))
+            };
+
+            let Some((parent, key)) = parent else { break; };
+            path.push(key);
+            crdt = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    fn check_op_access(&self, policy: &dyn AccessPolicy, v: LV, crdt: LVKey, kind: CRDTKind, key: Option<&str>, pending: &BTreeMap<LV, (LVKey, &str)>) -> Result<(), ParseError> {
+        let agent = self.cg.agent_assignment.local_to_agent_version(v).0;
+        let path = self.crdt_path(crdt, pending);
+        let op = IncomingOp {
+            agent: self.cg.agent_assignment.get_agent_name(agent),
+            crdt,
+            kind,
+            path: &path,
+            key,
+        };
+        if policy.allow(&op) { Ok(()) } else { Err(ParseError::AccessDenied) }
+    }
+
+    /// Check a single remote map operation against the access policy.
+    pub(crate) fn check_map_set_access(&self, crdt: LVKey, v: LV, key: &str) -> Result<(), ParseError> {
+        if let Some(policy) = self.access_policy.as_ref() {
+            self.check_op_access(policy.as_ref(), v, crdt, CRDTKind::Map, Some(key), &BTreeMap::new())
+        } else { Ok(()) }
+    }
+
+    /// Check a single remote text operation against the access policy.
+    pub(crate) fn check_text_op_access(&self, crdt: LVKey, v_range: DTRange) -> Result<(), ParseError> {
+        if let Some(policy) = self.access_policy.as_ref() {
+            self.check_op_access(policy.as_ref(), v_range.start, crdt, CRDTKind::Text, None, &BTreeMap::new())
+        } else { Ok(()) }
+    }
+
+    /// Check all the new operations in changes (which have been added to the causal graph in
+    /// new_range, but not applied yet) and any tags which would change against the access policy.
+    pub(crate) fn check_merge_access(&self, changes: &SerializedOps, new_range: DTRange) -> Result<(), ParseError> {
+        let Some(policy) = self.access_policy.as_ref() else { return Ok(()); };
+
+        self.tags.check_access(Some(policy), changes.tags.iter())?;
+        if new_range.is_empty() { return Ok(()); }
+
+        // CRDTs created by this set of changes aren't in the index yet.
+        let mut pending = BTreeMap::new();
+        for (crdt_r_name, rv, key, val) in changes.map_ops.iter() {
+            let lv = self.cg.agent_assignment.try_remote_to_local_version(*rv)
+                .map_err(ParseError::InvalidRemoteID)?;
+            if new_range.contains(lv) {
+                if let CreateValue::NewCRDT(_) = val {
+                    pending.insert(lv, (self.try_remote_to_crdt_name(*crdt_r_name)?, *key));
+                }
+            }
+        }
+
+        for (crdt_r_name, rv, key, _) in changes.map_ops.iter() {
+            let lv = self.cg.agent_assignment.try_remote_to_local_version(*rv)
+                .map_err(ParseError::InvalidRemoteID)?;
+            if new_range.contains(lv) {
+                let crdt = self.try_remote_to_crdt_name(*crdt_r_name)?;
+                self.check_op_access(policy.as_ref(), lv, crdt, CRDTKind::Map, Some(key), &pending)?;
+            }
+        }
+
+        for (crdt_r_name, rv, op_metrics) in changes.text_ops.iter() {
+            let lv = self.cg.agent_assignment.try_remote_to_local_version(*rv)
+                .map_err(ParseError::InvalidRemoteID)?;
+            if lv + op_metrics.len() > new_range.start {
+                let crdt = self.try_remote_to_crdt_name(*crdt_r_name)?;
+                self.check_op_access(policy.as_ref(), lv.max(new_range.start), crdt, CRDTKind::Text, None, &pending)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::{CRDTKind, CreateValue, OpLog, Primitive, ROOT_CRDT_ID};
+    use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::ListOpLog;
+    use crate::list::metadata::OpMetadata;
+    use crate::list::operation::TextOperation;
+    use super::*;
+
+    /// Reviewers can only edit the "comments" map. Everyone else can edit anything.
+    #[derive(Debug)]
+    struct ReviewerPolicy;
+
+    impl AccessPolicy for ReviewerPolicy {
+        fn allow(&self, op: &IncomingOp) -> bool {
+            op.agent != "reviewer" || op.path.first() == Some(&"comments")
+        }
+
+        fn allow_tag(&self, tag: &Tag) -> bool {
+            tag.set_by != "reviewer"
+        }
+    }
+
+    #[test]
+    fn list_decode_rejects_atomically() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+        let mut dest = ListOpLog::new();
+        dest.set_access_policy(Some(Arc::new(ReviewerPolicy)));
+        dest.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+
+        let reviewer = oplog.get_or_create_agent_id("reviewer");
+        oplog.add_insert(reviewer, 0, "nope ");
+        oplog.add_insert(seph, 0, "x");
+        let before = dest.clone();
+        assert_eq!(dest.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap_err(), ParseError::AccessDenied);
+        assert_eq!(dest, before);
+
+        assert_eq!(dest.merge_ops(&oplog).unwrap_err(), ParseError::AccessDenied);
+        assert_eq!(dest, before);
+
+        dest.set_access_policy(None);
+        dest.merge_ops(&oplog).unwrap();
+        assert_eq!(dest, oplog);
+    }
+
+    #[test]
+    fn oplog_merge_checks_paths() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "Oh hai!"));
+        let comments = oplog.local_map_set(seph, ROOT_CRDT_ID, "comments", CreateValue::NewCRDT(CRDTKind::Map));
+
+        let mut dest = OpLog::new();
+        dest.set_access_policy(Some(Arc::new(ReviewerPolicy)));
+        dest.merge_ops(oplog.ops_since(&[])).unwrap();
+        let v = oplog.cg.version.clone();
+
+        // The reviewer can add comments.
+        let reviewer = oplog.cg.get_or_create_agent_id("reviewer");
+        oplog.local_map_set(reviewer, comments, "c1", CreateValue::Primitive(Primitive::I64(1)));
+        dest.merge_ops(oplog.ops_since(v.as_ref())).unwrap();
+        assert_eq!(dest.checkout(), oplog.checkout());
+        let v = oplog.cg.version.clone();
+
+        // But they can't edit the text, or add keys outside the comments map.
+        oplog.local_text_op(reviewer, text, TextOperation::new_insert(0, "x"));
+        let cg_before = dest.cg.clone();
+        assert_eq!(dest.merge_ops(oplog.ops_since(v.as_ref())).unwrap_err(), ParseError::AccessDenied);
+        assert_eq!(dest.cg, cg_before);
+
+        let mut oplog2 = dest.clone();
+        let reviewer = oplog2.cg.get_or_create_agent_id("reviewer");
+        oplog2.local_map_set(reviewer, ROOT_CRDT_ID, "title", CreateValue::Primitive(Primitive::I64(2)));
+        assert_eq!(dest.merge_ops(oplog2.ops_since(dest.cg.version.as_ref())).unwrap_err(), ParseError::AccessDenied);
+        assert_eq!(dest.cg, cg_before);
+        dest.dbg_check(true);
+    }
+
+    #[test]
+    fn list_tags_and_metadata_are_checked() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let reviewer = oplog.get_or_create_agent_id("reviewer");
+        oplog.add_insert(seph, 0, "hi there");
+        oplog.add_insert(reviewer, 0, "nope ");
+
+        // The reviewer's operations were merged before there was a policy.
+        let mut dest = ListOpLog::new();
+        dest.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+        dest.set_access_policy(Some(Arc::new(ReviewerPolicy)));
+
+        // Metadata and tags from seph are fine.
+        oplog.attach_metadata((0..8).into(), &OpMetadata::default().with_message("seph"));
+        oplog.set_tag(seph, "v1", &[7]);
+        dest.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+        dest.merge_ops(&oplog).unwrap();
+        assert_eq!(dest.get_tag("v1").unwrap().as_ref(), &[7]);
+
+        // But the reviewer can't attach metadata to their operations, or set tags.
+        let mut oplog2 = oplog.clone();
+        oplog2.attach_metadata((8..13).into(), &OpMetadata::default().with_message("reviewer"));
+        let before = dest.clone();
+        assert_eq!(dest.decode_and_add(&oplog2.encode(ENCODE_FULL)).unwrap_err(), ParseError::AccessDenied);
+        assert_eq!(dest.merge_ops(&oplog2).unwrap_err(), ParseError::AccessDenied);
+        assert_eq!(dest, before);
+
+        let mut oplog3 = oplog.clone();
+        oplog3.set_tag(reviewer, "v1", &[12]);
+        assert_eq!(dest.decode_and_add(&oplog3.encode(ENCODE_FULL)).unwrap_err(), ParseError::AccessDenied);
+        assert_eq!(dest.merge_ops(&oplog3).unwrap_err(), ParseError::AccessDenied);
+        assert_eq!(dest, before);
+    }
+
+    #[test]
+    fn oplog_tags_are_checked() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "title", CreateValue::Primitive(Primitive::I64(1)));
+
+        let mut dest = OpLog::new();
+        dest.set_access_policy(Some(Arc::new(ReviewerPolicy)));
+        dest.merge_ops(oplog.ops_since(&[])).unwrap();
+
+        // Tags can be merged without any new operations.
+        let reviewer = oplog.cg.get_or_create_agent_id("reviewer");
+        oplog.set_tag(reviewer, "v1", &[0]);
+        let v = dest.cg.version.clone();
+        assert_eq!(dest.merge_ops(oplog.ops_since(v.as_ref())).unwrap_err(), ParseError::AccessDenied);
+        assert!(dest.tags().is_empty());
+
+        oplog.set_tag(seph, "v1", &[0]);
+        dest.merge_ops(oplog.ops_since(v.as_ref())).unwrap();
+        assert_eq!(dest.get_tag("v1").unwrap().as_ref(), &[0]);
+    }
+
+    #[test]
+    fn unknown_crdt_is_an_error() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+
+        // Point the text operation at a CRDT which doesn't exist.
+        let mut changes = oplog.ops_since(&[]);
+        changes.text_ops[0].0 = RemoteVersion("nobody", 10);
+
+        let mut dest = OpLog::new();
+        dest.set_access_policy(Some(Arc::new(ReviewerPolicy)));
+        assert!(matches!(dest.merge_ops(changes).unwrap_err(), ParseError::InvalidRemoteID(_)));
+        assert_eq!(dest.cg.len(), 0);
+    }
+
+    #[test]
+    fn paths_of_new_crdts() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let comments = oplog.local_map_set(seph, ROOT_CRDT_ID, "comments", CreateValue::NewCRDT(CRDTKind::Map));
+        let reviewer = oplog.cg.get_or_create_agent_id("reviewer");
+        let c = oplog.local_map_set(reviewer, comments, "c1", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(reviewer, c, TextOperation::new_insert(0, "looks good"));
+
+        // The comment CRDT is created and edited in the same set of changes.
+        let mut dest = OpLog::new();
+        dest.set_access_policy(Some(Arc::new(ReviewerPolicy)));
+        dest.merge_ops(oplog.ops_since(&[])).unwrap();
+        assert_eq!(dest.checkout(), oplog.checkout());
+    }
+}
@@ -1,13 +1,14 @@
 use smallvec::SmallVec;
-use rle::{HasLength, MergableSpan};
+use rle::{HasLength, MergableSpan, SplitableSpan};
 use rle::zip::rle_zip;
-use crate::{AgentId, CausalGraph, LV};
+use crate::{AgentId, CausalGraph, Frontier, LV};
 use crate::causalgraph::*;
 use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontier, RemoteFrontierOwned};
 use crate::causalgraph::entry::CGEntry;
 use crate::causalgraph::graph::GraphEntrySimple;
 use crate::causalgraph::agent_span::AgentSpan;
-use crate::rle::{RleSpanHelpers, RleVec};
+use crate::rle::{KVPair, RleKeyedAndSplitable, RleSpanHelpers, RleVec};
+use crate::encoding::parseerror::ParseError;
 
 impl CausalGraph {
     pub fn new() -> Self {
@@ -120,9 +121,77 @@ impl CausalGraph {
         time_span
     }
 
+    /// Check that the known operations in `lvs` were created with the given parents. Operations
+    /// after the first in the range must directly follow the operation before them. If they don't,
+    /// some agent has reused (agent, seq) IDs and `ParseError::AgentIdReused` is returned.
+    pub(crate) fn check_known_lv_span(&self, parents: &[LV], lvs: DTRange) -> Result<(), ParseError> {
+        for e in self.graph.iter_range(lvs) {
+            let matches = if e.span.start == lvs.start {
+                e.parents.as_ref() == parents
+            } else {
+                e.parents.as_ref() == [e.span.start - 1]
+            };
+
+            if !matches {
+                let (agent, seq) = self.agent_assignment.local_to_agent_version(e.span.start);
+                return Err(ParseError::AgentIdReused { agent, seq });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like [`check_known_lv_span`](CausalGraph::check_known_lv_span), but for a span of (agent,
+    /// seq) IDs. All the IDs in the span must be known.
+    pub(crate) fn check_known_span(&self, parents: &[LV], span: AgentSpan) -> Result<(), ParseError> {
+        let client = &self.agent_assignment.client_data[span.agent as usize];
+        let mut expected = Frontier::from_sorted(parents);
+        let mut seq = span.seq_range.start;
+
+        while seq < span.seq_range.end {
+            let lvs = client.try_seq_to_lv_span((seq..span.seq_range.end).into())
+                .ok_or(ParseError::AgentIdReused { agent: span.agent, seq })?;
+
+            self.check_known_lv_span(expected.as_ref(), lvs)?;
+            expected = Frontier::new_1(lvs.last());
+            seq += lvs.len();
+        }
+
+        Ok(())
+    }
+
+    /// Like [`merge_and_assign`](CausalGraph::merge_and_assign), but if some of the incoming span
+    /// is already known, this checks the known operations have the same parents. If they don't,
+    /// some agent has reused IDs and `ParseError::AgentIdReused` is returned without modifying the
+    /// causal graph.
+    ///
+    /// This should be used when merging data from remote peers.
+    pub fn try_merge_and_assign(&mut self, parents: &[LV], span: AgentSpan) -> Result<DTRange, ParseError> {
+        let client_data = &self.agent_assignment.client_data[span.agent as usize];
+
+        // The known part of the span is always at the start. (See merge_and_assign.)
+        let known_end = match client_data.item_times.find_index(span.seq_range.last()) {
+            Ok(_) => span.seq_range.end,
+            Err(idx) if idx >= 1 => client_data.item_times.0[idx - 1].end().max(span.seq_range.start),
+            Err(_) => span.seq_range.start,
+        };
+
+        if known_end > span.seq_range.start {
+            self.check_known_span(parents, AgentSpan {
+                agent: span.agent,
+                seq_range: (span.seq_range.start..known_end).into(),
+            })?;
+        }
+
+        Ok(self.merge_and_assign(parents, span))
+    }
+
     /// This method merges the specified entry into the causal graph. The incoming data might
     /// already be known by the causal graph.
     ///
+    /// Operations which are already known are skipped without being checked. Use
+    /// [`try_merge_and_assign`](CausalGraph::try_merge_and_assign) to detect reused IDs.
+    ///
     /// This takes a CGEntry rather than a CRDTSpan because that makes the overlap calculations much
     /// easier (its constant time rather than needing to loop, because subsequent ops in the region)
     /// all depend on the first).
@@ -200,6 +269,89 @@ impl CausalGraph {
         }
     }
 
+    /// Roll the causal graph back to a previous state, discarding all versions from `len` onwards
+    /// and any agents past `num_agents`. This is used to unwind partially merged data when
+    /// merging fails.
+    ///
+    /// `len` must be the length of the causal graph before the data was merged, and `version` its
+    /// version at that point.
+    pub(crate) fn truncate_to(&mut self, len: LV, num_agents: usize, version: Frontier) {
+        // This would be nicer with an RleVec iterator, but the iter implementation doesn't
+        // support iterating backwards.
+        while let Some(last) = self.agent_assignment.client_with_localtime.0.last_mut() {
+            debug_assert!(len <= last.end());
+            if len == last.end() { break; }
+            else {
+                // Truncate!
+                let KVPair(_, removed) = if len <= last.0 {
+                    // Drop entire entry
+                    self.agent_assignment.client_with_localtime.0.pop().unwrap()
+                } else {
+                    last.truncate(len - last.0)
+                };
+
+                let client_data = &mut self.agent_assignment.client_data[removed.agent as usize];
+                client_data.item_times.remove_ctx(removed.seq_range, &());
+            }
+        }
+
+        // Trim history
+        let hist_entries = &mut self.graph.entries;
+        let history_length = hist_entries.end();
+        if history_length > len {
+            // We can't use entries.remove because HistoryEntry doesn't support SplitableSpan.
+            // And also because we need to update child_indexes.
+            let del_span_start = len;
+
+            let first_idx = hist_entries.find_index(len).unwrap();
+
+            let e = &mut hist_entries.0[first_idx];
+            let first_truncated_idx = if del_span_start > e.span.start {
+                // The first entry just needs to be trimmed down.
+                e.span.truncate_from(del_span_start);
+                first_idx + 1
+            } else {
+                first_idx
+            };
+
+            let mut idx = first_truncated_idx;
+
+            // Go through and unwind from idx.
+            while idx < hist_entries.num_entries() {
+                // Cloning here is an ugly and kinda slow hack to work around the borrow
+                // checker. But this whole case is rare anyway, so idk.
+                let parents = hist_entries.0[idx].parents.clone();
+
+                for p in parents {
+                    if p < len { // If p >= len, the target will be discarded anyway.
+                        let parent_entry = hist_entries.find_mut(p).unwrap().0;
+                        while let Some(&c_idx) = parent_entry.child_indexes.last() {
+                            if c_idx >= first_truncated_idx {
+                                parent_entry.child_indexes.pop();
+                            } else { break; }
+                        }
+                    }
+                }
+
+                idx += 1;
+            }
+
+            self.graph.entries.0.truncate(first_truncated_idx);
+
+            while let Some(&last_idx) = self.graph.root_child_indexes.last() {
+                if last_idx >= self.graph.entries.num_entries() {
+                    self.graph.root_child_indexes.pop();
+                } else { break; }
+            }
+        }
+
+        // Remove excess agents
+        self.agent_assignment.client_data.truncate(num_agents);
+
+        self.hash_cache.truncate(len);
+        self.version = version;
+    }
+
     /// Iterate through history entries
     pub fn iter_parents(&self) -> impl Iterator<Item=GraphEntrySimple> + '_ {
         self.graph.iter()
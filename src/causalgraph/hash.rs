@@ -0,0 +1,311 @@
+//! Content-addressed hashes for versions in the causal graph.
+//!
+//! Normally operations are only identified by their (agent, seq) pair. That works fine so long as
+//! agents never reuse IDs - but if they do, peers can end up with different operations under the
+//! same name and nothing will notice.
+//!
+//! To catch that (and any other tampering), we can also compute a merkle hash of every operation.
+//! Each operation's hash covers the hashes of its parents, its agent name and sequence number, and
+//! the operation's content. The hash of a version is then the hash of the operations in its
+//! frontier. Two peers with the same version hash have exactly the same history.
+//!
+//! Hashes are computed per operation (not per graph entry) so they don't depend on how runs of
+//! operations happen to be stored locally.
+
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use rle::HasLength;
+use crate::{CausalGraph, DTRange, LV};
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 hash naming a single operation, or a version of a document.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct VersionHash(pub [u8; 32]);
+
+impl VersionHash {
+    pub fn as_bytes(&self) -> &[u8; 32] { &self.0 }
+}
+
+impl Display for VersionHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for VersionHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VersionHash({})", self)
+    }
+}
+
+fn push_len_prefixed(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn hash_sorted(domain: &[u8], hashes: &mut [VersionHash], extra: &[u8]) -> VersionHash {
+    hashes.sort_unstable();
+
+    let mut h = Sha256::new();
+    h.update(domain);
+    h.update((hashes.len() as u64).to_le_bytes());
+    for p in hashes.iter() {
+        h.update(p.0);
+    }
+    h.update(extra);
+    VersionHash(h.finalize().into())
+}
+
+/// Hashes are cached for the first operation in every graph entry, and for every
+/// `CHECKPOINT_INTERVAL`'th operation. Other hashes are recomputed from the closest cached hash.
+const CHECKPOINT_INTERVAL: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+struct Checkpoints {
+    /// Every operation before end has been hashed.
+    end: LV,
+
+    /// Cached hashes, sorted by LV.
+    hashes: Vec<(LV, VersionHash)>,
+}
+
+/// A cache of operation hashes, stored in the causal graph. Hashing the entire history of a
+/// document is slow, so hashes are computed once and then only new operations need to be hashed.
+///
+/// The cache is only valid for one `write_op` function. Anything which modifies existing
+/// operations (rather than appending new ones) must clear it.
+#[derive(Debug, Default)]
+pub(crate) struct HashCache(Mutex<Checkpoints>);
+
+impl Clone for HashCache {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.lock().clone()))
+    }
+}
+
+impl HashCache {
+    fn lock(&self) -> MutexGuard<'_, Checkpoints> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Forget the hashes of all operations from len onwards.
+    pub(crate) fn truncate(&mut self, len: LV) {
+        let c = self.0.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if c.end > len {
+            c.end = len;
+            let idx = c.hashes.partition_point(|(lv, _)| *lv < len);
+            c.hashes.truncate(idx);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl CausalGraph {
+    /// Hash a single operation, given the hashes of its parents.
+    fn hash_op<F: FnMut(LV, &mut Vec<u8>)>(&self, lv: LV, parents: &mut [VersionHash], buf: &mut Vec<u8>, write_op: &mut F) -> VersionHash {
+        let (agent, seq) = self.agent_assignment.local_to_agent_version(lv);
+        buf.clear();
+        push_len_prefixed(buf, self.agent_assignment.get_agent_name(agent).as_bytes());
+        buf.extend_from_slice(&(seq as u64).to_le_bytes());
+        write_op(lv, buf);
+
+        hash_sorted(b"dt-op\0", parents, buf)
+    }
+
+    /// Compute the hash of every operation in the causal graph, up to (but not including) `end`.
+    /// The result is indexed by local version.
+    ///
+    /// The causal graph doesn't know anything about the content of each operation. The `write_op`
+    /// function is called for every operation to append its content to the passed buffer. This must
+    /// be deterministic, and it must not depend on how operations are grouped into runs.
+    ///
+    /// This always hashes the whole history. Use [`item_hash`](CausalGraph::item_hash) or
+    /// [`version_hash`](CausalGraph::version_hash) to use the causal graph's hash cache instead.
+    pub fn item_hashes<F: FnMut(LV, &mut Vec<u8>)>(&self, end: LV, mut write_op: F) -> Vec<VersionHash> {
+        assert!(end <= self.len());
+        let mut result: Vec<VersionHash> = Vec::with_capacity(end);
+        if end == 0 { return result; }
+        let mut buf = Vec::new();
+        let mut parents = Vec::new();
+
+        for entry in self.graph.iter_range((0..end).into()) {
+            for lv in entry.span.start..entry.span.end {
+                parents.clear();
+                if lv == entry.span.start {
+                    parents.extend(entry.parents.iter().map(|p| result[*p]));
+                } else {
+                    parents.push(result[lv - 1]);
+                }
+
+                result.push(self.hash_op(lv, &mut parents, &mut buf, &mut write_op));
+            }
+        }
+
+        result
+    }
+
+    /// Look up the hash of an operation which has already been added to the cache.
+    fn cached_item_hash<F: FnMut(LV, &mut Vec<u8>)>(&self, cache: &Checkpoints, lv: LV, write_op: &mut F) -> VersionHash {
+        debug_assert!(lv < cache.end);
+        // Every graph entry starts with a cached hash, so all the operations between the closest
+        // cached hash and lv have linear history.
+        let idx = cache.hashes.partition_point(|(v, _)| *v <= lv) - 1;
+        let (mut v, mut hash) = cache.hashes[idx];
+
+        let mut buf = Vec::new();
+        while v < lv {
+            v += 1;
+            hash = self.hash_op(v, &mut [hash], &mut buf, write_op);
+        }
+        hash
+    }
+
+    /// Hash every operation before end which isn't in the cache yet.
+    fn extend_hash_cache<F: FnMut(LV, &mut Vec<u8>)>(&self, cache: &mut Checkpoints, end: LV, write_op: &mut F) {
+        assert!(end <= self.len());
+        if cache.end >= end { return; }
+
+        let mut buf = Vec::new();
+        let mut parents = Vec::new();
+        let mut prev = VersionHash::default();
+        for entry in self.graph.iter_range((cache.end..end).into()) {
+            for lv in entry.span.start..entry.span.end {
+                parents.clear();
+                if lv == entry.span.start {
+                    for p in entry.parents.iter() {
+                        parents.push(self.cached_item_hash(cache, *p, write_op));
+                    }
+                } else {
+                    parents.push(prev);
+                }
+
+                prev = self.hash_op(lv, &mut parents, &mut buf, write_op);
+                if lv == entry.span.start || lv % CHECKPOINT_INTERVAL == 0 {
+                    cache.hashes.push((lv, prev));
+                }
+            }
+            // The parents of later entries will be looked up in the cache.
+            cache.end = entry.span.end;
+        }
+    }
+
+    /// Get the hash of the operation at lv. See [`item_hashes`](CausalGraph::item_hashes) for
+    /// details on `write_op`.
+    ///
+    /// Hashes are cached in the causal graph, so only operations which haven't been hashed yet
+    /// are hashed. The cache assumes `write_op` is always the same function.
+    pub fn item_hash<F: FnMut(LV, &mut Vec<u8>)>(&self, lv: LV, mut write_op: F) -> VersionHash {
+        let mut cache = self.hash_cache.lock();
+        self.extend_hash_cache(&mut cache, lv + 1, &mut write_op);
+        self.cached_item_hash(&cache, lv, &mut write_op)
+    }
+
+    /// Get the hashes of all the operations in range, using the hash cache.
+    pub(crate) fn item_hashes_in<F: FnMut(LV, &mut Vec<u8>)>(&self, range: DTRange, mut write_op: F) -> Vec<VersionHash> {
+        let mut cache = self.hash_cache.lock();
+        self.extend_hash_cache(&mut cache, range.end, &mut write_op);
+
+        let mut result = Vec::with_capacity(range.len());
+        let mut buf = Vec::new();
+        for entry in self.graph.iter_range(range) {
+            for lv in entry.span.start..entry.span.end {
+                let hash = if lv == entry.span.start {
+                    self.cached_item_hash(&cache, lv, &mut write_op)
+                } else {
+                    self.hash_op(lv, &mut [*result.last().unwrap()], &mut buf, &mut write_op)
+                };
+                result.push(hash);
+            }
+        }
+        result
+    }
+
+    /// Get the hash of the named version. Like [`item_hash`](CausalGraph::item_hash), this uses
+    /// the causal graph's hash cache.
+    pub fn version_hash<F: FnMut(LV, &mut Vec<u8>)>(&self, version: &[LV], mut write_op: F) -> VersionHash {
+        let end = version.iter().max().map_or(0, |v| v + 1);
+        let mut cache = self.hash_cache.lock();
+        self.extend_hash_cache(&mut cache, end, &mut write_op);
+
+        let mut hashes: Vec<VersionHash> = version.iter()
+            .map(|v| self.cached_item_hash(&cache, *v, &mut write_op))
+            .collect();
+        hash_sorted(b"dt-version\0", &mut hashes, &[])
+    }
+
+    /// Compute the hash of a version, given the hashes of all operations (from
+    /// [`item_hashes`](CausalGraph::item_hashes)).
+    ///
+    /// The root version (with no operations) has a fixed hash.
+    pub fn version_hash_from_items(&self, item_hashes: &[VersionHash], version: &[LV]) -> VersionHash {
+        let mut hashes: Vec<VersionHash> = version.iter().map(|v| item_hashes[*v]).collect();
+        hash_sorted(b"dt-version\0", &mut hashes, &[])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::CausalGraph;
+
+    #[test]
+    fn hashes_depend_on_agent_and_parents() {
+        let mut cg1 = CausalGraph::new();
+        let seph = cg1.get_or_create_agent_id("seph");
+        cg1.assign_local_op_with_parents(&[], seph, 2);
+        cg1.assign_local_op_with_parents(&[0], seph, 1);
+
+        let mut cg2 = CausalGraph::new();
+        let seph2 = cg2.get_or_create_agent_id("seph");
+        cg2.assign_local_op_with_parents(&[], seph2, 3);
+
+        let h1 = cg1.item_hashes(cg1.len(), |_, _| {});
+        let h2 = cg2.item_hashes(cg2.len(), |_, _| {});
+        // The first two operations are identical. But in cg1, op 2 is concurrent with op 1.
+        assert_eq!(h1[..2], h2[..2]);
+        assert_ne!(h1[2], h2[2]);
+
+        assert_ne!(cg1.version_hash_from_items(&h1, &[1, 2]), cg2.version_hash_from_items(&h2, &[2]));
+        assert_eq!(cg1.version_hash_from_items(&h1, &[1]), cg2.version_hash_from_items(&h2, &[1]));
+        assert_eq!(cg1.version_hash_from_items(&h1, &[]), cg2.version_hash_from_items(&h2, &[]));
+    }
+
+    #[test]
+    fn cached_hashes_match_full_hashes() {
+        let write_op = |lv: usize, buf: &mut Vec<u8>| buf.extend_from_slice(&lv.to_le_bytes());
+        let check = |cg: &CausalGraph| {
+            let expected = cg.item_hashes(cg.len(), write_op);
+            // Query out of order, so some hashes come from the cache and some are recomputed.
+            for lv in (0..cg.len()).rev() {
+                assert_eq!(cg.item_hash(lv, write_op), expected[lv]);
+            }
+            assert_eq!(cg.item_hashes_in((50..cg.len()).into(), write_op), expected[50..]);
+            assert_eq!(cg.version_hash(cg.version.as_ref(), write_op),
+                       cg.version_hash_from_items(&expected, cg.version.as_ref()));
+        };
+
+        let mut cg = CausalGraph::new();
+        let seph = cg.get_or_create_agent_id("seph");
+        let mike = cg.get_or_create_agent_id("mike");
+        cg.assign_local_op_with_parents(&[], seph, 100);
+        cg.assign_local_op_with_parents(&[70], mike, 100);
+        cg.assign_local_op_with_parents(&[99, 199], seph, 10);
+        check(&cg);
+
+        // New operations are hashed incrementally.
+        cg.assign_local_op_with_parents(&[130], mike, 100);
+        cg.assign_local_op_with_parents(&[209, 309], seph, 5);
+        check(&cg);
+
+        // And truncated operations are forgotten.
+        let version = cg.graph.find_dominators(&[150, 209]);
+        cg.truncate_to(210, 2, version);
+        cg.assign_local_op_with_parents(&[150], seph, 20);
+        check(&cg);
+    }
+}
@@ -2,6 +2,7 @@
 
 use crate::{DTRange, Frontier, KVPair, Graph};
 use crate::causalgraph::agent_assignment::AgentAssignment;
+use crate::causalgraph::hash::HashCache;
 
 pub(crate) mod storage;
 mod causalgraph;
@@ -12,11 +13,15 @@ pub mod entry;
 pub mod summary;
 pub mod agent_span;
 pub mod agent_assignment;
+pub mod hash;
+pub mod query;
 
 #[cfg(test)]
 mod enc_fuzzer;
 #[cfg(feature = "dot_export")]
 pub mod dot;
+#[cfg(feature = "svg_export")]
+pub mod svg;
 
 #[derive(Clone, Debug, Default)]
 pub struct CausalGraph {
@@ -31,4 +36,7 @@ pub struct CausalGraph {
 
     /// This is the version you get if you load the entire causal graph
     pub version: Frontier,
+
+    /// Content-addressed hashes of operations, computed on demand.
+    pub(crate) hash_cache: HashCache,
 }
@@ -0,0 +1,331 @@
+//! Queries over the causal graph.
+//!
+//! These methods answer questions about how versions relate to one another - which versions are
+//! in the history of which others, what two branches have in common, and so on. They're built on
+//! top of the lower level graph tools (diff, find_dominators, etc), and are part of the stable
+//! public API.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use rle::{AppendRle, HasLength};
+use smallvec::SmallVec;
+use crate::{AgentId, CausalGraph, DTRange, Frontier, LV};
+use crate::causalgraph::entry::CGEntry;
+use crate::causalgraph::graph::Graph;
+use crate::rle::KVPair;
+
+impl Graph {
+    /// Returns true if version `a` is in the history of version `b`. Every version is considered
+    /// to be its own ancestor.
+    pub fn is_ancestor(&self, a: LV, b: LV) -> bool {
+        a <= b && self.frontier_contains_version(&[b], a)
+    }
+
+    /// Find the most recent versions shared by the histories of frontiers `a` and `b`. This is the
+    /// (possibly multi-headed) lowest common ancestor of the two frontiers.
+    pub fn common_ancestor(&self, a: &[LV], b: &[LV]) -> Frontier {
+        let only_a = self.diff(a, b).0;
+        let history_a = self.diff(&[], a).1;
+
+        // Any version in the history of a which isn't only in a is shared. Because histories are
+        // closed under taking ancestors, the intersection of a graph entry with the shared set is
+        // always a prefix of the entry. So the last version in each of those prefixes is a
+        // candidate, and the result is the dominators of the candidates.
+        let mut candidates = vec![];
+        let mut only_a = only_a.iter().peekable();
+        for r in history_a {
+            let mut pos = r.start;
+            while pos < r.end {
+                // Skip over any part of r which is only in a.
+                while let Some(o) = only_a.peek() {
+                    if o.end <= pos { only_a.next(); } else { break; }
+                }
+                let shared_end = match only_a.peek() {
+                    Some(o) if o.start <= pos => { pos = o.end.min(r.end); continue; }
+                    Some(o) => o.start.min(r.end),
+                    None => r.end,
+                };
+
+                let mut idx = self.entries.find_index(pos).unwrap();
+                loop {
+                    let e_end = self.entries.0[idx].span.end;
+                    candidates.push(e_end.min(shared_end) - 1);
+                    if e_end >= shared_end { break; }
+                    idx += 1;
+                }
+                pos = shared_end;
+            }
+        }
+
+        self.find_dominators(&candidates)
+    }
+
+    /// Returns all the versions which have `v` in their history (not including `v` itself), as a
+    /// sorted list of ranges.
+    pub fn descendants_of(&self, v: LV) -> SmallVec<[DTRange; 4]> {
+        let mut result: SmallVec<[DTRange; 4]> = SmallVec::new();
+        let first_idx = self.entries.find_index(v).unwrap();
+        let first_entry = &self.entries.0[first_idx];
+
+        // Everything after v in its own entry descends from v.
+        let mut included = vec![false; self.entries.num_entries()];
+        let mut queue = vec![];
+        let rest: DTRange = (v + 1..first_entry.span.end).into();
+        if !rest.is_empty() { result.push(rest); }
+
+        // Children which name v or anything after it in the entry as a parent descend from v.
+        for &c in first_entry.child_indexes.iter() {
+            let child = &self.entries.0[c];
+            if child.parents.iter().any(|&p| first_entry.span.contains(p) && p >= v) && !included[c] {
+                included[c] = true;
+                queue.push(c);
+            }
+        }
+
+        // From there, everything reachable through child_indexes is a descendant.
+        while let Some(idx) = queue.pop() {
+            for &c in self.entries.0[idx].child_indexes.iter() {
+                if !included[c] {
+                    included[c] = true;
+                    queue.push(c);
+                }
+            }
+        }
+
+        for (idx, inc) in included.iter().enumerate() {
+            if *inc { result.push_rle(self.entries.0[idx].span); }
+        }
+        result
+    }
+}
+
+/// A run of versions from a single agent, used while sorting the graph. Runs are ordered by their
+/// agent name then sequence number, which makes the ordering independent of local versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ReadyRun<'a> {
+    name: &'a str,
+    seq: usize,
+    start: LV,
+    end: LV,
+    chunk: usize,
+}
+
+impl CausalGraph {
+    /// Iterate through every version in the causal graph in a topological order (parents always
+    /// come before their children).
+    ///
+    /// When multiple versions are ready at the same time, the one with the lowest (agent name,
+    /// sequence number) pair is yielded first. This makes the ordering deterministic - every peer
+    /// with the same set of operations will produce the same sequence of versions, regardless of
+    /// the order in which they received them. Consecutive versions are yielded together as runs.
+    ///
+    /// The order is computed up front, in O(n log n) time in the number of runs.
+    pub fn iter_topological(&self) -> impl Iterator<Item = DTRange> {
+        // First split the graph into chunks, where each chunk is a linear run from one agent.
+        // Within a chunk, each version's only parent is the previous version.
+        let mut chunks: Vec<(DTRange, AgentId, usize, Frontier)> = vec![];
+        for e in self.graph.entries.iter() {
+            for KVPair(_, agent_span) in self.agent_assignment.client_with_localtime.iter_range(e.span) {
+                let start = chunks.last().map(|c| c.0.end).unwrap_or(0);
+                let span: DTRange = (start..start + agent_span.len()).into();
+                let parents = if span.start == e.span.start { e.parents.clone() } else { Frontier::new_1(span.start - 1) };
+                chunks.push((span, agent_span.agent, agent_span.seq_range.start, parents));
+            }
+        }
+
+        let chunk_of = |v: LV| chunks.binary_search_by(|c| {
+            if c.0.end <= v { std::cmp::Ordering::Less }
+            else if c.0.start > v { std::cmp::Ordering::Greater }
+            else { std::cmp::Ordering::Equal }
+        }).unwrap();
+
+        // For each chunk, the (parent version, child chunk) pairs which depend on it.
+        let mut children: Vec<Vec<(LV, usize)>> = vec![vec![]; chunks.len()];
+        let mut waiting_on: Vec<usize> = chunks.iter().map(|c| c.3.len()).collect();
+        for (idx, c) in chunks.iter().enumerate() {
+            for &p in c.3.iter() {
+                children[chunk_of(p)].push((p, idx));
+            }
+        }
+        for c in children.iter_mut() { c.sort_unstable(); }
+
+        let ready_run = |chunk: usize, start: LV| {
+            let (span, agent, seq, _) = &chunks[chunk];
+            ReadyRun {
+                name: self.agent_assignment.get_agent_name(*agent),
+                seq: seq + (start - span.start),
+                start,
+                end: span.end,
+                chunk,
+            }
+        };
+
+        let mut queue = BinaryHeap::new();
+        for (idx, w) in waiting_on.iter().enumerate() {
+            if *w == 0 { queue.push(Reverse(ready_run(idx, chunks[idx].0.start))); }
+        }
+
+        let mut result: Vec<DTRange> = vec![];
+        while let Some(Reverse(run)) = queue.pop() {
+            // Yield versions up to the next one which some other chunk depends on. At that point
+            // we need to check if the newly ready chunk should go first.
+            let kids = &children[run.chunk];
+            let first_kid = kids.partition_point(|(p, _)| *p < run.start);
+            let end = kids.get(first_kid)
+                .map(|(p, _)| (*p + 1).min(run.end))
+                .unwrap_or(run.end);
+
+            result.push_rle((run.start..end).into());
+
+            for &(p, kid) in kids[first_kid..].iter() {
+                if p >= end { break; }
+                waiting_on[kid] -= 1;
+                if waiting_on[kid] == 0 {
+                    queue.push(Reverse(ready_run(kid, chunks[kid].0.start)));
+                }
+            }
+
+            if end < run.end {
+                queue.push(Reverse(ready_run(run.chunk, end)));
+            }
+        }
+
+        result.into_iter()
+    }
+
+    /// Iterate through all the versions created by the named agent, in sequence number order.
+    pub fn entries_by_agent(&self, agent: AgentId) -> impl Iterator<Item = CGEntry> + '_ {
+        self.agent_assignment.client_data[agent as usize].item_times
+            .iter()
+            .flat_map(|KVPair(_, lv_range)| self.iter_range(*lv_range))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+    use rle::HasLength;
+    use crate::{CausalGraph, DTRange, Frontier, LV};
+    use crate::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
+    use crate::causalgraph::graph::random_graphs::with_random_cgs;
+
+    fn brute_is_ancestor(cg: &CausalGraph, a: LV, b: LV) -> bool {
+        let mut stack = vec![b];
+        while let Some(v) = stack.pop() {
+            if v == a { return true; }
+            if v > a { stack.extend(cg.graph.parents_at_version(v).iter().copied()); }
+        }
+        false
+    }
+
+    fn brute_history(cg: &CausalGraph, frontier: &[LV]) -> Vec<bool> {
+        (0..cg.len()).map(|v| frontier.iter().any(|f| brute_is_ancestor(cg, v, *f))).collect()
+    }
+
+    /// Simple per-version implementation of the topological sort, to compare against.
+    fn brute_topological(cg: &CausalGraph) -> Vec<LV> {
+        let mut waiting: Vec<usize> = (0..cg.len()).map(|v| cg.graph.parents_at_version(v).len()).collect();
+        let mut queue = BinaryHeap::new();
+        let key = |v: LV| {
+            let rv = cg.agent_assignment.local_to_remote_version(v);
+            Reverse((rv.0, rv.1, v))
+        };
+        for (v, w) in waiting.iter().enumerate() {
+            if *w == 0 { queue.push(key(v)); }
+        }
+
+        let mut result = vec![];
+        while let Some(Reverse((_, _, v))) = queue.pop() {
+            result.push(v);
+            for (c, w) in waiting.iter_mut().enumerate().skip(v + 1) {
+                if cg.graph.parents_at_version(c).0.contains(&v) {
+                    *w -= 1;
+                    if *w == 0 { queue.push(key(c)); }
+                }
+            }
+        }
+        result
+    }
+
+    fn expand(ranges: impl Iterator<Item = DTRange>) -> Vec<LV> {
+        ranges.flat_map(|r| r.iter()).collect()
+    }
+
+    #[test]
+    fn ancestors_and_descendants() {
+        with_random_cgs(321, (3, 40), |_i, cg, frontiers| {
+            let len = cg.len();
+            for v in 0..len {
+                let descendants = expand(cg.graph.descendants_of(v).into_iter());
+                let expected: Vec<LV> = (v + 1..len).filter(|w| brute_is_ancestor(cg, v, *w)).collect();
+                assert_eq!(descendants, expected);
+
+                for w in 0..len {
+                    assert_eq!(cg.graph.is_ancestor(v, w), brute_is_ancestor(cg, v, w));
+                }
+            }
+
+            for a in frontiers {
+                for b in frontiers {
+                    let history_a = brute_history(cg, a.as_ref());
+                    let history_b = brute_history(cg, b.as_ref());
+                    let shared: Vec<LV> = (0..len).filter(|v| history_a[*v] && history_b[*v]).collect();
+                    let expected = cg.graph.find_dominators(&shared);
+
+                    let actual = cg.graph.common_ancestor(a.as_ref(), b.as_ref());
+                    assert_eq!(actual, expected);
+                    assert_eq!(actual, cg.graph.common_ancestor(b.as_ref(), a.as_ref()));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn topological_order_is_deterministic() {
+        with_random_cgs(123, (3, 40), |_i, cg, _frontiers| {
+            let order = expand(cg.iter_topological());
+            assert_eq!(order, brute_topological(cg));
+
+            // Replaying the graph in topological order gives a graph with different local versions.
+            // The order (as remote versions) should be the same.
+            let mut cg2 = CausalGraph::new();
+            for r in cg.iter_topological() {
+                for e in cg.iter_range(r) {
+                    let parents = cg.agent_assignment.local_to_remote_frontier(e.parents.as_ref());
+                    let parents: Frontier = cg2.agent_assignment.remote_to_local_frontier(parents.iter().copied());
+                    let agent = cg2.get_or_create_agent_id(cg.agent_assignment.get_agent_name(e.span.agent));
+                    cg2.merge_and_assign(parents.as_ref(), crate::causalgraph::agent_span::AgentSpan {
+                        agent,
+                        seq_range: e.span.seq_range,
+                    });
+                }
+            }
+
+            let remote_order = |cg: &CausalGraph| -> Vec<RemoteVersionOwned> {
+                expand(cg.iter_topological()).into_iter()
+                    .map(|v| cg.agent_assignment.local_to_remote_version(v).into())
+                    .collect()
+            };
+            assert_eq!(remote_order(cg), remote_order(&cg2));
+        });
+    }
+
+    #[test]
+    fn entries_by_agent() {
+        with_random_cgs(7, (2, 30), |_i, cg, _frontiers| {
+            let mut total = 0;
+            for agent in 0..cg.agent_assignment.client_data.len() as u32 {
+                let mut next_seq = 0;
+                for e in cg.entries_by_agent(agent) {
+                    assert_eq!(e.span.agent, agent);
+                    assert_eq!(e.span.seq_range.start, next_seq);
+                    next_seq = e.span.seq_range.end;
+                    assert_eq!(e.parents, cg.graph.parents_at_version(e.start));
+                    total += e.span.seq_range.len();
+                }
+            }
+            assert_eq!(total, cg.len());
+        });
+    }
+}
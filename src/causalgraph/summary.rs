@@ -6,7 +6,12 @@ use rle::{HasLength, MergeableIterator, SplitableSpanHelpers};
 #[cfg(feature = "serde")]
 use serde::{Serialize, Deserialize};
 use crate::causalgraph::agent_assignment::AgentAssignment;
-use crate::rle::RleSpanHelpers;
+use crate::rle::{KVPair, RleSpanHelpers};
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+use crate::encoding::bufparser::BufParser;
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::tools::push_str;
+use crate::encoding::varint::push_usize;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -28,6 +33,114 @@ pub struct VersionSummary(Vec<VSEntry>);
 #[derive(Debug, Clone, Eq, PartialEq, Default)]
 pub struct VersionSummaryFlat(Vec<(SmartString, usize)>);
 
+/// Sort the ranges, merging any which overlap or touch and dropping empty ones.
+fn normalize_ranges(ranges: &[DTRange]) -> SmallVec<[DTRange; 2]> {
+    let mut sorted: SmallVec<[DTRange; 2]> = ranges.iter().copied().filter(|r| !r.is_empty()).collect();
+    sorted.sort_unstable_by_key(|r| r.start);
+
+    let mut result: SmallVec<[DTRange; 2]> = SmallVec::new();
+    for r in sorted {
+        match result.last_mut() {
+            Some(last) if r.start <= last.end => { last.end = last.end.max(r.end); }
+            _ => { result.push(r); }
+        }
+    }
+    result
+}
+
+impl VersionSummary {
+    pub fn entries(&self) -> &[VSEntry] {
+        &self.0
+    }
+
+    /// Encode the summary in a compact binary format. See [`decode`](VersionSummary::decode).
+    ///
+    /// Each agent's ranges are sorted and merged before they're encoded, so unsorted or
+    /// overlapping ranges decode to their normalized form.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        push_usize(&mut result, self.0.len());
+        for VSEntry { name, seq_ranges } in self.0.iter() {
+            let seq_ranges = normalize_ranges(seq_ranges);
+            push_str(&mut result, name);
+            push_usize(&mut result, seq_ranges.len());
+            // Ranges are stored as (gap since the previous range, length) pairs.
+            let mut last_end = 0;
+            for r in seq_ranges.iter() {
+                push_usize(&mut result, r.start - last_end);
+                push_usize(&mut result, r.len());
+                last_end = r.end;
+            }
+        }
+        result
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = BufParser(data);
+        let num_entries = reader.next_usize()?;
+        let mut entries = Vec::new();
+        for _ in 0..num_entries {
+            let name = reader.next_str()?;
+            let num_ranges = reader.next_usize()?;
+            let mut seq_ranges = SmallVec::new();
+            let mut last_end: usize = 0;
+            for _ in 0..num_ranges {
+                let start = last_end.checked_add(reader.next_usize()?).ok_or(ParseError::InvalidLength)?;
+                let len = reader.next_usize()?;
+                if len == 0 { return Err(ParseError::InvalidLength); }
+                last_end = start.checked_add(len).ok_or(ParseError::InvalidLength)?;
+                seq_ranges.push((start..last_end).into());
+            }
+            entries.push(VSEntry { name: name.into(), seq_ranges });
+        }
+        reader.expect_empty()?;
+        Ok(Self(entries))
+    }
+}
+
+impl From<Vec<VSEntry>> for VersionSummary {
+    fn from(entries: Vec<VSEntry>) -> Self {
+        Self(entries)
+    }
+}
+
+impl VersionSummaryFlat {
+    /// The (agent name, next sequence number) pairs in the summary.
+    pub fn entries(&self) -> &[(SmartString, usize)] {
+        &self.0
+    }
+
+    /// Encode the summary in a compact binary format. See [`decode`](VersionSummaryFlat::decode).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        push_usize(&mut result, self.0.len());
+        for (name, next_seq) in self.0.iter() {
+            push_str(&mut result, name);
+            push_usize(&mut result, *next_seq);
+        }
+        result
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = BufParser(data);
+        let num_entries = reader.next_usize()?;
+        let mut entries = Vec::new();
+        for _ in 0..num_entries {
+            let name = reader.next_str()?;
+            entries.push((name.into(), reader.next_usize()?));
+        }
+        reader.expect_empty()?;
+        Ok(Self(entries))
+    }
+}
+
+/// Classic version vectors map each agent to the next sequence number we expect from it.
+impl From<Vec<(SmartString, usize)>> for VersionSummaryFlat {
+    fn from(entries: Vec<(SmartString, usize)>) -> Self {
+        Self(entries)
+    }
+}
+
 // Serialize as {name1: [[start, end], [start, end], ..], name2: ...}.
 #[cfg(feature = "serde")]
 mod serde_encoding {
@@ -263,6 +376,108 @@ impl CausalGraph {
         )
     }
 
+    /// Returns the sequence ranges for each agent named in the history of frontier, indexed by
+    /// agent ID.
+    fn seq_ranges_in(&self, frontier: &[LV]) -> Vec<SmallVec<[DTRange; 2]>> {
+        let mut result: Vec<SmallVec<[DTRange; 2]>> = vec![SmallVec::new(); self.agent_assignment.client_data.len()];
+        for span in self.graph.diff_rev(&[], frontier).1 {
+            for KVPair(_, agent_span) in self.agent_assignment.client_with_localtime.iter_range(span) {
+                result[agent_span.agent as usize].push(agent_span.seq_range);
+            }
+        }
+
+        for ranges in result.iter_mut() {
+            ranges.sort_unstable_by_key(|r| r.start);
+            *ranges = ranges.iter().copied().merge_spans().collect();
+        }
+        result
+    }
+
+    /// Summarize all the versions in the history of `frontier`, as a set of sequence ranges for
+    /// each agent.
+    pub fn frontier_to_summary(&self, frontier: &[LV]) -> VersionSummary {
+        VersionSummary(self.seq_ranges_in(frontier).into_iter().enumerate().filter_map(|(agent, seq_ranges)| {
+            if seq_ranges.is_empty() { None } else {
+                Some(VSEntry {
+                    name: self.agent_assignment.client_data[agent].name.clone(),
+                    seq_ranges,
+                })
+            }
+        }).collect())
+    }
+
+    /// Convert a frontier into a classic version vector, naming the next sequence number for each
+    /// agent in the history of `frontier`.
+    ///
+    /// This is lossy if an agent's sequence numbers aren't linear in the causal graph (for example,
+    /// if the same agent has made concurrent changes). Use
+    /// [`frontier_to_summary`](CausalGraph::frontier_to_summary) when thats a problem.
+    pub fn frontier_to_flat_summary(&self, frontier: &[LV]) -> VersionSummaryFlat {
+        VersionSummaryFlat(self.seq_ranges_in(frontier).into_iter().enumerate().filter_map(|(agent, seq_ranges)| {
+            seq_ranges.last().map(|r| (self.agent_assignment.client_data[agent].name.clone(), r.end))
+        }).collect())
+    }
+
+    /// Convert a summary back into a frontier. Versions in the summary which we don't know about
+    /// are ignored, and returned as the second value.
+    pub fn summary_to_frontier(&self, summary: &VersionSummary) -> (Frontier, Option<VersionSummary>) {
+        self.intersect_with_summary(summary, &[])
+    }
+
+    /// Convert a classic version vector into a frontier. Versions in the summary which we don't know
+    /// about are ignored, and returned as the second value.
+    pub fn flat_summary_to_frontier(&self, summary: &VersionSummaryFlat) -> (Frontier, Option<VersionSummaryFlat>) {
+        self.intersect_with_flat_summary(summary, &[])
+    }
+
+    /// Returns true if we know about every version named in the summary.
+    pub fn contains_summary(&self, summary: &VersionSummary) -> bool {
+        let mut result = true;
+        self.agent_assignment.intersect_with_summary_full(summary, |_, _, v| {
+            if v.is_none() { result = false; }
+        });
+        result
+    }
+
+    /// Returns true if we know about every version named in the flat summary.
+    pub fn contains_flat_summary(&self, summary: &VersionSummaryFlat) -> bool {
+        let mut result = true;
+        self.agent_assignment.intersect_with_flat_summary_full(summary, |_, _, v| {
+            if v.is_none() { result = false; }
+        });
+        result
+    }
+
+    /// Encode a frontier in a compact binary format, as a list of (agent name, seq) pairs. Unlike
+    /// local versions, this can be understood by other peers.
+    pub fn encode_frontier(&self, frontier: &[LV]) -> Vec<u8> {
+        let mut result = Vec::new();
+        push_usize(&mut result, frontier.len());
+        for v in frontier {
+            let RemoteVersion(name, seq) = self.agent_assignment.local_to_remote_version(*v);
+            push_str(&mut result, name);
+            push_usize(&mut result, seq);
+        }
+        result
+    }
+
+    /// Decode a frontier written by [`encode_frontier`](CausalGraph::encode_frontier). Fails with
+    /// [`ParseError::InvalidRemoteID`] if the frontier names versions we don't know about.
+    pub fn decode_frontier(&self, data: &[u8]) -> Result<Frontier, ParseError> {
+        let mut reader = BufParser(data);
+        let len = reader.next_usize()?;
+        let mut versions: SmallVec<[LV; 2]> = SmallVec::new();
+        for _ in 0..len {
+            let name = reader.next_str()?;
+            let seq = reader.next_usize()?;
+            versions.push(self.agent_assignment.try_remote_to_local_version(RemoteVersion(name, seq))
+                .map_err(ParseError::InvalidRemoteID)?);
+        }
+        reader.expect_empty()?;
+        // The frontier could have come from anywhere. Make sure its well formed.
+        Ok(self.graph.find_dominators(&versions))
+    }
+
     // pub fn intersect_with_summary_full<V>(&self, summary: &VersionSummary, visitor: V)
     // where V: FnMut(&str, usize, usize, Option<Time>)
     // {
@@ -289,7 +504,8 @@ impl CausalGraph {
 #[cfg(test)]
 mod tests {
     use smallvec::smallvec;
-    use crate::CausalGraph;
+    use crate::{CausalGraph, Frontier};
+    use crate::encoding::parseerror::ParseError;
     use crate::causalgraph::summary::{VersionSummary, VersionSummaryFlat, VSEntry};
     use crate::causalgraph::agent_span::AgentSpan;
 
@@ -479,4 +695,80 @@ mod tests {
         let (frontier, _) = cg.intersect_with_summary(&vs, &[v]);
         assert_eq!(frontier.as_ref(), &[v]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn frontier_summary_conversions() {
+        let mut cg = CausalGraph::new();
+        let seph = cg.get_or_create_agent_id("seph");
+        let mike = cg.get_or_create_agent_id("mike");
+        cg.merge_and_assign(&[], AgentSpan { agent: seph, seq_range: (0..5).into() }); // 0..5
+        cg.merge_and_assign(&[], AgentSpan { agent: mike, seq_range: (0..3).into() }); // 5..8
+        cg.merge_and_assign(&[4], AgentSpan { agent: seph, seq_range: (5..10).into() }); // 8..13
+
+        let summary = cg.frontier_to_summary(&[2, 7]);
+        assert_eq!(summary, VersionSummary(vec![
+            VSEntry { name: "seph".into(), seq_ranges: smallvec![(0..3).into()] },
+            VSEntry { name: "mike".into(), seq_ranges: smallvec![(0..3).into()] },
+        ]));
+        assert_eq!(cg.summary_to_frontier(&summary), (Frontier::from_sorted(&[2, 7]), None));
+        assert!(cg.contains_summary(&summary));
+
+        let flat = cg.frontier_to_flat_summary(&[12]);
+        assert_eq!(flat, VersionSummaryFlat(vec![("seph".into(), 10)]));
+        assert_eq!(cg.flat_summary_to_frontier(&flat), (Frontier::new_1(12), None));
+        assert!(cg.contains_flat_summary(&flat));
+
+        assert_eq!(cg.frontier_to_summary(&[]), VersionSummary(vec![]));
+        assert_eq!(cg.frontier_to_summary(cg.version.as_ref()), cg.agent_assignment.summarize_versions());
+
+        // Versions we don't know about are reported back.
+        let unknown = VersionSummary(vec![
+            VSEntry { name: "mike".into(), seq_ranges: smallvec![(0..5).into()] },
+        ]);
+        assert!(!cg.contains_summary(&unknown));
+        let (frontier, missing) = cg.summary_to_frontier(&unknown);
+        assert_eq!(frontier.as_ref(), &[7]);
+        assert_eq!(missing, Some(VersionSummary(vec![
+            VSEntry { name: "mike".into(), seq_ranges: smallvec![(3..5).into()] },
+        ])));
+        assert!(!cg.contains_flat_summary(&VersionSummaryFlat(vec![("kaarina".into(), 1)])));
+    }
+
+    #[test]
+    fn binary_encodings_round_trip() {
+        let mut cg = CausalGraph::new();
+        let seph = cg.get_or_create_agent_id("seph");
+        let mike = cg.get_or_create_agent_id("mike");
+        cg.merge_and_assign(&[], AgentSpan { agent: seph, seq_range: (0..5).into() });
+        cg.merge_and_assign(&[], AgentSpan { agent: mike, seq_range: (0..3).into() });
+        cg.merge_and_assign(&[4], AgentSpan { agent: seph, seq_range: (10..20).into() });
+
+        let summary = cg.agent_assignment.summarize_versions();
+        assert_eq!(VersionSummary::decode(&summary.encode()).unwrap(), summary);
+        let flat = cg.agent_assignment.summarize_versions_flat();
+        assert_eq!(VersionSummaryFlat::decode(&flat.encode()).unwrap(), flat);
+
+        let data = cg.encode_frontier(cg.version.as_ref());
+        assert_eq!(cg.decode_frontier(&data).unwrap(), cg.version);
+
+        // Decoding frontiers into a graph which doesn't know about them fails.
+        let mut other = CausalGraph::new();
+        other.get_or_create_agent_id("seph");
+        assert!(matches!(other.decode_frontier(&data), Err(ParseError::InvalidRemoteID(_))));
+
+        // Corrupt data is rejected.
+        let data = summary.encode();
+        assert!(VersionSummary::decode(&data[..data.len() - 1]).is_err());
+        assert!(VersionSummary::decode(&[data.as_slice(), &[0]].concat()).is_err());
+    }
+
+    #[test]
+    fn encoding_normalizes_ranges() {
+        let summary = VersionSummary(vec![
+            VSEntry { name: "seph".into(), seq_ranges: smallvec![(10..20).into(), (0..5).into(), (3..8).into(), (8..8).into(), (20..22).into()] },
+        ]);
+        assert_eq!(VersionSummary::decode(&summary.encode()).unwrap(), VersionSummary(vec![
+            VSEntry { name: "seph".into(), seq_ranges: smallvec![(0..8).into(), (10..22).into()] },
+        ]));
+    }
+}
@@ -0,0 +1,404 @@
+//! A built-in renderer which draws the causal graph as an SVG image. Unlike the DOT exporter, this
+//! doesn't need graphviz installed, so it also works from wasm.
+//!
+//! The layout is a simple layered drawing:
+//!
+//! 1. Each run of operations from one agent becomes a box. Boxes are assigned to layers so every
+//!    box sits above all of its parents. The root of the graph is at the bottom.
+//! 2. Edges which skip over layers are routed through invisible waypoints in each layer they cross.
+//! 3. The nodes in each layer are reordered a few times to reduce crossings, by moving each node
+//!    toward the average position of its neighbours.
+//! 4. Nodes are packed left to right, and pulled toward the nodes they're connected to.
+//!
+//! Like in the DOT output, merges are drawn as small blue points joining the merged branches.
+//! Hovering over a box shows the agent, sequence numbers and parents of its operations.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use rle::{HasLength, SplitableSpan};
+use crate::{AgentId, CausalGraph, DTRange, Frontier, LV};
+use crate::causalgraph::entry::CGEntry;
+
+const FONT_SIZE: f64 = 12.0;
+/// Approximate width of each character. Labels use a monospace font so this is reasonably accurate.
+const CHAR_WIDTH: f64 = 7.2;
+const NODE_HEIGHT: f64 = 24.0;
+const NODE_PADDING: f64 = 8.0;
+const NODE_GAP: f64 = 16.0;
+const LAYER_GAP: f64 = 56.0;
+const MERGE_RADIUS: f64 = 4.0;
+const WAYPOINT_WIDTH: f64 = 8.0;
+const MARGIN: f64 = 16.0;
+const MAX_LABEL_AGENT_CHARS: usize = 20;
+
+const EDGE_COLOR: &str = "#333333";
+const MERGE_COLOR: &str = "#84a7e8";
+const ROOT_COLOR: &str = "#f28b82";
+const AGENT_COLORS: &[&str] = &[
+    "#98ea79", "#fdd663", "#a8c7fa", "#f6aea9", "#c2a5f5",
+    "#81e2d9", "#ffc38a", "#d7aefb", "#cbe86b", "#e6c9a8",
+];
+
+/// A run of operations from one agent, which is drawn as a single box.
+struct Run {
+    span: DTRange,
+    agent: AgentId,
+    seq_start: usize,
+    parents: Frontier,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum NodeKind {
+    Root,
+    Run(usize),
+    Merge,
+    Waypoint,
+}
+
+struct Node {
+    kind: NodeKind,
+    layer: usize,
+    width: f64,
+    x: f64,
+    /// Nodes in the layer below which this node is connected to.
+    down: Vec<usize>,
+    /// Nodes in the layer above which this node is connected to.
+    up: Vec<usize>,
+}
+
+/// An edge from a node down to one of its parents, via a waypoint in each layer in between.
+struct Edge {
+    path: Vec<usize>,
+    into_merge: bool,
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn text_width(s: &str) -> f64 {
+    s.chars().count() as f64 * CHAR_WIDTH + NODE_PADDING * 2.0
+}
+
+impl CausalGraph {
+    /// Split the graph into runs of operations from a single agent. Runs are split so every version
+    /// named as a parent is the last version in its run.
+    fn svg_runs(&self) -> Vec<Run> {
+        let entries: Vec<CGEntry> = self.iter().collect();
+        let split_points: BTreeSet<LV> = entries.iter()
+            .flat_map(|e| e.parents.iter().map(|p| p + 1))
+            .collect();
+
+        let mut runs = vec![];
+        for mut e in entries {
+            let end = e.start + e.len();
+            let split_at: Vec<LV> = split_points.range(e.start + 1..end).copied().collect();
+            for at in split_at.into_iter().chain(std::iter::once(end)) {
+                let rest = if at < end { Some(e.truncate(at - e.start)) } else { None };
+                runs.push(Run {
+                    span: (e.start..at).into(),
+                    agent: e.span.agent,
+                    seq_start: e.span.seq_range.start,
+                    parents: e.parents,
+                });
+                match rest {
+                    Some(rest) => e = rest,
+                    None => break,
+                }
+            }
+        }
+        runs
+    }
+
+    fn svg_label(&self, run: &Run) -> String {
+        let name = self.agent_assignment.get_agent_name(run.agent);
+        let mut label: String = name.chars().take(MAX_LABEL_AGENT_CHARS).collect();
+        if name.chars().count() > MAX_LABEL_AGENT_CHARS { label.push('…'); }
+
+        if run.span.len() == 1 {
+            write!(label, " {}", run.seq_start).unwrap();
+        } else {
+            write!(label, " {}..{}", run.seq_start, run.seq_start + run.span.len()).unwrap();
+        }
+        label
+    }
+
+    fn svg_fmt_versions(&self, versions: &[LV]) -> String {
+        versions.iter().map(|v| {
+            let (agent, seq) = self.agent_assignment.local_to_agent_version(*v);
+            format!("{} {}", self.agent_assignment.get_agent_name(agent), seq)
+        }).collect::<Vec<_>>().join(", ")
+    }
+
+    /// Render the causal graph as an SVG image.
+    pub fn to_svg(&self) -> String {
+        self.to_svg_with(|_| None)
+    }
+
+    /// Render the causal graph as an SVG image. `describe` is called with the span of each box
+    /// drawn, and can return extra text (like a summary of the operations) to show when the box is
+    /// hovered over.
+    pub fn to_svg_with<F: FnMut(DTRange) -> Option<String>>(&self, mut describe: F) -> String {
+        let runs = self.svg_runs();
+        let labels: Vec<String> = runs.iter().map(|r| self.svg_label(r)).collect();
+
+        // Assign every node to a layer. Parents always come before their children, so one pass is
+        // enough. Nodes are only ever connected directly to nodes in lower layers.
+        let mut nodes = vec![Node {
+            kind: NodeKind::Root, layer: 0, width: text_width("ROOT"), x: 0.0, down: vec![], up: vec![],
+        }];
+        let mut targets: Vec<(usize, usize, bool)> = vec![];
+        // Node index for each run.
+        let mut run_nodes: Vec<usize> = Vec::with_capacity(runs.len());
+        let mut merge_nodes: HashMap<Vec<LV>, usize> = HashMap::new();
+        let node_for_version = |run_nodes: &[usize], v: LV| {
+            run_nodes[runs.partition_point(|r| r.span.end <= v)]
+        };
+
+        for (i, run) in runs.iter().enumerate() {
+            let parent = match run.parents.len() {
+                0 => 0,
+                1 => node_for_version(&run_nodes, run.parents[0]),
+                _ => *merge_nodes.entry(run.parents.as_ref().to_vec()).or_insert_with(|| {
+                    let parents: Vec<usize> = run.parents.iter()
+                        .map(|p| node_for_version(&run_nodes, *p))
+                        .collect();
+                    let layer = parents.iter().map(|p| nodes[*p].layer).max().unwrap() + 1;
+                    let idx = nodes.len();
+                    nodes.push(Node {
+                        kind: NodeKind::Merge, layer, width: MERGE_RADIUS * 2.0, x: 0.0, down: vec![], up: vec![],
+                    });
+                    targets.extend(parents.into_iter().map(|p| (idx, p, true)));
+                    idx
+                }),
+            };
+
+            let idx = nodes.len();
+            nodes.push(Node {
+                kind: NodeKind::Run(i),
+                layer: nodes[parent].layer + 1,
+                width: text_width(&labels[i]),
+                x: 0.0, down: vec![], up: vec![],
+            });
+            targets.push((idx, parent, false));
+            run_nodes.push(idx);
+        }
+
+        // Route edges which cross several layers through waypoints.
+        let mut edges = Vec::with_capacity(targets.len());
+        for (from, to, into_merge) in targets {
+            let mut path = vec![from];
+            for layer in (nodes[to].layer + 1..nodes[from].layer).rev() {
+                path.push(nodes.len());
+                nodes.push(Node {
+                    kind: NodeKind::Waypoint, layer, width: WAYPOINT_WIDTH, x: 0.0, down: vec![], up: vec![],
+                });
+            }
+            path.push(to);
+
+            for w in path.windows(2) {
+                nodes[w[0]].down.push(w[1]);
+                nodes[w[1]].up.push(w[0]);
+            }
+            edges.push(Edge { path, into_merge });
+        }
+
+        let num_layers = nodes.iter().map(|n| n.layer).max().unwrap() + 1;
+        let mut layers: Vec<Vec<usize>> = vec![vec![]; num_layers];
+        for (i, n) in nodes.iter().enumerate() {
+            layers[n.layer].push(i);
+        }
+
+        // Reduce crossings by sorting each layer by the average position of each node's neighbours
+        // in the previous layer. We alternate sweeping up and down the graph.
+        let mut pos = vec![0.0; nodes.len()];
+        let update_pos = |layer: &[usize], pos: &mut [f64]| {
+            for (p, n) in layer.iter().enumerate() { pos[*n] = p as f64; }
+        };
+        for layer in layers.iter() { update_pos(layer, &mut pos); }
+
+        for sweep in 0..8 {
+            let upward = sweep % 2 == 0;
+            let order: Vec<usize> = if upward { (1..num_layers).collect() } else { (0..num_layers - 1).rev().collect() };
+            for l in order {
+                let mut keyed: Vec<(f64, usize)> = layers[l].iter().map(|n| {
+                    let neighbours = if upward { &nodes[*n].down } else { &nodes[*n].up };
+                    let key = if neighbours.is_empty() {
+                        pos[*n]
+                    } else {
+                        neighbours.iter().map(|m| pos[*m]).sum::<f64>() / neighbours.len() as f64
+                    };
+                    (key, *n)
+                }).collect();
+                keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+                layers[l] = keyed.into_iter().map(|(_, n)| n).collect();
+                update_pos(&layers[l], &mut pos);
+            }
+        }
+
+        // Assign x coordinates. Each node is pulled toward the average position of its neighbours,
+        // but kept in order without overlapping. We pack each layer against its desired positions
+        // from the left and from the right, and take the average of the two so nodes don't drift
+        // to one side.
+        let place = |nodes: &mut [Node], layer: &[usize], desired: &[f64]| {
+            let gap = |a: usize, b: usize| (nodes[a].width + nodes[b].width) / 2.0 + NODE_GAP;
+            let mut from_left = desired.to_vec();
+            for i in 1..layer.len() {
+                from_left[i] = from_left[i].max(from_left[i - 1] + gap(layer[i - 1], layer[i]));
+            }
+            let mut from_right = desired.to_vec();
+            for i in (0..layer.len().saturating_sub(1)).rev() {
+                from_right[i] = from_right[i].min(from_right[i + 1] - gap(layer[i], layer[i + 1]));
+            }
+            for (i, n) in layer.iter().enumerate() {
+                nodes[*n].x = (from_left[i] + from_right[i]) / 2.0;
+            }
+        };
+        for layer in layers.iter() {
+            let zeros = vec![0.0; layer.len()];
+            place(&mut nodes, layer, &zeros);
+        }
+        for sweep in 0..8 {
+            let order: Vec<usize> = if sweep % 2 == 0 { (0..num_layers).collect() } else { (0..num_layers).rev().collect() };
+            for l in order {
+                let desired: Vec<f64> = layers[l].iter().map(|n| {
+                    let node = &nodes[*n];
+                    let count = node.down.len() + node.up.len();
+                    if count == 0 { node.x }
+                    else { node.down.iter().chain(node.up.iter()).map(|m| nodes[*m].x).sum::<f64>() / count as f64 }
+                }).collect();
+                place(&mut nodes, &layers[l], &desired);
+            }
+        }
+
+        let left = nodes.iter().map(|n| n.x - n.width / 2.0).fold(f64::INFINITY, f64::min);
+        let right = nodes.iter().map(|n| n.x + n.width / 2.0).fold(f64::NEG_INFINITY, f64::max);
+        let width = right - left + MARGIN * 2.0;
+        let height = (num_layers - 1) as f64 * LAYER_GAP + NODE_HEIGHT + MARGIN * 2.0;
+        let x_of = |n: usize| nodes[n].x - left + MARGIN;
+        // The root is drawn at the bottom.
+        let y_of = |n: usize| MARGIN + NODE_HEIGHT / 2.0 + (num_layers - 1 - nodes[n].layer) as f64 * LAYER_GAP;
+        let half_height = |n: usize| match nodes[n].kind {
+            NodeKind::Root | NodeKind::Run(_) => NODE_HEIGHT / 2.0,
+            NodeKind::Merge => MERGE_RADIUS,
+            NodeKind::Waypoint => 0.0,
+        };
+
+        let mut out = String::new();
+        writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width:.0}" height="{height:.0}" viewBox="0 0 {width:.1} {height:.1}" font-family="monospace" font-size="{FONT_SIZE}">"#).unwrap();
+        writeln!(out, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+
+        // Edges are drawn first, so they go underneath the nodes.
+        writeln!(out, r#"<g fill="none" stroke-width="1.5">"#).unwrap();
+        for edge in edges.iter() {
+            let mut d = String::new();
+            for (i, w) in edge.path.windows(2).enumerate() {
+                let (x1, y1) = (x_of(w[0]), y_of(w[0]) + half_height(w[0]));
+                let (x2, y2) = (x_of(w[1]), y_of(w[1]) - half_height(w[1]));
+                let mid = (y1 + y2) / 2.0;
+                if i == 0 { write!(d, "M{x1:.1},{y1:.1}").unwrap(); }
+                else { write!(d, " L{x1:.1},{y1:.1}").unwrap(); }
+                write!(d, " C{x1:.1},{mid:.1} {x2:.1},{mid:.1} {x2:.1},{y2:.1}").unwrap();
+            }
+            let color = if edge.into_merge { MERGE_COLOR } else { EDGE_COLOR };
+            writeln!(out, r#"<path d="{d}" stroke="{color}"/>"#).unwrap();
+        }
+        writeln!(out, "</g>").unwrap();
+
+        for (i, node) in nodes.iter().enumerate() {
+            let (x, y) = (x_of(i), y_of(i));
+            match node.kind {
+                NodeKind::Waypoint => {}
+                NodeKind::Merge => {
+                    let parents: Vec<LV> = node.down.iter().map(|m| {
+                        // Follow the edge down through any waypoints to the run it came from.
+                        let mut m = *m;
+                        while nodes[m].kind == NodeKind::Waypoint { m = nodes[m].down[0]; }
+                        match nodes[m].kind {
+                            NodeKind::Run(r) => runs[r].span.last(),
+                            _ => unreachable!(),
+                        }
+                    }).collect();
+                    writeln!(out, r#"<g class="merge"><title>Merge of {}</title><circle cx="{x:.1}" cy="{y:.1}" r="{MERGE_RADIUS}" fill="{MERGE_COLOR}"/></g>"#,
+                        escape_xml(&self.svg_fmt_versions(&parents))).unwrap();
+                }
+                NodeKind::Root | NodeKind::Run(_) => {
+                    let (label, title, color) = match node.kind {
+                        NodeKind::Run(r) => {
+                            let run = &runs[r];
+                            let mut title = format!("{}\nVersions {}..{}\nParents: {}",
+                                labels[r], run.span.start, run.span.end,
+                                if run.parents.is_empty() { "ROOT".into() } else { self.svg_fmt_versions(run.parents.as_ref()) });
+                            if let Some(desc) = describe(run.span) {
+                                title.push_str("\n\n");
+                                title.push_str(&desc);
+                            }
+                            (labels[r].as_str(), title, AGENT_COLORS[run.agent as usize % AGENT_COLORS.len()])
+                        }
+                        _ => ("ROOT", "ROOT".to_string(), ROOT_COLOR),
+                    };
+                    writeln!(out, r#"<g class="node"><title>{}</title><rect x="{:.1}" y="{:.1}" width="{:.1}" height="{NODE_HEIGHT}" rx="3" fill="{color}" stroke="{EDGE_COLOR}"/><text x="{x:.1}" y="{y:.1}" text-anchor="middle" dominant-baseline="central">{}</text></g>"#,
+                        escape_xml(&title), x - node.width / 2.0, y - NODE_HEIGHT / 2.0, node.width, escape_xml(label)).unwrap();
+                }
+            }
+        }
+
+        out.push_str("</svg>\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::ListOpLog;
+    use super::escape_xml;
+
+    fn count(haystack: &str, needle: &str) -> usize {
+        haystack.matches(needle).count()
+    }
+
+    #[test]
+    fn escapes_xml() {
+        assert_eq!(escape_xml("a<b & \"c\">"), "a&lt;b &amp; &quot;c&quot;&gt;");
+    }
+
+    #[test]
+    fn empty_graph() {
+        let oplog = ListOpLog::new();
+        let svg = oplog.cg.to_svg();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(count(&svg, "<rect x="), 1); // Just the root.
+    }
+
+    #[test]
+    fn draws_runs_and_merges() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("<mike>");
+        let a = oplog.add_insert_at(seph, &[], 0, "aaa");
+        let b = oplog.add_insert_at(mike, &[], 0, "bbb");
+        oplog.add_insert_at(seph, &[a, b], 0, "c");
+        // Another merge of the same versions shares the merge point.
+        oplog.add_delete_at(mike, &[a, b], 0..1);
+
+        let svg = oplog.to_svg();
+        assert_eq!(count(&svg, "<g class=\"node\">"), 5);
+        assert_eq!(count(&svg, "<g class=\"merge\">"), 1);
+        assert_eq!(count(&svg, "<g"), count(&svg, "</g>"));
+        assert!(svg.contains("&lt;mike&gt; 0..3"));
+        assert!(!svg.contains("<mike>"));
+        assert!(svg.contains("ins 0..3 &quot;aaa&quot;"));
+    }
+}
@@ -108,6 +108,7 @@ fn read_cg_aa(reader: &mut BufParser, persist: bool, aa: &mut AgentAssignment, r
     let (agent, last_seq, idx) = if !is_known {
         if mapped_agent != 0 { return Err(ParseError::GenericInvalidData); }
         let agent_name = reader.next_str()?;
+        read_map.check_new_agent(agent_name)?;
         let agent = aa.get_or_create_agent_id(agent_name);
         let idx = read_map.agent_map.len();
         if persist {
@@ -115,11 +116,14 @@ fn read_cg_aa(reader: &mut BufParser, persist: bool, aa: &mut AgentAssignment, r
         }
         (agent, 0, idx)
     } else {
-        let entry = read_map.agent_map[mapped_agent];
+        let entry = *read_map.agent_map.get(mapped_agent).ok_or(ParseError::GenericInvalidData)?;
         (entry.0, entry.1, mapped_agent)
     };
 
     let len = reader.next_usize()?;
+    if len > read_map.limits.max_ops.saturating_sub(read_map.len()) {
+        return Err(ParseError::LimitExceeded);
+    }
 
     let jump = if has_jump {
         reader.next_zigzag_isize()?
@@ -127,7 +131,7 @@ fn read_cg_aa(reader: &mut BufParser, persist: bool, aa: &mut AgentAssignment, r
 
     let start = isize_try_add(last_seq, jump)
         .ok_or(ParseError::GenericInvalidData)?;
-    let end = start + len;
+    let end = start.checked_add(len).ok_or(ParseError::GenericInvalidData)?;
 
     if persist {
         read_map.agent_map[idx].1 = end;
@@ -187,7 +191,7 @@ pub(crate) fn read_cg_entry_into_cg(reader: &mut BufParser, persist: bool, cg: &
     // dbg!((&parents, span));
 
     // Save it into the causal graph, and update
-    let merged_span = cg.merge_and_assign(parents.as_ref(), span);
+    let merged_span = cg.try_merge_and_assign(parents.as_ref(), span)?;
 
     if persist {
         if merged_span.len() == span.len() {
@@ -0,0 +1,47 @@
+/// Caps on how much work and memory decoding a single chunk of data is allowed to use.
+///
+/// Encoded data is compact. A few bytes of LZ4 compressed data or a handful of large varints can
+/// otherwise make the decoder allocate gigabytes of memory. When data is loaded from a peer you
+/// don't trust, set these to something sensible for your application. If any limit is exceeded,
+/// decoding fails with `ParseError::LimitExceeded`.
+///
+/// The default value has no limits, which matches the behaviour of older versions of this library.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DecodeLimits {
+    /// The maximum number of bytes compressed data is allowed to expand to.
+    pub max_decompressed_bytes: usize,
+
+    /// The maximum number of operations (local versions) in the data.
+    pub max_ops: usize,
+
+    /// The maximum number of distinct agents named in the data.
+    pub max_agents: usize,
+
+    /// The maximum length of any agent name, in bytes.
+    pub max_agent_name_len: usize,
+
+    /// The maximum number of parents any single entry in the causal graph can have.
+    pub max_parents: usize,
+
+    /// The maximum size of any one piece of operation metadata, in bytes. This is the length of
+    /// the message, plus the length of every user data key and value, plus one for each user data
+    /// entry.
+    pub max_metadata_len: usize,
+}
+
+impl DecodeLimits {
+    pub const UNLIMITED: DecodeLimits = DecodeLimits {
+        max_decompressed_bytes: usize::MAX,
+        max_ops: usize::MAX,
+        max_agents: usize::MAX,
+        max_agent_name_len: usize::MAX,
+        max_parents: usize::MAX,
+        max_metadata_len: usize::MAX,
+    };
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
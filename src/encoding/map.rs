@@ -2,6 +2,8 @@ use rle::HasLength;
 use crate::{AgentId, DTRange, KVPair, RleVec, LV};
 use crate::causalgraph::agent_assignment::ClientData;
 use crate::rle::RleSpanHelpers;
+use crate::encoding::limits::DecodeLimits;
+use crate::encoding::parseerror::ParseError;
 
 /// This struct stores the information we need while reading to map from relative agent info and
 /// edits to the equivalent local times.
@@ -15,6 +17,9 @@ pub struct ReadMap {
     ///
     /// Packed.
     pub txn_map: RleVec<KVPair<DTRange>>,
+
+    /// Limits on the size of the data being read.
+    pub limits: DecodeLimits,
 }
 
 impl ReadMap {
@@ -22,6 +27,18 @@ impl ReadMap {
         Self::default()
     }
 
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        Self { limits, ..Self::default() }
+    }
+
+    /// Map a newly seen agent. Fails if the data names more agents than the limits allow.
+    pub(crate) fn check_new_agent(&self, agent_name: &str) -> Result<(), ParseError> {
+        if self.agent_map.len() >= self.limits.max_agents
+            || agent_name.len() > self.limits.max_agent_name_len {
+            Err(ParseError::LimitExceeded)
+        } else { Ok(()) }
+    }
+
     pub fn last_time(&self) -> Option<LV> {
         // Another way to implement this would be to default to ROOT_TIME or something and let
         // entries be "simple" if they're first, and they parent off ROOT. But that way lie bugs.
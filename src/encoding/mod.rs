@@ -20,6 +20,7 @@ pub(crate) mod cg_entry;
 pub(crate) mod op;
 pub(crate) mod chunk_reader;
 pub(crate) mod map;
+pub(crate) mod limits;
 // mod agent_assignment;
 
 
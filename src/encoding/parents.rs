@@ -115,8 +115,9 @@ pub(crate) fn read_parents_raw(reader: &mut BufParser, persist: bool, aa: &mut A
             let diff = n;
             // Local parents (parents inside this chunk of data) are stored using their local (file)
             // time offset.
-            let file_time = next_time - diff;
-            let (entry, offset) = read_map.txn_map.find_with_offset(file_time).unwrap();
+            let file_time = next_time.checked_sub(diff).ok_or(ParseError::GenericInvalidData)?;
+            let (entry, offset) = read_map.txn_map.find_with_offset(file_time)
+                .ok_or(ParseError::GenericInvalidData)?;
             entry.1.at_offset(offset)
         } else {
             let agent = match n {
@@ -128,6 +129,7 @@ pub(crate) fn read_parents_raw(reader: &mut BufParser, persist: bool, aa: &mut A
                 1 => {
                     // This is a foreign (unknown) item.
                     let agent_name = reader.next_str()?;
+                    read_map.check_new_agent(agent_name)?;
                     let agent = aa.get_or_create_agent_id(agent_name);
                     if persist {
                         read_map.agent_map.push((agent, 0));
@@ -137,7 +139,7 @@ pub(crate) fn read_parents_raw(reader: &mut BufParser, persist: bool, aa: &mut A
                 n => {
                     // n references a mapped agent.
                     let mapped_agent = n - 2;
-                    read_map.agent_map[mapped_agent].0
+                    read_map.agent_map.get(mapped_agent).ok_or(ParseError::GenericInvalidData)?.0
                 }
             };
 
@@ -146,6 +148,7 @@ pub(crate) fn read_parents_raw(reader: &mut BufParser, persist: bool, aa: &mut A
                 .ok_or(ParseError::DataMissing)? // missing expected (agent, seq).
         };
 
+        if parents.len() >= read_map.limits.max_parents { return Err(ParseError::LimitExceeded); }
         parents.push(parent);
         // debug_assert!(frontier_is_sorted(&parents));
 
@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use crate::AgentId;
 use crate::causalgraph::agent_assignment::remote_ids::VersionConversionError;
 
 
@@ -45,6 +46,28 @@ pub enum ParseError {
 
     ChecksumFailed,
 
+    /// The data contained a version hash which doesn't match the hash of the loaded history. Either
+    /// the data has been tampered with, or some agent has reused IDs for different operations.
+    VersionHashMismatch,
+
+    /// The oplog is configured to verify signatures, and the data contains operations which are
+    /// either unsigned or signed with the wrong key.
+    InvalidSignature,
+
+    /// The data is larger than one of the configured [`DecodeLimits`](crate::DecodeLimits) allows.
+    LimitExceeded,
+
+    /// The oplog has an access policy, and the data contains operations which the policy rejects.
+    AccessDenied,
+
+    /// The data contains an operation with the same (agent, seq) ID as an operation we already
+    /// have, but with different parents or content. Some agent has reused IDs. `agent` is the ID
+    /// of the agent in the oplog the data was merged into, and `seq` is the first reused sequence
+    /// number.
+    ///
+    /// See [`repair`](crate::list::repair) for a way to merge the data anyway.
+    AgentIdReused { agent: AgentId, seq: usize },
+
     /// This error is interesting. We're loading a chunk but missing some of the data. In the future
     /// I'd like to explicitly support this case, and allow the oplog to contain a somewhat- sparse
     /// set of data, and load more as needed.
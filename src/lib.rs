@@ -186,6 +186,7 @@ extern crate core;
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
 use jumprope::{JumpRope, JumpRopeBuf};
 use smallvec::SmallVec;
 use smartstring::alias::String as SmartString;
@@ -198,11 +199,14 @@ use crate::rle::{KVPair, RleVec};
 use crate::wal::WriteAheadLog;
 pub use ::rle::HasLength;
 pub use frontier::Frontier;
+pub use crate::encoding::limits::DecodeLimits;
 use crate::causalgraph::agent_span::AgentVersion;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
 use crate::textinfo::TextInfo;
+use crate::access::AccessPolicy;
+use crate::tags::{Tag, Tags};
 
 // use crate::list::internal_op::OperationInternal as TextOpInternal;
 
@@ -230,6 +234,8 @@ mod fuzzer;
 mod branch;
 mod textinfo;
 mod oplog;
+pub mod access;
+pub mod tags;
 #[cfg(feature = "storage")]
 mod storage;
 mod simple_checkout;
@@ -409,6 +415,12 @@ pub struct OpLog {
     // pretty similar to the _index data, in that its mainly just useful for branches doing
     // checkouts.
     deleted_crdts: BTreeSet<LVKey>,
+
+    /// If set, remote operations are only merged in if the policy allows them.
+    access_policy: Option<Arc<dyn AccessPolicy>>,
+
+    /// Named versions. See [`tags`](crate::tags).
+    tags: Tags,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -442,6 +454,12 @@ pub struct SerializedOps<'a> {
     map_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, &'a str, CreateValue)>,
     text_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, ListOpMetrics)>,
     text_context: ListOperationCtx,
+
+    /// Every tag is sent with every set of operations. Tags are small, and there's no other way
+    /// to tell which tags the remote peer already knows about. Defaults to empty, so operations
+    /// serialized by older versions can still be read.
+    #[cfg_attr(feature = "serde", serde(default))]
+    tags: Vec<Tag>,
 }
 
 /// This is used for checkouts. This is a value tree.
@@ -0,0 +1,246 @@
+//! This file contains code to find the regions of a document which were edited concurrently on two
+//! branches. This is useful for code editors and review tools, which want to highlight places where
+//! the merged result might not be what either user intended.
+
+use rle::{AppendRle, HasLength};
+use crate::list::ListOpLog;
+use crate::list::operation::ListOpKind;
+use crate::{AgentId, DTRange, LV};
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// A region of the merged document which was modified concurrently by both sides of a merge.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct MergeConflict {
+    /// The range of characters in the merged document (the checkout at the union of both versions)
+    /// covered by the conflicting edits. Deleted content takes up no space in the merged document,
+    /// so this range will be empty if the conflict is made up entirely of deletes.
+    pub range: DTRange,
+
+    /// The operations (as local version spans) from the first version which touched this region.
+    pub versions_a: Vec<DTRange>,
+
+    /// The operations (as local version spans) from the second version which touched this region.
+    pub versions_b: Vec<DTRange>,
+
+    /// All agents which made changes in this region. Sorted and deduplicated.
+    pub agents: Vec<AgentId>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Side { A, B, Shared }
+
+/// An edit made at some point during the merge, with its location mapped forward through all
+/// subsequent edits.
+#[derive(Debug, Clone, Copy)]
+struct EditRegion {
+    range: DTRange,
+    side: Side,
+    lv: DTRange,
+}
+
+/// Map a document position through a delete of `del` (in the same document coordinates).
+fn map_through_delete(pos: usize, del: DTRange) -> usize {
+    if pos <= del.start { pos }
+    else if pos >= del.end { pos - del.len() }
+    else { del.start }
+}
+
+fn side_of(only_a: &[DTRange], only_b: &[DTRange], lv: LV) -> Side {
+    if only_a.iter().any(|r| r.contains(lv)) { Side::A }
+    else if only_b.iter().any(|r| r.contains(lv)) { Side::B }
+    else { Side::Shared }
+}
+
+impl ListOpLog {
+    /// Find the regions of the document which were edited concurrently at versions `a` and `b`.
+    ///
+    /// Each returned [`MergeConflict`] names a range in the merged document - that is, the document
+    /// you get from checking out `version_union(a, b)` - alongside the operations from each side
+    /// which touched that range. Edits from the two sides conflict if they overlap or directly
+    /// abut each other once merged. (Eg, two users typing at the same location).
+    ///
+    /// If either version contains the other, there is no concurrency and the result is empty.
+    ///
+    /// Conflicts are returned in document order.
+    pub fn find_merge_conflicts(&self, a: &[LV], b: &[LV]) -> Vec<MergeConflict> {
+        let (only_a, only_b) = self.cg.graph.diff(a, b);
+        if only_a.is_empty() || only_b.is_empty() { return vec![]; }
+
+        let common = self.cg.graph.find_conflicting(a, b, |_, _| {});
+        let merged = self.cg.graph.version_union(a, b);
+
+        // Walk the transformed operations from the common ancestor to the merged version, tracking
+        // where each edit ends up in the final document. Regions are kept sorted by start position,
+        // so the regions after each edit can be found with a binary search. But every edit still
+        // checks every region to update its position, so this is O(edits * regions).
+        let mut regions: Vec<EditRegion> = vec![];
+        for (lv, op) in self.iter_xf_operations_from(common.as_ref(), merged.as_ref()) {
+            // Deletes which already happened have no effect on the document.
+            let Some(op) = op else { continue; };
+            let span = op.range();
+            let idx = regions.partition_point(|r| r.range.start < span.start);
+
+            match op.kind {
+                ListOpKind::Ins => {
+                    let len = span.len();
+                    for r in regions[idx..].iter_mut() {
+                        r.range.start += len;
+                        r.range.end += len;
+                    }
+                    for r in regions[..idx].iter_mut().filter(|r| r.range.end > span.start) {
+                        // The insert landed inside this region.
+                        r.range.end += len;
+                    }
+                }
+                ListOpKind::Del => {
+                    for r in regions[idx..].iter_mut() {
+                        r.range.start = map_through_delete(r.range.start, span);
+                        r.range.end = map_through_delete(r.range.end, span);
+                    }
+                    for r in regions[..idx].iter_mut().filter(|r| r.range.end > span.start) {
+                        r.range.end = map_through_delete(r.range.end, span);
+                    }
+                }
+            }
+
+            let range: DTRange = match op.kind {
+                ListOpKind::Ins => span,
+                ListOpKind::Del => (span.start..span.start).into(),
+            };
+
+            let side = side_of(&only_a, &only_b, lv.start);
+            if side == Side::Shared { continue; }
+            let idx = regions.partition_point(|r| (r.range.start, r.range.end) <= (range.start, range.end));
+            regions.insert(idx, EditRegion { range, side, lv });
+        }
+
+        // Sweep through the regions, grouping together runs of regions which overlap or touch.
+        let mut result = vec![];
+        let mut group: Vec<EditRegion> = vec![];
+        let mut group_end = 0;
+
+        for r in regions {
+            if !group.is_empty() && r.range.start > group_end {
+                self.flush_conflict_group(&mut group, &mut result);
+            }
+            group_end = if group.is_empty() { r.range.end } else { group_end.max(r.range.end) };
+            group.push(r);
+        }
+        self.flush_conflict_group(&mut group, &mut result);
+
+        result
+    }
+
+    fn flush_conflict_group(&self, group: &mut Vec<EditRegion>, result: &mut Vec<MergeConflict>) {
+        let has_a = group.iter().any(|r| r.side == Side::A);
+        let has_b = group.iter().any(|r| r.side == Side::B);
+
+        if has_a && has_b {
+            let start = group.iter().map(|r| r.range.start).min().unwrap();
+            let end = group.iter().map(|r| r.range.end).max().unwrap();
+
+            let mut lvs_a: Vec<DTRange> = vec![];
+            let mut lvs_b: Vec<DTRange> = vec![];
+            group.sort_by_key(|r| r.lv.start);
+            for r in group.iter() {
+                match r.side {
+                    Side::A => { lvs_a.push_rle(r.lv); }
+                    Side::B => { lvs_b.push_rle(r.lv); }
+                    Side::Shared => {}
+                }
+            }
+
+            let mut agents: Vec<AgentId> = lvs_a.iter().chain(lvs_b.iter())
+                .flat_map(|lv| self.iter_agent_mappings_range(*lv))
+                .map(|span| span.agent)
+                .collect();
+            agents.sort_unstable();
+            agents.dedup();
+
+            result.push(MergeConflict {
+                range: (start..end).into(),
+                versions_a: lvs_a,
+                versions_b: lvs_b,
+                agents,
+            });
+        }
+
+        group.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::ListOpLog;
+    use crate::list::conflicts::MergeConflict;
+
+    #[test]
+    fn no_conflicts_when_linear() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v1 = oplog.add_insert(seph, 0, "hi there");
+        let v2 = oplog.add_insert(seph, 2, "!");
+
+        assert!(oplog.find_merge_conflicts(&[v1], &[v2]).is_empty());
+    }
+
+    #[test]
+    fn concurrent_inserts_at_same_location() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "abc  xyz");
+
+        let a = oplog.add_insert_at(seph, &[base], 4, "AAA");
+        let b = oplog.add_insert_at(mike, &[base], 4, "BB");
+
+        let conflicts = oplog.find_merge_conflicts(&[a], &[b]);
+        // Merged document is "abc BBAAA xyz".
+        assert_eq!(conflicts, vec![MergeConflict {
+            range: (4..9).into(),
+            versions_a: vec![(8..11).into()],
+            versions_b: vec![(11..13).into()],
+            agents: vec![seph, mike],
+        }]);
+        assert_eq!(oplog.checkout_tip().content().to_string(), "abc BBAAA xyz");
+    }
+
+    #[test]
+    fn distant_edits_do_not_conflict() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "0123456789");
+
+        let a = oplog.add_insert_at(seph, &[base], 1, "A");
+        let b = oplog.add_delete_at(mike, &[base], 6..8);
+
+        assert!(oplog.find_merge_conflicts(&[a], &[b]).is_empty());
+    }
+
+    #[test]
+    fn insert_inside_concurrent_delete() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "0123456789");
+
+        let a = oplog.add_insert_at(seph, &[base], 5, "AA");
+        let b = oplog.add_delete_at(mike, &[base], 3..8);
+
+        // Merged document is "012AA89".
+        assert_eq!(oplog.checkout_tip().content().to_string(), "012AA89");
+        let conflicts = oplog.find_merge_conflicts(&[a], &[b]);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].range, (3..5).into());
+        assert_eq!(conflicts[0].agents, vec![seph, mike]);
+
+        // The result shouldn't depend on the order of the arguments.
+        let flipped = oplog.find_merge_conflicts(&[b], &[a]);
+        assert_eq!(flipped[0].range, conflicts[0].range);
+        assert_eq!(flipped[0].versions_a, conflicts[0].versions_b);
+    }
+}
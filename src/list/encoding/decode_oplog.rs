@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use smallvec::{smallvec, SmallVec};
 use crate::list::encoding::*;
 use crate::list::{ListOpLog, switch};
@@ -19,12 +20,43 @@ use crate::rle::{KVPair, RleKeyedAndSplitable, RleSpanHelpers, RleVec};
 use crate::encoding::parseerror::ParseError;
 use crate::encoding::tools::calc_checksum;
 use crate::list::encoding::leb::num_decode_zigzag_isize_old;
+use crate::list::signatures::RunSignature;
+use crate::tags::Tag;
+use crate::list::metadata::{add_metadata, OpMetadata};
+use crate::list::stats::ChunkStats;
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontierOwned, RemoteVersionOwned};
+use crate::encoding::limits::DecodeLimits;
 
 // If this is set to false, the compiler can optimize out the verbose printing code. This makes the
 // compiled output slightly smaller.
 const ALLOW_VERBOSE: bool = false;
 // const ALLOW_VERBOSE: bool = true;
 
+/// Sort spans by agent and seq, and merge any which overlap or touch.
+fn merge_agent_spans(mut spans: Vec<AgentSpan>) -> Vec<AgentSpan> {
+    spans.sort_unstable_by_key(|s| (s.agent, s.seq_range.start));
+
+    let mut result: Vec<AgentSpan> = Vec::with_capacity(spans.len());
+    for span in spans {
+        match result.last_mut() {
+            Some(last) if last.agent == span.agent && last.seq_range.end >= span.seq_range.start => {
+                last.seq_range.end = last.seq_range.end.max(span.seq_range.end);
+            }
+            _ => result.push(span),
+        }
+    }
+    result
+}
+
+/// Returns true if the sorted, merged spans contain every seq in the agent's seq_range.
+fn spans_contain(spans: &[AgentSpan], agent: AgentId, seq_range: DTRange) -> bool {
+    let idx = spans.partition_point(|s| (s.agent, s.seq_range.start) <= (agent, seq_range.start));
+    idx > 0 && {
+        let span = &spans[idx - 1];
+        span.agent == agent && span.seq_range.end >= seq_range.end
+    }
+}
+
 impl<'a> BufReader<'a> {
     fn read_next_agent_assignment(&mut self, map: &mut [(AgentId, usize)]) -> Result<Option<AgentSpan>, ParseError> {
         // Agent assignments are almost always (but not always) linear. They can have gaps, and
@@ -56,9 +88,9 @@ impl<'a> BufReader<'a> {
         let entry = &mut map[inner_agent];
         let agent = entry.0;
 
-        // TODO: Error if this overflows.
-        let start = (entry.1 as isize + jump) as usize;
-        let end = start + len;
+        if len == 0 { return Err(ParseError::InvalidLength); }
+        let start = entry.1.checked_add_signed(jump).ok_or(ParseError::InvalidLength)?;
+        let end = start.checked_add(len).ok_or(ParseError::InvalidLength)?;
         entry.1 = end;
 
         Ok(Some(AgentSpan {
@@ -76,7 +108,7 @@ impl<'a> BufReader<'a> {
             let seq = self.next_usize()?; // Bleh. Skip me when root!
             if mapped_agent == 0 { break; } // Root.
 
-            let agent = agent_map[mapped_agent - 1].0;
+            let agent = agent_map.get(mapped_agent - 1).ok_or(ParseError::InvalidLength)?.0;
 
             let time = oplog.try_crdt_id_to_time((agent, seq))
                 .ok_or(ParseError::BaseVersionUnknown)?;
@@ -85,14 +117,134 @@ impl<'a> BufReader<'a> {
             if !has_more { break; }
         }
 
-        sort_frontier(&mut result);
+        result.sort_unstable();
+        if result.windows(2).any(|w| w[0] == w[1]) {
+            return Err(ParseError::InvalidLength);
+        }
 
         self.expect_empty()?;
 
         Ok(Frontier(result))
     }
 
-    fn read_parents(&mut self, oplog: &ListOpLog, next_time: LV, agent_map: &[(AgentId, usize)]) -> Result<Frontier, ParseError> {
+    fn read_signatures(mut self, agent_map: &[(AgentId, usize)]) -> Result<Vec<RunSignature>, ParseError> {
+        let mut result = Vec::new();
+        while !self.is_empty() {
+            let mapped_agent = self.next_usize()?;
+            if mapped_agent == 0 || mapped_agent > agent_map.len() {
+                return Err(ParseError::InvalidLength);
+            }
+
+            let start = self.next_usize()?;
+            let len = self.next_usize()?;
+            let end = start.checked_add(len).ok_or(ParseError::InvalidLength)?;
+            let sig_len = self.next_usize()?;
+            let signature = self.next_n_bytes(sig_len)?.to_vec();
+
+            result.push(RunSignature {
+                agent: agent_map[mapped_agent - 1].0,
+                seq_range: (start..end).into(),
+                signature,
+            });
+        }
+        Ok(result)
+    }
+
+    fn read_tags(mut self, limits: &DecodeLimits) -> Result<Vec<Tag>, ParseError> {
+        let mut result = Vec::new();
+        while !self.is_empty() {
+            let name = self.next_str()?.into();
+            let set_by = self.next_str()?;
+            if set_by.len() > limits.max_agent_name_len {
+                return Err(ParseError::LimitExceeded);
+            }
+            let clock = self.next_u64()?;
+
+            let len = self.next_usize()?;
+            if len > limits.max_parents {
+                return Err(ParseError::LimitExceeded);
+            }
+            let mut target = RemoteFrontierOwned::new();
+            for _ in 0..len {
+                let agent = self.next_str()?;
+                if agent.len() > limits.max_agent_name_len {
+                    return Err(ParseError::LimitExceeded);
+                }
+                target.push(RemoteVersionOwned(agent.into(), self.next_usize()?));
+            }
+
+            result.push(Tag { name, target, set_by: set_by.into(), clock });
+        }
+        Ok(result)
+    }
+
+    /// Read the metadata chunk. Metadata can only be attached to operations contained in the file,
+    /// named by `file_spans` (which must be sorted and non-overlapping, from
+    /// [`merge_agent_spans`]). Otherwise a peer could claim metadata for another agent's future
+    /// operations.
+    fn read_metadata(mut self, agent_map: &[(AgentId, usize)], file_spans: &[AgentSpan], limits: &DecodeLimits) -> Result<Vec<(AgentId, DTRange, OpMetadata)>, ParseError> {
+        // Every value and every run describes at least one operation.
+        let num_values = self.next_usize()?;
+        if num_values > limits.max_ops {
+            return Err(ParseError::LimitExceeded);
+        }
+
+        let mut values = Vec::new();
+        for _ in 0..num_values {
+            let flags = self.next_usize()?;
+            if flags > 0b11 { return Err(ParseError::GenericInvalidData); }
+            let timestamp = if flags & 1 != 0 { Some(self.next_u64()?) } else { None };
+            let message = if flags & 2 != 0 { Some(self.next_str()?) } else { None };
+
+            let mut size = message.map_or(0, |m| m.len());
+            if size > limits.max_metadata_len {
+                return Err(ParseError::LimitExceeded);
+            }
+
+            let mut user_data = BTreeMap::new();
+            let num_pairs = self.next_usize()?;
+            for _ in 0..num_pairs {
+                let k = self.next_str()?;
+                let v = self.next_str()?;
+                size = size.saturating_add(k.len() + v.len() + 1);
+                if size > limits.max_metadata_len {
+                    return Err(ParseError::LimitExceeded);
+                }
+                user_data.insert(k.into(), v.to_string());
+            }
+            let message = message.map(|m| m.to_string());
+
+            values.push(OpMetadata { timestamp, message, user_data });
+        }
+
+        let mut result = Vec::new();
+        while !self.is_empty() {
+            if result.len() >= limits.max_ops {
+                return Err(ParseError::LimitExceeded);
+            }
+
+            let mapped_agent = self.next_usize()?;
+            if mapped_agent == 0 || mapped_agent > agent_map.len() {
+                return Err(ParseError::InvalidLength);
+            }
+
+            let agent = agent_map[mapped_agent - 1].0;
+            let start = self.next_usize()?;
+            let len = self.next_usize()?;
+            let end = start.checked_add(len).ok_or(ParseError::InvalidLength)?;
+            let meta = values.get(self.next_usize()?).ok_or(ParseError::InvalidLength)?;
+
+            let seq_range: DTRange = (start..end).into();
+            if seq_range.is_empty() || !spans_contain(file_spans, agent, seq_range) {
+                return Err(ParseError::GenericInvalidData);
+            }
+
+            result.push((agent, seq_range, meta.clone()));
+        }
+        Ok(result)
+    }
+
+    fn read_parents(&mut self, oplog: &ListOpLog, next_time: LV, agent_map: &[(AgentId, usize)], limits: &DecodeLimits) -> Result<Frontier, ParseError> {
         let mut parents = SmallVec::<[usize; 2]>::new();
         loop {
             let mut n = self.next_usize()?;
@@ -104,7 +256,7 @@ impl<'a> BufReader<'a> {
                     // The parents list is empty (ie, our parent is ROOT).
                     break;
                 } else {
-                    let agent = agent_map[n - 1].0;
+                    let agent = agent_map.get(n - 1).ok_or(ParseError::InvalidLength)?.0;
                     let seq = self.next_usize()?;
                     // dbg!((agent, seq));
                     if let Some(c) = oplog.cg.agent_assignment.client_data.get(agent as usize) {
@@ -118,9 +270,11 @@ impl<'a> BufReader<'a> {
             } else {
                 // Local parents (parents inside this chunk of data) are stored using their
                 // local time offset.
-                next_time - n
+                if n == 0 { return Err(ParseError::InvalidLength); }
+                next_time.checked_sub(n).ok_or(ParseError::InvalidLength)?
             };
 
+            if parents.len() >= limits.max_parents { return Err(ParseError::LimitExceeded); }
             parents.push(parent);
             // debug_assert!(frontier_is_sorted(&parents));
 
@@ -131,18 +285,22 @@ impl<'a> BufReader<'a> {
         // 1. The file is invalid. All local (non-foreign) changes should be in order).
         // or 2. We have foreign items - and they're not sorted based on the local versions.
         // This is fine and we should just re-sort.
-        sort_frontier(&mut parents);
+        parents.sort_unstable();
+        if parents.windows(2).any(|w| w[0] == w[1]) {
+            return Err(ParseError::InvalidLength);
+        }
 
         Ok(Frontier(parents))
     }
 
-    fn next_history_entry(&mut self, oplog: &ListOpLog, next_time: LV, agent_map: &[(AgentId, usize)]) -> Result<GraphEntrySimple, ParseError> {
+    fn next_history_entry(&mut self, oplog: &ListOpLog, next_time: LV, agent_map: &[(AgentId, usize)], limits: &DecodeLimits) -> Result<GraphEntrySimple, ParseError> {
         let len = self.next_usize()?;
-        let parents = self.read_parents(oplog, next_time, agent_map)?;
+        if len == 0 { return Err(ParseError::InvalidLength); }
+        let parents = self.read_parents(oplog, next_time, agent_map, limits)?;
 
         // Bleh its gross passing a &[Time] into here when we have a Frontier already.
         Ok(GraphEntrySimple {
-            span: (next_time..next_time + len).into(),
+            span: (next_time..next_time.checked_add(len).ok_or(ParseError::InvalidLength)?).into(),
             parents,
         })
     }
@@ -194,7 +352,7 @@ impl<'a> ChunkReader<'a> {
         }
     }
 
-    fn read_fileinfo(&mut self, oplog: &mut ListOpLog) -> Result<FileInfoData, ParseError> {
+    fn read_fileinfo(&mut self, oplog: &mut ListOpLog, limits: &DecodeLimits) -> Result<FileInfoData<'a>, ParseError> {
         let mut fileinfo = self.expect_chunk(ListChunkType::FileInfo)?.chunks();
 
         let doc_id = fileinfo.read_chunk_if_eq(ListChunkType::DocId)?;
@@ -214,6 +372,9 @@ impl<'a> ChunkReader<'a> {
         let mut agent_map = Vec::new();
         while !agent_names_chunk.0.is_empty() {
             let name = agent_names_chunk.next_str()?;
+            if agent_map.len() >= limits.max_agents || name.len() > limits.max_agent_name_len {
+                return Err(ParseError::LimitExceeded);
+            }
             let id = oplog.get_or_create_agent_id(name);
             agent_map.push((id, 0));
         }
@@ -238,8 +399,9 @@ struct FileInfoData<'a> {
 
 /// Returns (mapped span, remainder).
 /// The returned remainder is *NOT MAPPED*. This allows this method to be called in a loop.
-fn history_entry_map_and_truncate(mut hist_entry: GraphEntrySimple, version_map: &RleVec<KVPair<DTRange>>) -> (GraphEntrySimple, Option<GraphEntrySimple>) {
-    let (map_entry, offset) = version_map.find_packed_with_offset(hist_entry.span.start);
+fn history_entry_map_and_truncate(mut hist_entry: GraphEntrySimple, version_map: &RleVec<KVPair<DTRange>>) -> Result<(GraphEntrySimple, Option<GraphEntrySimple>), ParseError> {
+    let (map_entry, offset) = version_map.find_with_offset(hist_entry.span.start)
+        .ok_or(ParseError::InvalidLength)?;
 
     let mut map_entry = map_entry.1;
     map_entry.truncate_keeping_right(offset);
@@ -257,15 +419,20 @@ fn history_entry_map_and_truncate(mut hist_entry: GraphEntrySimple, version_map:
     // const UNDERWATER_LAST: usize = ROOT_TIME - 1;
     for p in hist_entry.parents.0.iter_mut() {
         if *p >= UNDERWATER_START {
-            let (span, offset) = version_map.find_packed_with_offset(*p);
+            let (span, offset) = version_map.find_with_offset(*p)
+                .ok_or(ParseError::InvalidLength)?;
             *p = span.1.start + offset;
         }
     }
 
     // Parents can become unsorted here because they might not map cleanly. Thanks, fuzzer.
-    sort_frontier(&mut hist_entry.parents.0);
+    // (And with corrupt data, they can end up with duplicates.)
+    hist_entry.parents.0.sort_unstable();
+    if hist_entry.parents.0.windows(2).any(|w| w[0] == w[1]) {
+        return Err(ParseError::InvalidLength);
+    }
 
-    (hist_entry, remainder)
+    Ok((hist_entry, remainder))
 }
 
 // I could just pass &mut last_cursor_pos to a flat read() function. Eh. Once again, generators
@@ -313,14 +480,22 @@ impl<'a> ReadPatchesIter<'a> {
         // dbg!(self.last_cursor_pos, diff);
         let raw_start = isize::wrapping_add(self.last_cursor_pos as isize, diff) as usize;
 
+        if len == 0 { return Err(ParseError::InvalidLength); }
+
         let (start, raw_end) = match (tag, fwd) {
-            (Ins, true) => (raw_start, raw_start + len),
+            (Ins, true) => (raw_start, raw_start.checked_add(len).ok_or(ParseError::InvalidLength)?),
             (Ins, false) | (Del, true) => (raw_start, raw_start), // Weird symmetry!
-            (Del, false) => (raw_start - len, raw_start - len),
+            (Del, false) => {
+                let start = raw_start.checked_sub(len).ok_or(ParseError::InvalidLength)?;
+                (start, start)
+            },
         };
         // dbg!((raw_start, tag, fwd, len, start, raw_end));
 
-        let end = start + len;
+        let end = start.checked_add(len).ok_or(ParseError::InvalidLength)?;
+        // No document can be this long. Rejecting these positions keeps the arithmetic below from
+        // overflowing when operations are merged.
+        if end >= UNDERWATER_START { return Err(ParseError::InvalidLength); }
 
         // dbg!(pos);
         self.last_cursor_pos = raw_end;
@@ -399,6 +574,7 @@ impl<'a> ReadPatchContentIter<'a> {
     fn next_internal(&mut self) -> Result<ContentItem<'a>, ParseError> {
         let n = self.run_chunk.next_usize()?;
         let (len, known) = strip_bit_usize(n);
+        if len == 0 { return Err(ParseError::InvalidLength); }
         let content = if known {
             let content = consume_chars(&mut self.content, len);
             if count_chars(content) != len { // Having a duplicate strlen here is gross.
@@ -430,6 +606,23 @@ pub struct DecodeOptions {
     /// Ignore CRC check failures. This is mostly used for debugging.
     pub ignore_crc: bool,
 
+    /// Skip checking the version hash, if the data contains one. Checking the hash requires
+    /// hashing the entire history of the document.
+    pub ignore_version_hash: bool,
+
+    /// Treat the data as untrusted. In this mode parents are checked against the causal graph as
+    /// they're read, and any data which would cause problems later is rejected with an error.
+    ///
+    /// Operations can only be checked against the document's length at their version once they're
+    /// part of the oplog. So the new operations are merged in first, then the document's entire
+    /// history is replayed to check them. If that fails, the merged data is rolled back (like any
+    /// other decoding error) and the oplog is left unchanged. This is slow for large documents.
+    /// Use it when accepting patches from clients you don't trust.
+    pub strict_validation: bool,
+
+    /// Limits on how large the decoded data is allowed to be. By default there are no limits.
+    pub limits: DecodeLimits,
+
     pub verbose: bool,
 }
 
@@ -438,6 +631,9 @@ impl Default for DecodeOptions {
     fn default() -> Self {
         Self {
             ignore_crc: false,
+            ignore_version_hash: false,
+            strict_validation: false,
+            limits: DecodeLimits::default(),
             verbose: false,
         }
     }
@@ -494,89 +690,17 @@ impl ListOpLog {
 
         if result.is_err() {
             // Unwind changes back to len.
-            // This would be nicer with an RleVec iterator, but the iter implementation doesn't
-            // support iterating backwards.
             self.doc_id = doc_id;
 
-            while let Some(last) = self.cg.agent_assignment.client_with_localtime.0.last_mut() {
-                debug_assert!(len <= last.end());
-                if len == last.end() { break; }
-                else {
-                    // Truncate!
-                    let KVPair(_, removed) = if len <= last.0 {
-                        // Drop entire entry
-                        self.cg.agent_assignment.client_with_localtime.0.pop().unwrap()
-                    } else {
-                        last.truncate(len - last.0)
-                    };
-
-                    let client_data = &mut self.cg.agent_assignment.client_data[removed.agent as usize];
-                    client_data.item_times.remove_ctx(removed.seq_range, &());
-                }
-            }
-
             let num_operations = self.operations.end();
             if num_operations > len {
                 self.operations.remove_ctx((len..num_operations).into(), &self.operation_ctx);
             }
 
-            // Trim history
-            let hist_entries = &mut self.cg.graph.entries;
-            let history_length = hist_entries.end();
-            if history_length > len {
-                // We can't use entries.remove because HistoryEntry doesn't support SplitableSpan.
-                // And also because we need to update child_indexes.
-                let del_span_start = len;
-
-                let first_idx = hist_entries.find_index(len).unwrap();
-
-                let e = &mut hist_entries.0[first_idx];
-                let first_truncated_idx = if del_span_start > e.span.start {
-                    // The first entry just needs to be trimmed down.
-                    e.span.truncate_from(del_span_start);
-                    first_idx + 1
-                } else {
-                    first_idx
-                };
-
-                let mut idx = first_truncated_idx;
-
-                // Go through and unwind from idx.
-                while idx < hist_entries.num_entries() {
-                    // Cloning here is an ugly and kinda slow hack to work around the borrow
-                    // checker. But this whole case is rare anyway, so idk.
-                    let parents = hist_entries.0[idx].parents.clone();
-
-                    for p in parents {
-                        if p < len { // If p >= len, the target will be discarded anyway.
-                            let parent_entry = hist_entries.find_mut(p).unwrap().0;
-                            while let Some(&c_idx) = parent_entry.child_indexes.last() {
-                                if c_idx >= first_truncated_idx {
-                                    parent_entry.child_indexes.pop();
-                                } else { break; }
-                            }
-                        }
-                    }
-
-                    idx += 1;
-                }
-
-                self.cg.graph.entries.0.truncate(first_truncated_idx);
-
-                while let Some(&last_idx) = self.cg.graph.root_child_indexes.last() {
-                    if last_idx >= self.cg.graph.entries.num_entries() {
-                        self.cg.graph.root_child_indexes.pop();
-                    } else { break; }
-                }
-            }
-
-            // Remove excess agents
-            self.cg.agent_assignment.client_data.truncate(num_known_agents);
+            self.cg.truncate_to(len, num_known_agents, old_frontier);
 
             self.operation_ctx.ins_content.truncate(ins_content_length);
             self.operation_ctx.del_content.truncate(del_content_length);
-
-            self.cg.version = old_frontier;
         }
 
         result
@@ -588,6 +712,13 @@ impl ListOpLog {
     /// TODO: Currently if this method returns an error, the local state is undefined & invalid.
     /// Until this is fixed, the signature of the method will stay kinda weird to prevent misuse.
     fn decode_internal(&mut self, data: &[u8], opts: DecodeOptions) -> Result<Frontier, ParseError> {
+        self.decode_internal_tracking(data, opts, &mut None)
+    }
+
+    /// Same as decode_internal, but `current_chunk` is updated with the chunk being read as decoding
+    /// progresses. If decoding fails, it names the chunk which contained the bad data (or None if
+    /// the file's header is invalid). This is used by fsck.
+    pub(super) fn decode_internal_tracking(&mut self, data: &[u8], opts: DecodeOptions, current_chunk: &mut Option<ListChunkType>) -> Result<Frontier, ParseError> {
         // Written to be symmetric with encode functions.
         let mut reader = BufReader(data);
 
@@ -604,6 +735,7 @@ impl ListOpLog {
 
         // The rest of the file is made of chunks!
         let mut reader = reader.chunks();
+        *current_chunk = Some(ListChunkType::CompressedFieldsLZ4);
 
         // *** Compressed data ***
         // If there is a compressed chunk, it can contain data for other fields, all mushed
@@ -621,6 +753,15 @@ impl ListOpLog {
         #[cfg(feature = "lz4")] {
             _compressed_chunk_raw = if let Some(mut c) = reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)? {
                 let uncompressed_len = c.next_usize()?;
+                if uncompressed_len > opts.limits.max_decompressed_bytes {
+                    return Err(ParseError::LimitExceeded);
+                }
+
+                // LZ4 can't compress data by more than 255x. Checking this stops corrupt data from
+                // making us allocate huge buffers.
+                if uncompressed_len > c.0.len().saturating_mul(255) {
+                    return Err(ParseError::LZ4DecompressionError);
+                }
 
                 // The rest of the bytes contain lz4 compressed data.
                 let data = lz4_flex::decompress(c.0, uncompressed_len)
@@ -633,11 +774,12 @@ impl ListOpLog {
         }
 
         // *** FileInfo ***
+        *current_chunk = Some(ListChunkType::FileInfo);
         // fileinfo has DocID, UserData and AgentNames.
         // The agent_map is a map from agent_id in the file to agent_id in self.
         let FileInfoData {
             userdata: _userdata, doc_id, mut agent_map,
-        } = reader.read_fileinfo(self)?;
+        } = reader.read_fileinfo(self, &opts.limits)?;
 
         // If we already have a doc_id, make sure they match before merging.
         if let Some(file_doc_id) = doc_id {
@@ -650,6 +792,7 @@ impl ListOpLog {
         }
 
         // *** StartBranch ***
+        *current_chunk = Some(ListChunkType::StartBranch);
         let mut start_branch = reader.expect_chunk(ListChunkType::StartBranch)?.chunks();
 
         // Start version - which if missing defaults to ROOT ([]).
@@ -670,7 +813,13 @@ impl ListOpLog {
         let patches_overlap = !local_frontier_eq(start_version.as_ref(), self.cg.version.as_ref());
         // dbg!(patches_overlap);
 
+        let first_new_time = self.len();
+
+        // Every (agent, seq) span in the file, including operations we already have.
+        let mut file_agent_spans = Vec::new();
+
         // *** Patches ***
+        *current_chunk = Some(ListChunkType::Patches);
         let file_frontier = {
             // This chunk contains the actual set of edits to the document.
             let mut patch_chunk = reader.expect_chunk(ListChunkType::Patches)?
@@ -702,7 +851,6 @@ impl ListOpLog {
             let mut patches_iter = ReadPatchesIter::new(pos_patches_chunk)
                 .buffered();
 
-            let first_new_time = self.len();
             let mut next_patch_time = first_new_time;
 
             // The file we're loading has a list of operations. The list's item order is shared in a
@@ -728,7 +876,9 @@ impl ListOpLog {
             let mut version_map = RleVec::new();
 
             // Take and merge the next exactly n patches
-            let mut parse_next_patches = |oplog: &mut ListOpLog, mut n: usize, keep: bool| -> Result<(), ParseError> {
+            // If overlap is set, the patches are for operations we already have (starting at that local
+            // version). They're checked against our copy instead of being merged.
+            let mut parse_next_patches = |oplog: &mut ListOpLog, mut n: usize, mut overlap: Option<LV>| -> Result<(), ParseError> {
                 while n > 0 {
                     let mut max_len = n;
 
@@ -761,7 +911,13 @@ impl ListOpLog {
                         // dbg!(keep, (next_patch_time, &op, content_here));
 
                         // self.operations.push(KVPair(next_time, op));
-                        if keep {
+                        if let Some(lv) = overlap.as_mut() {
+                            if let Some(offset) = oplog.find_op_mismatch(*lv, op.kind, op.loc, content_here) {
+                                let (agent, seq) = oplog.lv_to_agent_version(*lv + offset);
+                                return Err(ParseError::AgentIdReused { agent, seq });
+                            }
+                            *lv += max_len;
+                        } else {
                             oplog.push_op_internal(next_patch_time, op.loc, op.kind, content_here);
                             next_patch_time += max_len;
                         }
@@ -777,12 +933,21 @@ impl ListOpLog {
                 Ok(())
             };
 
+            let mut file_len: usize = 0;
             while let Some(mut crdt_span) = agent_assignment_chunk.read_next_agent_assignment(&mut agent_map)? {
+                // Local versions can't get anywhere near the underwater range.
+                file_len = file_len.saturating_add(crdt_span.len());
+                if file_len > opts.limits.max_ops { return Err(ParseError::LimitExceeded); }
+                if first_new_time.saturating_add(file_len) >= UNDERWATER_START {
+                    return Err(ParseError::InvalidLength);
+                }
+
                 // let mut crdt_span = crdt_span; // TODO: Remove me. Blerp clion.
                 // dbg!(crdt_span);
                 if crdt_span.agent as usize >= self.cg.agent_assignment.client_data.len() {
                     return Err(ParseError::InvalidLength);
                 }
+                file_agent_spans.push(crdt_span);
 
                 if patches_overlap {
                     // Sooo, if the current document overlaps with the data we're loading, we need
@@ -803,12 +968,12 @@ impl ListOpLog {
                         let consume_here = crdt_span.seq_range.truncate_keeping_right_from(end);
                         let len = consume_here.len();
 
-                        let keep = if let Some(overlap_start) = overlap_start {
+                        let overlap = if let Some(overlap_start) = overlap_start {
                             let overlap = (overlap_start .. overlap_start + len).into();
                             // There's overlap. We'll filter out this item.
                             version_map.push_rle(KVPair(next_file_time, overlap));
                             // println!("push overlap {:?}", KVPair(next_file_time, overlap));
-                            false
+                            Some(overlap_start)
                         } else {
                             self.assign_time_to_crdt_span(next_assignment_time, AgentSpan {
                                 agent: crdt_span.agent,
@@ -824,13 +989,13 @@ impl ListOpLog {
                                 (next_assignment_time..next_assignment_time + len).into(),
                             ));
                             next_assignment_time += len;
-                            true
+                            None
                         };
                         next_file_time += len;
 
                         // dbg!(&file_to_local_version_map);
 
-                        parse_next_patches(self, len, keep)?;
+                        parse_next_patches(self, len, overlap)?;
 
                         // And deal with history.
                         // parse_next_history(&mut self, &file_to_self_agent_map, &version_map, len, keep)?;
@@ -840,12 +1005,20 @@ impl ListOpLog {
                     // Optimization - don't bother with the filtering code above if loaded changes
                     // follow local changes. Most calls to this function load into an empty
                     // document, and this is the case.
+
+                    // A well formed file never names the same (agent, seq) pair twice.
+                    let client = &self.cg.agent_assignment.client_data[crdt_span.agent as usize];
+                    match client.item_times.find_sparse(crdt_span.seq_range.start).0 {
+                        Err(empty_span) if empty_span.end >= crdt_span.seq_range.end => {},
+                        _ => { return Err(ParseError::GenericInvalidData); }
+                    }
+
                     self.assign_time_to_crdt_span(next_assignment_time, crdt_span);
                     let len = crdt_span.len();
                     let timespan = (next_assignment_time..next_assignment_time+len).into();
                     // file_to_local_version_map.push_rle((next_assignment_time..next_assignment_time + len).into());
                     version_map.push_rle(KVPair(next_file_time, timespan));
-                    parse_next_patches(self, len, true)?;
+                    parse_next_patches(self, len, None)?;
                     // parse_next_history(&mut self, &file_to_self_agent_map, &version_map, len, true)?;
 
                     next_assignment_time += len;
@@ -859,8 +1032,9 @@ impl ListOpLog {
 
             let mut file_frontier = start_version;
 
+            *current_chunk = Some(ListChunkType::OpParents);
             while !history_chunk.is_empty() {
-                let mut entry = history_chunk.next_history_entry(self, next_file_time, &agent_map)?;
+                let mut entry = history_chunk.next_history_entry(self, next_file_time, &agent_map, &opts.limits)?;
                 // So at this point the entry has underwater entry spans, and parents are underwater
                 // when they're local to the file (and non-underwater when they refer to our items).
                 // This makes the entry safe to truncate(), but we need to map it before we can use
@@ -878,19 +1052,41 @@ impl ListOpLog {
 
                 loop {
                     let (mut mapped, remainder)
-                        = history_entry_map_and_truncate(entry, &version_map);
+                        = history_entry_map_and_truncate(entry, &version_map)?;
                     // dbg!(&mapped);
                     mapped.parents.debug_check_sorted();
                     assert!(mapped.span.start <= next_history_time);
 
                     // We'll update merge parents even if nothing is merged.
                     // dbg!((&file_frontier, &mapped));
+                    if file_frontier.iter().any(|v| mapped.span.contains(*v)) {
+                        return Err(ParseError::GenericInvalidData);
+                    }
                     file_frontier.advance_by_known_run(mapped.parents.as_ref(), mapped.span);
                     // dbg!(&file_frontier);
 
+                    // Operations we already have must have the same parents as our copy. If they
+                    // don't, the (agent, seq) IDs have been reused.
+                    if mapped.span.start < first_new_time {
+                        let known = (mapped.span.start..mapped.span.end.min(first_new_time)).into();
+                        self.cg.check_known_lv_span(mapped.parents.as_ref(), known)?;
+                    }
+
                     if mapped.span.end > next_history_time {
                         // We'll merge items from mapped.
 
+                        // Parents must always come before the items themselves.
+                        if mapped.parents.iter().any(|p| *p >= mapped.span.start) {
+                            return Err(ParseError::InvalidLength);
+                        }
+
+                        // And in strict mode, they must be a valid frontier. (No parent can be an
+                        // ancestor of another parent.)
+                        if opts.strict_validation && mapped.parents.len() > 1
+                            && self.cg.graph.find_dominators(mapped.parents.as_ref()) != mapped.parents {
+                            return Err(ParseError::GenericInvalidData);
+                        }
+
                         // This is needed because the overlapping & new items aren't strictly
                         // separated in version_map. Its kinda ugly though - I'd like a better way
                         // to deal with this case.
@@ -936,7 +1132,26 @@ impl ListOpLog {
             file_frontier
         }; // End of patches
 
+        *current_chunk = Some(ListChunkType::Signatures);
+        let file_signatures = if let Some(chunk) = reader.read_chunk_if_eq(ListChunkType::Signatures)? {
+            chunk.read_signatures(&agent_map)?
+        } else { Vec::new() };
+
+        *current_chunk = Some(ListChunkType::Tags);
+        let file_tags = if let Some(chunk) = reader.read_chunk_if_eq(ListChunkType::Tags)? {
+            chunk.read_tags(&opts.limits)?
+        } else { Vec::new() };
+
+        *current_chunk = Some(ListChunkType::Metadata);
+        let file_metadata = if let Some(chunk) = reader.read_chunk_if_eq(ListChunkType::Metadata)? {
+            chunk.read_metadata(&agent_map, &merge_agent_spans(file_agent_spans), &opts.limits)?
+        } else { Vec::new() };
+
+        *current_chunk = Some(ListChunkType::VersionHash);
+        let expected_hash = reader.read_chunk_if_eq(ListChunkType::VersionHash)?;
+
         // TODO: Move checksum check to the start, so if it fails we don't modify the document.
+        *current_chunk = Some(ListChunkType::Crc);
         let reader_len = reader.0.len();
         if let Some(mut crc_reader) = reader.read_chunk_if_eq(ListChunkType::Crc)? {
             // So this is a bit dirty. The bytes which have been checksummed is everything up to
@@ -954,12 +1169,60 @@ impl ListOpLog {
             }
         }
 
+        // The hash is checked last, because its much slower than checking the CRC.
+        *current_chunk = Some(ListChunkType::VersionHash);
+        if let Some(mut hash_reader) = expected_hash {
+            let expected = hash_reader.next_n_bytes(32)?;
+            hash_reader.expect_empty()?;
+            if !opts.ignore_version_hash && self.version_hash(file_frontier.as_ref()).as_bytes() != expected {
+                return Err(ParseError::VersionHashMismatch);
+            }
+        }
+
+        *current_chunk = None;
+        // This needs the new operations to be merged in. If they're invalid, the caller rolls the
+        // oplog back.
+        if opts.strict_validation && !self.ops_are_valid() {
+            return Err(ParseError::GenericInvalidData);
+        }
+
+        let new_ops: DTRange = (first_new_time..self.len()).into();
+        self.check_access(self.access_policy.as_ref(), &[new_ops])?;
+        self.tags.check_access(self.access_policy.as_ref(), &file_tags)?;
+        self.check_metadata_access(self.access_policy.as_ref(), &self.metadata,
+            file_metadata.iter().map(|(agent, seq_range, _)| (*agent, *seq_range, *agent)))?;
+
+        // Signatures are checked last of all. We need the new operations to be merged to check them.
+        let num_signatures = self.signatures.len();
+        self.add_signatures(file_signatures);
+        if let Some(verifier) = self.signature_verifier.as_ref() {
+            if let Err(e) = self.check_signatures(verifier.as_ref(), &[new_ops], num_signatures) {
+                self.signatures.truncate(num_signatures);
+                return Err(e);
+            }
+        }
+
+        for tag in file_tags {
+            self.tags.merge_tag(tag);
+        }
+
+        for (agent, seq_range, meta) in file_metadata {
+            add_metadata(&mut self.metadata, agent, seq_range, &meta);
+        }
+
         // self.frontier = end_frontier_chunk.read_full_frontier(&self)?;
 
         Ok(file_frontier)
     }
 }
 
+impl ListOpLog {
+    /// Read the type and size of each chunk in an encoded oplog. Used by [`ListOpLog::stats`].
+    pub(crate) fn encoded_chunk_stats(data: &[u8]) -> Result<Vec<ChunkStats>, ParseError> {
+        BufReader(data).chunk_stats()
+    }
+}
+
 #[allow(unused)]
 pub(super) fn dbg_print_chunks_in(bytes: &[u8]) {
     BufReader(bytes).dbg_print_chunk_tree();
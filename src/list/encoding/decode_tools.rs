@@ -3,6 +3,7 @@ use crate::encoding::parseerror::ParseError;
 use crate::list::encoding::leb::num_decode_zigzag_isize_old;
 use crate::list::encoding::{DataType, ListChunkType, MAGIC_BYTES};
 use crate::list::encoding::leb::{decode_leb_u32, decode_leb_u64, decode_leb_usize};
+use crate::list::stats::ChunkStats;
 
 #[derive(Debug, Clone)]
 pub struct BufReader<'a>(pub(super) &'a [u8]);
@@ -62,8 +63,8 @@ impl<'a> BufReader<'a> {
     }
 
     pub(super) fn next_u32_le(&mut self) -> Result<u32, ParseError> {
-        // self.check_has_bytes(size_of::<u32>())?;
-        let val = u32::from_le_bytes(self.0[0..4].try_into().map_err(|_| ParseError::UnexpectedEOF)?);
+        let bytes = self.0.get(0..size_of::<u32>()).ok_or(ParseError::UnexpectedEOF)?;
+        let val = u32::from_le_bytes(bytes.try_into().unwrap());
         self.consume(size_of::<u32>());
         Ok(val)
     }
@@ -177,6 +178,26 @@ impl<'a> BufReader<'a> {
             eprintln!("-> Error parsing ({:?})", e);
         }
     }
+
+    /// Read the type and size of each chunk in an encoded file. Like dbg_print_chunk_tree, this
+    /// descends into the FileInfo, StartBranch and Patches chunks.
+    pub(super) fn chunk_stats(mut self) -> Result<Vec<ChunkStats>, ParseError> {
+        fn read_chunks(chunks: ChunkReader, descend: bool) -> Result<Vec<ChunkStats>, ParseError> {
+            chunks.map(|c| {
+                let (chunk, reader) = c?;
+                let has_children = descend && matches!(chunk, ListChunkType::FileInfo | ListChunkType::StartBranch | ListChunkType::Patches);
+                Ok(ChunkStats {
+                    chunk: format!("{:?}", chunk),
+                    bytes: reader.len(),
+                    children: if has_children { read_chunks(reader.chunks(), false)? } else { vec![] },
+                })
+            }).collect()
+        }
+
+        self.read_magic()?;
+        let _protocol_version = self.next_usize()?;
+        read_chunks(self.chunks(), true)
+    }
 }
 
 
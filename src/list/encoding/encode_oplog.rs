@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use jumprope::JumpRope;
 use rle::{HasLength, RleRun};
 use crate::list::encoding::*;
@@ -10,8 +11,10 @@ use crate::frontier::local_frontier_is_root;
 use crate::list::op_metrics::ListOpMetrics;
 use crate::list::operation::ListOpKind;
 use crate::dtrange::DTRange;
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
+use crate::list::metadata::{iter_metadata_in, OpMetadata};
 use crate::encoding::tools::calc_checksum;
-use crate::list::encoding::encode_tools::{Merger, push_leb_chunk, push_leb_str, push_leb_u32, push_leb_usize, push_u32_le, write_leb_bit_run};
+use crate::list::encoding::encode_tools::{Merger, push_leb_chunk, push_leb_str, push_leb_u32, push_leb_u64, push_leb_usize, push_u32_le, write_leb_bit_run};
 use crate::list::encoding::leb::{encode_leb_u32, encode_leb_usize, num_encode_zigzag_isize_old};
 
 const ALLOW_VERBOSE: bool = false;
@@ -106,6 +109,10 @@ pub struct EncodeOptions<'a> {
 
     pub compress_content: bool,
 
+    /// Store a content-addressed hash of the resulting version, which is checked when the data is
+    /// loaded. This is slow to compute for large documents.
+    pub store_version_hash: bool,
+
     pub verbose: bool,
 }
 
@@ -116,6 +123,7 @@ pub const ENCODE_PATCH: EncodeOptions = EncodeOptions {
     store_inserted_content: true,
     store_deleted_content: false,
     compress_content: true,
+    store_version_hash: false,
     verbose: false
 };
 
@@ -126,6 +134,7 @@ pub const ENCODE_FULL: EncodeOptions = EncodeOptions {
     store_inserted_content: true,
     store_deleted_content: false, // ?? Not sure about this one!
     compress_content: true,
+    store_version_hash: false,
     verbose: false
 };
 
@@ -601,6 +610,92 @@ impl ListOpLog {
         ops_writer.flush();
         txns_writer.flush2(&mut agent_mapping);
 
+        // *** Signatures ***
+        // We include the signature for every signed run which overlaps the operations in the file.
+        // This also needs to happen before we write out agent_mapping.
+        let mut signatures_chunk = Vec::new();
+        if !self.signatures.is_empty() {
+            let included = if local_frontier_is_root(from_version) { None } else {
+                Some(self.cg.graph.diff(from_version, self.cg.version.as_ref()).1)
+            };
+
+            for sig in self.signatures.iter() {
+                if let Some(included) = included.as_ref() {
+                    if !self.signature_overlaps(sig, included) { continue; }
+                }
+
+                let mapped_agent = agent_mapping.map(self, sig.agent);
+                push_leb_usize(&mut signatures_chunk, mapped_agent as usize);
+                push_leb_usize(&mut signatures_chunk, sig.seq_range.start);
+                push_leb_usize(&mut signatures_chunk, sig.seq_range.len());
+                push_leb_usize(&mut signatures_chunk, sig.signature.len());
+                signatures_chunk.extend_from_slice(&sig.signature);
+            }
+        }
+
+        // *** Metadata ***
+        // Metadata is trimmed to the operations in the file. Each distinct metadata value is
+        // written once, and runs of operations refer to values by index. This also needs to happen
+        // before we write out agent_mapping.
+        let mut metadata_chunk = Vec::new();
+        if !self.metadata.is_empty() {
+            let included = if local_frontier_is_root(from_version) {
+                smallvec::smallvec![(0..self.len()).into()]
+            } else {
+                self.cg.graph.diff(from_version, self.cg.version.as_ref()).1
+            };
+
+            let mut values: Vec<&OpMetadata> = Vec::new();
+            let mut value_idx: HashMap<&OpMetadata, usize> = HashMap::new();
+            // (mapped agent, seq range, value index).
+            let mut runs: Vec<(AgentId, DTRange, usize)> = Vec::new();
+            for range in included.iter() {
+                for KVPair(_, span) in self.cg.agent_assignment.client_with_localtime.iter_range(*range) {
+                    for (seqs, meta) in iter_metadata_in(&self.metadata, span.agent, span.seq_range) {
+                        let Some(meta) = meta else { continue; };
+                        let idx = *value_idx.entry(meta).or_insert_with(|| {
+                            values.push(meta);
+                            values.len() - 1
+                        });
+
+                        let mapped_agent = agent_mapping.map(self, span.agent);
+                        match runs.last_mut() {
+                            Some((a, r, i)) if *a == mapped_agent && r.end == seqs.start && *i == idx => {
+                                r.end = seqs.end;
+                            }
+                            _ => runs.push((mapped_agent, seqs, idx)),
+                        }
+                    }
+                }
+            }
+
+            if !runs.is_empty() {
+                push_leb_usize(&mut metadata_chunk, values.len());
+                for meta in values {
+                    let flags = (meta.timestamp.is_some() as usize) | ((meta.message.is_some() as usize) << 1);
+                    push_leb_usize(&mut metadata_chunk, flags);
+                    if let Some(timestamp) = meta.timestamp {
+                        push_leb_u64(&mut metadata_chunk, timestamp);
+                    }
+                    if let Some(message) = meta.message.as_ref() {
+                        push_leb_str(&mut metadata_chunk, message);
+                    }
+                    push_leb_usize(&mut metadata_chunk, meta.user_data.len());
+                    for (k, v) in meta.user_data.iter() {
+                        push_leb_str(&mut metadata_chunk, k);
+                        push_leb_str(&mut metadata_chunk, v);
+                    }
+                }
+
+                for (agent, seqs, idx) in runs {
+                    push_leb_usize(&mut metadata_chunk, agent as usize);
+                    push_leb_usize(&mut metadata_chunk, seqs.start);
+                    push_leb_usize(&mut metadata_chunk, seqs.len());
+                    push_leb_usize(&mut metadata_chunk, idx);
+                }
+            }
+        }
+
         // This nominally needs to happen before we write out agent_mapping.
         // TODO: Support partial data sets. (from_frontier)
         let mut start_branch = Vec::new();
@@ -726,6 +821,37 @@ impl ListOpLog {
 
         write_chunk(ListChunkType::Patches, &mut patches_buf);
 
+        if !signatures_chunk.is_empty() {
+            push_leb_chunk(&mut result, ListChunkType::Signatures, &signatures_chunk);
+        }
+
+        // *** Tags ***
+        // Every tag is included, regardless of from_version. Tags refer to versions by name, so
+        // the tagged operations don't need to be in the file.
+        if !self.tags.is_empty() {
+            let mut tags_chunk = Vec::new();
+            for tag in self.tags.iter() {
+                push_leb_str(&mut tags_chunk, &tag.name);
+                push_leb_str(&mut tags_chunk, &tag.set_by);
+                push_leb_u64(&mut tags_chunk, tag.clock);
+                push_leb_usize(&mut tags_chunk, tag.target.len());
+                for RemoteVersionOwned(agent, seq) in tag.target.iter() {
+                    push_leb_str(&mut tags_chunk, agent);
+                    push_leb_usize(&mut tags_chunk, *seq);
+                }
+            }
+            push_leb_chunk(&mut result, ListChunkType::Tags, &tags_chunk);
+        }
+
+        if !metadata_chunk.is_empty() {
+            push_leb_chunk(&mut result, ListChunkType::Metadata, &metadata_chunk);
+        }
+
+        if opts.store_version_hash {
+            let hash = self.local_version_hash();
+            push_leb_chunk(&mut result, ListChunkType::VersionHash, hash.as_bytes());
+        }
+
         // TODO (later): Final branch content.
 
         // println!("checksum {checksum}");
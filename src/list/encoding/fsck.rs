@@ -0,0 +1,355 @@
+//! Tools for diagnosing and recovering damaged .dt files.
+//!
+//! When a file fails to load, [`ListOpLog::fsck`] walks through the file's chunks and works out
+//! which chunk contains the bad data. [`ListOpLog::salvage`] then recovers as much of the file as
+//! it can:
+//!
+//! - Checksum and version hash mismatches are ignored.
+//! - Damaged optional chunks (signatures, tags, metadata) are left out.
+//! - If the operations themselves are damaged, the operations before the damage are kept.
+//!
+//! Damage to the file's header, FileInfo or StartBranch chunks can't be repaired.
+
+use std::ops::Range;
+use crate::Frontier;
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::tools::calc_checksum;
+use crate::list::ListOpLog;
+use crate::list::encoding::{DecodeOptions, ListChunkType, PROTOCOL_VERSION};
+use crate::list::encoding::decode_tools::BufReader;
+use crate::list::operation::ListOpKind;
+
+/// A top level chunk in an encoded file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChunkReport {
+    /// The type of chunk, eg `"Patches"`.
+    pub chunk: String,
+    /// Offset of the start of the chunk (including its header) in the file.
+    pub offset: usize,
+    /// The size of the chunk's contents in bytes.
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ChecksumStatus {
+    /// The file doesn't contain a checksum.
+    Missing,
+    Valid,
+    Invalid,
+}
+
+/// The result of checking an encoded file. See [`ListOpLog::fsck`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FsckReport {
+    pub file_bytes: usize,
+    /// Set if the file's magic bytes or protocol version are invalid. If so, nothing else could be
+    /// checked.
+    pub header_error: Option<ParseError>,
+    /// The top level chunks which could be read, in order.
+    pub chunks: Vec<ChunkReport>,
+    /// Set if the chunks stopped early (eg because the file is truncated). Contains the offset
+    /// where reading stopped, and why.
+    pub chunks_error: Option<(usize, ParseError)>,
+    pub checksum: ChecksumStatus,
+    /// The error from loading the file normally. None if the file loads successfully.
+    pub load_error: Option<ParseError>,
+    /// The chunk containing data which couldn't be decoded, if any. For damaged operations this
+    /// names the column (eg `OpParents`) where possible.
+    pub bad_chunk: Option<String>,
+}
+
+impl FsckReport {
+    /// Returns true if the file loads successfully.
+    pub fn is_ok(&self) -> bool {
+        self.load_error.is_none()
+    }
+}
+
+/// The data recovered from a damaged file. See [`ListOpLog::salvage`].
+#[derive(Debug, Clone)]
+pub struct Salvaged {
+    pub oplog: ListOpLog,
+    /// Optional chunks which were damaged, and left out.
+    pub dropped_chunks: Vec<String>,
+    /// Set if the file's operations were damaged. Only the operations before the damage (and their
+    /// history) were recovered.
+    pub ops_truncated: bool,
+}
+
+struct RawChunk {
+    kind: ListChunkType,
+    /// The bytes of the whole chunk, including its header.
+    range: Range<usize>,
+    content_len: usize,
+}
+
+struct RawFile {
+    header_len: usize,
+    chunks: Vec<RawChunk>,
+    chunks_error: Option<(usize, ParseError)>,
+}
+
+fn read_raw_chunks(data: &[u8]) -> Result<RawFile, ParseError> {
+    let mut reader = BufReader(data);
+    reader.read_magic()?;
+    if reader.next_usize()? != PROTOCOL_VERSION {
+        return Err(ParseError::UnsupportedProtocolVersion);
+    }
+    let header_len = data.len() - reader.0.len();
+
+    let mut chunks = reader.chunks();
+    let mut result = RawFile { header_len, chunks: vec![], chunks_error: None };
+    while !chunks.is_empty() {
+        let start = data.len() - chunks.0.len();
+        match chunks.next_chunk() {
+            Ok((kind, content)) => {
+                result.chunks.push(RawChunk {
+                    kind,
+                    range: start..data.len() - chunks.0.len(),
+                    content_len: content.len(),
+                });
+            }
+            Err(e) => {
+                result.chunks_error = Some((start, e));
+                break;
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Options used to decode damaged files. Checksums are checked separately.
+fn salvage_opts() -> DecodeOptions {
+    DecodeOptions {
+        ignore_crc: true,
+        ignore_version_hash: true,
+        ..DecodeOptions::default()
+    }
+}
+
+impl ListOpLog {
+    /// Check an encoded oplog for damage. The returned report says whether the file loads, and if
+    /// not, which part of the file is damaged.
+    pub fn fsck(data: &[u8]) -> FsckReport {
+        let mut report = FsckReport {
+            file_bytes: data.len(),
+            header_error: None,
+            chunks: vec![],
+            chunks_error: None,
+            checksum: ChecksumStatus::Missing,
+            load_error: ListOpLog::load_from(data).err(),
+            bad_chunk: None,
+        };
+
+        let raw = match read_raw_chunks(data) {
+            Ok(raw) => raw,
+            Err(e) => {
+                report.header_error = Some(e);
+                return report;
+            }
+        };
+
+        report.chunks = raw.chunks.iter().map(|c| ChunkReport {
+            chunk: format!("{:?}", c.kind),
+            offset: c.range.start,
+            bytes: c.content_len,
+        }).collect();
+        report.chunks_error = raw.chunks_error;
+
+        if let Some(crc) = raw.chunks.iter().find(|c| c.kind == ListChunkType::Crc) {
+            let expected = data.get(crc.range.end - 4..crc.range.end)
+                .filter(|_| crc.content_len == 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+            report.checksum = if expected == Some(calc_checksum(&data[..crc.range.start])) {
+                ChecksumStatus::Valid
+            } else {
+                ChecksumStatus::Invalid
+            };
+        }
+
+        // Checksum failures don't tell us where the damage is. Decode the file again without
+        // checking the checksum to find any chunks which can't be read.
+        if report.load_error.is_some() {
+            let mut current_chunk = None;
+            if ListOpLog::new().decode_internal_tracking(data, salvage_opts(), &mut current_chunk).is_err() {
+                report.bad_chunk = current_chunk.map(|c| format!("{:?}", c));
+            }
+        }
+
+        report
+    }
+
+    /// Recover as much data as possible from a damaged encoded oplog. This returns an error if the
+    /// file's header, FileInfo or StartBranch chunks are damaged.
+    ///
+    /// The salvaged oplog may be missing data, so check it before relying on it.
+    pub fn salvage(data: &[u8]) -> Result<Salvaged, ParseError> {
+        let raw = read_raw_chunks(data)?;
+        let mut dropped: Vec<ListChunkType> = vec![];
+
+        loop {
+            // Rebuild the file from the chunks we can read, leaving out any damaged chunks.
+            let mut bytes = data[..raw.header_len].to_vec();
+            for c in raw.chunks.iter().filter(|c| !dropped.contains(&c.kind)) {
+                bytes.extend_from_slice(&data[c.range.clone()]);
+            }
+
+            let mut oplog = ListOpLog::new();
+            let mut current_chunk = None;
+            let err = match oplog.decode_internal_tracking(&bytes, salvage_opts(), &mut current_chunk) {
+                Ok(_) => {
+                    return Ok(Salvaged {
+                        oplog,
+                        dropped_chunks: dropped.iter().map(|c| format!("{:?}", c)).collect(),
+                        ops_truncated: false,
+                    });
+                }
+                Err(e) => e,
+            };
+
+            match current_chunk {
+                Some(c @ (ListChunkType::Signatures | ListChunkType::Tags | ListChunkType::Metadata
+                    | ListChunkType::VersionHash | ListChunkType::Crc)) if !dropped.contains(&c) => {
+                    dropped.push(c);
+                }
+                Some(ListChunkType::Patches | ListChunkType::OpParents) => {
+                    // The chunks after the operations are never read, so they're dropped too.
+                    dropped.extend(raw.chunks.iter()
+                        .map(|c| c.kind)
+                        .filter(|k| matches!(k, ListChunkType::Signatures | ListChunkType::Tags | ListChunkType::Metadata)));
+                    oplog.truncate_to_decoded_prefix();
+                    return Ok(Salvaged {
+                        oplog,
+                        dropped_chunks: dropped.iter().map(|c| format!("{:?}", c)).collect(),
+                        ops_truncated: true,
+                    });
+                }
+                _ => { return Err(err); }
+            }
+        }
+    }
+
+    /// After decoding fails part way through the operations, trim the oplog back to the
+    /// operations which were fully decoded (with their agent assignment and parents).
+    fn truncate_to_decoded_prefix(&mut self) {
+        let ops_end = self.operations.end();
+        let len = ops_end
+            .min(self.cg.agent_assignment.client_with_localtime.end())
+            .min(self.cg.graph.entries.end());
+
+        let mut version = Frontier::root();
+        if len > 0 {
+            version.advance(&self.cg.graph, (0..len).into());
+        }
+
+        if ops_end > len {
+            self.operations.remove_ctx((len..ops_end).into(), &self.operation_ctx);
+        }
+        self.cg.truncate_to(len, self.cg.agent_assignment.client_data.len(), version);
+
+        // And drop any content which belonged to the removed operations.
+        let content_end = |kind: ListOpKind| self.operations.iter()
+            .filter(|op| op.1.kind == kind)
+            .filter_map(|op| op.1.content_pos.map(|pos| pos.end))
+            .max()
+            .unwrap_or(0);
+        let ins_end = content_end(ListOpKind::Ins);
+        let del_end = content_end(ListOpKind::Del);
+        self.operation_ctx.ins_content.truncate(ins_end);
+        self.operation_ctx.del_content.truncate(del_end);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::encoding::EncodeOptions;
+    use crate::list::ListOpLog;
+    use crate::encoding::parseerror::ParseError;
+    use super::ChecksumStatus;
+
+    fn make_oplog() -> ListOpLog {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let mut v = oplog.add_insert(seph, 0, "hello there");
+        for i in 0..10 {
+            let agent = if i % 2 == 0 { mike } else { seph };
+            v = oplog.add_insert_at(agent, &[v], i, "x");
+            if i % 3 == 0 {
+                v = oplog.add_delete_at(agent, &[v], i..i + 1);
+            }
+        }
+        let version = oplog.local_frontier();
+        oplog.set_tag(seph, "v1", version.as_ref());
+        oplog
+    }
+
+    #[test]
+    fn fsck_valid_file() {
+        let data = make_oplog().encode(EncodeOptions::default());
+        let report = ListOpLog::fsck(&data);
+        assert!(report.is_ok());
+        assert_eq!(report.checksum, ChecksumStatus::Valid);
+        assert_eq!(report.bad_chunk, None);
+        assert!(report.chunks.iter().any(|c| c.chunk == "Patches"));
+    }
+
+    #[test]
+    fn salvage_checksum_failure() {
+        let oplog = make_oplog();
+        let mut data = oplog.encode(EncodeOptions::default());
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+
+        let report = ListOpLog::fsck(&data);
+        assert_eq!(report.load_error, Some(ParseError::ChecksumFailed));
+        assert_eq!(report.checksum, ChecksumStatus::Invalid);
+        // The data itself is fine.
+        assert_eq!(report.bad_chunk, None);
+
+        let salvaged = ListOpLog::salvage(&data).unwrap();
+        assert!(!salvaged.ops_truncated);
+        assert!(salvaged.dropped_chunks.is_empty());
+        assert_eq!(salvaged.oplog, oplog);
+    }
+
+    #[test]
+    fn salvage_truncated_file() {
+        let oplog = make_oplog();
+        let data = oplog.encode(EncodeOptions::default());
+        // Cut the file off part way through the CRC.
+        let data = &data[..data.len() - 2];
+
+        let report = ListOpLog::fsck(data);
+        assert!(!report.is_ok());
+        assert!(report.chunks_error.is_some());
+
+        let salvaged = ListOpLog::salvage(data).unwrap();
+        assert!(!salvaged.ops_truncated);
+        assert_eq!(salvaged.oplog.cg, oplog.cg);
+        assert_eq!(salvaged.oplog.tags, oplog.tags);
+    }
+
+    #[test]
+    fn salvage_never_panics() {
+        // Damage random bytes in the file. Whatever we salvage must be internally consistent.
+        let oplog = make_oplog();
+        let data = oplog.encode(EncodeOptions::default());
+        let mut rng = SmallRng::seed_from_u64(321);
+
+        for _ in 0..500 {
+            let mut damaged = data.clone();
+            for _ in 0..rng.gen_range(1..3) {
+                let i = rng.gen_range(0..damaged.len());
+                damaged[i] = rng.gen();
+            }
+
+            let _report = ListOpLog::fsck(&damaged);
+            if let Ok(salvaged) = ListOpLog::salvage(&damaged) {
+                salvaged.oplog.dbg_check(true);
+                assert!(salvaged.oplog.len() <= oplog.len());
+            }
+        }
+    }
+}
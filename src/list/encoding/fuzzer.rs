@@ -1,6 +1,6 @@
 use rand::prelude::*;
-use crate::list::{ListCRDT, ListOpLog};
-use crate::list::encoding::EncodeOptions;
+use crate::list::{ListBranch, ListCRDT, ListOpLog};
+use crate::list::encoding::{DecodeOptions, EncodeOptions, ENCODE_PATCH};
 use crate::list::old_fuzzer_tools::old_make_random_change;
 use crate::list_fuzzer_tools::{choose_2, make_random_change};
 use crate::listmerge::simple_oplog::{SimpleBranch, SimpleOpLog};
@@ -29,7 +29,8 @@ fn fuzz_encode_decode_once(seed: u64) {
             store_inserted_content: true,
             store_deleted_content: true,
             compress_content: true,
-            verbose: false
+            verbose: false,
+            ..Default::default()
         });
 
         let decoded = ListOpLog::load_from(&bytes).unwrap();
@@ -96,7 +97,8 @@ fn fuzz_encode_decode_multi(seed: u64, verbose: bool) {
             store_inserted_content: true,
             store_deleted_content: true,
             compress_content: true,
-            verbose: false
+            verbose: false,
+            ..Default::default()
         };
         let a_data = a.oplog.encode(encode_opts.clone());
         b.merge_data_and_ff(&a_data).unwrap();
@@ -131,3 +133,90 @@ fn encode_decode_multi_fuzz_forever() {
         fuzz_encode_decode_multi(seed, false);
     }
 }
+
+// This fuzzer corrupts encoded data and feeds it to decode_and_add in strict validation mode. This
+// should never panic - either the data is rejected (and the oplog is left untouched), or it loads
+// and the result can be checked out.
+fn fuzz_decode_untrusted_once(seed: u64, verbose: bool) {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut docs = [ListCRDT::new(), ListCRDT::new()];
+    for (i, doc) in docs.iter_mut().enumerate() {
+        doc.get_or_create_agent_id(agent_name(i).as_str());
+    }
+
+    // Make some concurrent changes, so the data contains merges.
+    for _i in 0..5 {
+        for doc in docs.iter_mut() {
+            for _j in 0..rng.gen_range(1..4) {
+                old_make_random_change(doc, None, 0, &mut rng);
+            }
+        }
+        let [a, b] = &mut docs;
+        let a_data = a.oplog.encode(ENCODE_PATCH);
+        b.merge_data_and_ff(&a_data).unwrap();
+    }
+
+    let [a, b] = &docs;
+    let opts = EncodeOptions {
+        store_deleted_content: rng.gen_bool(0.5),
+        compress_content: rng.gen_bool(0.5),
+        ..ENCODE_PATCH
+    };
+    let valid = if rng.gen_bool(0.5) {
+        b.oplog.encode(opts)
+    } else {
+        b.oplog.encode_from(opts, a.oplog.cg.version.as_ref())
+    };
+
+    for _i in 0..200 {
+        let mut data = valid.clone();
+        if rng.gen_bool(0.05) {
+            // Entirely random bytes.
+            data.truncate(rng.gen_range(0..data.len()));
+            rng.fill_bytes(&mut data[..]);
+        } else {
+            for _k in 0..rng.gen_range(1..4) {
+                if data.is_empty() { break; }
+                let pos = rng.gen_range(0..data.len());
+                match rng.gen_range(0..5) {
+                    0 => { data[pos] ^= 1 << rng.gen_range(0..8); }
+                    1 => { data[pos] = rng.gen(); }
+                    2 => { data.insert(pos, rng.gen()); }
+                    3 => { data.remove(pos); }
+                    _ => { data.truncate(pos); }
+                }
+            }
+        }
+
+        let before = if rng.gen_bool(0.5) { ListOpLog::new() } else { a.oplog.clone() };
+        let mut oplog = before.clone();
+        let result = oplog.decode_and_add_opts(&data, DecodeOptions {
+            ignore_crc: true,
+            strict_validation: true,
+            ..DecodeOptions::default()
+        });
+        if verbose { println!("{} {:?}", _i, result); }
+
+        if result.is_err() {
+            assert_eq!(oplog, before);
+        }
+        oplog.dbg_check(true);
+        ListBranch::new_at_tip(&oplog);
+    }
+}
+
+#[test]
+fn decode_untrusted_fuzz_once() {
+    for seed in 0..10 {
+        fuzz_decode_untrusted_once(seed, false);
+    }
+}
+
+#[test]
+#[ignore]
+fn decode_untrusted_fuzz_forever() {
+    for seed in 0.. {
+        if seed % 100 == 0 { println!("seed {seed}"); }
+        fuzz_decode_untrusted_once(seed, false);
+    }
+}
@@ -12,12 +12,15 @@ mod fuzzer;
 pub mod encode_tools;
 mod decode_tools;
 pub mod save_transformed;
+pub mod fsck;
 pub(crate) mod leb;
 
 use rle::MergableSpan;
 use crate::encoding::varint::*;
 use num_enum::TryFromPrimitive;
 pub use encode_oplog::{ENCODE_FULL, ENCODE_PATCH, EncodeOptions};
+pub use decode_oplog::DecodeOptions;
+pub use crate::encoding::limits::DecodeLimits;
 
 const MAGIC_BYTES: [u8; 8] = *b"DMNDTYPS";
 
@@ -56,6 +59,18 @@ enum ListChunkType {
 
     TransformedPositions = 27, // Currently unused
 
+    /// Content-addressed hash of the oplog's version once the patches have been merged. Optional.
+    VersionHash = 30,
+
+    /// Signatures over runs of operations from each agent. Optional.
+    Signatures = 31,
+
+    /// Named versions. Optional.
+    Tags = 32,
+
+    /// Metadata attached to runs of operations. Optional.
+    Metadata = 33,
+
     Crc = 100,
 }
 
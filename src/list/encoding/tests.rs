@@ -23,6 +23,7 @@ fn check_encode_decode_matches(oplog: &ListOpLog) {
         store_deleted_content: true,
         compress_content: true,
         verbose: false,
+        ..Default::default()
     });
 
     let oplog2 = ListOpLog::load_from(&data).unwrap();
@@ -188,7 +189,8 @@ fn check_unroll_works(dest: &ListOpLog, src: &ListOpLog) {
         store_inserted_content: true,
         store_deleted_content: true,
         compress_content: true,
-        verbose: false
+        verbose: false,
+        ..Default::default()
     });
 
     // dbg!(encoded_proper.len());
@@ -208,6 +210,9 @@ fn check_unroll_works(dest: &ListOpLog, src: &ListOpLog) {
 
         let result = actual_output.decode_and_add_opts(&corrupted, DecodeOptions {
             ignore_crc: false,
+            ignore_version_hash: false,
+            strict_validation: false,
+            limits: DecodeLimits::default(),
             verbose: true,
         });
 
@@ -245,7 +250,8 @@ fn save_load_save_load() {
         store_inserted_content: false,
         store_deleted_content: false,
         compress_content: true,
-        verbose: false
+        verbose: false,
+        ..Default::default()
     });
     dbg_print_chunks_in(&bytes);
     let oplog2 = ListOpLog::load_from(&bytes).unwrap();
@@ -258,7 +264,8 @@ fn save_load_save_load() {
         store_inserted_content: false, // Need to say false here to avoid an assert for this.
         store_deleted_content: true,
         compress_content: true,
-        verbose: false
+        verbose: false,
+        ..Default::default()
     });
     let oplog3 = ListOpLog::load_from(&bytes2).unwrap();
 
@@ -279,6 +286,54 @@ fn doc_id_preserved() {
     assert_eq!(oplog.doc_id, result.doc_id);
 }
 
+#[test]
+fn version_hash_round_trips() {
+    let oplog = simple_doc().oplog;
+    let bytes = oplog.encode(EncodeOptions {
+        store_version_hash: true,
+        ..ENCODE_FULL
+    });
+    let result = ListOpLog::load_from(&bytes).unwrap();
+    assert_eq!(oplog, result);
+
+    // Corrupt the hash. The hash sits right before the CRC chunk (6 bytes).
+    let mut corrupted = bytes.clone();
+    let hash_end = corrupted.len() - 6;
+    corrupted[hash_end - 1] ^= 1;
+    let opts = DecodeOptions { ignore_crc: true, ..DecodeOptions::default() };
+    assert_eq!(ListOpLog::load_from_opts(&corrupted, opts.clone()).unwrap_err(), ParseError::VersionHashMismatch);
+    ListOpLog::load_from_opts(&corrupted, DecodeOptions { ignore_version_hash: true, ..opts }).unwrap();
+}
+
+#[test]
+fn version_hash_detects_reused_agent_ids() {
+    let mut oplog1 = ListOpLog::new();
+    let seph = oplog1.get_or_create_agent_id("seph");
+    oplog1.add_insert(seph, 0, "hi");
+
+    let mut oplog2 = ListOpLog::new();
+    let seph = oplog2.get_or_create_agent_id("seph");
+    oplog2.add_insert(seph, 0, "yo");
+
+    let bytes = oplog2.encode(EncodeOptions {
+        store_version_hash: true,
+        ..ENCODE_FULL
+    });
+    let old = oplog1.clone();
+    // The reused ID is caught by comparing operation content, before the hash is checked.
+    assert_eq!(oplog1.decode_and_add(&bytes).unwrap_err(), ParseError::AgentIdReused { agent: seph, seq: 0 });
+    assert_eq!(oplog1, old);
+
+    // Without the inserted content the operations look the same, but the hash still differs.
+    let bytes = oplog2.encode(EncodeOptions {
+        store_version_hash: true,
+        store_inserted_content: false,
+        ..ENCODE_FULL
+    });
+    assert_eq!(oplog1.decode_and_add(&bytes).unwrap_err(), ParseError::VersionHashMismatch);
+    assert_eq!(oplog1, old);
+}
+
 #[test]
 fn mismatched_doc_id_errors() {
     let mut oplog1 = simple_doc().oplog;
@@ -406,7 +461,8 @@ fn compat_simple_doc() {
         store_inserted_content: true,
         store_deleted_content: false,
         compress_content: true,
-        verbose: false
+        verbose: false,
+        ..Default::default()
     }));
 
     // From commit 5d1d21cd519a2c631aa1fedc59744f30c0787488
@@ -422,4 +478,71 @@ fn compat_simple_doc() {
         let bytes2_compressed_full = &[68, 77, 78, 68, 84, 89, 80, 83, 0, 5, 11, 9, 144, 104, 105, 32, 116, 104, 101, 114, 101, 109, 1, 7, 3, 5, 4, 115, 101, 112, 104, 10, 0, 20, 24, 24, 8, 0, 14, 2, 4, 9, 25, 1, 19, 21, 2, 2, 13, 22, 4, 65, 79, 11, 0, 23, 2, 13, 1, 100, 4, 128, 32, 8, 191];
         assert_eq!(ListOpLog::load_from(bytes2_compressed_full).unwrap(), doc.oplog);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn decode_limits_are_enforced() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    oplog.add_insert_at(seph, &[], 0, "aaa");
+    oplog.add_insert_at(mike, &[], 0, "bbb");
+    oplog.add_insert(seph, 0, "c"); // Merges both branches.
+
+    let bytes = oplog.encode(ENCODE_FULL);
+
+    let load = |limits: DecodeLimits| {
+        ListOpLog::load_from_opts(&bytes, DecodeOptions { limits, ..DecodeOptions::default() })
+    };
+    assert_eq!(load(DecodeLimits::default()).unwrap(), oplog);
+    // 7 operations, 2 agents, a merge with 2 parents.
+    assert_eq!(load(DecodeLimits {
+        max_ops: 7,
+        max_agents: 2,
+        max_agent_name_len: 4,
+        max_parents: 2,
+        ..DecodeLimits::default()
+    }).unwrap(), oplog);
+
+    for limits in [
+        DecodeLimits { max_ops: 6, ..DecodeLimits::default() },
+        DecodeLimits { max_agents: 1, ..DecodeLimits::default() },
+        DecodeLimits { max_agent_name_len: 3, ..DecodeLimits::default() },
+        DecodeLimits { max_parents: 1, ..DecodeLimits::default() },
+    ] {
+        assert_eq!(load(limits).unwrap_err(), ParseError::LimitExceeded);
+    }
+
+    // And make sure nothing is left behind when merging fails.
+    let mut dest = ListOpLog::new();
+    let err = dest.decode_and_add_opts(&bytes, DecodeOptions {
+        limits: DecodeLimits { max_parents: 1, ..DecodeLimits::default() },
+        ..DecodeOptions::default()
+    });
+    assert_eq!(err.unwrap_err(), ParseError::LimitExceeded);
+    assert_eq!(dest, ListOpLog::new());
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn decode_limits_decompressed_bytes() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    oplog.add_insert(seph, 0, &"x".repeat(1000));
+    let bytes = oplog.encode(ENCODE_FULL);
+
+    let opts = DecodeOptions {
+        limits: DecodeLimits { max_decompressed_bytes: 100, ..DecodeLimits::default() },
+        ..DecodeOptions::default()
+    };
+    assert_eq!(ListOpLog::load_from_opts(&bytes, opts).unwrap_err(), ParseError::LimitExceeded);
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn lz4_overlong_literals_are_an_error() {
+    // Regression found by decode_untrusted_fuzz_once. This block has 4 bytes of literals, but
+    // claims to decompress to 3 bytes. lz4_flex 0.10 panics on this instead of returning an error,
+    // so we depend on 0.11.
+    assert!(lz4_flex::decompress(&[0x40, 1, 2, 3, 4], 3).is_err());
+}
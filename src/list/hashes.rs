@@ -0,0 +1,127 @@
+use rle::{HasLength, SplitableSpanCtx};
+use crate::causalgraph::hash::VersionHash;
+use crate::list::ListOpLog;
+use crate::list::operation::ListOpKind;
+use crate::{DTRange, LV};
+
+impl ListOpLog {
+    /// Append the content of the operation at `lv` to buf, for hashing. Each operation is written
+    /// as if it were an individual (length 1) operation, so the result doesn't depend on how
+    /// operations are run-length encoded.
+    fn write_op_for_hash(&self, lv: LV, buf: &mut Vec<u8>) {
+        let (pair, offset) = self.operations.find_packed_with_offset(lv);
+        let mut op = pair.1.clone();
+        if offset > 0 {
+            op.truncate_keeping_right_ctx(offset, &self.operation_ctx);
+        }
+        if op.len() > 1 {
+            op.truncate_ctx(1, &self.operation_ctx);
+        }
+
+        buf.push(match op.kind { ListOpKind::Ins => 0, ListOpKind::Del => 1 });
+        buf.extend_from_slice(&(op.loc.span.start as u64).to_le_bytes());
+
+        // Deleted content is optional (and implied by the rest of the history), so its not hashed.
+        if op.kind == ListOpKind::Ins {
+            match op.get_content(&self.operation_ctx) {
+                Some(content) => {
+                    buf.push(1);
+                    buf.extend_from_slice(content.as_bytes());
+                }
+                None => buf.push(0),
+            }
+        }
+    }
+
+    /// Compute the content-addressed hash of every operation in the oplog, indexed by local
+    /// version. See [`CausalGraph::item_hashes`](crate::CausalGraph::item_hashes).
+    pub fn item_hashes(&self) -> Vec<VersionHash> {
+        self.cg.item_hashes(self.len(), |lv, buf| self.write_op_for_hash(lv, buf))
+    }
+
+    /// Get the content-addressed hash of the operation at lv. Hashes are cached, so this only
+    /// needs to hash operations which haven't been hashed before.
+    pub fn item_hash(&self, lv: LV) -> VersionHash {
+        self.cg.item_hash(lv, |lv, buf| self.write_op_for_hash(lv, buf))
+    }
+
+    /// Get the hashes of the operations in range, using the hash cache.
+    pub(crate) fn item_hashes_in(&self, range: DTRange) -> Vec<VersionHash> {
+        self.cg.item_hashes_in(range, |lv, buf| self.write_op_for_hash(lv, buf))
+    }
+
+    /// Compute the content-addressed hash of the named version. The hash covers the entire history
+    /// of the version (including the content of every inserted character) - so if two peers
+    /// compute the same hash for a version, they have exactly the same history up to that point.
+    ///
+    /// If two peers name the same version using the same remote IDs but compute different hashes,
+    /// some agent has reused an (agent, seq) pair for different operations, or the data has been
+    /// tampered with.
+    ///
+    /// Hashes are cached, so after the first call only new operations need to be hashed.
+    pub fn version_hash(&self, version: &[LV]) -> VersionHash {
+        self.cg.version_hash(version, |lv, buf| self.write_op_for_hash(lv, buf))
+    }
+
+    /// Compute the content-addressed hash of the current version of the oplog.
+    pub fn local_version_hash(&self) -> VersionHash {
+        self.version_hash(self.cg.version.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::ListOpLog;
+    use crate::list::encoding::ENCODE_FULL;
+
+    #[test]
+    fn hashes_are_independent_of_run_encoding() {
+        // These two oplogs contain the same operations, but oplog1 stores them as a single run.
+        let mut oplog1 = ListOpLog::new();
+        let seph = oplog1.get_or_create_agent_id("seph");
+        oplog1.add_insert(seph, 0, "abc");
+        oplog1.add_delete_without_content(seph, 0..2);
+
+        let mut oplog2 = ListOpLog::new();
+        let seph = oplog2.get_or_create_agent_id("seph");
+        oplog2.add_insert(seph, 0, "a");
+        oplog2.add_insert(seph, 1, "bc");
+        oplog2.add_delete_without_content(seph, 0..1);
+        oplog2.add_delete_without_content(seph, 0..1);
+
+        assert_eq!(oplog1.item_hashes(), oplog2.item_hashes());
+        assert_eq!(oplog1.local_version_hash(), oplog2.local_version_hash());
+
+        // And the hashes survive a round trip through the encoding.
+        let oplog3 = ListOpLog::load_from(&oplog1.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(oplog1.local_version_hash(), oplog3.local_version_hash());
+    }
+
+    #[test]
+    fn reused_agent_ids_are_detected() {
+        let mut oplog1 = ListOpLog::new();
+        let seph = oplog1.get_or_create_agent_id("seph");
+        oplog1.add_insert(seph, 0, "hi");
+
+        let mut oplog2 = ListOpLog::new();
+        let seph = oplog2.get_or_create_agent_id("seph");
+        oplog2.add_insert(seph, 0, "yo");
+
+        // Both oplogs have the same remote version.
+        assert_eq!(oplog1.remote_frontier(), oplog2.remote_frontier());
+        assert_ne!(oplog1.local_version_hash(), oplog2.local_version_hash());
+        assert_eq!(oplog1.version_hash(&[]), oplog2.version_hash(&[]));
+    }
+
+    #[test]
+    fn renamed_operations_are_rehashed() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi");
+        let old_hash = oplog.local_version_hash();
+
+        oplog.rename_agent_from(seph, 0, "mike");
+        assert_ne!(oplog.local_version_hash(), old_hash);
+        assert_eq!(oplog.local_version_hash(), oplog.cg.version_hash_from_items(&oplog.item_hashes(), oplog.cg.version.as_ref()));
+    }
+}
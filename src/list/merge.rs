@@ -1,7 +1,7 @@
 use rle::HasLength;
 use crate::frontier::FrontierRef;
 use crate::list::{ListBranch, ListOpLog};
-use crate::list::operation::{ListOpKind, TextOperation};
+use crate::list::operation::{ListOpKind, MissingContentError, TextOperation};
 use crate::listmerge::merge::{reverse_str, TransformedOpsIter};
 use crate::listmerge::merge::TransformedResult::{BaseMoved, DeleteAlreadyHappened};
 use crate::{DTRange, LV};
@@ -37,6 +37,14 @@ impl ListOpLog {
             })
     }
 
+    /// Like [`checkout`](ListOpLog::checkout), but returns an error instead of panicking if the
+    /// oplog is missing the inserted content needed to reconstruct the document.
+    pub fn try_checkout(&self, local_version: &[LV]) -> Result<ListBranch, MissingContentError> {
+        let mut branch = ListBranch::new();
+        branch.try_merge_internal(self, local_version)?;
+        Ok(branch)
+    }
+
     /// Get all transformed operations from the start of time.
     ///
     /// This is a shorthand for `oplog.get_xf_operations(&[], oplog.local_version)`, but
@@ -47,6 +55,13 @@ impl ListOpLog {
         self.iter_xf_operations_from(&[], self.cg.version.as_ref())
     }
 
+    /// Returns true if merging all the operations in the oplog causes any concurrent inserts to
+    /// land at the same location in the document. In that case the resulting document order is
+    /// decided by the tie-breaking rules rather than by the users' intent.
+    ///
+    /// This is only available with the `merge_conflict_checks` feature. Use
+    /// [`find_merge_conflicts`](ListOpLog::find_merge_conflicts) to find out *where* concurrent
+    /// edits overlap.
     #[cfg(feature = "merge_conflict_checks")]
     pub fn has_conflicts_when_merging(&self) -> bool {
         let mut iter = TransformedOpsIter::new(&self.cg.graph, &self.cg.agent_assignment,
@@ -61,14 +76,20 @@ impl ListOpLog {
 impl ListBranch {
     /// Add everything in merge_frontier into the set..
     pub fn merge(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) {
+        self.try_merge_internal(oplog, merge_frontier)
+            .expect("Oplog is missing inserted content");
+    }
+
+    /// Merge changes into the branch. If the oplog is missing inserted content, the branch is left
+    /// partially merged and should be discarded.
+    fn try_merge_internal(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) -> Result<(), MissingContentError> {
         let mut iter = oplog.get_xf_operations_full(self.version.as_ref(), merge_frontier);
 
         for (_lv, origin_op, xf) in &mut iter {
             match (origin_op.kind, xf) {
                 (ListOpKind::Ins, BaseMoved(pos)) => {
                     // println!("Insert '{}' at {} (len {})", op.content, ins_pos, op.len());
-                    debug_assert!(origin_op.content_pos.is_some()); // Ok if this is false - we'll just fill with junk.
-                    let content = origin_op.get_content(&oplog.operation_ctx).unwrap();
+                    let content = origin_op.get_content(&oplog.operation_ctx).ok_or(MissingContentError)?;
                     assert!(pos <= self.content.len_chars());
                     if origin_op.loc.fwd {
                         self.content.insert(pos, content);
@@ -92,6 +113,7 @@ impl ListBranch {
 
         // dbg!(iter.count_range_tracker_size());
         self.version = iter.into_frontier();
+        Ok(())
     }
 
 }
\ No newline at end of file
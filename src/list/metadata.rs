@@ -0,0 +1,331 @@
+//! Metadata attached to runs of operations.
+//!
+//! Diamond types doesn't need to know when operations were created, or why. But applications often
+//! do - for example to show a timeline of changes to a document. This module lets applications
+//! attach an [`OpMetadata`] (a wall-clock timestamp, a message and arbitrary key-value pairs) to
+//! operations.
+//!
+//! Metadata is stored against (agent, seq) ranges, so it's sent along with the operations when
+//! oplogs are encoded and merged. Runs of operations with identical metadata are stored (and
+//! encoded) together. Once an operation has metadata, attaching more metadata to it (locally or by
+//! merging in changes from a peer) has no effect. Each peer keeps whichever metadata it saw first.
+//!
+//! Metadata is attached separately from creating operations, using
+//! [`attach_metadata`](ListOpLog::attach_metadata). Nothing stops a peer from attaching metadata to
+//! operations created by other agents, and if two peers attach different metadata to the same
+//! operation they will disagree about it forever. To avoid this, applications should only attach
+//! metadata to their own operations, right after creating them.
+
+use std::collections::BTreeMap;
+use rle::HasLength;
+use smartstring::alias::String as SmartString;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::{AgentId, DTRange, Frontier, LV};
+use crate::causalgraph::graph::GraphEntrySimple;
+use crate::list::ListOpLog;
+use crate::rle::KVPair;
+
+/// Information about when and why a run of operations was created.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct OpMetadata {
+    /// Wall-clock time when the operations were created, in milliseconds since the unix epoch.
+    pub timestamp: Option<u64>,
+
+    /// A free-form message describing the change, like a commit message.
+    pub message: Option<String>,
+
+    /// Any other application specific data.
+    pub user_data: BTreeMap<SmartString, String>,
+}
+
+impl OpMetadata {
+    /// Create metadata with the current time as its timestamp.
+    pub fn now() -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_millis() as u64);
+
+        Self { timestamp, ..Default::default() }
+    }
+
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+}
+
+/// Metadata for a run of operations from one agent, starting at the seq number its keyed by.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct MetadataRun {
+    pub seq_end: usize,
+    pub meta: OpMetadata,
+}
+
+/// All the metadata in an oplog, keyed by (agent, start seq).
+pub(crate) type MetadataRuns = BTreeMap<(AgentId, usize), MetadataRun>;
+
+/// Add metadata for the named agent's seq_range, skipping any operations which already have
+/// metadata. Adjacent runs with the same metadata are merged.
+pub(crate) fn add_metadata(runs: &mut MetadataRuns, agent: AgentId, seq_range: DTRange, meta: &OpMetadata) {
+    let mut start = seq_range.start;
+    while start < seq_range.end {
+        // Skip over the run containing start, if there is one.
+        if let Some((&(_, run_start), run)) = runs.range(..=(agent, start)).next_back()
+            .filter(|((a, _), _)| *a == agent) {
+            if run.seq_end > start {
+                debug_assert!(run_start <= start);
+                start = run.seq_end;
+                continue;
+            }
+        }
+
+        let end = runs.range((agent, start)..(agent, seq_range.end)).next()
+            .map(|((_, next_start), _)| *next_start)
+            .unwrap_or(seq_range.end);
+
+        // Merge with the previous run if we can.
+        let mut key = (agent, start);
+        if let Some((&prev_key, prev)) = runs.range_mut(..(agent, start)).next_back() {
+            if prev_key.0 == agent && prev.seq_end == start && prev.meta == *meta {
+                prev.seq_end = end;
+                key = prev_key;
+            }
+        }
+        if key.1 == start {
+            runs.insert(key, MetadataRun { seq_end: end, meta: meta.clone() });
+        }
+
+        // And the next run.
+        if let Some(next) = runs.get(&(agent, end)) {
+            if next.meta == *meta {
+                let next_end = next.seq_end;
+                runs.remove(&(agent, end));
+                runs.get_mut(&key).unwrap().seq_end = next_end;
+            }
+        }
+
+        start = end;
+    }
+}
+
+/// Iterate through the runs of metadata which overlap seq_range, trimmed to seq_range. Gaps are
+/// yielded with no metadata.
+pub(crate) fn iter_metadata_in(runs: &MetadataRuns, agent: AgentId, seq_range: DTRange) -> impl Iterator<Item = (DTRange, Option<&OpMetadata>)> + '_ {
+    let first = runs.range(..=(agent, seq_range.start)).next_back()
+        .filter(|((a, _), run)| *a == agent && run.seq_end > seq_range.start);
+
+    let mut iter = first.into_iter()
+        .chain(runs.range((agent, seq_range.start + 1)..(agent, seq_range.end)))
+        .peekable();
+
+    let mut pos = seq_range.start;
+    std::iter::from_fn(move || {
+        if pos >= seq_range.end { return None; }
+
+        match iter.peek() {
+            Some(((_, start), run)) if *start <= pos => {
+                let end = run.seq_end.min(seq_range.end);
+                let result = ((pos..end).into(), Some(&run.meta));
+                iter.next();
+                pos = end;
+                Some(result)
+            }
+            Some(((_, start), _)) => {
+                let result = ((pos..*start).into(), None);
+                pos = *start;
+                Some(result)
+            }
+            None => {
+                let result = ((pos..seq_range.end).into(), None);
+                pos = seq_range.end;
+                Some(result)
+            }
+        }
+    })
+}
+
+impl ListOpLog {
+    /// Attach metadata to the operations in the named range of local versions. This should be
+    /// called right after the operations are created, by the agent which created them.
+    ///
+    /// Metadata can't be changed once it has been attached. Any operations in the range which
+    /// already have metadata are skipped. This isn't checked, but if peers attach different
+    /// metadata to the same operations they won't converge. See the [module docs](crate::list::metadata).
+    pub fn attach_metadata(&mut self, range: DTRange, meta: &OpMetadata) {
+        for KVPair(_, span) in self.cg.agent_assignment.client_with_localtime.iter_range(range) {
+            add_metadata(&mut self.metadata, span.agent, span.seq_range, meta);
+        }
+    }
+
+    /// Get the metadata attached to the named operation, if any.
+    pub fn metadata_at(&self, v: LV) -> Option<&OpMetadata> {
+        let (agent, seq) = self.lv_to_agent_version(v);
+        iter_metadata_in(&self.metadata, agent, (seq..seq + 1).into())
+            .next()
+            .and_then(|(_, meta)| meta)
+    }
+
+    /// Iterate through the history of the oplog (like [`iter_history`](ListOpLog::iter_history)),
+    /// along with the metadata attached to each run of operations. History entries are split so
+    /// every operation in each yielded entry has the same metadata.
+    pub fn iter_history_with_metadata(&self) -> impl Iterator<Item = (GraphEntrySimple, Option<&OpMetadata>)> + '_ {
+        self.iter_history().flat_map(move |entry| {
+            let mut result: Vec<(GraphEntrySimple, Option<&OpMetadata>)> = vec![];
+            for KVPair(lv, span) in self.cg.agent_assignment.client_with_localtime.iter_range(entry.span) {
+                let mut lv = lv;
+                for (seqs, meta) in iter_metadata_in(&self.metadata, span.agent, span.seq_range) {
+                    if let Some((last, last_meta)) = result.last_mut() {
+                        if *last_meta == meta && last.span.end == lv {
+                            last.span.end += seqs.len();
+                            lv += seqs.len();
+                            continue;
+                        }
+                    }
+
+                    let parents = if lv == entry.span.start {
+                        entry.parents.clone()
+                    } else {
+                        Frontier::new_1(lv - 1)
+                    };
+
+                    result.push((GraphEntrySimple {
+                        span: (lv..lv + seqs.len()).into(),
+                        parents,
+                    }, meta));
+                    lv += seqs.len();
+                }
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encoding::limits::DecodeLimits;
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::{DecodeOptions, ENCODE_FULL};
+    use crate::list::ListOpLog;
+    use super::*;
+
+    fn meta(msg: &str) -> OpMetadata {
+        OpMetadata {
+            timestamp: Some(1000),
+            message: Some(msg.into()),
+            user_data: Default::default(),
+        }
+    }
+
+    #[test]
+    fn runs_are_merged_and_immutable() {
+        let mut runs = MetadataRuns::new();
+        add_metadata(&mut runs, 0, (0..5).into(), &meta("a"));
+        add_metadata(&mut runs, 0, (10..15).into(), &meta("a"));
+        add_metadata(&mut runs, 0, (3..12).into(), &meta("b"));
+        add_metadata(&mut runs, 1, (0..2).into(), &meta("a"));
+        add_metadata(&mut runs, 0, (15..20).into(), &meta("a"));
+
+        let items: Vec<_> = iter_metadata_in(&runs, 0, (2..22).into())
+            .map(|(r, m)| (r, m.and_then(|m| m.message.clone())))
+            .collect();
+        assert_eq!(items, vec![
+            ((2..5).into(), Some("a".into())),
+            ((5..10).into(), Some("b".into())),
+            ((10..20).into(), Some("a".into())),
+            ((20..22).into(), None),
+        ]);
+
+        // Operations which already have metadata are left alone.
+        add_metadata(&mut runs, 0, (0..20).into(), &meta("c"));
+        assert_eq!(runs.len(), 4);
+
+        // Runs are merged with the run after them too.
+        add_metadata(&mut runs, 0, (40..50).into(), &meta("c"));
+        add_metadata(&mut runs, 0, (30..40).into(), &meta("c"));
+        assert_eq!(runs.len(), 5);
+        assert_eq!(runs[&(0, 30)].seq_end, 50);
+    }
+
+    #[test]
+    fn metadata_is_encoded_and_merged() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hi");
+        oplog.attach_metadata((0..2).into(), &meta("hello"));
+        oplog.add_insert(mike, 2, " there");
+        let v = oplog.local_frontier();
+        oplog.add_insert(seph, 0, "yo ");
+        oplog.attach_metadata((8..11).into(), &meta("yo").with_message("yo!"));
+
+        assert_eq!(oplog.metadata_at(1).unwrap().message.as_deref(), Some("hello"));
+        assert_eq!(oplog.metadata_at(5), None);
+        assert_eq!(oplog.metadata_at(9).unwrap().message.as_deref(), Some("yo!"));
+
+        let history: Vec<_> = oplog.iter_history_with_metadata()
+            .map(|(e, m)| (e.span, m.is_some()))
+            .collect();
+        assert_eq!(history, vec![((0..2).into(), true), ((2..8).into(), false), ((8..11).into(), true)]);
+
+        let data = oplog.encode(ENCODE_FULL);
+        let loaded = ListOpLog::load_from(&data).unwrap();
+        assert_eq!(loaded.metadata, oplog.metadata);
+
+        // Patches contain the metadata for the included operations.
+        let patch = oplog.encode_from(ENCODE_FULL, v.as_ref());
+        let mut partial = ListOpLog::load_from(&oplog.encode(ENCODE_FULL)).unwrap();
+        partial.metadata.clear();
+        partial.decode_and_add(&patch).unwrap();
+        assert_eq!(partial.metadata_at(1), None);
+        assert_eq!(partial.metadata_at(9).unwrap().message.as_deref(), Some("yo!"));
+
+        let mut merged = ListOpLog::new();
+        merged.merge_ops(&oplog).unwrap();
+        for lv in 0..oplog.len() {
+            assert_eq!(merged.metadata_at(lv), oplog.metadata_at(lv));
+        }
+    }
+
+    #[test]
+    fn metadata_must_describe_operations_in_the_file() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi");
+        oplog.attach_metadata((0..2).into(), &meta("MSG"));
+        let mut data = oplog.encode(ENCODE_FULL);
+
+        // Find the run (agent 1, seqs 0..2, value 0) after the message, and stretch it to cover
+        // seph's future operations.
+        let run = [b'M', b'S', b'G', 0, 1, 0, 2, 0];
+        let pos = data.windows(run.len()).position(|w| w == run).unwrap();
+        data[pos + 6] = 50;
+
+        let opts = DecodeOptions { ignore_crc: true, ..DecodeOptions::default() };
+        assert_eq!(ListOpLog::load_from_opts(&data, opts).unwrap_err(), ParseError::GenericInvalidData);
+    }
+
+    #[test]
+    fn metadata_decode_limits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi");
+        let mut m = meta("hello");
+        m.user_data.insert("k".into(), "v".into());
+        oplog.attach_metadata((0..2).into(), &m);
+        let data = oplog.encode(ENCODE_FULL);
+
+        let load = |max_metadata_len: usize| {
+            ListOpLog::load_from_opts(&data, DecodeOptions {
+                limits: DecodeLimits { max_metadata_len, ..DecodeLimits::default() },
+                ..DecodeOptions::default()
+            })
+        };
+        // "hello" + "k" + "v" + 1 for the user data entry.
+        assert_eq!(load(8).unwrap().metadata, oplog.metadata);
+        assert_eq!(load(7).unwrap_err(), ParseError::LimitExceeded);
+        assert_eq!(load(4).unwrap_err(), ParseError::LimitExceeded);
+    }
+}
@@ -11,6 +11,11 @@ use crate::list::operation::ListOpKind;
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
 use crate::{CausalGraph, Frontier};
 use crate::rle::{KVPair, RleVec};
+use std::sync::Arc;
+use crate::list::signatures::{RunSignature, SignatureVerifier};
+use crate::access::AccessPolicy;
+use crate::tags::Tags;
+use crate::list::metadata::MetadataRuns;
 
 pub mod operation;
 mod list;
@@ -33,6 +38,16 @@ mod oplog_merge_fuzzer;
 pub(crate) mod buffered_iter;
 mod stochastic_summary;
 mod merge;
+pub mod conflicts;
+mod version_diff;
+mod revert;
+pub mod set_content;
+pub mod position_unit;
+mod hashes;
+pub mod signatures;
+pub mod metadata;
+pub mod repair;
+pub mod stats;
 
 // TODO!
 // trait InlineReplace<T> {
@@ -116,6 +131,21 @@ pub struct ListOpLog {
     // TODO: Replace me with a compact form of this data.
     pub(crate) operations: RleVec<KVPair<ListOpMetrics>>,
 
+    /// Signatures over runs of operations. See [`signatures`](crate::list::signatures).
+    pub(crate) signatures: Vec<RunSignature>,
+
+    /// If set, remote operations are only merged in if they're signed by their agent's key.
+    signature_verifier: Option<Arc<dyn SignatureVerifier>>,
+
+    /// If set, remote operations are only merged in if the policy allows them.
+    pub(crate) access_policy: Option<Arc<dyn AccessPolicy>>,
+
+    /// Named versions. See [`tags`](crate::tags).
+    pub(crate) tags: Tags,
+
+    /// Metadata attached to runs of operations. See [`metadata`](crate::list::metadata).
+    pub(crate) metadata: MetadataRuns,
+
     // /// This is the LocalVersion for the entire oplog. So, if you merged every change we store into
     // /// a branch, this is the version of that branch.
     // ///
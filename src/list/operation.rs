@@ -4,6 +4,7 @@
 ///
 /// Updates are made up of a series of insert / delete components, each at some position.
 
+use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::ops::Range;
 use smartstring::alias::{String as SmartString};
@@ -39,6 +40,19 @@ impl Display for ListOpKind {
     }
 }
 
+/// Returned by methods which need to know the content of inserted text, when the oplog doesn't
+/// store it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MissingContentError;
+
+impl Display for MissingContentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Inserted content is missing from the oplog")
+    }
+}
+
+impl Error for MissingContentError {}
+
 /// So the span here is interesting. For inserts, this is the range of positions the inserted
 /// characters *will have* after they've been inserted.
 ///
@@ -25,6 +25,11 @@ impl ListOpLog {
             cg: Default::default(),
             operation_ctx: ListOperationCtx::new(),
             operations: Default::default(),
+            signatures: Vec::new(),
+            signature_verifier: None,
+            access_policy: None,
+            tags: Default::default(),
+            metadata: Default::default(),
             // inserted_content: "".to_string(),
         }
     }
@@ -99,6 +104,12 @@ impl ListOpLog {
         self.cg.agent_assignment.client_with_localtime.is_empty()
     }
 
+    /// Returns true if the oplog contains the content of every insert. Oplogs encoded without
+    /// their inserted content can't be checked out.
+    pub fn has_inserted_content(&self) -> bool {
+        self.operations.iter().all(|KVPair(_, op)| op.kind != ListOpKind::Ins || op.content_pos.is_some())
+    }
+
     // Unused for now, but it should work.
     // #[allow(unused)]
     // pub(crate) fn assign_next_time_to_client(&mut self, agent: AgentId, len: usize) {
@@ -302,6 +313,36 @@ impl ListOpLog {
         self.cg.graph.iter_range(range)
     }
 
+    /// Render the causal graph as an SVG image. Hovering over each run of operations in the image
+    /// shows a summary of the operations.
+    #[cfg(feature = "svg_export")]
+    pub fn to_svg(&self) -> String {
+        const MAX_OPS: usize = 5;
+        const MAX_EXCERPT: usize = 30;
+
+        self.cg.to_svg_with(|range| {
+            let mut lines = vec![];
+            for (i, (KVPair(_, op), content)) in self.iter_range_simple(range).enumerate() {
+                if i == MAX_OPS {
+                    lines.push("...".to_string());
+                    break;
+                }
+                let kind = match op.kind {
+                    ListOpKind::Ins => "ins",
+                    ListOpKind::Del => "del",
+                };
+                let mut line = format!("{kind} {}..{}", op.loc.span.start, op.loc.span.end);
+                if let Some(content) = content {
+                    let excerpt: String = content.chars().take(MAX_EXCERPT).collect();
+                    line.push_str(&format!(" {excerpt:?}"));
+                    if content.chars().count() > MAX_EXCERPT { line.push_str("..."); }
+                }
+                lines.push(line);
+            }
+            Some(lines.join("\n"))
+        })
+    }
+
     /// Returns a `&[usize]` reference to the tip of the oplog. This version contains all
     /// known operations.
     ///
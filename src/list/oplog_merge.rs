@@ -6,6 +6,9 @@ use crate::dtrange::DTRange;
 use crate::rle::KVPair;
 use crate::{AgentId, CausalGraph};
 use crate::causalgraph::graph::GraphEntrySimple;
+use crate::encoding::parseerror::ParseError;
+use crate::list::signatures::RunSignature;
+use crate::list::metadata::add_metadata;
 
 impl CausalGraph {
     /// Find all the items to merge from other into self.
@@ -89,7 +92,52 @@ impl CausalGraph {
 impl ListOpLog {
     /// Add all missing operations from the other oplog into this oplog. This method is mostly used
     /// by testing code, since you rarely have two local oplogs to merge together.
+    ///
+    /// Signatures are copied across, but they aren't checked. Use
+    /// [`merge_ops`](ListOpLog::merge_ops) to check signatures while merging.
     pub fn add_missing_operations_from(&mut self, other: &Self) {
+        let agent_map = self.map_agents_from(other);
+
+        // So we need to figure out which changes in other *aren't* in self. To do that, I'll walk
+        // backwards through other, looking for changes which are missing in self.
+        let spans = self.cg.to_merge(&other.cg, &agent_map);
+        // dbg!(&spans);
+
+        self.merge_spans_from(other, &agent_map, &spans);
+    }
+
+    /// Merge all missing operations from the other oplog into this oplog.
+    ///
+    /// If this oplog has a [signature verifier](ListOpLog::set_signature_verifier), the missing
+    /// operations must all be covered by valid signatures in other. If they aren't, nothing is
+    /// merged and this method returns [`ParseError::InvalidSignature`]. Likewise if this oplog has
+    /// an [access policy](ListOpLog::set_access_policy), every missing operation (and every new tag
+    /// and piece of metadata) must be allowed by the policy.
+    pub fn merge_ops(&mut self, other: &Self) -> Result<(), ParseError> {
+        let num_known_agents = self.cg.agent_assignment.client_data.len();
+        let agent_map = self.map_agents_from(other);
+        let spans = self.cg.to_merge(&other.cg, &agent_map);
+
+        let mut result = other.check_access(self.access_policy.as_ref(), &spans)
+            .and_then(|_| self.tags.check_access(self.access_policy.as_ref(), other.tags.iter()))
+            .and_then(|_| other.check_metadata_access(self.access_policy.as_ref(), &self.metadata,
+                other.metadata.iter().map(|(&(agent, start), run)| {
+                    (agent, (start..run.seq_end).into(), agent_map[agent as usize])
+                })));
+        if let (Ok(()), Some(verifier)) = (&result, self.signature_verifier.as_ref()) {
+            result = other.check_signatures(verifier.as_ref(), &spans, 0);
+        }
+        if let Err(e) = result {
+            self.cg.agent_assignment.client_data.truncate(num_known_agents);
+            return Err(e);
+        }
+
+        self.merge_spans_from(other, &agent_map, &spans);
+        Ok(())
+    }
+
+    /// Returns a map from other's agent IDs to agent IDs in self.
+    fn map_agents_from(&mut self, other: &Self) -> Vec<AgentId> {
         // [other.agent] => self.agent
         let mut agent_map = Vec::with_capacity(other.cg.agent_assignment.client_data.len());
 
@@ -98,13 +146,11 @@ impl ListOpLog {
             let self_agent = self.get_or_create_agent_id(c.name.as_str());
             agent_map.push(self_agent);
         }
+        agent_map
+    }
 
-        // So we need to figure out which changes in other *aren't* in self. To do that, I'll walk
-        // backwards through other, looking for changes which are missing in self.
-
-        let spans = self.cg.to_merge(&other.cg, &agent_map);
-        // dbg!(&spans);
-
+    /// Merge the named spans (in reverse order, from to_merge) from other into self.
+    fn merge_spans_from(&mut self, other: &Self, agent_map: &[AgentId], spans: &[DTRange]) {
         let mut time = self.len();
         for &s in spans.iter().rev() {
             // Operations
@@ -153,6 +199,16 @@ impl ListOpLog {
 
             time += s.len();
         }
+
+        self.add_signatures(other.signatures.iter().map(|sig| RunSignature {
+            agent: agent_map[sig.agent as usize],
+            ..sig.clone()
+        }));
+        self.tags.merge(&other.tags);
+
+        for (&(agent, start), run) in other.metadata.iter() {
+            add_metadata(&mut self.metadata, agent_map[agent as usize], (start..run.seq_end).into(), &run.meta);
+        }
     }
 }
 
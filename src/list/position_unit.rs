@@ -0,0 +1,481 @@
+//! Internally, diamond types measures all positions in unicode characters (codepoints). But most
+//! other systems don't. Javascript (and LSP) positions are measured in UTF-16 code units, and rust
+//! strings are indexed by UTF-8 byte offset.
+//!
+//! This file contains methods to create and read operations with positions measured in any of
+//! these units. Converting positions requires knowing the document's content at the time the
+//! operation applies. The branch methods get that for free, but the oplog methods need to create a
+//! checkout of the document, so they're much slower than their char-based equivalents.
+//!
+//! Operations are returned as [`UnitTextOperation`]s rather than [`TextOperation`]s, since a
+//! `TextOperation`'s length always matches the number of characters in its content.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use jumprope::{JumpRope, JumpRopeBuf};
+use rle::HasLength;
+use smartstring::alias::String as SmartString;
+use crate::list::{ListBranch, ListCRDT, ListOpLog};
+use crate::list::operation::{ListOpKind, MissingContentError, TextOperation};
+use crate::listmerge::merge::reverse_str;
+use crate::rev_range::RangeRev;
+use crate::unicount::{bytes_to_chars, count_chars};
+use crate::{AgentId, DTRange, LV};
+
+/// The units used to measure positions in a document.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum PositionUnit {
+    /// Unicode characters (codepoints). This is what diamond types uses internally.
+    #[default]
+    Chars,
+
+    /// UTF-16 code units. Characters outside the basic multilingual plane take up 2 units. This
+    /// matches string indexes in javascript, and positions in the language server protocol.
+    Utf16,
+
+    /// UTF-8 bytes. This matches string indexes in rust.
+    Utf8Bytes,
+}
+
+/// Returned when an operation or position can't be converted between units.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnitConversionError {
+    /// The position is past the end of the document.
+    PastEnd,
+
+    /// The position is inside a character. For example, between the two UTF-16 code units of a
+    /// surrogate pair, or inside a multi-byte UTF-8 character.
+    NotCharBoundary,
+
+    /// An insert's length can't be converted because the oplog doesn't store its content.
+    MissingContent,
+}
+
+impl Display for UnitConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "UnitConversionError {:?}", self)
+    }
+}
+
+impl Error for UnitConversionError {}
+
+impl From<MissingContentError> for UnitConversionError {
+    fn from(_: MissingContentError) -> Self {
+        UnitConversionError::MissingContent
+    }
+}
+
+impl PositionUnit {
+    /// Returns the length of the string, measured in these units.
+    pub fn len_of(self, s: &str) -> usize {
+        match self {
+            PositionUnit::Chars => count_chars(s),
+            PositionUnit::Utf16 => str_indices::utf16::count(s),
+            PositionUnit::Utf8Bytes => s.len(),
+        }
+    }
+
+    /// Convert a position within the string from these units to chars. The position must be
+    /// within the string.
+    fn str_pos_to_chars(self, s: &str, pos: usize) -> Result<usize, UnitConversionError> {
+        let byte_pos = match self {
+            PositionUnit::Chars => return Ok(pos),
+            PositionUnit::Utf16 => {
+                let byte_pos = str_indices::utf16::to_byte_idx(s, pos);
+                if str_indices::utf16::count(&s[..byte_pos]) != pos {
+                    return Err(UnitConversionError::NotCharBoundary);
+                }
+                byte_pos
+            }
+            PositionUnit::Utf8Bytes => {
+                if !s.is_char_boundary(pos) {
+                    return Err(UnitConversionError::NotCharBoundary);
+                }
+                pos
+            }
+        };
+        Ok(bytes_to_chars(s, byte_pos))
+    }
+
+    /// Convert a character position in the document to these units.
+    pub(crate) fn chars_to_pos(self, rope: &JumpRope, char_pos: usize) -> usize {
+        match self {
+            PositionUnit::Chars => char_pos,
+            #[cfg(feature = "wchar_conversion")]
+            PositionUnit::Utf16 => rope.chars_to_wchars(char_pos),
+            PositionUnit::Utf8Bytes if char_pos == rope.len_chars() => rope.len_bytes(),
+            _ => rope.slice_substrings(0..char_pos).map(|s| self.len_of(s)).sum(),
+        }
+    }
+
+    /// Convert a position in the document measured in these units to a character position.
+    pub(crate) fn pos_to_chars(self, rope: &JumpRope, pos: usize) -> Result<usize, UnitConversionError> {
+        match self {
+            PositionUnit::Chars => {
+                if pos > rope.len_chars() { Err(UnitConversionError::PastEnd) } else { Ok(pos) }
+            }
+            #[cfg(feature = "wchar_conversion")]
+            PositionUnit::Utf16 => {
+                if pos > rope.len_wchars() { return Err(UnitConversionError::PastEnd); }
+                let chars = rope.wchars_to_chars(pos);
+                if rope.chars_to_wchars(chars) != pos { return Err(UnitConversionError::NotCharBoundary); }
+                Ok(chars)
+            }
+            _ => {
+                let mut remaining = pos;
+                let mut chars = 0;
+                for (s, char_len) in rope.substrings_with_len() {
+                    let len = self.len_of(s);
+                    if remaining < len {
+                        return Ok(chars + self.str_pos_to_chars(s, remaining)?);
+                    }
+                    remaining -= len;
+                    chars += char_len;
+                }
+                if remaining == 0 { Ok(chars) } else { Err(UnitConversionError::PastEnd) }
+            }
+        }
+    }
+
+    fn range_to_chars(self, rope: &JumpRope, range: Range<usize>) -> Result<Range<usize>, UnitConversionError> {
+        Ok(self.pos_to_chars(rope, range.start)?..self.pos_to_chars(rope, range.end)?)
+    }
+}
+
+/// A [`TextOperation`] with its location measured in some [`PositionUnit`].
+///
+/// The range (and so `len()`) is measured in `unit`, but the content is the same as the
+/// corresponding `TextOperation`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnitTextOperation {
+    /// The range of the document being modified by this operation, measured in `unit`.
+    pub loc: RangeRev,
+
+    /// Is this operation an insert or a delete?
+    pub kind: ListOpKind,
+
+    /// What content is being inserted or deleted, if known.
+    pub content: Option<SmartString>,
+
+    /// The units `loc` is measured in.
+    pub unit: PositionUnit,
+}
+
+impl HasLength for UnitTextOperation {
+    fn len(&self) -> usize {
+        self.loc.len()
+    }
+}
+
+impl UnitTextOperation {
+    /// Wrap a char based operation. No conversion is needed.
+    fn from_chars(op: TextOperation) -> Self {
+        UnitTextOperation { loc: op.loc, kind: op.kind, content: op.content, unit: PositionUnit::Chars }
+    }
+
+    pub fn range(&self) -> DTRange {
+        self.loc.span
+    }
+
+    #[inline]
+    pub fn start(&self) -> usize {
+        self.loc.span.start
+    }
+
+    #[inline]
+    pub fn end(&self) -> usize {
+        self.loc.span.end
+    }
+
+    pub fn content_as_str(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+}
+
+/// Convert an operation (in chars) to the passed units, then apply it to the rope. The rope is
+/// left unmodified if the operation can't be converted.
+fn convert_and_apply(rope: &mut JumpRopeBuf, op: TextOperation, unit: PositionUnit) -> Result<UnitTextOperation, UnitConversionError> {
+    let start = op.start();
+
+    let span = match op.kind {
+        ListOpKind::Ins => {
+            let content = op.content_as_str().ok_or(UnitConversionError::MissingContent)?;
+            let unit_start = unit.chars_to_pos(&rope.borrow(), start);
+            let unit_len = unit.len_of(content);
+            if op.loc.fwd {
+                rope.insert(start, content);
+            } else {
+                rope.insert(start, &reverse_str(content));
+            }
+            (unit_start..unit_start + unit_len).into()
+        }
+        ListOpKind::Del => {
+            let r = rope.borrow();
+            let unit_range = unit.chars_to_pos(&r, start)..unit.chars_to_pos(&r, op.end());
+            drop(r);
+            rope.remove(op.range().into());
+            unit_range.into()
+        }
+    };
+
+    Ok(UnitTextOperation {
+        loc: RangeRev { span, fwd: op.loc.fwd },
+        kind: op.kind,
+        content: op.content,
+        unit,
+    })
+}
+
+impl ListOpLog {
+    /// Iterate through the transformed operations from some version, like
+    /// [`iter_xf_operations_from`](ListOpLog::iter_xf_operations_from). But the positions in the
+    /// returned operations are measured in the requested units.
+    ///
+    /// If an operation can't be converted, the iterator yields an error and then stops.
+    pub fn iter_xf_operations_with_unit(&self, from: &[LV], merging: &[LV], unit: PositionUnit) -> impl Iterator<Item=Result<(DTRange, Option<UnitTextOperation>), UnitConversionError>> + '_ {
+        let rope = if unit == PositionUnit::Chars {
+            // We don't need to track the document's content.
+            Ok(JumpRopeBuf::new())
+        } else {
+            self.try_checkout(from).map(|branch| branch.content)
+        };
+        let (mut rope, checkout_err) = match rope {
+            Ok(rope) => (rope, None),
+            Err(e) => (JumpRopeBuf::new(), Some(Err(e.into()))),
+        };
+
+        let mut failed = checkout_err.is_some();
+        checkout_err.into_iter().chain(self.iter_xf_operations_from(from, merging).map_while(move |(lv, op)| {
+            if failed { return None; }
+            let op = op.map(|op| {
+                if unit == PositionUnit::Chars { Ok(UnitTextOperation::from_chars(op)) }
+                else { convert_and_apply(&mut rope, op, unit) }
+            }).transpose();
+            failed = op.is_err();
+            Some(op.map(|op| (lv, op)))
+        }))
+    }
+
+    /// Compute the operations which transform the document at version `a` into the document at
+    /// version `b`, like [`diff_versions`](ListOpLog::diff_versions). But the positions in the
+    /// returned operations are measured in the requested units.
+    pub fn diff_versions_with_unit(&self, a: &[LV], b: &[LV], unit: PositionUnit) -> Result<Vec<UnitTextOperation>, UnitConversionError> {
+        let ops = self.diff_versions(a, b)?;
+        if unit == PositionUnit::Chars {
+            return Ok(ops.into_iter().map(UnitTextOperation::from_chars).collect());
+        }
+
+        let mut rope = self.try_checkout(a)?.content;
+        ops.into_iter().map(|op| convert_and_apply(&mut rope, op, unit)).collect()
+    }
+
+    /// Add an insert operation to the oplog with the specified parents, with the insert position
+    /// measured in the requested units.
+    pub fn add_insert_at_with_unit(&mut self, agent: AgentId, parents: &[LV], pos: usize, ins_content: &str, unit: PositionUnit) -> Result<LV, UnitConversionError> {
+        let pos = if unit == PositionUnit::Chars { pos } else {
+            unit.pos_to_chars(&self.try_checkout(parents)?.content.borrow(), pos)?
+        };
+        Ok(self.add_insert_at(agent, parents, pos, ins_content))
+    }
+
+    /// Add a delete operation to the oplog with the specified parents, with the deleted range
+    /// measured in the requested units.
+    pub fn add_delete_at_with_unit(&mut self, agent: AgentId, parents: &[LV], loc: Range<usize>, unit: PositionUnit) -> Result<LV, UnitConversionError> {
+        let loc = if unit == PositionUnit::Chars { loc } else {
+            unit.range_to_chars(&self.try_checkout(parents)?.content.borrow(), loc)?
+        };
+        Ok(self.add_delete_at(agent, parents, loc))
+    }
+}
+
+impl ListBranch {
+    /// Returns the length of the document, measured in the requested units.
+    pub fn len_with_unit(&self, unit: PositionUnit) -> usize {
+        match unit {
+            PositionUnit::Chars => self.content.len_chars(),
+            PositionUnit::Utf8Bytes => self.content.len_bytes(),
+            PositionUnit::Utf16 => unit.chars_to_pos(&self.content.borrow(), self.content.len_chars()),
+        }
+    }
+
+    /// Insert content at the specified position, measured in the requested units.
+    pub fn insert_with_unit(&mut self, oplog: &mut ListOpLog, agent: AgentId, pos: usize, ins_content: &str, unit: PositionUnit) -> Result<LV, UnitConversionError> {
+        let char_pos = unit.pos_to_chars(&self.content.borrow(), pos)?;
+        Ok(self.insert(oplog, agent, char_pos, ins_content))
+    }
+
+    /// Delete the content in the specified range, measured in the requested units.
+    pub fn delete_with_unit(&mut self, oplog: &mut ListOpLog, agent: AgentId, del_span: Range<usize>, unit: PositionUnit) -> Result<LV, UnitConversionError> {
+        let char_span = unit.range_to_chars(&self.content.borrow(), del_span)?;
+        Ok(self.delete(oplog, agent, char_span))
+    }
+
+    /// Merge changes into the branch, like [`merge`](ListBranch::merge). Returns the operations
+    /// which were applied to the branch's content, with positions measured in the requested units.
+    ///
+    /// The branch is left unchanged if any of the operations can't be converted.
+    pub fn merge_with_unit(&mut self, oplog: &ListOpLog, merge_frontier: &[LV], unit: PositionUnit) -> Result<Vec<UnitTextOperation>, UnitConversionError> {
+        let ops: Vec<TextOperation> = oplog.iter_xf_operations_from(self.version.as_ref(), merge_frontier)
+            .filter_map(|(_, op)| op)
+            .collect();
+        if ops.iter().any(|op| op.kind == ListOpKind::Ins && op.content.is_none()) {
+            return Err(UnitConversionError::MissingContent);
+        }
+
+        let result = ops.into_iter()
+            .map(|op| convert_and_apply(&mut self.content, op, unit))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.version = oplog.cg.graph.version_union(self.version.as_ref(), merge_frontier);
+        Ok(result)
+    }
+}
+
+impl ListCRDT {
+    /// Insert content at the specified position, measured in the requested units.
+    pub fn insert_with_unit(&mut self, agent: AgentId, pos: usize, ins_content: &str, unit: PositionUnit) -> Result<LV, UnitConversionError> {
+        self.branch.insert_with_unit(&mut self.oplog, agent, pos, ins_content, unit)
+    }
+
+    /// Delete the content in the specified range, measured in the requested units.
+    pub fn delete_with_unit(&mut self, agent: AgentId, del_span: Range<usize>, unit: PositionUnit) -> Result<LV, UnitConversionError> {
+        self.branch.delete_with_unit(&mut self.oplog, agent, del_span, unit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rle::HasLength;
+    use crate::list::{ListBranch, ListCRDT, ListOpLog};
+    use crate::list::operation::{ListOpKind, MissingContentError, TextOperation};
+    use super::PositionUnit::*;
+    use super::UnitConversionError::*;
+    use super::{PositionUnit, UnitTextOperation};
+
+    fn ins(range: std::ops::Range<usize>, content: &str, unit: PositionUnit) -> UnitTextOperation {
+        UnitTextOperation { loc: range.into(), kind: ListOpKind::Ins, content: Some(content.into()), unit }
+    }
+
+    fn del(range: std::ops::Range<usize>, unit: PositionUnit) -> UnitTextOperation {
+        UnitTextOperation { loc: range.into(), kind: ListOpKind::Del, content: None, unit }
+    }
+
+    #[test]
+    fn insert_and_delete_with_units() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "a💖b");
+
+        assert_eq!(doc.branch.len_with_unit(Chars), 3);
+        assert_eq!(doc.branch.len_with_unit(Utf16), 4);
+        assert_eq!(doc.branch.len_with_unit(Utf8Bytes), 6);
+
+        doc.insert_with_unit(seph, 3, "x", Utf16).unwrap();
+        assert_eq!(doc.branch.content().to_string(), "a💖xb");
+        doc.insert_with_unit(seph, 6, "y", Utf8Bytes).unwrap();
+        assert_eq!(doc.branch.content().to_string(), "a💖xyb");
+        doc.delete_with_unit(seph, 1..5, Utf8Bytes).unwrap();
+        assert_eq!(doc.branch.content().to_string(), "axyb");
+        doc.delete_with_unit(seph, 3..4, Utf16).unwrap();
+        assert_eq!(doc.branch.content().to_string(), "axy");
+    }
+
+    #[test]
+    fn positions_must_be_on_char_boundary() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "💖");
+        assert_eq!(doc.insert_with_unit(seph, 2, "x", Utf8Bytes), Err(NotCharBoundary));
+        assert_eq!(doc.insert_with_unit(seph, 1, "x", Utf16), Err(NotCharBoundary));
+        assert_eq!(doc.delete_with_unit(seph, 0..3, Utf8Bytes), Err(NotCharBoundary));
+        assert_eq!(doc.branch.content().to_string(), "💖");
+    }
+
+    #[test]
+    fn positions_must_be_in_the_document() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "💖");
+        assert_eq!(doc.insert_with_unit(seph, 2, "x", Chars), Err(PastEnd));
+        assert_eq!(doc.insert_with_unit(seph, 3, "x", Utf16), Err(PastEnd));
+        assert_eq!(doc.delete_with_unit(seph, 0..5, Utf8Bytes), Err(PastEnd));
+
+        let base = doc.oplog.local_frontier();
+        assert_eq!(doc.oplog.add_insert_at_with_unit(seph, base.as_ref(), 5, "x", Utf8Bytes), Err(PastEnd));
+        assert_eq!(doc.branch.content().to_string(), "💖");
+    }
+
+    #[test]
+    fn missing_content_is_an_error() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_operations(seph, &[TextOperation {
+            loc: (0..3).into(), kind: ListOpKind::Ins, content: None
+        }]);
+        let tip = oplog.local_frontier();
+
+        assert_eq!(oplog.diff_versions_with_unit(&[], tip.as_ref(), Utf8Bytes), Err(MissingContent));
+        let ops: Vec<_> = oplog.iter_xf_operations_with_unit(&[], tip.as_ref(), Utf16).collect();
+        assert_eq!(ops, vec![Err(MissingContent)]);
+
+        let mut branch = ListBranch::new();
+        assert_eq!(branch.merge_with_unit(&oplog, tip.as_ref(), Utf16), Err(MissingContent));
+        assert!(branch.version.is_root());
+    }
+
+    #[test]
+    fn missing_content_at_parents_is_an_error() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_operations(seph, &[TextOperation {
+            loc: (0..3).into(), kind: ListOpKind::Ins, content: None
+        }]);
+        let base = oplog.local_frontier();
+        let v = oplog.add_insert(seph, 1, "x");
+
+        // We need the content at the passed version to convert positions.
+        let ops: Vec<_> = oplog.iter_xf_operations_with_unit(base.as_ref(), &[v], Utf16).collect();
+        assert_eq!(ops, vec![Err(MissingContent)]);
+        assert_eq!(oplog.add_insert_at_with_unit(seph, base.as_ref(), 1, "y", Utf8Bytes), Err(MissingContent));
+        assert_eq!(oplog.add_delete_at_with_unit(seph, &[v], 0..1, Utf16), Err(MissingContent));
+        assert_eq!(oplog.len(), 4);
+
+        // Chars don't need the document's content.
+        let ops: Vec<_> = oplog.iter_xf_operations_with_unit(base.as_ref(), &[v], Chars).collect();
+        assert_eq!(ops.len(), 1);
+        assert!(oplog.add_insert_at_with_unit(seph, base.as_ref(), 1, "y", Chars).is_ok());
+    }
+
+    #[test]
+    fn xf_ops_with_units() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "ü💖");
+        let a = oplog.add_insert_at_with_unit(seph, &[base], 3, "A", Utf16).unwrap();
+        let b = oplog.add_delete_at_with_unit(mike, &[base], 0..2, Utf8Bytes).unwrap();
+        assert_eq!(oplog.checkout_tip().content().to_string(), "💖A");
+
+        let ops: Vec<_> = oplog.iter_xf_operations_with_unit(&[base], &[a, b], Utf16)
+            .filter_map(|r| r.unwrap().1)
+            .collect();
+        assert_eq!(ops, vec![
+            ins(3..4, "A", Utf16),
+            del(0..1, Utf16),
+        ]);
+
+        let ops = oplog.diff_versions_with_unit(&[a], &[], Utf8Bytes).unwrap();
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].len(), 7);
+        assert_eq!(ops[0].range(), (0..7).into());
+        assert_eq!(ops[0].content_as_str(), Some("ü💖A"));
+
+        let mut branch = oplog.checkout(&[base]);
+        let ops = branch.merge_with_unit(&oplog, &[a, b], Utf8Bytes).unwrap();
+        assert_eq!(ops, vec![
+            ins(6..7, "A", Utf8Bytes),
+            del(0..2, Utf8Bytes),
+        ]);
+        assert_eq!(branch, oplog.checkout_tip());
+    }
+}
@@ -0,0 +1,404 @@
+//! Detecting and repairing reused agent IDs.
+//!
+//! Every operation is named by an (agent, seq) pair, and diamond types assumes each pair is only
+//! ever used once. If an agent reuses IDs (for example because two devices were configured with
+//! the same agent name), peers silently diverge - each peer keeps whichever version of the
+//! operation it saw first.
+//!
+//! When merging data, diamond types compares incoming operations against any operations it already
+//! has with the same IDs. If they differ, the merge fails with `ParseError::AgentIdReused`.
+//!
+//! The data can still be merged by treating the conflicting operations as if they came from a
+//! different agent. [`decode_and_add_renaming_collisions`](ListOpLog::decode_and_add_renaming_collisions)
+//! and [`merge_ops_renaming_collisions`](ListOpLog::merge_ops_renaming_collisions) do this
+//! automatically, renaming each conflicting agent's operations (from the first conflicting seq
+//! onwards) to a fresh agent name.
+
+use rle::HasLength;
+use smartstring::alias::String as SmartString;
+use crate::{AgentId, DTRange, Frontier, LV};
+use crate::encoding::parseerror::ParseError;
+use crate::list::ListOpLog;
+use crate::list::operation::ListOpKind;
+use crate::rev_range::RangeRev;
+use crate::rle::{KVPair, RleSpanHelpers, RleVec};
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
+use crate::causalgraph::agent_span::AgentSpan;
+use crate::list::metadata::add_metadata;
+use crate::unicount::split_at_char;
+
+/// Operations from `agent` with sequence numbers from `from_seq` onwards were renamed to a new
+/// agent, `renamed_to`. The operations' sequence numbers are shifted down by `from_seq`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AgentRename {
+    pub agent: SmartString,
+    pub from_seq: usize,
+    pub renamed_to: SmartString,
+}
+
+impl ListOpLog {
+    /// Compare the operations starting at `lv` (up to the length of `loc`) with the passed
+    /// operation. Returns the offset of the first operation which differs, or None if they all
+    /// match. Content is only compared if both sides have it.
+    pub(crate) fn find_op_mismatch(&self, lv: LV, kind: ListOpKind, loc: RangeRev, content: Option<&str>) -> Option<usize> {
+        let mut offset = 0;
+        let mut loc = loc;
+        let mut content = content;
+
+        for (KVPair(_, known), known_content) in self.iter_range_simple((lv..lv + loc.len()).into()) {
+            let len = known.len();
+            let rest = if len < loc.len() {
+                Some(loc.truncate_tagged_span(kind, len))
+            } else { None };
+
+            let content_here = content.map(|c| {
+                let (here, rest) = split_at_char(c, len);
+                content = Some(rest);
+                here
+            });
+
+            // The direction of single item operations is meaningless.
+            let same_op = known.kind == kind && known.loc.span == loc.span && (len == 1 || known.loc.fwd == loc.fwd);
+            let same_content = match (content_here, known_content) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            };
+
+            if !same_op || !same_content {
+                // Find the first item in this run which differs.
+                if len == 1 { return Some(offset); }
+                let mut known_loc = known.loc;
+                let mut known_content = known_content;
+                let mut content_here = content_here;
+                for i in 0..len {
+                    let next_known = known_loc.truncate_tagged_span(known.kind, 1);
+                    let next_loc = loc.truncate_tagged_span(kind, 1);
+                    let a = content_here.map(|c| {
+                        let (a, rest) = split_at_char(c, 1);
+                        content_here = Some(rest);
+                        a
+                    });
+                    let b = known_content.map(|c| {
+                        let (b, rest) = split_at_char(c, 1);
+                        known_content = Some(rest);
+                        b
+                    });
+
+                    if known.kind != kind || known_loc.span != loc.span || matches!((a, b), (Some(a), Some(b)) if a != b) {
+                        return Some(offset + i);
+                    }
+                    known_loc = next_known;
+                    loc = next_loc;
+                }
+                // Only the direction differed. Blame the whole run.
+                return Some(offset);
+            }
+
+            offset += len;
+            if let Some(rest) = rest { loc = rest; }
+        }
+        None
+    }
+
+    /// Compare the operations at self_lv and other_lv. Returns the number of operations from the
+    /// start of the range which match.
+    fn count_matching(&self, self_lv: LV, other: &ListOpLog, other_lv: LV, len: usize) -> usize {
+        let sorted_parents = |oplog: &ListOpLog, lv: LV| {
+            let mut parents: Vec<RemoteVersionOwned> = oplog.cg.graph.parents_at_version(lv).iter()
+                .map(|p| oplog.cg.agent_assignment.local_to_remote_version(*p).into())
+                .collect();
+            parents.sort_unstable_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+            parents
+        };
+
+        let mut offset = 0;
+        for (KVPair(_, op), content) in other.iter_range_simple((other_lv..other_lv + len).into()) {
+            for i in 0..op.len() {
+                if sorted_parents(self, self_lv + offset + i) != sorted_parents(other, other_lv + offset + i) {
+                    return offset + i;
+                }
+            }
+
+            if let Some(i) = self.find_op_mismatch(self_lv + offset, op.kind, op.loc, content) {
+                return offset + i;
+            }
+            offset += op.len();
+        }
+        len
+    }
+
+    /// Find agents which have operations in both self and other with the same (agent, seq) IDs,
+    /// but different parents or content. Returns the name of each such agent, along with the first
+    /// conflicting sequence number.
+    pub fn find_agent_collisions(&self, other: &ListOpLog) -> Vec<(SmartString, usize)> {
+        let mut result = vec![];
+
+        'agent: for other_client in other.cg.agent_assignment.client_data.iter() {
+            let Some(agent) = self.get_agent_id(&other_client.name) else { continue; };
+            let client = &self.cg.agent_assignment.client_data[agent as usize];
+
+            for KVPair(seq_start, other_lvs) in other_client.item_times.iter() {
+                let seq_end = seq_start + other_lvs.len();
+                let mut seq = *seq_start;
+                while seq < seq_end {
+                    let (entry, offset) = client.item_times.find_sparse(seq);
+                    match entry {
+                        Ok(entry) => {
+                            let len = (entry.end() - seq).min(seq_end - seq);
+                            let self_lv = entry.1.start + offset;
+                            let other_lv = other_lvs.start + (seq - seq_start);
+                            let matching = self.count_matching(self_lv, other, other_lv, len);
+                            if matching < len {
+                                result.push((other_client.name.clone(), seq + matching));
+                                continue 'agent;
+                            }
+                            seq += len;
+                        }
+                        Err(empty) => {
+                            seq = empty.end.min(seq_end);
+                        }
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Rename the operations from `agent` with sequence numbers `from_seq` onwards, so they're
+    /// owned by the agent `new_name` instead. The renamed operations' sequence numbers are shifted
+    /// down by `from_seq`. `new_name` must not have any operations.
+    ///
+    /// This changes the IDs of operations, so it should only be used to repair data which can't
+    /// otherwise be merged. Signatures over the renamed operations are discarded.
+    pub fn rename_agent_from(&mut self, agent: AgentId, from_seq: usize, new_name: &str) -> AgentId {
+        let new_agent = self.get_or_create_agent_id(new_name);
+        assert_ne!(agent, new_agent);
+        let aa = &mut self.cg.agent_assignment;
+        assert!(aa.client_data[new_agent as usize].item_times.is_empty(), "New agent already has operations");
+
+        // Split the agent's seq -> LV mapping.
+        let old_times = std::mem::take(&mut aa.client_data[agent as usize].item_times);
+        let mut kept = RleVec::new();
+        let mut moved = RleVec::new();
+        for KVPair(seq, lvs) in old_times.0 {
+            if seq >= from_seq {
+                moved.push(KVPair(seq - from_seq, lvs));
+            } else if seq + lvs.len() <= from_seq {
+                kept.push(KVPair(seq, lvs));
+            } else {
+                let split = lvs.start + (from_seq - seq);
+                kept.push(KVPair(seq, (lvs.start..split).into()));
+                moved.push(KVPair(0, (split..lvs.end).into()));
+            }
+        }
+        aa.client_data[agent as usize].item_times = kept;
+        aa.client_data[new_agent as usize].item_times = moved;
+
+        // And the LV -> (agent, seq) mapping.
+        let old_assignment = std::mem::take(&mut aa.client_with_localtime);
+        for KVPair(lv, span) in old_assignment.0 {
+            let s = span.seq_range;
+            if span.agent != agent || s.end <= from_seq {
+                aa.client_with_localtime.push(KVPair(lv, span));
+                continue;
+            }
+
+            if s.start < from_seq {
+                let keep_len = from_seq - s.start;
+                aa.client_with_localtime.push(KVPair(lv, AgentSpan { agent, seq_range: (s.start..from_seq).into() }));
+                aa.client_with_localtime.push(KVPair(lv + keep_len, AgentSpan {
+                    agent: new_agent,
+                    seq_range: (0..s.end - from_seq).into(),
+                }));
+            } else {
+                aa.client_with_localtime.push(KVPair(lv, AgentSpan {
+                    agent: new_agent,
+                    seq_range: (s.start - from_seq..s.end - from_seq).into(),
+                }));
+            }
+        }
+
+        // Metadata follows the operations.
+        let old_runs = std::mem::take(&mut self.metadata);
+        for ((a, start), run) in old_runs {
+            let range: DTRange = (start..run.seq_end).into();
+            if a != agent || range.end <= from_seq {
+                add_metadata(&mut self.metadata, a, range, &run.meta);
+                continue;
+            }
+            if range.start < from_seq {
+                add_metadata(&mut self.metadata, a, (range.start..from_seq).into(), &run.meta);
+            }
+            let moved: DTRange = (range.start.max(from_seq) - from_seq..range.end - from_seq).into();
+            add_metadata(&mut self.metadata, new_agent, moved, &run.meta);
+        }
+
+        self.signatures.retain(|sig| sig.agent != agent || sig.seq_range.end <= from_seq);
+
+        // The renamed operations (and everything after them) have new hashes.
+        self.cg.hash_cache.clear();
+
+        // Tags which point to the renamed operations are updated too.
+        let old_name = self.get_agent_name(agent).to_string();
+        let mut tags = std::mem::take(&mut self.tags);
+        tags.rename_versions(&old_name, from_seq, new_name);
+        self.tags = tags;
+
+        new_agent
+    }
+
+    /// Pick a name for a renamed agent which isn't used in self or other.
+    fn fresh_agent_name(&self, other: &ListOpLog, name: &str) -> SmartString {
+        (1..).map(|i| SmartString::from(format!("{name}~{i}")))
+            .find(|n| self.get_agent_id(n).is_none() && other.get_agent_id(n).is_none())
+            .unwrap()
+    }
+
+    /// Merge all operations from other into self, like [`merge_ops`](ListOpLog::merge_ops). But if
+    /// any of other's agents have reused IDs of operations in self, the conflicting operations
+    /// are renamed to a fresh agent first.
+    ///
+    /// Returns the list of renamed agents.
+    pub fn merge_ops_renaming_collisions(&mut self, other: &ListOpLog) -> Result<Vec<AgentRename>, ParseError> {
+        let collisions = self.find_agent_collisions(other);
+        if collisions.is_empty() {
+            self.merge_ops(other)?;
+            return Ok(vec![]);
+        }
+
+        let mut other = other.clone();
+        let mut renames = vec![];
+        for (name, from_seq) in collisions {
+            let renamed_to = self.fresh_agent_name(&other, &name);
+            let agent = other.get_agent_id(&name).unwrap();
+            other.rename_agent_from(agent, from_seq, &renamed_to);
+            renames.push(AgentRename { agent: name, from_seq, renamed_to });
+        }
+
+        self.merge_ops(&other)?;
+        Ok(renames)
+    }
+
+    /// Decode and merge the passed data, like [`decode_and_add`](ListOpLog::decode_and_add). If
+    /// the data contains operations which reuse IDs of operations in self, the conflicting
+    /// operations are renamed to a fresh agent, and then merged.
+    ///
+    /// Returns the version of the loaded data (after renaming), and the list of renamed agents.
+    ///
+    /// Renaming needs the data to be loadable by itself, so this only works for data which
+    /// contains its full history (not patches).
+    pub fn decode_and_add_renaming_collisions(&mut self, data: &[u8]) -> Result<(Frontier, Vec<AgentRename>), ParseError> {
+        match self.decode_and_add(data) {
+            Ok(v) => Ok((v, vec![])),
+            Err(ParseError::AgentIdReused { .. }) => {
+                let other = ListOpLog::load_from(data)?;
+                let renames = self.merge_ops_renaming_collisions(&other)?;
+
+                let mut version = other.cg.agent_assignment.local_to_remote_frontier_owned(other.cg.version.as_ref());
+                for rv in version.iter_mut() {
+                    if let Some(r) = renames.iter().find(|r| r.agent == rv.0 && rv.1 >= r.from_seq) {
+                        rv.0 = r.renamed_to.clone();
+                        rv.1 -= r.from_seq;
+                    }
+                }
+                Ok((self.cg.agent_assignment.remote_to_local_frontier(version.iter()), renames))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::ListOpLog;
+
+    /// Two oplogs where the agent "seph" has reused seq 2 onwards.
+    fn colliding_oplogs() -> (ListOpLog, ListOpLog) {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi");
+
+        let mut b = a.clone();
+        a.add_insert(seph, 2, " there");
+        b.add_insert(seph, 0, "oh ");
+        (a, b)
+    }
+
+    #[test]
+    fn reused_ids_are_detected() {
+        let (mut a, b) = colliding_oplogs();
+        let seph = a.get_agent_id("seph").unwrap();
+        let before = a.clone();
+
+        assert_eq!(a.decode_and_add(&b.encode(ENCODE_FULL)), Err(ParseError::AgentIdReused { agent: seph, seq: 2 }));
+        assert_eq!(a, before);
+
+        assert_eq!(a.find_agent_collisions(&b), vec![("seph".into(), 2)]);
+        assert_eq!(b.find_agent_collisions(&a), vec![("seph".into(), 2)]);
+
+        // Content changes with the same parents are detected too. ("h" matches.)
+        let mut c = ListOpLog::new();
+        let seph = c.get_or_create_agent_id("seph");
+        c.add_insert(seph, 0, "ho");
+        assert_eq!(a.find_agent_collisions(&c), vec![("seph".into(), 1)]);
+        assert_eq!(a.decode_and_add(&c.encode(ENCODE_FULL)), Err(ParseError::AgentIdReused { agent: seph, seq: 1 }));
+
+        // But matching data is fine.
+        let mut d = a.clone();
+        d.decode_and_add(&before.encode(ENCODE_FULL)).unwrap();
+        assert!(a.find_agent_collisions(&d).is_empty());
+    }
+
+    #[test]
+    fn reused_ids_are_detected_in_causal_graph() {
+        use crate::CausalGraph;
+        use crate::causalgraph::agent_span::AgentSpan;
+
+        let mut cg = CausalGraph::new();
+        let seph = cg.get_or_create_agent_id("seph");
+        let mike = cg.get_or_create_agent_id("mike");
+        cg.merge_and_assign(&[], AgentSpan { agent: seph, seq_range: (0..5).into() });
+        cg.merge_and_assign(&[], AgentSpan { agent: mike, seq_range: (0..2).into() });
+
+        // Fine - same parents.
+        assert_eq!(cg.try_merge_and_assign(&[], AgentSpan { agent: seph, seq_range: (0..3).into() }), Ok((7..7).into()));
+        assert_eq!(cg.try_merge_and_assign(&[2], AgentSpan { agent: seph, seq_range: (3..7).into() }), Ok((7..9).into()));
+
+        assert_eq!(cg.try_merge_and_assign(&[6], AgentSpan { agent: seph, seq_range: (0..1).into() }),
+            Err(ParseError::AgentIdReused { agent: seph, seq: 0 }));
+        assert_eq!(cg.try_merge_and_assign(&[6], AgentSpan { agent: seph, seq_range: (5..8).into() }),
+            Err(ParseError::AgentIdReused { agent: seph, seq: 5 }));
+        assert_eq!(cg.len(), 9);
+    }
+
+    #[test]
+    fn collisions_can_be_repaired() {
+        let (mut a, b) = colliding_oplogs();
+        let (v, renames) = a.decode_and_add_renaming_collisions(&b.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].agent, "seph");
+        assert_eq!(renames[0].from_seq, 2);
+        assert_eq!(renames[0].renamed_to, "seph~1");
+        let v = a.cg.agent_assignment.local_to_remote_frontier_owned(v.as_ref());
+        assert_eq!(v.len(), 1);
+        assert_eq!((v[0].0.as_str(), v[0].1), ("seph~1", 2));
+        a.dbg_check(true);
+
+        // Both edits are kept.
+        let content = a.checkout_tip().content().to_string();
+        assert!(content == "oh hi there" || content == "hi thereoh ", "{content}");
+        assert_eq!(a.len(), 11);
+
+        // And merging again doesn't find any more collisions.
+        let mut b2 = b.clone();
+        let seph = b2.get_agent_id("seph").unwrap();
+        b2.rename_agent_from(seph, 2, "seph~1");
+        b2.dbg_check(true);
+        assert!(a.find_agent_collisions(&b2).is_empty());
+        a.merge_ops(&b2).unwrap();
+        assert_eq!(a.len(), 11);
+    }
+}
@@ -0,0 +1,460 @@
+//! This file contains code to revert or cherry-pick a chosen range of operations.
+//!
+//! Both work by replaying the transformed operations to find out what the range did to the
+//! document, then generating operations which undo (or redo) that change. These operations make
+//! sense at some earlier version, and the normal transform code moves them to the version where we
+//! want them.
+//!
+//! To transform operations which aren't in the oplog yet, we add them to a `Scratch` oplog. This
+//! only contains the operations the transform needs to look at (the operations since the version
+//! the new operations were made at), so its size depends on how far back the reverted range is,
+//! not on the size of the whole oplog.
+
+use std::cmp::Ordering;
+use jumprope::JumpRopeBuf;
+use rle::{AppendRle, HasLength};
+use crate::causalgraph::agent_assignment::{AgentAssignment, ClientData};
+use crate::causalgraph::agent_span::AgentSpan;
+use crate::causalgraph::graph::Graph;
+use crate::list::ListOpLog;
+use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
+use crate::list::operation::{ListOpKind, MissingContentError, TextOperation};
+use crate::listmerge::merge::{reverse_str, TransformedOpsIter};
+use crate::listmerge::merge::TransformedResult::{BaseMoved, DeleteAlreadyHappened};
+use crate::rle::{KVPair, RleVec};
+use crate::{AgentId, DTRange, Frontier, LV};
+
+/// Apply a (transformed) operation to the document content. Returns the operation which undoes it.
+fn apply_and_invert(content: &mut JumpRopeBuf, op: TextOperation) -> Result<TextOperation, MissingContentError> {
+    let range = op.range();
+    match op.kind {
+        ListOpKind::Ins => {
+            let s = op.content_as_str().ok_or(MissingContentError)?;
+            if op.loc.fwd {
+                content.insert(range.start, s);
+            } else {
+                content.insert(range.start, &reverse_str(s));
+            }
+            Ok(TextOperation::new_delete(range.into()))
+        }
+        ListOpKind::Del => {
+            let deleted: String = content.borrow().slice_chars(range.into()).collect();
+            content.remove(range.into());
+            Ok(TextOperation::new_insert(range.start, &deleted))
+        }
+    }
+}
+
+/// A truncated copy of an oplog, used to transform some new operations without adding them to the
+/// real oplog.
+///
+/// This contains the operations in the conflict zone between the new operations' parents and some
+/// other version, followed by the new operations. Operations keep their LVs from the real oplog.
+/// Anything before the conflict zone is left out - the transform code treats the document at the
+/// start of the conflict zone as placeholder content.
+struct Scratch {
+    graph: Graph,
+    aa: AgentAssignment,
+    ctx: ListOperationCtx,
+    ops: RleVec<KVPair<ListOpMetrics>>,
+
+    /// The version `other` in the subgraph.
+    other: Frontier,
+    /// The last LV of the new operations.
+    v: LV,
+}
+
+impl Scratch {
+    fn new(oplog: &ListOpLog, agent: AgentId, parents: &[LV], new_ops: &[TextOperation], other: &[LV]) -> Self {
+        let cg = &oplog.cg;
+        let zone = cg.graph.find_conflicting_simple(parents, other).rev_spans;
+        let frontier = cg.graph.version_union(parents, other);
+        let (mut graph, _) = cg.graph.subgraph_raw(zone.iter().copied(), frontier.as_ref());
+
+        // Agent IDs are kept, because they're stored in the operations. But we only need sequence
+        // numbers for the operations in the scratch oplog.
+        let mut aa = AgentAssignment {
+            client_with_localtime: RleVec::new(),
+            client_data: cg.agent_assignment.client_data.iter()
+                .map(|c| ClientData { name: c.name.clone(), item_times: RleVec::new() })
+                .collect(),
+        };
+        let mut ctx = ListOperationCtx::new();
+        let mut ops = RleVec::new();
+
+        for &span in zone.iter().rev() {
+            for e in cg.agent_assignment.client_with_localtime.iter_range(span) {
+                aa.client_with_localtime.push(e);
+            }
+            for KVPair(lv, mut op) in oplog.operations.iter_range_ctx(span, &oplog.operation_ctx) {
+                op.content_pos = op.get_content(&oplog.operation_ctx).map(|c| ctx.push_str(op.kind, c));
+                ops.push(KVPair(lv, op));
+            }
+        }
+
+        let start = oplog.len();
+        let mut next = start;
+        for op in new_ops {
+            let content_pos = op.content_as_str().map(|c| ctx.push_str(op.kind, c));
+            ops.push(KVPair(next, ListOpMetrics { loc: op.loc, kind: op.kind, content_pos }));
+            next += op.len();
+        }
+        let seq = cg.agent_assignment.client_data[agent as usize].get_next_seq();
+        aa.client_with_localtime.push(KVPair(start, AgentSpan {
+            agent,
+            seq_range: (seq..seq + next - start).into(),
+        }));
+        let parents = cg.graph.project_onto_subgraph_raw(zone.iter().copied(), parents);
+        graph.push(parents.as_ref(), (start..next).into());
+
+        let other = cg.graph.project_onto_subgraph_raw(zone.iter().copied(), other);
+        Self { graph, aa, ctx, ops, other, v: next - 1 }
+    }
+
+    /// Returns the transformed operations from `from` to `merging`, which must both be versions
+    /// within the scratch oplog.
+    fn xf_ops(&self, from: &[LV], merging: &[LV]) -> Vec<TextOperation> {
+        TransformedOpsIter::new(&self.graph, &self.aa, &self.ctx, &self.ops, from, merging)
+            .filter_map(|(_, mut op, xf)| match xf {
+                BaseMoved(base) => {
+                    op.loc.span = (base..base + op.len()).into();
+                    let content = op.get_content(&self.ctx);
+                    Some((op, content).into())
+                }
+                DeleteAlreadyHappened => None,
+            })
+            .collect()
+    }
+
+    fn merged(&self) -> Frontier {
+        self.graph.version_union(self.other.as_ref(), &[self.v])
+    }
+}
+
+impl ListOpLog {
+    /// Apply the transformed operations from `from` to `to` to `content`, which must contain the
+    /// document at `from`. Returns the operations which undo them, in the order they should be
+    /// applied.
+    fn apply_xf_ops(&self, content: &mut JumpRopeBuf, from: &[LV], to: &[LV]) -> Result<Vec<TextOperation>, MissingContentError> {
+        let mut undo = vec![];
+        for (_, op) in self.iter_xf_operations_from(from, to) {
+            if let Some(op) = op {
+                undo.push(apply_and_invert(content, op)?);
+            }
+        }
+        undo.reverse();
+        Ok(undo)
+    }
+
+    /// Returns the version containing every operation in the oplog except the operations in
+    /// `range` and everything which depends on them.
+    fn version_without(&self, range: DTRange) -> Frontier {
+        let mut kept: Vec<DTRange> = vec![];
+        let mut removed: Vec<DTRange> = vec![];
+        let is_removed = |removed: &[DTRange], lv: LV| {
+            removed.binary_search_by(|r| {
+                if r.end <= lv { Ordering::Less } else if r.start > lv { Ordering::Greater } else { Ordering::Equal }
+            }).is_ok()
+        };
+
+        for e in self.iter_history() {
+            // Nothing before the range can depend on it.
+            let start = e.span.start.max(range.start);
+            if start > e.span.start {
+                kept.push_rle((e.span.start..start.min(e.span.end)).into());
+            }
+            if start >= e.span.end { continue; }
+
+            // Each item in an entry depends on the item before it. So if the first item we're
+            // looking at is removed, so is the rest of the entry.
+            let parent_removed = if start > e.span.start {
+                is_removed(&removed, start - 1)
+            } else {
+                e.parents.iter().any(|p| is_removed(&removed, *p))
+            };
+            let span: DTRange = (start..e.span.end).into();
+            if start < range.end || parent_removed {
+                removed.push_rle(span);
+            } else {
+                kept.push_rle(span);
+            }
+        }
+
+        self.cg.graph.project_onto_subgraph(&kept, self.cg.version.as_ref())
+    }
+
+    /// Returns the version directly before the operations in `range`. That is, the version
+    /// containing everything the range depends on, but none of the range itself.
+    fn version_before(&self, range: DTRange) -> Frontier {
+        let parents: Vec<LV> = self.iter_history_range(range)
+            .flat_map(|e| e.parents.into_iter())
+            .filter(|p| !range.contains(*p))
+            .collect();
+        self.cg.graph.find_dominators(&parents)
+    }
+
+    /// Returns `base` with all the operations in `range` added.
+    fn version_with(&self, base: &[LV], range: DTRange) -> Frontier {
+        let mut version: Vec<LV> = self.iter_history_range(range)
+            .map(|e| e.span.last())
+            .collect();
+        version.extend_from_slice(base);
+        self.cg.graph.find_dominators(&version)
+    }
+
+    fn add_operations_at_or_noop(&mut self, agent: AgentId, parents: &[LV], ops: &[TextOperation]) -> Frontier {
+        if ops.is_empty() {
+            parents.into()
+        } else {
+            Frontier::new_1(self.add_operations_at(agent, parents, ops))
+        }
+    }
+
+    /// Add new operations at the current tip of the oplog which undo the effect of all the
+    /// operations in the specified LV range. The new operations are attributed to the named agent.
+    ///
+    /// - Characters inserted by the range are deleted (if they still exist)
+    /// - Characters deleted by the range are reinserted, unless they were also deleted by some
+    ///   other concurrent operation.
+    ///
+    /// Reinserted characters are new items. Returns an error if the oplog doesn't contain the
+    /// inserted content needed to work out what the range deleted.
+    ///
+    /// Returns the new tip version of the oplog.
+    pub fn revert(&mut self, agent: AgentId, range: DTRange) -> Result<Frontier, MissingContentError> {
+        let tip = self.cg.version.clone();
+        if range.is_empty() { return Ok(tip); }
+
+        // Replay the range on top of everything which doesn't depend on it. Parts of deletes which
+        // were also done concurrently are skipped by the transform, so they won't be undone.
+        let before = self.version_without(range);
+        let after = self.version_with(before.as_ref(), range);
+        let mut content = self.try_checkout(before.as_ref())?.content;
+        let undo = self.apply_xf_ops(&mut content, before.as_ref(), after.as_ref())?;
+        if undo.is_empty() { return Ok(tip); }
+
+        // The undo operations make sense at `after`. Add them there in a scratch oplog, then
+        // transform them to the tip.
+        let scratch = Scratch::new(self, agent, after.as_ref(), &undo, tip.as_ref());
+        let ops = scratch.xf_ops(scratch.other.as_ref(), scratch.merged().as_ref());
+
+        Ok(self.add_operations_at_or_noop(agent, tip.as_ref(), &ops))
+    }
+
+    /// Replay the changes made by the operations in the specified LV range on top of some other
+    /// version of the document. The new operations are attributed to the named agent, and use
+    /// `onto` as their parents.
+    ///
+    /// Only the effect of the operations in the range is copied. Other changes (including changes
+    /// the range itself depends on) are not. Returns an error if the oplog doesn't contain the
+    /// inserted content needed to work out what those other changes did.
+    ///
+    /// Returns the version containing `onto` and the cherry-picked changes.
+    pub fn cherry_pick(&mut self, agent: AgentId, range: DTRange, onto: &[LV]) -> Result<Frontier, MissingContentError> {
+        if range.is_empty() { return Ok(onto.into()); }
+
+        // The range's operations make sense on top of `base`. But base may contain changes which
+        // aren't in `onto`, so we undo those changes first.
+        let base = self.cg.graph.version_union(onto, self.version_before(range).as_ref());
+        let mut content = self.try_checkout(onto)?.content;
+        let undo = self.apply_xf_ops(&mut content, onto, base.as_ref())?;
+        let picked = self.version_with(base.as_ref(), range);
+
+        let ops: Vec<TextOperation> = if undo.is_empty() {
+            self.iter_xf_operations_from(base.as_ref(), picked.as_ref())
+                .filter_map(|(_, op)| op)
+                .collect()
+        } else {
+            // Add the undo operations in a scratch oplog, then transform the range on top of them.
+            // The resulting operations apply to a document with the same content as `onto`.
+            let scratch = Scratch::new(self, agent, base.as_ref(), &undo, picked.as_ref());
+            scratch.xf_ops(&[scratch.v], scratch.merged().as_ref())
+        };
+
+        Ok(self.add_operations_at_or_noop(agent, onto, &ops))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::ListOpLog;
+    use crate::list::operation::{ListOpKind, MissingContentError, TextOperation};
+
+    fn content_at_tip(oplog: &ListOpLog) -> String {
+        oplog.checkout_tip().content().to_string()
+    }
+
+    #[test]
+    fn revert_insert() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hello world");
+        let start = oplog.len();
+        oplog.add_insert(mike, 5, " there");
+        let end = oplog.len();
+        oplog.add_insert(seph, 0, ">> ");
+        assert_eq!(content_at_tip(&oplog), ">> hello there world");
+
+        oplog.revert(seph, (start..end).into()).unwrap();
+        assert_eq!(content_at_tip(&oplog), ">> hello world");
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn revert_delete() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "abcdef");
+        let start = oplog.len();
+        oplog.add_delete_without_content(mike, 1..4);
+        let end = oplog.len();
+        oplog.add_insert(seph, 1, "X");
+        assert_eq!(content_at_tip(&oplog), "aXef");
+
+        oplog.revert(seph, (start..end).into()).unwrap();
+        assert_eq!(content_at_tip(&oplog), "aXbcdef");
+    }
+
+    #[test]
+    fn revert_keeps_concurrent_deletes() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "abcdef");
+        let start = oplog.len();
+        oplog.add_delete_at(mike, &[base], 1..4);
+        let end = oplog.len();
+        // Seph concurrently deletes "cd".
+        oplog.add_delete_at(seph, &[base], 2..4);
+        assert_eq!(content_at_tip(&oplog), "aef");
+
+        oplog.revert(seph, (start..end).into()).unwrap();
+        assert_eq!(content_at_tip(&oplog), "abef");
+    }
+
+    #[test]
+    fn revert_edits_to_own_inserts() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "abc");
+        let start = oplog.len();
+        oplog.add_insert(mike, 1, "XYZ");
+        oplog.add_delete_without_content(mike, 2..5);
+        let end = oplog.len();
+        assert_eq!(content_at_tip(&oplog), "aXc");
+
+        oplog.revert(seph, (start..end).into()).unwrap();
+        assert_eq!(content_at_tip(&oplog), "abc");
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn revert_nothing_is_noop() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "abc");
+        let len = oplog.len();
+        let v = oplog.revert(seph, (len..len).into()).unwrap();
+        assert_eq!(v, oplog.local_frontier());
+        assert_eq!(oplog.len(), len);
+    }
+
+    #[test]
+    fn cherry_pick_onto_branch() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "one two three");
+
+        // Mike's branch: some setup edit, then the edit we want to cherry-pick.
+        let m1 = oplog.add_insert_at(mike, &[base], 0, "[draft] ");
+        let start = oplog.len();
+        let m2 = oplog.add_delete_at(mike, &[m1], 12..16); // Delete "two "
+        let m2 = oplog.add_insert_at(mike, &[m2], 12, "2 ");
+        let end = oplog.len();
+        assert_eq!(oplog.checkout(&[m2]).content().to_string(), "[draft] one 2 three");
+
+        // Seph's branch.
+        let s1 = oplog.add_insert_at(seph, &[base], 13, "!");
+
+        let v = oplog.cherry_pick(seph, (start..end).into(), &[s1]).unwrap();
+        assert_eq!(oplog.checkout(v.as_ref()).content().to_string(), "one 2 three!");
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn revert_needs_inserted_content() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_operations(seph, &[TextOperation {
+            loc: (0..3).into(), kind: ListOpKind::Ins, content: None
+        }]);
+        let start = oplog.len();
+        oplog.add_delete_without_content(seph, 1..2);
+        let end = oplog.len();
+
+        assert_eq!(oplog.revert(seph, (start..end).into()), Err(MissingContentError));
+        assert_eq!(oplog.len(), end);
+    }
+
+    #[test]
+    fn scratch_matches_full_oplog() {
+        use rand::prelude::*;
+        use crate::Frontier;
+        use crate::list_fuzzer_tools::random_str;
+        use super::Scratch;
+
+        let mut rng = SmallRng::seed_from_u64(42);
+        for _ in 0..30 {
+            let mut oplog = ListOpLog::new();
+            let agents = [oplog.get_or_create_agent_id("seph"), oplog.get_or_create_agent_id("mike")];
+            let mut versions = vec![Frontier::root()];
+
+            for _ in 0..30 {
+                let v = versions.choose(&mut rng).unwrap().clone();
+                let mut branch = oplog.checkout(v.as_ref());
+                let agent = *agents.choose(&mut rng).unwrap();
+                let len = branch.len();
+
+                if len == 0 || rng.gen_bool(0.6) {
+                    let content = random_str(rng.gen_range(1..4), &mut rng);
+                    branch.insert(&mut oplog, agent, rng.gen_range(0..=len), &content);
+                } else {
+                    let start = rng.gen_range(0..len);
+                    let end = rng.gen_range(start + 1..=len.min(start + 4));
+                    branch.delete(&mut oplog, agent, start..end);
+                }
+                versions.push(branch.local_frontier());
+            }
+
+            for _ in 0..10 {
+                // Some new operations, made at a random version.
+                let parents = versions.choose(&mut rng).unwrap();
+                let other = versions.choose(&mut rng).unwrap();
+                let len = oplog.checkout(parents.as_ref()).len();
+                let pos = rng.gen_range(0..=len);
+                let mut ops = vec![TextOperation::new_insert(pos, &random_str(3, &mut rng))];
+                if len > 0 {
+                    let start = rng.gen_range(0..len);
+                    ops.push(TextOperation::new_delete(start..len.min(start + 3)));
+                }
+                let agent = *agents.choose(&mut rng).unwrap();
+
+                let mut full = oplog.clone();
+                let v = full.add_operations_at(agent, parents.as_ref(), &ops);
+                let merged = full.cg.graph.version_union(other.as_ref(), &[v]);
+                let expect_from_other: Vec<TextOperation> = full.iter_xf_operations_from(other.as_ref(), merged.as_ref())
+                    .filter_map(|(_, op)| op).collect();
+                let expect_from_v: Vec<TextOperation> = full.iter_xf_operations_from(&[v], merged.as_ref())
+                    .filter_map(|(_, op)| op).collect();
+
+                let scratch = Scratch::new(&oplog, agent, parents.as_ref(), &ops, other.as_ref());
+                assert_eq!(scratch.xf_ops(scratch.other.as_ref(), scratch.merged().as_ref()), expect_from_other);
+                assert_eq!(scratch.xf_ops(&[scratch.v], scratch.merged().as_ref()), expect_from_v);
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! This file contains a simple text diff implementation, used to replace the content of a branch
+//! with a new snapshot of the document.
+//!
+//! This is useful when syncing with something which only gives us the whole document (like a file
+//! on disk). We diff the old and new content, then apply the minimal set of inserts and deletes.
+//!
+//! The diff uses Myers' O(ND) algorithm, in its linear space (bisecting) form.
+
+use std::ops::Range;
+use crate::list::{ListBranch, ListCRDT, ListOpLog};
+use crate::list::list::apply_local_operations;
+use crate::list::operation::TextOperation;
+use crate::{AgentId, LV, LVKey, OpLog};
+
+/// The unit of change when diffing old and new document content in
+/// [`set_content_with`](ListBranch::set_content_with).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum DiffGranularity {
+    /// Diff individual characters. This generates the smallest changes.
+    #[default]
+    Chars,
+
+    /// Diff whole lines. Any modified line is deleted and reinserted. This is much faster than
+    /// diffing characters in large documents, and it often better matches what the user intended.
+    Lines,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum DiffTag { Equal, Delete, Insert }
+
+fn push_run(out: &mut Vec<(DiffTag, usize)>, tag: DiffTag, len: usize) {
+    if len == 0 { return; }
+    if let Some((last_tag, last_len)) = out.last_mut() {
+        if *last_tag == tag {
+            *last_len += len;
+            return;
+        }
+    }
+    out.push((tag, len));
+}
+
+/// Diff a and b, appending runs of (tag, number of items) to out.
+fn diff_into<T: PartialEq>(a: &[T], b: &[T], out: &mut Vec<(DiffTag, usize)>) {
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = a.iter().rev().zip(b.iter().rev()).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+
+    push_run(out, DiffTag::Equal, prefix);
+
+    if a.is_empty() || b.is_empty() {
+        push_run(out, DiffTag::Delete, a.len());
+        push_run(out, DiffTag::Insert, b.len());
+    } else {
+        match bisect(a, b) {
+            Some((x, y)) if (x, y) != (0, 0) && (x, y) != (a.len(), b.len()) => {
+                diff_into(&a[..x], &b[..y], out);
+                diff_into(&a[x..], &b[y..], out);
+            }
+            _ => {
+                // Nothing in common.
+                push_run(out, DiffTag::Delete, a.len());
+                push_run(out, DiffTag::Insert, b.len());
+            }
+        }
+    }
+
+    push_run(out, DiffTag::Equal, suffix);
+}
+
+/// Find the middle snake of the optimal path through the edit graph of a and b, by searching
+/// forwards from the start and backwards from the end at the same time. Returns the point at which
+/// the problem should be split in two.
+fn bisect<T: PartialEq>(a: &[T], b: &[T]) -> Option<(usize, usize)> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d;
+    let v_len = 2 * max_d + 2;
+
+    // v[k] is the furthest x reached along diagonal k (where k = x - y), or -1.
+    let mut v1 = vec![-1isize; v_len as usize];
+    let mut v2 = vec![-1isize; v_len as usize];
+    v1[(offset + 1) as usize] = 0;
+    v2[(offset + 1) as usize] = 0;
+
+    let delta = n - m;
+    // If the total number of items is odd, the forward path will collide with the reverse path.
+    let front = delta % 2 != 0;
+
+    // Offsets to skip diagonals which have run off the edge of the edit graph.
+    let (mut k1start, mut k1end, mut k2start, mut k2end) = (0, 0, 0, 0);
+
+    for d in 0..max_d {
+        // Walk the forward path one step.
+        let mut k1 = -d + k1start;
+        while k1 <= d - k1end {
+            let k1_offset = (offset + k1) as usize;
+            let mut x1 = if k1 == -d || (k1 != d && v1[k1_offset - 1] < v1[k1_offset + 1]) {
+                v1[k1_offset + 1]
+            } else {
+                v1[k1_offset - 1] + 1
+            };
+            let mut y1 = x1 - k1;
+            while x1 < n && y1 < m && a[x1 as usize] == b[y1 as usize] {
+                x1 += 1;
+                y1 += 1;
+            }
+            v1[k1_offset] = x1;
+
+            if x1 > n {
+                // Ran off the right of the graph.
+                k1end += 2;
+            } else if y1 > m {
+                // Ran off the bottom of the graph.
+                k1start += 2;
+            } else if front {
+                let k2_offset = offset + delta - k1;
+                if k2_offset >= 0 && k2_offset < v_len && v2[k2_offset as usize] != -1 {
+                    // Mirror x2 onto the top-left coordinate system.
+                    let x2 = n - v2[k2_offset as usize];
+                    if x1 >= x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+            k1 += 2;
+        }
+
+        // Walk the reverse path one step.
+        let mut k2 = -d + k2start;
+        while k2 <= d - k2end {
+            let k2_offset = (offset + k2) as usize;
+            let mut x2 = if k2 == -d || (k2 != d && v2[k2_offset - 1] < v2[k2_offset + 1]) {
+                v2[k2_offset + 1]
+            } else {
+                v2[k2_offset - 1] + 1
+            };
+            let mut y2 = x2 - k2;
+            while x2 < n && y2 < m && a[(n - x2 - 1) as usize] == b[(m - y2 - 1) as usize] {
+                x2 += 1;
+                y2 += 1;
+            }
+            v2[k2_offset] = x2;
+
+            if x2 > n {
+                k2end += 2;
+            } else if y2 > m {
+                k2start += 2;
+            } else if !front {
+                let k1_offset = offset + delta - k2;
+                if k1_offset >= 0 && k1_offset < v_len && v1[k1_offset as usize] != -1 {
+                    let x1 = v1[k1_offset as usize];
+                    let y1 = offset + x1 - k1_offset;
+                    if x1 >= n - x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+            k2 += 2;
+        }
+    }
+
+    None
+}
+
+/// Split a string into tokens, based on the requested granularity. Each token is a slice of the
+/// original string.
+fn tokenize(s: &str, granularity: DiffGranularity) -> Vec<&str> {
+    match granularity {
+        DiffGranularity::Chars => s.char_indices()
+            .map(|(i, c)| &s[i..i + c.len_utf8()])
+            .collect(),
+        DiffGranularity::Lines => s.split_inclusive('\n').collect(),
+    }
+}
+
+/// Byte range in s covered by the tokens in the specified range.
+fn token_bytes(s: &str, tokens: &[&str], range: Range<usize>) -> Range<usize> {
+    if range.is_empty() { return 0..0; }
+    let start = tokens[range.start].as_ptr() as usize - s.as_ptr() as usize;
+    let last = tokens[range.end - 1];
+    let end = last.as_ptr() as usize - s.as_ptr() as usize + last.len();
+    start..end
+}
+
+/// Compute the list of operations which will transform `old` into `new`, in order. The operations
+/// include the deleted content.
+pub(crate) fn diff_text_ops(old: &str, new: &str, granularity: DiffGranularity) -> Vec<TextOperation> {
+    let a = tokenize(old, granularity);
+    let b = tokenize(new, granularity);
+    let mut runs = vec![];
+    diff_into(&a, &b, &mut runs);
+
+    let mut result = vec![];
+    // Position (in chars) in the document as it is being modified.
+    let mut pos = 0;
+    let (mut i, mut j) = (0, 0);
+
+    for (tag, len) in runs {
+        match tag {
+            DiffTag::Equal => {
+                let bytes = token_bytes(old, &a, i..i + len);
+                pos += old[bytes].chars().count();
+                i += len;
+                j += len;
+            }
+            DiffTag::Delete => {
+                let bytes = token_bytes(old, &a, i..i + len);
+                let content = &old[bytes];
+                let del_len = content.chars().count();
+                result.push(TextOperation::new_delete_with_content_range(pos..pos + del_len, content.into()));
+                i += len;
+            }
+            DiffTag::Insert => {
+                let bytes = token_bytes(new, &b, j..j + len);
+                let content = &new[bytes];
+                result.push(TextOperation::new_insert(pos, content));
+                pos += content.chars().count();
+                j += len;
+            }
+        }
+    }
+
+    result
+}
+
+impl ListBranch {
+    /// Replace the content of the branch with `new_content`. This diffs the current content with
+    /// the new content (character by character), and then applies the resulting changes to the
+    /// branch and the oplog as local edits by the named agent.
+    ///
+    /// The new operations use the branch's current version as their parents, so the branch does
+    /// not need to be at the tip of the oplog.
+    ///
+    /// Returns the LV of the last change made, or None if the content was unchanged.
+    pub fn set_content(&mut self, oplog: &mut ListOpLog, agent: AgentId, new_content: &str) -> Option<LV> {
+        self.set_content_with(oplog, agent, new_content, DiffGranularity::Chars)
+    }
+
+    /// Replace the content of the branch with `new_content`, diffing the content at the requested
+    /// granularity. See [`set_content`](ListBranch::set_content) for details.
+    pub fn set_content_with(&mut self, oplog: &mut ListOpLog, agent: AgentId, new_content: &str, granularity: DiffGranularity) -> Option<LV> {
+        let old = self.content.to_string();
+        let ops = diff_text_ops(&old, new_content, granularity);
+        if ops.is_empty() { return None; }
+        Some(apply_local_operations(oplog, self, agent, &ops))
+    }
+}
+
+impl OpLog {
+    /// Replace the content of a text CRDT with `new_content`, by diffing it with `old_content`.
+    /// The new operations have the named parents, and `old_content` must be the content of the
+    /// text CRDT at that version.
+    ///
+    /// Returns the LV of the last change made, or None if the content was unchanged.
+    pub fn set_text_content_at(&mut self, agent: AgentId, crdt: LVKey, parents: &[LV], old_content: &str, new_content: &str, granularity: DiffGranularity) -> Option<LV> {
+        let mut last = None;
+        for op in diff_text_ops(old_content, new_content, granularity) {
+            let v = match last {
+                None => self.local_text_op_at(agent, crdt, parents, op),
+                Some(last) => self.local_text_op_at(agent, crdt, &[last], op),
+            };
+            last = Some(v.last());
+        }
+        last
+    }
+}
+
+impl ListCRDT {
+    /// Replace the content of the document with `new_content`. See
+    /// [`ListBranch::set_content`] for details.
+    pub fn set_content(&mut self, agent: AgentId, new_content: &str) -> Option<LV> {
+        self.branch.set_content(&mut self.oplog, agent, new_content)
+    }
+
+    /// Replace the content of the document with `new_content`, diffing the content at the
+    /// requested granularity. See [`ListBranch::set_content`] for details.
+    pub fn set_content_with(&mut self, agent: AgentId, new_content: &str, granularity: DiffGranularity) -> Option<LV> {
+        self.branch.set_content_with(&mut self.oplog, agent, new_content, granularity)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use rle::HasLength;
+    use crate::list::ListCRDT;
+    use crate::list::operation::TextOperation;
+    use crate::list_fuzzer_tools::random_str;
+    use crate::{CRDTKind, CreateValue, ROOT_CRDT_ID};
+    use super::*;
+
+    fn check_diff(old: &str, new: &str, granularity: DiffGranularity) -> Vec<TextOperation> {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        if !old.is_empty() { doc.insert(seph, 0, old); }
+        let v = doc.set_content_with(seph, new, granularity);
+        assert_eq!(doc.branch.content().to_string(), new);
+        assert_eq!(v.is_none(), old == new);
+        assert_eq!(doc.oplog.checkout_tip().content().to_string(), new);
+        diff_text_ops(old, new, granularity)
+    }
+
+    #[test]
+    fn simple_diffs() {
+        assert!(check_diff("", "", DiffGranularity::Chars).is_empty());
+        assert!(check_diff("hi there", "hi there", DiffGranularity::Chars).is_empty());
+        assert_eq!(check_diff("", "hi", DiffGranularity::Chars), vec![TextOperation::new_insert(0, "hi")]);
+        assert_eq!(check_diff("hi there", "hi", DiffGranularity::Chars), vec![
+            TextOperation::new_delete_with_content_range(2..8, " there".into())
+        ]);
+        assert_eq!(check_diff("abcd", "abXd", DiffGranularity::Chars), vec![
+            TextOperation::new_delete_with_content_range(2..3, "c".into()),
+            TextOperation::new_insert(2, "X"),
+        ]);
+        check_diff("a💖b", "💖c", DiffGranularity::Chars);
+    }
+
+    #[test]
+    fn diff_is_minimal() {
+        // "ABCABBA" -> "CBABAC" is the example from Myers' paper, with an edit distance of 5.
+        let ops = check_diff("ABCABBA", "CBABAC", DiffGranularity::Chars);
+        let edits: usize = ops.iter().map(|op| op.len()).sum();
+        assert_eq!(edits, 5);
+    }
+
+    #[test]
+    fn line_diffs() {
+        let old = "one\ntwo\nthree\n";
+        let new = "one\n2\nthree\nfour";
+        assert_eq!(check_diff(old, new, DiffGranularity::Lines), vec![
+            TextOperation::new_delete_with_content_range(4..8, "two\n".into()),
+            TextOperation::new_insert(4, "2\n"),
+            TextOperation::new_insert(12, "four"),
+        ]);
+        check_diff("", "a\nb\n", DiffGranularity::Lines);
+        check_diff("a\nb", "", DiffGranularity::Lines);
+        check_diff("x\n\n\ny", "\ny\n\nx", DiffGranularity::Lines);
+    }
+
+    #[test]
+    fn random_diffs() {
+        let mut rng = SmallRng::seed_from_u64(321);
+        for _ in 0..300 {
+            let old = random_str(rng.gen_range(0..30), &mut rng);
+            let mut new = old.clone();
+            for _ in 0..rng.gen_range(0..5) {
+                let chars: Vec<char> = new.chars().collect();
+                let pos = rng.gen_range(0..=chars.len());
+                let end = rng.gen_range(pos..=chars.len().min(pos + 5));
+                let mut s: String = chars[..pos].iter().collect();
+                if rng.gen_bool(0.5) {
+                    s.push_str(&random_str(rng.gen_range(1..5), &mut rng));
+                    if rng.gen_bool(0.3) { s.push('\n'); }
+                }
+                s.extend(chars[end..].iter());
+                new = s;
+            }
+
+            check_diff(&old, &new, DiffGranularity::Chars);
+            check_diff(&old, &new, DiffGranularity::Lines);
+        }
+    }
+
+    #[test]
+    fn set_text_content_in_oplog() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mike = oplog.cg.get_or_create_agent_id("mike");
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::NewCRDT(CRDTKind::Text));
+        let v1 = oplog.set_text_content_at(seph, text, &[text], "", "hello world", DiffGranularity::Chars).unwrap();
+        assert_eq!(oplog.set_text_content_at(seph, text, &[v1], "hello world", "hello world", DiffGranularity::Chars), None);
+
+        // Two concurrent edits from the same version are merged.
+        let a = oplog.set_text_content_at(seph, text, &[v1], "hello world", "hi world", DiffGranularity::Chars).unwrap();
+        let b = oplog.set_text_content_at(mike, text, &[v1], "hello world", "hello world!", DiffGranularity::Chars).unwrap();
+        assert_eq!(oplog.checkout_text_at(text, &[a]).to_string(), "hi world");
+        assert_eq!(oplog.checkout_text_at(text, &[b]).to_string(), "hello world!");
+        assert_eq!(oplog.checkout_text(text).to_string(), "hi world!");
+    }
+}
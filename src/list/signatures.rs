@@ -0,0 +1,387 @@
+//! Signed operations.
+//!
+//! Normally anyone can create operations under any agent name, so in a multi-tenant deployment any
+//! peer can forge edits from any other user. This module adds an optional signing layer on top of
+//! the oplog.
+//!
+//! Each agent's operations are grouped into runs (ranges of sequence numbers), and each run carries
+//! a signature over the [content-addressed hash](crate::causalgraph::hash) of every operation in
+//! the run. Operation hashes cover the operation's content and parents - so the signature commits
+//! to exactly what the agent wrote, and where they wrote it.
+//!
+//! Signatures are stored in the oplog and saved alongside the operations when the oplog is encoded.
+//! If the oplog has a [`SignatureVerifier`], [`decode_and_add`](ListOpLog::decode_and_add) and
+//! [`merge_ops`](ListOpLog::merge_ops) will refuse to add operations which aren't covered by a
+//! valid signature from their agent.
+//!
+//! The signature scheme is pluggable. Ed25519 keys are supported via the `ed25519` feature.
+
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::sync::Arc;
+use rle::HasLength;
+use crate::{AgentId, DTRange};
+use crate::encoding::parseerror::ParseError;
+use crate::list::ListOpLog;
+use crate::rle::KVPair;
+
+#[cfg(feature = "ed25519")]
+pub use ed25519::AgentKeys;
+
+/// Something which can sign runs of operations on behalf of a (local) agent.
+pub trait OpSigner {
+    fn sign(&self, message: &[u8]) -> Vec<u8>;
+}
+
+/// Checks signatures on remote operations. Usually this will map agent names to public keys.
+pub trait SignatureVerifier: Debug + Send + Sync {
+    /// Returns true if `signature` is a valid signature of `message` by the named agent.
+    fn verify(&self, agent: &str, message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// A signature over a run of operations from a single agent.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct RunSignature {
+    pub agent: AgentId,
+    pub seq_range: DTRange,
+    pub signature: Vec<u8>,
+}
+
+/// Returns the signed sequence ranges for each agent. The ranges are sorted and merged.
+fn signed_ranges(sigs: &[RunSignature], num_agents: usize) -> Vec<Vec<DTRange>> {
+    let mut result = vec![Vec::new(); num_agents];
+    for s in sigs {
+        result[s.agent as usize].push(s.seq_range);
+    }
+
+    for ranges in result.iter_mut() {
+        ranges.sort_unstable_by_key(|r: &DTRange| r.start);
+        let mut merged: Vec<DTRange> = Vec::with_capacity(ranges.len());
+        for r in ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if r.start <= last.end => { last.end = last.end.max(r.end); }
+                _ => merged.push(r),
+            }
+        }
+        *ranges = merged;
+    }
+
+    result
+}
+
+fn is_covered(signed: &[DTRange], span: DTRange) -> bool {
+    let idx = signed.partition_point(|r| r.end <= span.start);
+    signed.get(idx).is_some_and(|r| r.start <= span.start && r.end >= span.end)
+}
+
+/// Push the parts of span which aren't in signed to out.
+fn push_unsigned(span: DTRange, signed: &[DTRange], out: &mut Vec<DTRange>) {
+    let mut start = span.start;
+    for r in &signed[signed.partition_point(|r| r.end <= span.start)..] {
+        if r.start >= span.end { break; }
+        if r.start > start {
+            out.push((start..r.start).into());
+        }
+        start = start.max(r.end);
+    }
+    if start < span.end {
+        out.push((start..span.end).into());
+    }
+}
+
+impl ListOpLog {
+    /// The message signed for a run of operations. Returns None if we don't have all the
+    /// operations in the run.
+    fn signature_message(&self, agent: AgentId, seq_range: DTRange) -> Option<Vec<u8>> {
+        let client = &self.cg.agent_assignment.client_data[agent as usize];
+
+        let mut message = Vec::new();
+        message.extend_from_slice(b"dt-sig\0");
+        message.extend_from_slice(&(client.name.len() as u64).to_le_bytes());
+        message.extend_from_slice(client.name.as_bytes());
+        message.extend_from_slice(&(seq_range.start as u64).to_le_bytes());
+        message.extend_from_slice(&(seq_range.end as u64).to_le_bytes());
+
+        let mut seq = seq_range.start;
+        while seq < seq_range.end {
+            let lvs = client.try_seq_to_lv_span((seq..seq_range.end).into())?;
+            for hash in self.item_hashes_in(lvs) {
+                message.extend_from_slice(hash.as_bytes());
+            }
+            seq += lvs.len();
+        }
+
+        Some(message)
+    }
+
+    /// Sign all of the named agent's operations which haven't been signed yet. Usually this is
+    /// called after making local changes, before sending them to remote peers.
+    pub fn sign_ops(&mut self, agent: AgentId, signer: &dyn OpSigner) {
+        let client = &self.cg.agent_assignment.client_data[agent as usize];
+        let signed = signed_ranges(&self.signatures, agent as usize + 1).swap_remove(agent as usize);
+
+        let mut known: Vec<DTRange> = Vec::new();
+        for KVPair(seq, lvs) in client.item_times.iter() {
+            let span: DTRange = (*seq..*seq + lvs.len()).into();
+            match known.last_mut() {
+                Some(last) if last.end == span.start => { last.end = span.end; }
+                _ => known.push(span),
+            }
+        }
+
+        let mut unsigned = Vec::new();
+        for span in known {
+            push_unsigned(span, &signed, &mut unsigned);
+        }
+        if unsigned.is_empty() { return; }
+
+        for seq_range in unsigned {
+            let message = self.signature_message(agent, seq_range).unwrap();
+            self.signatures.push(RunSignature {
+                agent,
+                seq_range,
+                signature: signer.sign(&message),
+            });
+        }
+    }
+
+    /// Set (or clear) the verifier used to check signatures on incoming operations. When set,
+    /// [`decode_and_add`](ListOpLog::decode_and_add) and [`merge_ops`](ListOpLog::merge_ops) will
+    /// return [`ParseError::InvalidSignature`] if any new operation isn't covered by a valid
+    /// signature.
+    ///
+    /// Local changes (eg from [`add_insert`](ListOpLog::add_insert)) are never checked.
+    pub fn set_signature_verifier(&mut self, verifier: Option<Arc<dyn SignatureVerifier>>) {
+        self.signature_verifier = verifier;
+    }
+
+    /// Check every signature in the oplog, and make sure every operation is signed.
+    pub fn verify_signatures(&self, verifier: &dyn SignatureVerifier) -> Result<(), ParseError> {
+        self.check_signatures(verifier, &[(0..self.len()).into()], 0)
+    }
+
+    /// Check that every operation in spans is covered by a signature. Signatures from index
+    /// `unverified_from` onwards are checked with the verifier. Earlier signatures are trusted.
+    pub(crate) fn check_signatures(&self, verifier: &dyn SignatureVerifier, spans: &[DTRange], unverified_from: usize) -> Result<(), ParseError> {
+        let unverified = &self.signatures[unverified_from..];
+        if !unverified.is_empty() {
+            for sig in unverified {
+                let name = self.cg.agent_assignment.get_agent_name(sig.agent);
+                let valid = self.signature_message(sig.agent, sig.seq_range)
+                    .is_some_and(|message| verifier.verify(name, &message, &sig.signature));
+
+                if !valid { return Err(ParseError::InvalidSignature); }
+            }
+        }
+
+        let signed = signed_ranges(&self.signatures, self.cg.agent_assignment.client_data.len());
+        for &span in spans {
+            if span.is_empty() { continue; }
+            for KVPair(_, agent_span) in self.cg.agent_assignment.client_with_localtime.iter_range_ctx(span, &()) {
+                if !is_covered(&signed[agent_span.agent as usize], agent_span.seq_range) {
+                    return Err(ParseError::InvalidSignature);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add signatures to the oplog, skipping any runs which have already been signed.
+    pub(crate) fn add_signatures<I: IntoIterator<Item = RunSignature>>(&mut self, sigs: I) {
+        let mut existing: HashSet<(AgentId, usize, usize)> = self.signatures.iter()
+            .map(|s| (s.agent, s.seq_range.start, s.seq_range.end))
+            .collect();
+
+        for sig in sigs {
+            if existing.insert((sig.agent, sig.seq_range.start, sig.seq_range.end)) {
+                self.signatures.push(sig);
+            }
+        }
+    }
+
+    /// Returns true if any of the operations in the signed run are in the passed (sorted) spans.
+    pub(crate) fn signature_overlaps(&self, sig: &RunSignature, spans: &[DTRange]) -> bool {
+        let client = &self.cg.agent_assignment.client_data[sig.agent as usize];
+        let mut seq = sig.seq_range.start;
+        while seq < sig.seq_range.end {
+            let Some(lvs) = client.try_seq_to_lv_span((seq..sig.seq_range.end).into()) else {
+                return false;
+            };
+            let idx = spans.partition_point(|s| s.end <= lvs.start);
+            if spans.get(idx).is_some_and(|s| s.start < lvs.end) {
+                return true;
+            }
+            seq += lvs.len();
+        }
+        false
+    }
+}
+
+#[cfg(feature = "ed25519")]
+mod ed25519 {
+    use std::collections::HashMap;
+    use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+    use smartstring::alias::String as SmartString;
+    use crate::list::signatures::{OpSigner, SignatureVerifier};
+
+    impl OpSigner for SigningKey {
+        fn sign(&self, message: &[u8]) -> Vec<u8> {
+            Signer::sign(self, message).to_bytes().to_vec()
+        }
+    }
+
+    /// A map from agent names to their Ed25519 public keys. Operations from agents without a
+    /// registered key are rejected.
+    #[derive(Debug, Clone, Default)]
+    pub struct AgentKeys(HashMap<SmartString, VerifyingKey>);
+
+    impl AgentKeys {
+        pub fn new() -> Self { Self::default() }
+
+        pub fn insert(&mut self, agent: &str, key: VerifyingKey) {
+            self.0.insert(agent.into(), key);
+        }
+
+        pub fn get(&self, agent: &str) -> Option<&VerifyingKey> {
+            self.0.get(agent)
+        }
+    }
+
+    impl SignatureVerifier for AgentKeys {
+        fn verify(&self, agent: &str, message: &[u8], signature: &[u8]) -> bool {
+            let (Some(key), Ok(signature)) = (self.0.get(agent), Signature::from_slice(signature)) else {
+                return false;
+            };
+            key.verify_strict(message, &signature).is_ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::ListOpLog;
+    use crate::list::signatures::{OpSigner, SignatureVerifier};
+    use sha2::{Digest, Sha256};
+
+    // A toy signature scheme so we can test without any real crypto. Each agent's "key" is just
+    // their name.
+    struct TestSigner(&'static str);
+
+    impl OpSigner for TestSigner {
+        fn sign(&self, message: &[u8]) -> Vec<u8> {
+            let mut data = self.0.as_bytes().to_vec();
+            data.extend_from_slice(message);
+            Sha256::digest(&data).to_vec()
+        }
+    }
+
+    #[derive(Debug)]
+    struct TestVerifier;
+
+    impl SignatureVerifier for TestVerifier {
+        fn verify(&self, agent: &str, message: &[u8], signature: &[u8]) -> bool {
+            TestSigner(if agent == "seph" { "seph" } else { "mike" }).sign(message) == signature
+        }
+    }
+
+    fn verifying_oplog() -> ListOpLog {
+        let mut oplog = ListOpLog::new();
+        oplog.set_signature_verifier(Some(Arc::new(TestVerifier)));
+        oplog
+    }
+
+    #[test]
+    fn signed_ops_round_trip() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+        oplog.sign_ops(seph, &TestSigner("seph"));
+        oplog.add_delete_without_content(seph, 0..3);
+        oplog.sign_ops(seph, &TestSigner("seph"));
+        assert_eq!(oplog.signatures.len(), 2);
+        oplog.verify_signatures(&TestVerifier).unwrap();
+
+        let data = oplog.encode(ENCODE_FULL);
+        let mut loaded = verifying_oplog();
+        loaded.decode_and_add(&data).unwrap();
+        assert_eq!(loaded, oplog);
+        assert_eq!(loaded.signatures, oplog.signatures);
+
+        let mut merged = verifying_oplog();
+        merged.merge_ops(&oplog).unwrap();
+        assert_eq!(merged, oplog);
+
+        // Patches only carry the signatures they need.
+        let v = oplog.cg.version.clone();
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(mike, 0, "yo");
+        oplog.sign_ops(mike, &TestSigner("mike"));
+        let patch = oplog.encode_from(ENCODE_FULL, v.as_ref());
+        loaded.decode_and_add(&patch).unwrap();
+        assert_eq!(loaded, oplog);
+        assert_eq!(loaded.signatures.len(), 3);
+    }
+
+    #[test]
+    fn unsigned_or_forged_ops_are_rejected() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi");
+        oplog.sign_ops(seph, &TestSigner("seph"));
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(mike, 0, "yo");
+
+        // mike's operation is unsigned.
+        let mut loaded = verifying_oplog();
+        assert_eq!(loaded.decode_and_add(&oplog.encode(ENCODE_FULL)), Err(ParseError::InvalidSignature));
+        assert!(loaded.is_empty());
+        assert_eq!(loaded.merge_ops(&oplog), Err(ParseError::InvalidSignature));
+        assert!(loaded.is_empty());
+
+        // Signed by the wrong key.
+        oplog.sign_ops(mike, &TestSigner("seph"));
+        assert_eq!(loaded.decode_and_add(&oplog.encode(ENCODE_FULL)), Err(ParseError::InvalidSignature));
+        assert!(loaded.is_empty());
+        assert!(loaded.signatures.is_empty());
+
+        // But without a verifier, everything is accepted.
+        let mut unchecked = ListOpLog::new();
+        unchecked.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(unchecked, oplog);
+        assert_eq!(unchecked.verify_signatures(&TestVerifier), Err(ParseError::InvalidSignature));
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn ed25519_keys() {
+        use ed25519_dalek::SigningKey;
+        use crate::list::signatures::AgentKeys;
+
+        let seph_key = SigningKey::from_bytes(&[1; 32]);
+        let mike_key = SigningKey::from_bytes(&[2; 32]);
+        let mut keys = AgentKeys::new();
+        keys.insert("seph", seph_key.verifying_key());
+        keys.insert("mike", mike_key.verifying_key());
+
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hi");
+        oplog.sign_ops(seph, &seph_key);
+        oplog.add_insert(mike, 2, " there");
+        oplog.sign_ops(mike, &seph_key); // Whoops!
+
+        let mut loaded = ListOpLog::new();
+        loaded.set_signature_verifier(Some(Arc::new(keys.clone())));
+        assert_eq!(loaded.decode_and_add(&oplog.encode(ENCODE_FULL)), Err(ParseError::InvalidSignature));
+
+        oplog.signatures.pop();
+        oplog.sign_ops(mike, &mike_key);
+        loaded.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+        loaded.verify_signatures(&keys).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! Statistics about the size and shape of an oplog. This is useful for understanding where the
+//! bytes in a .dt file go, and how well different kinds of editing histories compress.
+//!
+//! Unlike [`ListOpLog::print_stats`], these are returned as data so they can be compared or
+//! exported (with the serde feature enabled, [`OpLogStats`] serializes to JSON).
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use rle::HasLength;
+use crate::{Frontier, LV};
+use crate::encoding::parseerror::ParseError;
+use crate::list::ListOpLog;
+use crate::list::encoding::EncodeOptions;
+use crate::list::operation::ListOpKind;
+
+/// The size of a chunk in an encoded file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ChunkStats {
+    /// The type of chunk, eg `"Patches"` or `"OpParents"`.
+    pub chunk: String,
+    /// The size of the chunk's contents in bytes. This doesn't include the chunk's header.
+    pub bytes: usize,
+    /// Chunks nested inside this chunk, for chunks which contain other chunks.
+    pub children: Vec<ChunkStats>,
+}
+
+/// How well one of the oplog's run-length encoded columns is compressing.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ColumnStats {
+    pub name: &'static str,
+    /// The number of items (usually operations) stored in the column.
+    pub items: usize,
+    /// The number of runs used to store those items.
+    pub runs: usize,
+    /// Average number of items in each run. Higher is better.
+    pub items_per_run: f64,
+}
+
+impl ColumnStats {
+    fn new(name: &'static str, items: usize, runs: usize) -> Self {
+        Self {
+            name, items, runs,
+            items_per_run: if runs == 0 { 0.0 } else { items as f64 / runs as f64 },
+        }
+    }
+}
+
+/// Stats about either the inserts or the deletes in an oplog.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct OpKindStats {
+    /// Runs which contain a single operation.
+    pub single_runs: usize,
+    /// Runs of operations moving forwards (eg typing).
+    pub fwd_runs: usize,
+    /// Runs of operations moving backwards (eg holding backspace).
+    pub rev_runs: usize,
+    /// The total number of characters inserted or deleted.
+    pub chars: usize,
+    /// The number of bytes of inserted or deleted content stored in the oplog. Deleted content is
+    /// usually not stored.
+    pub content_bytes: usize,
+}
+
+/// How much concurrent editing happened in the document.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ConcurrencyStats {
+    /// The largest number of concurrent branches at any point in time.
+    pub max_width: usize,
+    /// The number of operations which merge several branches.
+    pub merges: usize,
+    /// How the number of concurrent branches changed over time. Each entry `(v, width)` means
+    /// that once version `v` was added, the document had `width` concurrent branches (until the
+    /// next entry).
+    pub width_over_time: Vec<(LV, usize)>,
+}
+
+/// A report on the size and shape of an oplog. See [`ListOpLog::stats`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct OpLogStats {
+    pub num_ops: usize,
+    pub num_agents: usize,
+    /// The number of runs of operations in the causal graph.
+    pub cg_entries: usize,
+    pub ins: OpKindStats,
+    pub del: OpKindStats,
+    pub concurrency: ConcurrencyStats,
+    /// The in-memory columns of the oplog.
+    pub columns: Vec<ColumnStats>,
+    /// The total size of the encoded oplog, in bytes.
+    pub encoded_bytes: usize,
+    /// The chunks in the encoded oplog.
+    pub chunks: Vec<ChunkStats>,
+}
+
+impl ListOpLog {
+    fn concurrency_stats(&self) -> ConcurrencyStats {
+        let mut stats = ConcurrencyStats::default();
+        let mut frontier = Frontier::root();
+
+        for e in self.cg.graph.iter() {
+            if e.parents.len() >= 2 { stats.merges += 1; }
+
+            frontier.advance_by_known_run(e.parents.as_ref(), e.span);
+            let width = frontier.len();
+            if stats.width_over_time.last().is_none_or(|(_, w)| *w != width) {
+                stats.width_over_time.push((e.span.start, width));
+            }
+            stats.max_width = stats.max_width.max(width);
+        }
+        stats
+    }
+
+    /// Gather stats about the oplog. The chunk sizes are measured by encoding the oplog with the
+    /// default options.
+    pub fn stats(&self) -> OpLogStats {
+        let data = self.encode(EncodeOptions::default());
+        self.stats_for_encoded(&data).unwrap()
+    }
+
+    /// Gather stats about the oplog, measuring chunk sizes from `data`. This should be the encoded
+    /// oplog (eg the file the oplog was loaded from).
+    pub fn stats_for_encoded(&self, data: &[u8]) -> Result<OpLogStats, ParseError> {
+        let mut ins = OpKindStats {
+            content_bytes: self.operation_ctx.ins_content.len(),
+            ..Default::default()
+        };
+        let mut del = OpKindStats {
+            content_bytes: self.operation_ctx.del_content.len(),
+            ..Default::default()
+        };
+
+        for op in self.operations.iter_merged() {
+            let stats = match op.1.kind {
+                ListOpKind::Ins => &mut ins,
+                ListOpKind::Del => &mut del,
+            };
+            match (op.len(), op.1.loc.fwd) {
+                (1, _) => stats.single_runs += 1,
+                (_, true) => stats.fwd_runs += 1,
+                (_, false) => stats.rev_runs += 1,
+            }
+            stats.chars += op.len();
+        }
+
+        Ok(OpLogStats {
+            num_ops: self.len(),
+            num_agents: self.cg.agent_assignment.client_data.len(),
+            cg_entries: self.cg.graph.entries.num_entries(),
+            ins,
+            del,
+            concurrency: self.concurrency_stats(),
+            columns: vec![
+                ColumnStats::new("operations", self.len(), self.operations.num_entries()),
+                ColumnStats::new("causal_graph", self.len(), self.cg.graph.entries.num_entries()),
+                ColumnStats::new("agent_assignment", self.len(), self.cg.agent_assignment.client_with_localtime.num_entries()),
+            ],
+            encoded_bytes: data.len(),
+            chunks: Self::encoded_chunk_stats(data)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::encoding::EncodeOptions;
+    use crate::list::ListOpLog;
+
+    #[test]
+    fn stats_smoke() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let a = oplog.add_insert_at(seph, &[], 0, "hello");
+        let b = oplog.add_insert_at(mike, &[], 0, "yo");
+        let c = oplog.add_delete_at(seph, &[a], 0..2);
+        oplog.add_insert_at(mike, &[b, c], 0, "x");
+
+        let stats = oplog.stats();
+        assert_eq!(stats.num_ops, 10);
+        assert_eq!(stats.num_agents, 2);
+        assert_eq!(stats.ins.chars, 8);
+        assert_eq!(stats.ins.single_runs, 1);
+        assert_eq!(stats.del.chars, 2);
+        assert_eq!(stats.concurrency.max_width, 2);
+        assert_eq!(stats.concurrency.merges, 1);
+        assert_eq!(stats.concurrency.width_over_time, vec![(0, 1), (5, 2), (9, 1)]);
+
+        let data = oplog.encode(EncodeOptions::default());
+        assert_eq!(stats.encoded_bytes, data.len());
+        let chunk_bytes: usize = stats.chunks.iter().map(|c| c.bytes).sum();
+        assert!(chunk_bytes < data.len());
+        let patches = stats.chunks.iter().find(|c| c.chunk == "Patches").unwrap();
+        assert!(patches.children.iter().any(|c| c.chunk == "OpParents"));
+    }
+
+    #[test]
+    fn stats_of_empty_oplog() {
+        let stats = ListOpLog::new().stats();
+        assert_eq!(stats.num_ops, 0);
+        assert_eq!(stats.concurrency.max_width, 0);
+        assert!(stats.concurrency.width_over_time.is_empty());
+    }
+}
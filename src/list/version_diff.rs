@@ -0,0 +1,221 @@
+//! This file contains code to compute the difference between two arbitrary versions of a document,
+//! expressed as a list of operations in document coordinates.
+//!
+//! Every character in a document is uniquely identified by the LV of the operation which inserted
+//! it, and the relative order of any two characters is the same in every version where they both
+//! appear. So to diff two versions we replay the transformed operations for each version, tracking
+//! the ID of every character, then walk both lists of IDs side by side.
+
+use std::pin::Pin;
+use content_tree::{ContentTreeRaw, RawPositionMetricsUsize};
+use jumprope::JumpRopeBuf;
+use rle::{AppendRle, HasLength};
+use crate::list::ListOpLog;
+use crate::list::operation::{ListOpKind, MissingContentError, TextOperation};
+use crate::listmerge::merge::reverse_str;
+use crate::rev_range::RangeRev;
+use crate::LV;
+
+impl ListOpLog {
+    /// Check out the document at the specified version, also returning the LV of the insert which
+    /// created each character (in document order).
+    fn checkout_with_ids(&self, version: &[LV]) -> Result<(JumpRopeBuf, Vec<LV>), MissingContentError> {
+        let mut content = JumpRopeBuf::new();
+        let mut ids: Pin<Box<ContentTreeRaw<RangeRev, RawPositionMetricsUsize>>> = ContentTreeRaw::new();
+
+        for (lv, op) in self.iter_xf_operations_from(&[], version) {
+            let Some(op) = op else { continue; };
+            let pos = op.start();
+
+            match op.kind {
+                ListOpKind::Ins => {
+                    let s = op.content_as_str().ok_or(MissingContentError)?;
+                    if op.loc.fwd {
+                        content.insert(pos, s);
+                    } else {
+                        content.insert(pos, &reverse_str(s));
+                    }
+                    ids.insert_at_offset(pos, RangeRev { span: lv, fwd: op.loc.fwd });
+                }
+                ListOpKind::Del => {
+                    content.remove(pos..pos + op.len());
+                    ids.delete_at_offset(pos, op.len());
+                }
+            }
+        }
+
+        let ids = ids.raw_iter()
+            .flat_map(|r| (0..r.len()).map(move |i| r.time_at_offset(i)))
+            .collect();
+
+        Ok((content, ids))
+    }
+
+    /// Returns a list of operations which, when applied in order, transform the document at
+    /// version `a` into the document at version `b`.
+    ///
+    /// This works for any two versions - neither version needs to be an ancestor of the other.
+    /// The returned operations are in document coordinates and include the inserted and deleted
+    /// content, so they can be applied directly to a checkout at `a`.
+    ///
+    /// The diff is computed based on the *identity* of each character rather than the textual
+    /// content. If a character was deleted and then the same character retyped, this will show up
+    /// as a delete followed by an insert.
+    ///
+    /// Returns an error if the oplog doesn't store the content of some insert in either version.
+    pub fn diff_versions(&self, a: &[LV], b: &[LV]) -> Result<Vec<TextOperation>, MissingContentError> {
+        let mut result: Vec<TextOperation> = vec![];
+        if self.cg.graph.frontier_contains_frontier(a, b) && self.cg.graph.frontier_contains_frontier(b, a) {
+            return Ok(result);
+        }
+
+        let (content_a, ids_a) = self.checkout_with_ids(a)?;
+        let (content_b, ids_b) = self.checkout_with_ids(b)?;
+        let chars_a: Vec<char> = content_a.to_string().chars().collect();
+        let chars_b: Vec<char> = content_b.to_string().chars().collect();
+
+        let mut sorted_b = ids_b.clone();
+        sorted_b.sort_unstable();
+
+        // Position in the document as it is being transformed from a to b.
+        let mut pos = 0;
+        let mut i = 0;
+        let mut j = 0;
+
+        while i < ids_a.len() || j < ids_b.len() {
+            if i < ids_a.len() && j < ids_b.len() && ids_a[i] == ids_b[j] {
+                // This character exists in both versions.
+                pos += 1;
+                i += 1;
+                j += 1;
+            } else if i < ids_a.len() && sorted_b.binary_search(&ids_a[i]).is_err() {
+                let mut s = smartstring::alias::String::new();
+                s.push(chars_a[i]);
+                result.push_rle(TextOperation::new_delete_with_content(pos, s));
+                i += 1;
+            } else {
+                // Because characters have a consistent order in every version, the next character
+                // in b must be missing from a.
+                let mut s = [0u8; 4];
+                result.push_rle(TextOperation::new_insert(pos, chars_b[j].encode_utf8(&mut s)));
+                pos += 1;
+                j += 1;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::ListOpLog;
+    use crate::list::operation::{ListOpKind, MissingContentError, TextOperation};
+    use crate::{Frontier, LV};
+
+    fn apply_ops(oplog: &ListOpLog, from: &[LV], ops: &[TextOperation]) -> String {
+        let mut content = oplog.checkout(from).content().borrow().clone();
+        for op in ops {
+            match op.kind {
+                ListOpKind::Ins => content.insert(op.start(), op.content_as_str().unwrap()),
+                ListOpKind::Del => content.remove(op.start()..op.end()),
+            }
+        }
+        content.to_string()
+    }
+
+    fn check_diff(oplog: &ListOpLog, a: &[LV], b: &[LV]) -> Vec<TextOperation> {
+        let ops = oplog.diff_versions(a, b).unwrap();
+        assert_eq!(apply_ops(oplog, a, &ops), oplog.checkout(b).content().to_string());
+        ops
+    }
+
+    #[test]
+    fn diff_linear() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v1 = oplog.add_insert(seph, 0, "hello world");
+        let v2 = oplog.add_delete_without_content(seph, 0..6);
+        let v3 = oplog.add_insert(seph, 5, "!!");
+
+        assert!(check_diff(&oplog, &[v1], &[v1]).is_empty());
+        assert_eq!(check_diff(&oplog, &[v1], &[v2]), vec![
+            TextOperation::new_delete_with_content(0, "hello ".into())
+        ]);
+        assert_eq!(check_diff(&oplog, &[v2], &[v1]), vec![
+            TextOperation::new_insert(0, "hello ")
+        ]);
+        check_diff(&oplog, &[v1], &[v3]);
+        check_diff(&oplog, &[v3], &[]);
+        check_diff(&oplog, &[], &[v3]);
+    }
+
+    #[test]
+    fn diff_concurrent() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "abcdefgh");
+
+        let a = oplog.add_delete_at(seph, &[base], 2..5);
+        let a = oplog.add_insert_at(seph, &[a], 0, "AA");
+        let b = oplog.add_delete_at(mike, &[base], 3..7);
+        let b = oplog.add_insert_at(mike, &[b], 3, "B");
+
+        check_diff(&oplog, &[a], &[b]);
+        check_diff(&oplog, &[b], &[a]);
+        check_diff(&oplog, &[a], &[a, b]);
+        check_diff(&oplog, &[a, b], &[b]);
+    }
+
+    #[test]
+    fn diff_without_content_is_an_error() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v1 = oplog.add_operations(seph, &[TextOperation {
+            loc: (0..3).into(), kind: ListOpKind::Ins, content: None
+        }]);
+        let v2 = oplog.add_insert(seph, 3, "abc");
+
+        assert_eq!(oplog.diff_versions(&[v1], &[v2]), Err(MissingContentError));
+        assert_eq!(oplog.diff_versions(&[v2], &[v2]), Ok(vec![]));
+    }
+
+    #[test]
+    fn diff_random_versions() {
+        use rand::prelude::*;
+        use crate::list_fuzzer_tools::random_str;
+
+        let mut rng = SmallRng::seed_from_u64(123);
+        for _ in 0..20 {
+            let mut oplog = ListOpLog::new();
+            let agents = [oplog.get_or_create_agent_id("seph"), oplog.get_or_create_agent_id("mike")];
+            let mut versions = vec![Frontier::root()];
+
+            for _ in 0..30 {
+                // Make a random change on a branch at some random earlier version.
+                let v = versions.choose(&mut rng).unwrap().clone();
+                let mut branch = oplog.checkout(v.as_ref());
+                let agent = *agents.choose(&mut rng).unwrap();
+                let len = branch.len();
+
+                if len == 0 || rng.gen_bool(0.6) {
+                    let content = random_str(rng.gen_range(1..4), &mut rng);
+                    branch.insert(&mut oplog, agent, rng.gen_range(0..=len), &content);
+                } else {
+                    let start = rng.gen_range(0..len);
+                    let end = rng.gen_range(start + 1..=len.min(start + 4));
+                    branch.delete(&mut oplog, agent, start..end);
+                }
+                versions.push(branch.local_frontier());
+            }
+            versions.push(oplog.local_frontier());
+
+            for _ in 0..20 {
+                let a = versions.choose(&mut rng).unwrap();
+                let b = versions.choose(&mut rng).unwrap();
+                check_diff(&oplog, a.as_ref(), b.as_ref());
+            }
+        }
+    }
+}
@@ -579,6 +579,52 @@ impl M2Tracker {
 
         walker.into_frontier()
     }
+
+    /// Walk through a set of spans like [`walk`](M2Tracker::walk), but before applying each
+    /// operation check that it only names positions which exist in the document at that
+    /// operation's version, and that inserts have known content. Returns false (and stops) as soon
+    /// as an invalid operation is found.
+    ///
+    /// This only works on a fresh tracker replaying operations from ROOT, because everything in
+    /// the tracker when we start is assumed to be underwater.
+    pub(super) fn walk_checked(&mut self, graph: &Graph, aa: &AgentAssignment, op_ctx: &ListOperationCtx, ops: &RleVec<KVPair<ListOpMetrics>>, rev_spans: &[DTRange]) -> bool {
+        let underwater_len = self.range_tree.content_len();
+        let mut walker = SpanningTreeWalker::new(graph, rev_spans, Frontier::root());
+
+        for walk in &mut walker {
+            for range in walk.retreat {
+                self.retreat_by_range(range);
+            }
+
+            for range in walk.advance_rev.into_iter().rev() {
+                self.advance_by_range(range);
+            }
+
+            let mut iter = OpMetricsIter::new(ops, op_ctx, walk.consume);
+            while let Some(mut pair) = iter.next() {
+                loop {
+                    let span = aa.local_span_to_agent_span(pair.span());
+                    let remainder = pair.trim_ctx(span.len(), iter.ctx);
+
+                    let doc_len = self.range_tree.content_len() - underwater_len;
+                    let op = &pair.1;
+                    let valid = match op.kind {
+                        ListOpKind::Ins => op.loc.fwd && op.content_pos.is_some() && op.start() <= doc_len,
+                        ListOpKind::Del => op.end() <= doc_len,
+                    };
+                    if !valid { return false; }
+
+                    self.apply_to(aa, op_ctx, span.agent, &pair, None, None);
+
+                    if let Some(r) = remainder {
+                        pair = r;
+                    } else { break; }
+                }
+            }
+        }
+
+        true
+    }
 }
 
 #[derive(Debug)]
@@ -940,6 +986,18 @@ impl<'a> Iterator for TransformedOpsIter<'a> {
     }
 }
 
+impl ListOpLog {
+    /// Replay every operation in the oplog, checking that each operation is valid at its version.
+    /// Invalid operations (eg, deleting past the end of the document) would otherwise cause panics
+    /// when the document is checked out.
+    pub(crate) fn ops_are_valid(&self) -> bool {
+        let mut tracker = M2Tracker::new();
+        let rev_spans = self.cg.graph.diff_rev(&[], self.cg.version.as_ref()).1;
+        tracker.walk_checked(&self.cg.graph, &self.cg.agent_assignment, &self.operation_ctx,
+                             &self.operations, &rev_spans)
+    }
+}
+
 pub fn reverse_str(s: &str) -> SmartString {
     let mut result = SmartString::new();
     result.extend(s.chars().rev());
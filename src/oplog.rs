@@ -9,7 +9,7 @@ use serde::{Serialize, Serializer};
 
 use rle::{HasLength, SplitableSpanCtx};
 use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
-use crate::{AgentId, CRDTKind, CreateValue, DTRange, DTValue, OpLog, LV, LVKey, RegisterInfo, RegisterValue, ROOT_CRDT_ID, SerializedOps, ValPair};
+use crate::{AgentId, CRDTKind, CreateValue, DecodeLimits, DTRange, DTValue, OpLog, LV, LVKey, RegisterInfo, RegisterValue, ROOT_CRDT_ID, SerializedOps, ValPair};
 use crate::encoding::bufparser::BufParser;
 use crate::encoding::cg_entry::{read_cg_entry_into_cg, write_cg_entry_iter};
 use crate::encoding::map::{ReadMap, WriteMap};
@@ -260,7 +260,26 @@ impl OpLog {
         v
     }
 
-    // This function requires that the lv has already been added to the causal graph.
+    /// Like [`local_map_set`](OpLog::local_map_set), but the new operation has the named parents
+    /// instead of the oplog's current version. This is useful for importing edits made on other
+    /// branches.
+    pub fn local_map_set_at(&mut self, agent: AgentId, crdt: LVKey, parents: &[LV], key: &str, value: CreateValue) -> LV {
+        let v = self.cg.assign_local_op_with_parents(parents, agent, 1).start;
+        self.remote_map_set(crdt, v, key, value);
+        v
+    }
+
+    /// Like [`remote_map_set`](OpLog::remote_map_set), but returns [`ParseError::AccessDenied`]
+    /// (and does nothing) if the oplog's [access policy](OpLog::set_access_policy) rejects the
+    /// operation.
+    pub fn try_remote_map_set(&mut self, crdt: LVKey, v: LV, key: &str, value: CreateValue) -> Result<(), ParseError> {
+        self.check_map_set_access(crdt, v, key)?;
+        self.remote_map_set(crdt, v, key, value);
+        Ok(())
+    }
+
+    // This function requires that the lv has already been added to the causal graph. The access
+    // policy is not checked here. Use try_remote_map_set for that.
     pub fn remote_map_set(&mut self, crdt: LVKey, v: LV, key: &str, value: CreateValue) {
         if let CreateValue::NewCRDT(kind) = value {
             self.create_child_crdt(v, kind);
@@ -336,6 +355,25 @@ impl OpLog {
         v_range
     }
 
+    /// Like [`local_text_op`](OpLog::local_text_op), but the new operation has the named parents
+    /// instead of the oplog's current version. The operation's positions are relative to the
+    /// content of the text CRDT at `parents`.
+    pub fn local_text_op_at(&mut self, agent: AgentId, crdt: LVKey, parents: &[LV], op: TextOperation) -> DTRange {
+        let v_range = self.cg.assign_local_op_with_parents(parents, agent, op.len());
+        self.remote_text_op(crdt, v_range, op);
+        v_range
+    }
+
+    /// Like [`remote_text_op`](OpLog::remote_text_op), but returns [`ParseError::AccessDenied`]
+    /// (and does nothing) if the oplog's [access policy](OpLog::set_access_policy) rejects the
+    /// operation.
+    pub fn try_remote_text_op(&mut self, crdt: LVKey, v_range: DTRange, op: TextOperation) -> Result<(), ParseError> {
+        self.check_text_op_access(crdt, v_range)?;
+        self.remote_text_op(crdt, v_range, op);
+        Ok(())
+    }
+
+    // The access policy is not checked here. Use try_remote_text_op for that.
     pub fn remote_text_op(&mut self, crdt: LVKey, v_range: DTRange, op: TextOperation) {
         debug_assert_eq!(v_range.len(), op.len());
 
@@ -393,6 +431,15 @@ impl OpLog {
         result
     }
 
+    /// Check out the content of a text CRDT at some version of the oplog.
+    pub fn checkout_text_at(&self, crdt: LVKey, version: &[LV]) -> JumpRopeBuf {
+        let info = self.texts.get(&crdt).unwrap();
+
+        let mut result = JumpRopeBuf::new();
+        info.merge_into(&mut result, &self.cg, &[], version);
+        result
+    }
+
     pub fn checkout_map(&self, crdt: LVKey) -> BTreeMap<SmartString, Box<DTValue>> {
         let empty_str: SmartString = "".into();
         // dbg!((crdt, empty_str.clone())..(crdt, empty_str));
@@ -475,11 +522,19 @@ impl OpLog {
         }
     }
 
-    fn remote_to_crdt_name(&self, crdt_rv: RemoteVersion) -> LVKey {
+    pub(crate) fn remote_to_crdt_name(&self, crdt_rv: RemoteVersion) -> LVKey {
         if crdt_rv.0 == "ROOT" { ROOT_CRDT_ID }
         else { self.cg.agent_assignment.remote_to_local_version(crdt_rv) }
     }
 
+    pub(crate) fn try_remote_to_crdt_name(&self, crdt_rv: RemoteVersion) -> Result<LVKey, ParseError> {
+        if crdt_rv.0 == "ROOT" { Ok(ROOT_CRDT_ID) }
+        else {
+            self.cg.agent_assignment.try_remote_to_local_version(crdt_rv)
+                .map_err(ParseError::InvalidRemoteID)
+        }
+    }
+
     // pub fn xf_text_changes_since(&self, text_item: LVKey, since_frontier: &[LV]) {
     //     let crdt = self.texts.get(&text_item).unwrap();
     //
@@ -561,23 +616,51 @@ impl OpLog {
             map_ops,
             text_ops,
             text_context,
+            tags: self.tags.iter().cloned().collect(),
         }
     }
 
 
     pub fn merge_ops(&mut self, changes: SerializedOps) -> Result<DTRange, ParseError> {
-        let mut read_map = ReadMap::new();
+        self.merge_ops_with_limits(changes, DecodeLimits::default())
+    }
+
+    /// Merge serialized operations from a remote peer, failing with `ParseError::LimitExceeded`
+    /// if the changes are bigger than `limits` allows.
+    pub fn merge_ops_with_limits(&mut self, changes: SerializedOps, limits: DecodeLimits) -> Result<DTRange, ParseError> {
+        if changes.map_ops.len().saturating_add(changes.text_ops.len()) > limits.max_ops {
+            return Err(ParseError::LimitExceeded);
+        }
+
+        let mut read_map = ReadMap::with_limits(limits);
 
         let old_end = self.cg.len();
+        let num_known_agents = self.cg.agent_assignment.client_data.len();
+        let old_version = self.cg.version.clone();
 
         let mut buf = BufParser(&changes.cg_changes);
-        while !buf.is_empty() {
-            read_cg_entry_into_cg(&mut buf, true, &mut self.cg, &mut read_map)?;
+        let mut result = Ok(());
+        while !buf.is_empty() && result.is_ok() {
+            result = read_cg_entry_into_cg(&mut buf, true, &mut self.cg, &mut read_map).map(|_| ());
         }
 
         let new_end = self.cg.len();
         let new_range: DTRange = (old_end..new_end).into();
 
+        // Check everything before any operations are applied, so if anything is rejected we can
+        // just roll back the causal graph.
+        if result.is_ok() {
+            result = self.check_merge_access(&changes, new_range);
+        }
+        if let Err(e) = result {
+            self.cg.truncate_to(old_end, num_known_agents, old_version);
+            return Err(e);
+        }
+
+        for tag in changes.tags {
+            self.tags.merge_tag(tag);
+        }
+
         if new_range.is_empty() { return Ok(new_range); }
 
         for (crdt_r_name, rv, key, val) in changes.map_ops {
@@ -620,7 +703,8 @@ impl OpLog {
 mod tests {
     #[cfg(feature = "serde")]
     use serde::{Deserialize, Serialize};
-    use crate::{CRDTKind, CreateValue, OpLog, Primitive, ROOT_CRDT_ID, SerializedOps};
+    use crate::{CRDTKind, CreateValue, DecodeLimits, OpLog, Primitive, ROOT_CRDT_ID, SerializedOps};
+    use crate::encoding::parseerror::ParseError;
     use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
     use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
     use crate::list::operation::TextOperation;
@@ -671,6 +755,56 @@ mod tests {
         assert_eq!(oplog.checkout(), oplog_2.checkout());
     }
 
+    #[test]
+    fn concurrent_edits_at_version() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mike = oplog.cg.get_or_create_agent_id("mike");
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::NewCRDT(CRDTKind::Text));
+        let base = oplog.local_text_op(seph, text, TextOperation::new_insert(0, "abc")).last();
+
+        let a = oplog.set_text_content_at(seph, text, &[base], "abc", "aXbc", Default::default()).unwrap();
+        let b = oplog.local_text_op_at(mike, text, &[base], TextOperation::new_delete(2..3)).last();
+        let title = oplog.local_map_set_at(mike, ROOT_CRDT_ID, &[b], "title", CreateValue::Primitive(Primitive::I64(1)));
+        oplog.dbg_check(true);
+
+        assert_eq!(oplog.checkout_text_at(text, &[base]).to_string(), "abc");
+        assert_eq!(oplog.checkout_text_at(text, &[a]).to_string(), "aXbc");
+        assert_eq!(oplog.checkout_text_at(text, &[b]).to_string(), "ab");
+        assert_eq!(oplog.checkout_text_at(text, &[a, title]).to_string(), "aXb");
+        assert_eq!(oplog.checkout_text(text).to_string(), "aXb");
+    }
+
+    #[test]
+    fn merge_ops_respects_limits() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "Oh hai!"));
+
+        let mut dest = OpLog::new();
+        assert_eq!(dest.merge_ops_with_limits(oplog.ops_since(&[]), DecodeLimits {
+            max_ops: 7,
+            ..DecodeLimits::default()
+        }).unwrap_err(), ParseError::LimitExceeded);
+
+        let mut dest = OpLog::new();
+        assert_eq!(dest.merge_ops_with_limits(oplog.ops_since(&[]), DecodeLimits {
+            max_agent_name_len: 3,
+            ..DecodeLimits::default()
+        }).unwrap_err(), ParseError::LimitExceeded);
+
+        let mut dest = OpLog::new();
+        dest.merge_ops_with_limits(oplog.ops_since(&[]), DecodeLimits {
+            max_ops: 8,
+            max_agents: 1,
+            max_agent_name_len: 4,
+            max_parents: 1,
+            ..DecodeLimits::default()
+        }).unwrap();
+        assert_eq!(dest.checkout(), oplog.checkout());
+    }
+
     #[test]
     fn concurrent_changes() {
         let mut oplog1 = OpLog::new();
@@ -0,0 +1,281 @@
+//! Named tags on versions.
+//!
+//! A tag gives a name (like "v1" or "sent to legal") to a version of a document. Tags are stored
+//! in the oplog alongside the operations. They're saved when the oplog is encoded, and they're
+//! sent to remote peers with every patch and every set of serialized operations.
+//!
+//! Tags can be moved. Concurrent changes to the same tag are resolved with a last-writer-wins
+//! rule: every change to a tag is stamped with a lamport clock, and the change with the highest
+//! clock wins. Ties are broken in favour of the agent with the greater name.
+//!
+//! Tags point at a remote frontier, so a peer can receive a tag before it receives the operations
+//! the tag points at. In that case [`get_tag`](ListOpLog::get_tag) returns `None` until the
+//! operations arrive.
+
+use std::collections::BTreeMap;
+use smartstring::alias::String as SmartString;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::{AgentId, Frontier, LV, OpLog};
+use crate::causalgraph::agent_assignment::remote_ids::RemoteFrontierOwned;
+use crate::causalgraph::CausalGraph;
+use crate::list::ListOpLog;
+
+/// A named version.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tag {
+    pub name: SmartString,
+
+    /// The version the tag points to.
+    pub target: RemoteFrontierOwned,
+
+    /// The name of the agent which most recently set the tag.
+    pub set_by: SmartString,
+
+    /// Lamport clock used to order changes to the tag.
+    pub clock: u64,
+}
+
+impl Tag {
+    /// Returns true if this change to a tag should replace `other`.
+    fn wins_over(&self, other: &Tag) -> bool {
+        // The target is only compared so two peers which somehow set a tag with the same clock
+        // and agent name still converge.
+        let target_key = |t: &Tag| t.target.iter()
+            .map(|rv| (rv.0.clone(), rv.1))
+            .collect::<Vec<_>>();
+
+        (self.clock, &self.set_by).cmp(&(other.clock, &other.set_by))
+            .then_with(|| target_key(self).cmp(&target_key(other)))
+            .is_gt()
+    }
+}
+
+/// The set of tags in an oplog.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Tags(BTreeMap<SmartString, Tag>);
+
+impl Tags {
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        self.0.get(name)
+    }
+
+    /// Iterate through all tags, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = &Tag> {
+        self.0.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns true if merging the tag would modify self.
+    pub(crate) fn would_change(&self, tag: &Tag) -> bool {
+        self.0.get(&tag.name).is_none_or(|existing| tag.wins_over(existing))
+    }
+
+    /// Merge in a (possibly remote) change to a tag. Returns true if the tag was modified.
+    pub fn merge_tag(&mut self, tag: Tag) -> bool {
+        match self.0.get_mut(&tag.name) {
+            Some(existing) => {
+                if tag.wins_over(existing) {
+                    *existing = tag;
+                    true
+                } else { false }
+            }
+            None => {
+                self.0.insert(tag.name.clone(), tag);
+                true
+            }
+        }
+    }
+
+    /// Merge all the tags from other into self.
+    pub fn merge(&mut self, other: &Tags) {
+        for tag in other.iter() {
+            self.merge_tag(tag.clone());
+        }
+    }
+
+    /// Update tag targets when an agent's operations from from_seq onwards are renamed.
+    pub(crate) fn rename_versions(&mut self, agent: &str, from_seq: usize, new_name: &str) {
+        for tag in self.0.values_mut() {
+            for rv in tag.target.iter_mut() {
+                if rv.0 == agent && rv.1 >= from_seq {
+                    rv.0 = new_name.into();
+                    rv.1 -= from_seq;
+                }
+            }
+        }
+    }
+
+    fn set(&mut self, cg: &CausalGraph, agent: AgentId, name: &str, version: &[LV]) {
+        let clock = self.0.values().map(|t| t.clock).max().unwrap_or(0) + 1;
+        self.merge_tag(Tag {
+            name: name.into(),
+            target: cg.agent_assignment.local_to_remote_frontier_owned(version),
+            set_by: cg.agent_assignment.get_agent_name(agent).into(),
+            clock,
+        });
+    }
+
+    fn local_version(&self, cg: &CausalGraph, name: &str) -> Option<Frontier> {
+        let tag = self.get(name)?;
+        cg.agent_assignment.try_remote_to_local_frontier(tag.target.iter()).ok()
+    }
+}
+
+impl ListOpLog {
+    /// Set the named tag to point to the given version, replacing any existing tag with the same
+    /// name.
+    pub fn set_tag(&mut self, agent: AgentId, name: &str, version: &[LV]) {
+        self.tags.set(&self.cg, agent, name, version);
+    }
+
+    /// Get the version the named tag points to. Returns None if there is no such tag, or if the
+    /// oplog doesn't contain the operations the tag points to.
+    pub fn get_tag(&self, name: &str) -> Option<Frontier> {
+        self.tags.local_version(&self.cg, name)
+    }
+
+    pub fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    /// Merge tags from a remote peer into the oplog.
+    pub fn merge_tags(&mut self, tags: &Tags) {
+        self.tags.merge(tags);
+    }
+}
+
+impl OpLog {
+    /// Set the named tag to point to the given version, replacing any existing tag with the same
+    /// name.
+    pub fn set_tag(&mut self, agent: AgentId, name: &str, version: &[LV]) {
+        self.tags.set(&self.cg, agent, name, version);
+    }
+
+    /// Get the version the named tag points to. Returns None if there is no such tag, or if the
+    /// oplog doesn't contain the operations the tag points to.
+    pub fn get_tag(&self, name: &str) -> Option<Frontier> {
+        self.tags.local_version(&self.cg, name)
+    }
+
+    pub fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    /// Merge tags from a remote peer into the oplog.
+    pub fn merge_tags(&mut self, tags: &Tags) {
+        self.tags.merge(tags);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::ListOpLog;
+    use crate::{CreateValue, OpLog, Primitive, ROOT_CRDT_ID};
+
+    #[test]
+    fn tags_are_last_writer_wins() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi");
+        a.set_tag(seph, "v1", &[0]);
+        assert_eq!(a.get_tag("v1").unwrap().as_ref(), &[0]);
+        assert_eq!(a.get_tag("nope"), None);
+
+        let mut b = a.clone();
+        let tom = b.get_or_create_agent_id("tom");
+        b.add_insert(tom, 2, "!");
+
+        // Concurrent changes with the same clock. tom > seph, so tom's change wins.
+        a.set_tag(seph, "v1", &[1]);
+        b.set_tag(tom, "v1", &[2]);
+        assert_eq!(a.tags().get("v1").unwrap().clock, b.tags().get("v1").unwrap().clock);
+
+        let b_tags = b.tags().clone();
+        a.merge_tags(&b_tags);
+        // a doesn't have tom's operations yet.
+        assert_eq!(a.get_tag("v1"), None);
+        assert_eq!(a.tags().get("v1").unwrap().set_by, "tom");
+
+        b.merge_tags(&a.tags().clone());
+        assert_eq!(a.tags(), b.tags());
+
+        // A later change (with a higher clock) wins regardless of agent.
+        a.set_tag(seph, "v1", &[0]);
+        b.merge_tags(&a.tags().clone());
+        assert_eq!(b.get_tag("v1").unwrap().as_ref(), &[0]);
+    }
+
+    #[test]
+    fn tags_are_encoded_and_synced() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+        oplog.set_tag(seph, "draft", &[1]);
+        oplog.set_tag(seph, "final", &[7]);
+
+        let data = oplog.encode(ENCODE_FULL);
+        let loaded = ListOpLog::load_from(&data).unwrap();
+        assert_eq!(loaded.tags(), oplog.tags());
+        assert_eq!(loaded.get_tag("draft").unwrap().as_ref(), &[1]);
+
+        // Tags are included in patches too.
+        let mut other = loaded.clone();
+        let mike = other.get_or_create_agent_id("mike");
+        other.set_tag(mike, "final", &[3]);
+        let patch = other.encode_from(ENCODE_FULL, other.cg.version.as_ref());
+        oplog.decode_and_add(&patch).unwrap();
+        assert_eq!(oplog.get_tag("final").unwrap().as_ref(), &[3]);
+
+        // And merging oplogs directly.
+        let mut a = ListOpLog::new();
+        a.merge_ops(&oplog).unwrap();
+        assert_eq!(a.tags(), oplog.tags());
+    }
+
+    #[test]
+    fn oplog_tags_sync_with_ops() {
+        let mut a = OpLog::new();
+        let seph = a.cg.get_or_create_agent_id("seph");
+        let v = a.local_map_set(seph, ROOT_CRDT_ID, "x", CreateValue::Primitive(Primitive::I64(1)));
+        a.set_tag(seph, "release", &[v]);
+
+        let mut b = OpLog::new();
+        b.merge_ops(a.ops_since(&[])).unwrap();
+        assert_eq!(b.get_tag("release").unwrap().as_ref(), &[v]);
+
+        // Tags are sent even when there are no new operations.
+        let mike = b.cg.get_or_create_agent_id("mike");
+        b.set_tag(mike, "release", &[]);
+        a.merge_ops(b.ops_since(b.cg.version.as_ref())).unwrap();
+        assert_eq!(a.get_tag("release").unwrap().as_ref(), &[] as &[usize]);
+        assert_eq!(a.tags(), b.tags());
+    }
+
+    #[test]
+    #[cfg(all(feature = "serde", feature = "serde_json"))]
+    fn serialized_ops_without_tags() {
+        let mut a = OpLog::new();
+        let seph = a.cg.get_or_create_agent_id("seph");
+        a.local_map_set(seph, ROOT_CRDT_ID, "x", CreateValue::Primitive(Primitive::I64(1)));
+
+        // Operations serialized before tags existed don't have a tags field.
+        let mut json: serde_json::Value = serde_json::to_value(a.ops_since(&[])).unwrap();
+        json.as_object_mut().unwrap().remove("tags").unwrap();
+        let json = serde_json::to_string(&json).unwrap();
+
+        let mut b = OpLog::new();
+        b.merge_ops(serde_json::from_str(&json).unwrap()).unwrap();
+        assert_eq!(b.checkout(), a.checkout());
+        assert!(b.tags().is_empty());
+    }
+}